@@ -0,0 +1,334 @@
+use std::{
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use db_engine::{
+    Database, DatabaseBuilder, DumpSummary, FileKind, SSTableReader, SSTableReaderOptions,
+    WriteAheadLog,
+};
+
+use crate::{encoding::Encoding, Cli, Command};
+
+/// Keys read at once by `scan` and `export`, the most held in memory at once
+const PAGE_SIZE: usize = 1000;
+
+/// How a command went, besides an error
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Done,
+    NotFound,
+    /// `verify` or `inspect` found corrupted records
+    Corrupted,
+}
+
+/// Open the database of `cli.dir` and run the command, the output goes to `out`
+pub async fn run(cli: Cli, out: &mut impl Write) -> Result<Outcome> {
+    let encoding = cli.encoding();
+    if let Command::Inspect { file, raw } = &cli.command {
+        return inspect(file, *raw, out).await;
+    }
+    let Some(dir) = cli.dir else {
+        bail!("the --dir of the database is required by this command");
+    };
+    let mut db = DatabaseBuilder::new(dir.clone())
+        .build()
+        .await
+        .with_context(|| format!("open the database in {:?}", dir))?;
+
+    let outcome = match cli.command {
+        Command::Get { key } => match db.get(&encoding.decode(&key)?).await {
+            Some(entry) => {
+                out.write_all(&encoding.encode(&entry.value))?;
+                out.write_all(b"\n")?;
+                Outcome::Done
+            }
+            None => Outcome::NotFound,
+        },
+        Command::Set { key, value } => {
+            db.set(&encoding.decode(&key)?, &encoding.decode(&value)?)
+                .await?;
+            db.close().await?;
+            Outcome::Done
+        }
+        Command::Delete { key } => {
+            let key = encoding.decode(&key)?;
+            match db.get(&key).await {
+                Some(_) => {
+                    db.delete(&key).await?;
+                    db.close().await?;
+                    Outcome::Done
+                }
+                None => Outcome::NotFound,
+            }
+        }
+        Command::Scan { prefix, limit } => {
+            scan(&db, &encoding.decode(&prefix)?, limit, encoding, out).await?;
+            Outcome::Done
+        }
+        Command::Stats => {
+            let files = db.sstable_files().await?;
+            let stats = serde_json::json!({
+                "dir": db.dir(),
+                "database": db.stats(),
+                "sstable_files": files.len(),
+                "sstable_bytes": files.iter().map(|(_, size)| size).sum::<u64>(),
+            });
+            serde_json::to_writer_pretty(&mut *out, &stats)?;
+            out.write_all(b"\n")?;
+            Outcome::Done
+        }
+        Command::Compact { size_limit } => {
+            let report = db.compact(size_limit).await?;
+            serde_json::to_writer_pretty(&mut *out, &report)?;
+            out.write_all(b"\n")?;
+            Outcome::Done
+        }
+        Command::Export { out: path, prefix } => {
+            let file = std::fs::File::create(&path)
+                .with_context(|| format!("create export file {:?}", path))?;
+            let mut file = BufWriter::new(file);
+            let exported = export(&db, &encoding.decode(&prefix)?, &mut file).await?;
+            file.flush()?;
+            writeln!(out, "Exported {} entries to {:?}", exported, path)?;
+            Outcome::Done
+        }
+        Command::Verify => {
+            let report = db.verify().await?;
+            serde_json::to_writer_pretty(&mut *out, &report)?;
+            out.write_all(b"\n")?;
+            match report.is_ok() {
+                true => Outcome::Done,
+                false => Outcome::Corrupted,
+            }
+        }
+        Command::Inspect { .. } => unreachable!("inspect does not open the database"),
+    };
+    out.flush()?;
+    Ok(outcome)
+}
+
+/// Dump the SSTable or WAL `file`, without the database nor its lock. The dump is gathered in
+/// memory, a file is at most a few times the size of a MemTable.
+async fn inspect(file: &Path, raw: bool, out: &mut impl Write) -> Result<Outcome> {
+    let mut dump = Vec::new();
+    let summary: DumpSummary = match FileKind::detect(file).await? {
+        Some(FileKind::SSTable) => {
+            // the records are still reachable through an index rebuilt from them
+            let options = SSTableReaderOptions {
+                rebuild_corrupt_index: true,
+                ..SSTableReaderOptions::default()
+            };
+            let reader = SSTableReader::with_options(&file.to_path_buf(), options).await?;
+            reader.dump(&mut dump, raw).await?
+        }
+        Some(FileKind::Wal) => WriteAheadLog::dump(file, &mut dump, raw).await?,
+        None => bail!("{:?} is neither an SSTable nor a WAL file", file),
+    };
+    out.write_all(&dump)?;
+    out.flush()?;
+    Ok(match summary.corruptions {
+        0 => Outcome::Done,
+        _ => Outcome::Corrupted,
+    })
+}
+
+/// Print the first `limit` live pairs of `prefix`, a page of keys at a time
+async fn scan(
+    db: &Database,
+    prefix: &[u8],
+    mut limit: Option<usize>,
+    encoding: Encoding,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut start_after = None;
+    loop {
+        let (entries, next_start) = db
+            .scan_prefix_page(prefix, start_after.as_deref(), PAGE_SIZE)
+            .await?;
+        for entry in entries {
+            if limit == Some(0) {
+                return Ok(());
+            }
+            limit = limit.map(|limit| limit - 1);
+            out.write_all(&encoding.encode(&entry.key))?;
+            out.write_all(b"\t")?;
+            out.write_all(&encoding.encode(&entry.value))?;
+            out.write_all(b"\n")?;
+        }
+        start_after = match next_start {
+            Some(key) => Some(key),
+            None => return Ok(()),
+        };
+    }
+}
+
+/// Write the live entries of `prefix` as NDJSON, a [`db_engine::DbEntry`] in JSON per line
+/// with the key and value in base64. Returns how many.
+async fn export(db: &Database, prefix: &[u8], out: &mut impl Write) -> Result<u64> {
+    let (mut exported, mut start_after) = (0, None);
+    loop {
+        let (entries, next_start) = db
+            .scan_prefix_page(prefix, start_after.as_deref(), PAGE_SIZE)
+            .await?;
+        for entry in entries {
+            serde_json::to_writer(&mut *out, &entry)?;
+            out.write_all(b"\n")?;
+            exported += 1;
+        }
+        start_after = match next_start {
+            Some(key) => Some(key),
+            None => return Ok(exported),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use serde_json::Value;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    /// Run `db-cli --dir <dir> <args>`, returns the outcome and the output
+    async fn db_cli(dir: &TempDir, args: &[&str]) -> Result<(Outcome, String)> {
+        let dir = dir.path().to_str().unwrap();
+        let cli = Cli::try_parse_from(["db-cli", "--dir", dir].iter().chain(args))?;
+        let mut out = Vec::new();
+        let outcome = run(cli, &mut out).await?;
+        Ok((outcome, String::from_utf8(out)?))
+    }
+
+    #[tokio::test]
+    async fn it_runs_the_commands_on_the_directory() -> Result<()> {
+        let tmpdir = TempDir::new("db_cli")?;
+
+        db_cli(&tmpdir, &["set", "hello", "world"]).await?;
+        db_cli(&tmpdir, &["--hex", "set", "00ff", "cafe"]).await?;
+        assert_eq!(
+            db_cli(&tmpdir, &["get", "hello"]).await?,
+            (Outcome::Done, "world\n".into())
+        );
+        assert_eq!(
+            db_cli(&tmpdir, &["get", "--base64", "AP8="]).await?,
+            (Outcome::Done, "yv4=\n".into())
+        );
+        assert_eq!(
+            db_cli(&tmpdir, &["get", "missing"]).await?,
+            (Outcome::NotFound, "".into())
+        );
+        assert!(db_cli(&tmpdir, &["get", "--hex", "0g"]).await.is_err());
+
+        for i in 0..5 {
+            db_cli(&tmpdir, &["set", &format!("user:{}", i), "value"]).await?;
+        }
+        let (_, scan) = db_cli(&tmpdir, &["scan", "--prefix", "user:", "--limit", "3"]).await?;
+        assert_eq!(scan, "user:0\tvalue\nuser:1\tvalue\nuser:2\tvalue\n");
+        let (_, scan) = db_cli(&tmpdir, &["--hex", "scan", "--prefix", "00"]).await?;
+        assert_eq!(scan, "00ff\tcafe\n");
+
+        assert_eq!(
+            db_cli(&tmpdir, &["delete", "user:4"]).await?.0,
+            Outcome::Done
+        );
+        assert_eq!(
+            db_cli(&tmpdir, &["delete", "user:4"]).await?.0,
+            Outcome::NotFound
+        );
+
+        let export = tmpdir.path().join("export.ndjson");
+        let export_arg = export.to_str().unwrap();
+        db_cli(
+            &tmpdir,
+            &["export", "--out", export_arg, "--prefix", "user:"],
+        )
+        .await?;
+        let lines = std::fs::read_to_string(&export)?;
+        assert_eq!(lines.lines().count(), 4);
+        let first: Value = serde_json::from_str(lines.lines().next().unwrap())?;
+        assert_eq!(first["key"], "dXNlcjow");
+
+        let (_, stats) = db_cli(&tmpdir, &["stats"]).await?;
+        let stats: Value = serde_json::from_str(&stats)?;
+        assert!(stats["sstable_files"].as_u64().unwrap() > 1);
+        let (_, report) = db_cli(&tmpdir, &["compact"]).await?;
+        let report: Value = serde_json::from_str(&report)?;
+        assert_eq!(report["output_files"], 1);
+        let (outcome, report) = db_cli(&tmpdir, &["verify"]).await?;
+        assert_eq!(outcome, Outcome::Done);
+        let report: Value = serde_json::from_str(&report)?;
+        // the deleted key went with its tombstone
+        assert_eq!(report["entries"], 6);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_inspects_the_sstables_and_the_wal() -> Result<()> {
+        let tmpdir = TempDir::new("db_cli_inspect")?;
+        db_cli(&tmpdir, &["set", "flushed", "value"]).await?;
+        // left in the WAL
+        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .build()
+            .await?;
+        db.set(b"logged", b"value").await?;
+        db.delete(b"flushed").await?;
+        let (sstable, _) = db.sstable_files().await?.remove(0);
+        let wal = db.wal_path();
+        drop(db);
+
+        let inspect = |file: &Path| {
+            let cli = Cli::try_parse_from(["db-cli", "inspect", "--raw", file.to_str().unwrap()]);
+            async move {
+                let mut out = Vec::new();
+                let outcome = run(cli?, &mut out).await?;
+                anyhow::Ok((outcome, String::from_utf8(out)?))
+            }
+        };
+        let (outcome, dump) = inspect(&sstable).await?;
+        assert_eq!(outcome, Outcome::Done);
+        assert!(dump.starts_with("SSTable"));
+        assert!(dump.contains("key \"flushed\""));
+        assert!(dump.contains("76616c7565"));
+        let (outcome, dump) = inspect(&wal).await?;
+        assert_eq!(outcome, Outcome::Done);
+        assert!(dump.starts_with("WAL"));
+        assert!(dump.contains("key \"flushed\"") && dump.contains("tombstone"));
+        assert!(dump.ends_with("2 records, 0 corrupted\n"));
+
+        let other = tmpdir.path().join("notes.txt");
+        std::fs::write(&other, "not a database file")?;
+        assert!(inspect(&other).await.is_err());
+        // the other commands need the database
+        assert!(
+            run(Cli::try_parse_from(["db-cli", "stats"])?, &mut Vec::new())
+                .await
+                .is_err()
+        );
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_refuses_a_directory_in_use() -> Result<()> {
+        let tmpdir = TempDir::new("db_cli_locked")?;
+        let server = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .build()
+            .await?;
+
+        let err = db_cli(&tmpdir, &["get", "hello"]).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("already open"));
+
+        drop(server);
+        assert_eq!(
+            db_cli(&tmpdir, &["get", "hello"]).await?.0,
+            Outcome::NotFound
+        );
+        tmpdir.close()?;
+        Ok(())
+    }
+}
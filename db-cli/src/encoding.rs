@@ -0,0 +1,50 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// How the keys and values are written on the command line and in the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// As they are, for text
+    #[default]
+    Raw,
+    Hex,
+    Base64,
+}
+
+impl Encoding {
+    pub fn decode(self, input: &str) -> Result<Vec<u8>> {
+        match self {
+            Self::Raw => Ok(input.as_bytes().to_vec()),
+            Self::Hex => hex::decode(input).with_context(|| format!("invalid hex {:?}", input)),
+            Self::Base64 => STANDARD
+                .decode(input)
+                .with_context(|| format!("invalid base64 {:?}", input)),
+        }
+    }
+
+    pub fn encode(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Raw => bytes.to_vec(),
+            Self::Hex => hex::encode(bytes).into_bytes(),
+            Self::Base64 => STANDARD.encode(bytes).into_bytes(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_the_binary_bytes() {
+        let bytes = [0, 159, 146, 150, b'\t', b'\n'];
+        for encoding in [Encoding::Hex, Encoding::Base64] {
+            let encoded = String::from_utf8(encoding.encode(&bytes)).unwrap();
+            assert_eq!(encoding.decode(&encoded).unwrap(), bytes);
+        }
+        assert_eq!(Encoding::Hex.encode(b"hi"), b"6869");
+        assert_eq!(Encoding::Base64.encode(b"hi"), b"aGk=");
+        assert!(Encoding::Hex.decode("6g").is_err());
+        assert!(Encoding::Base64.decode("a!").is_err());
+    }
+}
@@ -0,0 +1,100 @@
+mod commands;
+mod encoding;
+
+use std::{io, path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use commands::Outcome;
+use encoding::Encoding;
+
+/// Exit code of a `get` or a `delete` of a missing key
+const EXIT_NOT_FOUND: u8 = 1;
+/// Exit code of an error, e.g. the database is open in a running server
+const EXIT_ERROR: u8 = 2;
+/// Exit code of a `verify` or an `inspect` which found corrupted records
+const EXIT_CORRUPTED: u8 = 3;
+
+#[derive(Debug, Parser)]
+#[command(
+    about = "Read and write a database directory without a server",
+    after_help = "Exit codes: 0 on success, 1 when the key is not found, 2 on an error, \
+                  3 when verify or inspect finds corrupted records."
+)]
+pub struct Cli {
+    /// Directory of the database, locked for as long as the command runs. Required by every
+    /// command but `inspect`.
+    #[arg(long, value_name = "PATH")]
+    dir: Option<PathBuf>,
+    /// Keys and values are hex, on the command line and in the output
+    #[arg(long, global = true, conflicts_with = "base64")]
+    hex: bool,
+    /// Keys and values are base64, on the command line and in the output
+    #[arg(long, global = true)]
+    base64: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+impl Cli {
+    fn encoding(&self) -> Encoding {
+        match (self.hex, self.base64) {
+            (true, _) => Encoding::Hex,
+            (_, true) => Encoding::Base64,
+            _ => Encoding::Raw,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Print the value of a key
+    Get { key: String },
+    /// Set the value of a key
+    Set { key: String, value: String },
+    /// Delete a key
+    Delete { key: String },
+    /// Print the live keys and their values, a tab between them, in ascending key order
+    Scan {
+        #[arg(long, default_value = "")]
+        prefix: String,
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Print the statistics of the database and its SSTables as JSON
+    Stats,
+    /// Compact the SSTables smaller than the size limit
+    Compact {
+        #[arg(long, default_value_t = 50 * 1024 * 1024)]
+        size_limit: u64,
+    },
+    /// Write the live entries as NDJSON, the format the server imports
+    Export {
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+        #[arg(long, default_value = "")]
+        prefix: String,
+    },
+    /// Read back every SSTable record and check its checksum
+    Verify,
+    /// Print every record of an SSTable or WAL file, told apart by their magic or extension
+    Inspect {
+        file: PathBuf,
+        /// Print the values too, in hex
+        #[arg(long)]
+        raw: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match commands::run(cli, &mut io::stdout().lock()).await {
+        Ok(Outcome::Done) => ExitCode::SUCCESS,
+        Ok(Outcome::NotFound) => ExitCode::from(EXIT_NOT_FOUND),
+        Ok(Outcome::Corrupted) => ExitCode::from(EXIT_CORRUPTED),
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! The hot paths of the engine, `cargo bench -p db-engine --features testutil`. The data comes
+//! from `db_engine::testutil`, the same on every run.
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use db_engine::{
+    testutil, Compaction, DatabaseBuilder, MemTable, SSTableReader, SyncPolicy, WriteBatch,
+};
+use tempdir::TempDir;
+use tokio::{runtime::Runtime, sync::Mutex};
+
+const SMALL_VALUE: usize = 16;
+const KB_VALUE: usize = 1024;
+const LARGE_VALUE: usize = 4096;
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("build the tokio runtime")
+}
+
+/// `Database::set` of new keys in order or not, on a fresh database for every sample
+fn database_set(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("database_set");
+    for (order, value_len) in [
+        ("sequential", SMALL_VALUE),
+        ("sequential", LARGE_VALUE),
+        ("random", SMALL_VALUE),
+        ("random", LARGE_VALUE),
+    ] {
+        let value = testutil::value(0, value_len);
+        group.throughput(Throughput::Bytes(value_len as u64));
+        group.bench_function(BenchmarkId::new(order, value_len), |b| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let value = &value;
+                async move {
+                    let tmpdir = TempDir::new("bench_set").unwrap();
+                    let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+                        .build()
+                        .await
+                        .unwrap();
+                    let ids = match order {
+                        "random" => testutil::shuffled(iters, 42),
+                        _ => (0..iters).collect(),
+                    };
+                    let keys = ids.into_iter().map(testutil::key).collect::<Vec<_>>();
+
+                    let started_at = Instant::now();
+                    for key in &keys {
+                        db.set(key, value).await.unwrap();
+                    }
+                    started_at.elapsed()
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// `Database::set` from 64 tasks at once sharing the database behind a lock, as the server
+/// does, with every write synced or not
+fn database_set_concurrent(c: &mut Criterion) {
+    const SETTERS: u64 = 64;
+    let rt = runtime();
+    let mut group = c.benchmark_group("database_set_concurrent");
+    let value = Arc::new(testutil::value(0, SMALL_VALUE));
+    for (name, sync_policy) in [("never", SyncPolicy::Never), ("always", SyncPolicy::Always)] {
+        group.bench_function(BenchmarkId::new(name, SETTERS), |b| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let value = Arc::clone(&value);
+                async move {
+                    let tmpdir = TempDir::new("bench_set_concurrent").unwrap();
+                    let db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+                        .sync_policy(sync_policy)
+                        .build()
+                        .await
+                        .unwrap();
+                    let db = Arc::new(Mutex::new(db));
+
+                    let started_at = Instant::now();
+                    let setters = (0..SETTERS)
+                        .map(|setter| {
+                            let (db, value) = (Arc::clone(&db), Arc::clone(&value));
+                            tokio::spawn(async move {
+                                for i in (setter..iters).step_by(SETTERS as usize) {
+                                    db.lock()
+                                        .await
+                                        .set(&testutil::key(i), &value)
+                                        .await
+                                        .unwrap();
+                                }
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    for setter in setters {
+                        setter.await.unwrap();
+                    }
+                    started_at.elapsed()
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// 10k small entries written with one `Database::set` each or with one `Database::write` of a
+/// batch, on a fresh database for every iteration
+fn database_write_batch(c: &mut Criterion) {
+    const ENTRIES: u64 = 10_000;
+    let rt = runtime();
+    let entries = testutil::entries(0..ENTRIES, SMALL_VALUE, 1).collect::<Vec<_>>();
+    let mut group = c.benchmark_group("database_write_batch");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Elements(ENTRIES));
+    for batched in [false, true] {
+        let name = match batched {
+            true => "batch",
+            false => "per_entry",
+        };
+        group.bench_function(BenchmarkId::new(name, ENTRIES), |b| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let entries = &entries;
+                async move {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let tmpdir = TempDir::new("bench_write_batch").unwrap();
+                        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+                            .max_mem_table_size(usize::MAX)
+                            .build()
+                            .await
+                            .unwrap();
+
+                        let started_at = Instant::now();
+                        match batched {
+                            true => {
+                                let mut batch = WriteBatch::new();
+                                for entry in entries {
+                                    batch.set(&entry.key, entry.value.as_deref().unwrap());
+                                }
+                                db.write(batch).await.unwrap();
+                            }
+                            false => {
+                                for entry in entries {
+                                    db.set(&entry.key, entry.value.as_deref().unwrap())
+                                        .await
+                                        .unwrap();
+                                }
+                            }
+                        }
+                        elapsed += started_at.elapsed();
+                    }
+                    elapsed
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// `Database::get` of existing keys in a random order, out of the MemTable or of an SSTable,
+/// with small and 1 KB values which are handed out without a copy
+fn database_get(c: &mut Criterion) {
+    const KEYS: u64 = 10_000;
+    let rt = runtime();
+    let mut group = c.benchmark_group("database_get");
+    for (source, flush, value_len) in [
+        ("mem_table", false, SMALL_VALUE),
+        ("mem_table", false, KB_VALUE),
+        ("sstable", true, SMALL_VALUE),
+        ("sstable", true, KB_VALUE),
+    ] {
+        let tmpdir = TempDir::new("bench_get").unwrap();
+        let db = rt
+            .block_on(testutil::populated_db(
+                tmpdir.path(),
+                KEYS,
+                value_len,
+                flush,
+            ))
+            .unwrap();
+        let keys = testutil::shuffled(KEYS, 7)
+            .into_iter()
+            .map(testutil::key)
+            .collect::<Vec<_>>();
+        let mut keys = keys.iter().cycle();
+        let db = &db;
+        group.throughput(Throughput::Bytes(value_len as u64));
+        group.bench_function(BenchmarkId::new(source, value_len), |b| {
+            b.to_async(&rt).iter(|| {
+                let key = keys.next().unwrap();
+                async move { db.get(key).await.unwrap() }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// `MemTable::set` of as many keys in a random order into an empty MemTable
+fn mem_table_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mem_table_set");
+    let value = testutil::value(0, SMALL_VALUE);
+    for entries in [10_000, 100_000] {
+        let keys = testutil::shuffled(entries, 3)
+            .into_iter()
+            .map(testutil::key)
+            .collect::<Vec<_>>();
+        group.throughput(Throughput::Elements(entries));
+        group.bench_function(BenchmarkId::from_parameter(entries), |b| {
+            b.iter(|| {
+                let mut mem_table = MemTable::new();
+                for (timestamp, key) in keys.iter().enumerate() {
+                    mem_table.set(key, &value, timestamp as u128);
+                }
+                mem_table
+            })
+        });
+    }
+    group.finish();
+}
+
+/// `MemTable::get` of existing keys in a random order from 8 threads at once, through one
+/// lock as before the MemTable could be shared, or through a shared snapshot of it
+fn mem_table_get_concurrent(c: &mut Criterion) {
+    const READERS: u64 = 8;
+    const KEYS: u64 = 100_000;
+    let mut mem_table = MemTable::new();
+    for i in 0..KEYS {
+        mem_table.set(&testutil::key(i), &testutil::value(i, SMALL_VALUE), 1);
+    }
+    let keys = Arc::new(
+        testutil::shuffled(KEYS, 5)
+            .into_iter()
+            .map(testutil::key)
+            .collect::<Vec<_>>(),
+    );
+    let locked = Arc::new(std::sync::Mutex::new(mem_table.clone()));
+    let snapshot = mem_table.snapshot();
+
+    let mut group = c.benchmark_group("mem_table_get_concurrent");
+    group.bench_function(BenchmarkId::new("locked", READERS), |b| {
+        b.iter_custom(|iters| {
+            read_concurrently(READERS, iters, &keys, |key| {
+                locked.lock().unwrap().get(key).is_some()
+            })
+        })
+    });
+    group.bench_function(BenchmarkId::new("snapshot", READERS), |b| {
+        b.iter_custom(|iters| {
+            read_concurrently(READERS, iters, &keys, |key| snapshot.get(key).is_some())
+        })
+    });
+    group.finish();
+}
+
+/// Time `readers` threads sharing `iters` calls of `get` over the `keys`
+fn read_concurrently(
+    readers: u64,
+    iters: u64,
+    keys: &Arc<Vec<Vec<u8>>>,
+    get: impl Fn(&[u8]) -> bool + Sync,
+) -> Duration {
+    let started_at = Instant::now();
+    std::thread::scope(|scope| {
+        for reader in 0..readers {
+            let (keys, get) = (Arc::clone(keys), &get);
+            scope.spawn(move || {
+                for i in (reader..iters).step_by(readers as usize) {
+                    assert!(get(&keys[i as usize % keys.len()]));
+                }
+            });
+        }
+    });
+    started_at.elapsed()
+}
+
+/// `SSTableReader::get` of existing keys in a random order, in a table of a million entries
+fn sstable_get(c: &mut Criterion) {
+    const ENTRIES: u64 = 1_000_000;
+    let rt = runtime();
+    let tmpdir = TempDir::new("bench_sstable_get").unwrap();
+    let path = tmpdir.path().join("1.db");
+    let reader = rt
+        .block_on(async {
+            testutil::write_sstable(&path, testutil::entries(0..ENTRIES, SMALL_VALUE, 1)).await?;
+            SSTableReader::new(&path).await
+        })
+        .unwrap();
+    let keys = testutil::shuffled(ENTRIES, 11)
+        .into_iter()
+        .take(100_000)
+        .map(testutil::key)
+        .collect::<Vec<_>>();
+    let mut keys = keys.iter().cycle();
+    let reader = &reader;
+    c.bench_function("sstable_get/1000000", |b| {
+        b.to_async(&rt).iter(|| {
+            let key = keys.next().unwrap();
+            async move { reader.get(key).await.unwrap() }
+        })
+    });
+}
+
+/// Opening a database which replays a WAL of 100k records
+fn wal_replay(c: &mut Criterion) {
+    const RECORDS: u64 = 100_000;
+    let rt = runtime();
+    let tmpdir = TempDir::new("bench_wal_replay").unwrap();
+    rt.block_on(testutil::write_wal(tmpdir.path(), RECORDS, SMALL_VALUE))
+        .unwrap();
+    let mut group = c.benchmark_group("wal_replay");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Elements(RECORDS));
+    group.bench_function(BenchmarkId::from_parameter(RECORDS), |b| {
+        b.to_async(&rt).iter(|| async {
+            let db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+                .max_mem_table_size(usize::MAX)
+                .build()
+                .await
+                .unwrap();
+            assert_eq!(db.recovery_report().records_applied as u64, RECORDS);
+        })
+    });
+    group.finish();
+}
+
+/// A full compaction of 10 overlapping SSTables of 10k entries each, written anew for every
+/// iteration
+fn compaction(c: &mut Criterion) {
+    const FILES: u64 = 10;
+    const ENTRIES: u64 = 10_000;
+    let rt = runtime();
+    let mut group = c.benchmark_group("compaction");
+    group.sample_size(10);
+    group.bench_function(BenchmarkId::new("files", FILES), |b| {
+        b.to_async(&rt).iter_custom(|iters| async move {
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                let tmpdir = TempDir::new("bench_compaction").unwrap();
+                for file in 0..FILES {
+                    // every file overwrites half of the keys of the previous one
+                    let keys = file * ENTRIES / 2..file * ENTRIES / 2 + ENTRIES;
+                    let path = tmpdir.path().join(format!("{}.db", file + 1));
+                    let entries = testutil::entries(keys, SMALL_VALUE, u128::from(file) + 1);
+                    testutil::write_sstable(&path, entries).await.unwrap();
+                }
+
+                let started_at = Instant::now();
+                Compaction::new(tmpdir.path().to_path_buf(), u64::MAX, "db")
+                    .compact()
+                    .await
+                    .unwrap();
+                elapsed += started_at.elapsed();
+            }
+            elapsed
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    database_set,
+    database_set_concurrent,
+    database_write_batch,
+    database_get,
+    mem_table_set,
+    mem_table_get_concurrent,
+    sstable_get,
+    wal_replay,
+    compaction
+);
+criterion_main!(benches);
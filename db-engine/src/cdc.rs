@@ -0,0 +1,495 @@
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use anyhow::{Context as _, Result};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+
+use crate::{
+    entries::Entry,
+    errors::{CdcError, WalReadError},
+    events::ChangeEvent,
+    sstable::{SSTableReader, SSTableReaderOptions},
+    storage::Storage,
+    wal::{WALIterator, WalRecord},
+};
+
+/// Directory of a database where the WAL files of the flushed MemTables wait for the
+/// [`CdcStream`]s which may still replay them, named `{max timestamp}-{file name}`
+pub(crate) const ARCHIVE_DIR_NAME: &str = "cdc";
+
+/// The changes read back from the files, once per write and in the order of their timestamps
+pub(crate) type History = BTreeMap<(u128, Vec<u8>), ChangeEvent>;
+
+/// The positions of the open [`CdcStream`]s, the flushes keep the WAL files they still need
+#[derive(Default)]
+pub(crate) struct CdcCursors {
+    next_id: AtomicU64,
+    positions: Mutex<BTreeMap<u64, u128>>,
+}
+
+impl CdcCursors {
+    /// Register a cursor which needs the changes newer than `position`
+    pub(crate) fn open(self: &Arc<Self>, position: u128) -> CdcCursor {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.positions.lock().unwrap().insert(id, position);
+        CdcCursor {
+            id,
+            cursors: Arc::clone(self),
+        }
+    }
+
+    /// The position of the cursor the furthest behind, `None` without any
+    fn min_position(&self) -> Option<u128> {
+        self.positions.lock().unwrap().values().min().copied()
+    }
+}
+
+/// An entry of [`CdcCursors`], removed on drop
+pub(crate) struct CdcCursor {
+    id: u64,
+    cursors: Arc<CdcCursors>,
+}
+
+impl CdcCursor {
+    fn advance(&self, position: u128) {
+        if let Some(current) = self.cursors.positions.lock().unwrap().get_mut(&self.id) {
+            *current = position;
+        }
+    }
+}
+
+impl Drop for CdcCursor {
+    fn drop(&mut self) {
+        self.cursors.positions.lock().unwrap().remove(&self.id);
+    }
+}
+
+fn archive_dir(dir: &Path) -> PathBuf {
+    dir.join(ARCHIVE_DIR_NAME)
+}
+
+/// Remove the WAL files of a flushed MemTable whose newest entry is at `max_timestamp`, oldest
+/// first. They are moved to the archive instead while a cursor has not read that far yet, and
+/// the archived files every cursor is past go.
+pub(crate) async fn retire_wal_files(
+    dir: &Path,
+    wal_paths: Vec<PathBuf>,
+    max_timestamp: Option<u128>,
+    cursors: &CdcCursors,
+    storage: &dyn Storage,
+) -> Result<()> {
+    let position = cursors.min_position();
+    match position.zip(max_timestamp) {
+        Some((position, max_timestamp)) if max_timestamp > position => {
+            let archive = archive_dir(dir);
+            storage
+                .create_dir_all(&archive)
+                .await
+                .context("create cdc archive dir")?;
+            for wal_path in wal_paths {
+                let file_name = wal_path.file_name().unwrap_or_default().to_string_lossy();
+                let archived = archive.join(format!("{}-{}", max_timestamp, file_name));
+                storage
+                    .rename(&wal_path, &archived)
+                    .await
+                    .with_context(|| format!("archive wal file {:?}", wal_path))?;
+            }
+        }
+        _ => {
+            for wal_path in wal_paths {
+                storage
+                    .remove(&wal_path)
+                    .await
+                    .with_context(|| format!("remove wal file {:?}", wal_path))?;
+            }
+        }
+    }
+    prune_archive(dir, position, storage).await
+}
+
+/// Remove the archived WAL files with nothing newer than `position`, all of them without one.
+pub(crate) async fn prune_archive(
+    dir: &Path,
+    position: Option<u128>,
+    storage: &dyn Storage,
+) -> Result<()> {
+    for (max_timestamp, path) in archived_wal_files(dir, storage).await? {
+        if position.is_none_or(|position| max_timestamp <= position) {
+            match storage.remove(&path).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("remove archived wal file {:?}", path));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The archived WAL files with the max timestamp of their MemTable, oldest first
+pub(crate) async fn archived_wal_files(
+    dir: &Path,
+    storage: &dyn Storage,
+) -> Result<Vec<(u128, PathBuf)>> {
+    let paths = match storage.list(&archive_dir(dir)).await {
+        Ok(paths) => paths,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("list cdc archive dir"),
+    };
+    let mut files = Vec::new();
+    for path in paths {
+        let max_timestamp = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split_once('-'))
+            .and_then(|(max_timestamp, _)| max_timestamp.parse().ok());
+        if let Some(max_timestamp) = max_timestamp {
+            files.push((max_timestamp, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Whether a file of the error went away meanwhile
+pub(crate) fn is_not_found(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+    })
+}
+
+fn add_to_history(history: &mut History, entry: Entry, from: u128) {
+    if entry.timestamp <= from {
+        return;
+    }
+    let entry = match entry.decompressed() {
+        Ok(entry) => entry,
+        Err(e) => return tracing::error!("Skip an entry whose value is corrupted: {:?}", e),
+    };
+    let event = ChangeEvent::from(entry);
+    history.insert((event.timestamp, event.key.clone()), event);
+}
+
+/// Read the changes newer than `from` out of the `wal_paths`, then out of the archive. A file
+/// retired by a flush meanwhile is in the archive by then, unless nothing of it was needed.
+pub(crate) async fn read_wal_history(
+    dir: &Path,
+    wal_paths: Vec<PathBuf>,
+    from: u128,
+    history: &mut History,
+    storage: &dyn Storage,
+    max_field_len: usize,
+) -> Result<()> {
+    for wal_path in wal_paths {
+        read_wal_file(&wal_path, from, history, storage, max_field_len).await?;
+    }
+    for (_, wal_path) in archived_wal_files(dir, storage).await? {
+        read_wal_file(&wal_path, from, history, storage, max_field_len).await?;
+    }
+    Ok(())
+}
+
+/// The entries of a batch count once its commit record is read, the file ends at the first
+/// bad record like on a restore. A file which is gone is skipped.
+async fn read_wal_file(
+    wal_path: &Path,
+    from: u128,
+    history: &mut History,
+    storage: &dyn Storage,
+    max_field_len: usize,
+) -> Result<()> {
+    let mut wal_iter = match WALIterator::with_storage(wal_path.to_path_buf(), storage).await {
+        Ok(wal_iter) => wal_iter.with_max_field_len(max_field_len),
+        Err(e) if is_not_found(&e) => return Ok(()),
+        Err(e) => return Err(e.context(format!("open wal file {:?}", wal_path))),
+    };
+    let mut batch: Option<Vec<Entry>> = None;
+    while let Some(record) = wal_iter.next().await {
+        match record {
+            Ok(WalRecord::Entry(entry)) => match batch.as_mut() {
+                Some(batch) => batch.push(entry),
+                None => add_to_history(history, entry, from),
+            },
+            Ok(WalRecord::BatchBegin { .. }) => batch = Some(Vec::new()),
+            Ok(WalRecord::BatchCommit { .. }) => {
+                for entry in batch.take().unwrap_or_default() {
+                    add_to_history(history, entry, from);
+                }
+            }
+            Err(WalReadError::Io(e)) => {
+                return Err(e).with_context(|| format!("read wal file {:?}", wal_path));
+            }
+            Err(e) => {
+                tracing::warn!("Stop reading {:?} for the CDC history: {}", wal_path, e);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read the changes newer than `from` out of the SSTables. Fails when one of them is gone, a
+/// compaction replaced it and the listing is out of date.
+pub(crate) async fn read_sstable_history(
+    sstable_paths: impl IntoIterator<Item = PathBuf>,
+    from: u128,
+    history: &mut History,
+    storage: &dyn Storage,
+    max_field_len: usize,
+) -> Result<()> {
+    for path in sstable_paths {
+        if !SSTableReader::may_be_newer(&path, from, storage).await {
+            continue;
+        }
+        let options = SSTableReaderOptions {
+            max_field_len,
+            ..Default::default()
+        };
+        let reader = SSTableReader::with_storage(&path, options, storage).await?;
+        let mut entries = reader.iter();
+        while let Some(entry) = entries.next().await {
+            match entry {
+                Ok(entry) => add_to_history(history, entry, from),
+                Err(e) if is_not_found(&e) => return Err(e),
+                Err(e) => tracing::error!("Skip a corrupted entry of {:?}: {:?}", path, e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The changes of a database from a timestamp on, see
+/// [`Database::cdc_stream`](crate::Database::cdc_stream). Yields the history read from the
+/// files first, then the live writes, each change once in the order of the timestamps.
+pub struct CdcStream {
+    history: VecDeque<ChangeEvent>,
+    live: BroadcastStream<ChangeEvent>,
+    from: u128,
+    /// Timestamp of the last change handed out, with the keys handed out at it: the entries
+    /// of a batch share their timestamp
+    last: u128,
+    keys_at_last: HashSet<Vec<u8>>,
+    cursor: CdcCursor,
+    lagged: bool,
+}
+
+impl CdcStream {
+    pub(crate) fn new(
+        history: History,
+        live: BroadcastStream<ChangeEvent>,
+        from: u128,
+        cursor: CdcCursor,
+    ) -> Self {
+        Self {
+            history: history.into_values().collect(),
+            live,
+            from,
+            last: from,
+            keys_at_last: HashSet::new(),
+            cursor,
+            lagged: false,
+        }
+    }
+
+    /// The timestamp to resume from without a gap, the changes at the one of the last change
+    /// handed out may come again
+    fn resume_from(&self) -> u128 {
+        match self.last > self.from {
+            true => self.last - 1,
+            false => self.from,
+        }
+    }
+
+    fn is_handed_out(&self, event: &ChangeEvent) -> bool {
+        event.timestamp <= self.from
+            || event.timestamp < self.last
+            || (event.timestamp == self.last && self.keys_at_last.contains(&event.key))
+    }
+
+    fn hand_out(&mut self, event: ChangeEvent) -> Poll<Option<Result<ChangeEvent, CdcError>>> {
+        if event.timestamp != self.last {
+            self.last = event.timestamp;
+            self.keys_at_last.clear();
+        }
+        self.keys_at_last.insert(event.key.clone());
+        self.cursor.advance(self.resume_from());
+        Poll::Ready(Some(Ok(event)))
+    }
+}
+
+impl Stream for CdcStream {
+    type Item = Result<ChangeEvent, CdcError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.lagged {
+            return Poll::Ready(None);
+        }
+        if let Some(event) = self.history.pop_front() {
+            return self.hand_out(event);
+        }
+        loop {
+            match Pin::new(&mut self.live).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Ready(Some(Ok(event))) if self.is_handed_out(&event) => {}
+                Poll::Ready(Some(Ok(event))) => return self.hand_out(event),
+                Poll::Ready(Some(Err(BroadcastStreamRecvError::Lagged(_)))) => {
+                    self.lagged = true;
+                    let resume_from = self.resume_from();
+                    return Poll::Ready(Some(Err(CdcError::Lagged { resume_from })));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use bytes::Bytes;
+    use tempdir::TempDir;
+    use tokio::{sync::broadcast, time::timeout};
+
+    use super::*;
+    use crate::{ChangeKind, DatabaseBuilder, WriteBatch};
+
+    fn event(timestamp: u128, key: &str) -> ChangeEvent {
+        ChangeEvent {
+            kind: ChangeKind::Set,
+            key: key.into(),
+            value: Some(Bytes::from_static(b"value")),
+            content_type: None,
+            expires_at: None,
+            timestamp,
+        }
+    }
+
+    async fn next_key(stream: &mut CdcStream) -> Result<Vec<u8>> {
+        Ok(stream.next().await.unwrap()?.key)
+    }
+
+    #[tokio::test]
+    async fn it_hands_out_each_change_once() -> Result<()> {
+        let (sender, receiver) = broadcast::channel(4);
+        let mut history = History::new();
+        for event in [event(7, "b"), event(6, "a"), event(7, "a")] {
+            history.insert((event.timestamp, event.key.clone()), event);
+        }
+        let cursor = Arc::new(CdcCursors::default()).open(5);
+        let mut stream = CdcStream::new(history, BroadcastStream::new(receiver), 5, cursor);
+
+        // the batch at 7 was half written when the history was read
+        for event in [event(4, "old"), event(7, "a"), event(7, "c")] {
+            sender.send(event)?;
+        }
+        let mut keys = Vec::new();
+        for _ in 0..4 {
+            keys.push(next_key(&mut stream).await?);
+        }
+        assert_eq!(keys, [b"a", b"a", b"b", b"c"]);
+
+        for timestamp in 8..13 {
+            sender.send(event(timestamp, "d"))?;
+        }
+        assert_eq!(
+            stream.next().await,
+            Some(Err(CdcError::Lagged { resume_from: 6 }))
+        );
+        assert_eq!(stream.next().await, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_replays_the_history_then_the_live_writes() -> Result<()> {
+        let tmpdir = TempDir::new("cdc_stream")?;
+        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .build()
+            .await?;
+        let from = db.set(b"a", b"1").await?;
+        db.set(b"b", b"2").await?;
+        db.flush().await?;
+        db.set(b"c", b"3").await?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"e", b"5").set(b"d", b"4");
+        db.write(batch).await?;
+        db.delete(b"a").await?;
+
+        let mut stream = db.cdc_stream(from).await?;
+        db.set(b"f", b"6").await?;
+        let mut keys = Vec::new();
+        for _ in 0..4 {
+            keys.push(next_key(&mut stream).await?);
+        }
+        assert_eq!(keys, [b"b", b"c", b"d", b"e"]);
+        let deleted = stream.next().await.unwrap()?;
+        assert_eq!((deleted.kind, deleted.value), (ChangeKind::Delete, None));
+        let set = stream.next().await.unwrap()?;
+        assert_eq!((set.key, set.value), (b"f".to_vec(), Some("6".into())));
+        assert!(timeout(Duration::from_millis(50), stream.next())
+            .await
+            .is_err());
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_wal_files_an_open_stream_needs() -> Result<()> {
+        let tmpdir = TempDir::new("cdc_archive")?;
+        let archive = archive_dir(tmpdir.path());
+        let archived = || std::fs::read_dir(&archive).map_or(0, |files| files.count());
+        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .build()
+            .await?;
+
+        db.set(b"a", b"1").await?;
+        let stream = db.cdc_stream(0).await?;
+        db.set(b"b", b"2").await?;
+        db.flush().await?;
+        assert_eq!(archived(), 1);
+
+        // the archive and the SSTable hold the same writes
+        let mut replay = db.cdc_stream(0).await?;
+        assert_eq!(next_key(&mut replay).await?, b"a");
+        assert_eq!(next_key(&mut replay).await?, b"b");
+        assert!(timeout(Duration::from_millis(50), replay.next())
+            .await
+            .is_err());
+
+        // no stream left which needs it
+        drop((stream, replay));
+        db.set(b"c", b"3").await?;
+        db.flush().await?;
+        assert_eq!(archived(), 0);
+
+        // nor after a restart
+        let _stream = db.cdc_stream(0).await?;
+        db.set(b"d", b"4").await?;
+        db.flush().await?;
+        assert_eq!(archived(), 1);
+        drop(db);
+        DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .build()
+            .await?;
+        assert_eq!(archived(), 0);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::{Context, Result};
+use std::{io, path::Path, time::Duration};
+
+use crate::{
+    cdc::archived_wal_files,
+    sstable::list_level_files,
+    storage::{self, Storage},
+};
+
+/// Files modified more recently than that are never removed by a cleanup: a flush or a
+/// compaction may still be writing them.
+pub const MIN_CLEANUP_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// Files removed by [`Database::cleanup_orphans`](crate::Database::cleanup_orphans) and
+/// [`WriteAheadLog::prune_archive`](crate::WriteAheadLog::prune_archive).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CleanupReport {
+    /// `.tmp` files of the flushes, compactions and WAL repairs a crash interrupted
+    pub tmp_files_removed: usize,
+    /// `.idx` and `.bf` files whose SSTable is gone
+    pub orphaned_files_removed: usize,
+    /// WAL files archived for the CDC streams
+    pub archived_wal_files_removed: usize,
+    pub bytes_freed: u64,
+}
+
+impl CleanupReport {
+    /// Add the removals of `other`, e.g. of another database.
+    pub fn add(&mut self, other: &CleanupReport) {
+        self.tmp_files_removed += other.tmp_files_removed;
+        self.orphaned_files_removed += other.orphaned_files_removed;
+        self.archived_wal_files_removed += other.archived_wal_files_removed;
+        self.bytes_freed += other.bytes_freed;
+    }
+}
+
+/// Remove the `.tmp` files and the SSTable sidecar files without an SSTable in `dir` and its
+/// level directories of `storage`, the ones modified in the last [`MIN_CLEANUP_AGE`] excepted.
+pub(crate) async fn cleanup_orphans(dir: &Path, storage: &dyn Storage) -> Result<CleanupReport> {
+    let mut report = CleanupReport::default();
+    for path in list_level_files(dir, "tmp", storage)
+        .await?
+        .into_iter()
+        .flatten()
+    {
+        if let Some(len) = remove_older(&path, MIN_CLEANUP_AGE, storage).await? {
+            tracing::info!("Removed the unfinished file {:?}", path);
+            report.tmp_files_removed += 1;
+            report.bytes_freed += len;
+        }
+    }
+    for ext in ["idx", "bf"] {
+        for path in list_level_files(dir, ext, storage)
+            .await?
+            .into_iter()
+            .flatten()
+        {
+            let db_path = path.with_extension("");
+            if db_path.extension().is_none_or(|ext| ext != "db")
+                || storage::exists(storage, &db_path).await?
+            {
+                continue;
+            }
+            if let Some(len) = remove_older(&path, MIN_CLEANUP_AGE, storage).await? {
+                tracing::info!("Removed the file {:?}, its SSTable is gone", path);
+                report.orphaned_files_removed += 1;
+                report.bytes_freed += len;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Remove the WAL files archived in `dir` of `storage` which were last written more than `age`
+/// ago, and at least [`MIN_CLEANUP_AGE`].
+pub(crate) async fn prune_archive(
+    dir: &Path,
+    age: Duration,
+    storage: &dyn Storage,
+) -> Result<CleanupReport> {
+    let mut report = CleanupReport::default();
+    for (_, path) in archived_wal_files(dir, storage).await? {
+        if let Some(len) = remove_older(&path, age.max(MIN_CLEANUP_AGE), storage).await? {
+            tracing::info!("Removed the archived wal file {:?}", path);
+            report.archived_wal_files_removed += 1;
+            report.bytes_freed += len;
+        }
+    }
+    Ok(report)
+}
+
+/// Remove the file at `path` unless it was modified less than `min_age` ago, returns its length
+/// once removed. A file gone meanwhile is left alone.
+async fn remove_older(
+    path: &Path,
+    min_age: Duration,
+    storage: &dyn Storage,
+) -> Result<Option<u64>> {
+    let metadata = match storage.metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("read metadata of {:?}", path)),
+    };
+    // a modification time in the future counts as a recent one
+    let age = metadata.modified.elapsed().unwrap_or_default();
+    if age < min_age {
+        return Ok(None);
+    }
+    match storage.remove(path).await {
+        Ok(()) => Ok(Some(metadata.len)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("remove {:?}", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use std::{fs, time::SystemTime};
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{cdc::ARCHIVE_DIR_NAME, sstable::level_dir, storage::LocalFs, WriteAheadLog};
+
+    /// Write a file at `path` last modified `age` ago
+    fn write_aged(path: &Path, age: Duration) -> Result<()> {
+        fs::write(path, [0; 10])?;
+        fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::now() - age)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_removes_the_old_orphans_only() -> Result<()> {
+        let tmpdir = TempDir::new("cleanup_orphans")?;
+        let dir = tmpdir.path();
+        let level1 = level_dir(dir, 1);
+        fs::create_dir_all(&level1)?;
+        let old = MIN_CLEANUP_AGE + Duration::from_secs(60);
+
+        write_aged(&dir.join("1.db.tmp"), old)?;
+        write_aged(&level1.join("2.db.tmp"), old)?;
+        write_aged(&dir.join("3.db.idx"), old)?;
+        write_aged(&level1.join("4.db.bf"), old)?;
+        // recent, or still next to their SSTable
+        write_aged(&dir.join("5.db.tmp"), Duration::ZERO)?;
+        write_aged(&dir.join("6.db.idx"), Duration::ZERO)?;
+        write_aged(&dir.join("7.db"), old)?;
+        write_aged(&dir.join("7.db.bf"), old)?;
+
+        let report = cleanup_orphans(dir, &LocalFs).await?;
+        assert_eq!(
+            report,
+            CleanupReport {
+                tmp_files_removed: 2,
+                orphaned_files_removed: 2,
+                archived_wal_files_removed: 0,
+                bytes_freed: 40,
+            }
+        );
+        for kept in ["5.db.tmp", "6.db.idx", "7.db", "7.db.bf"] {
+            assert!(dir.join(kept).exists());
+        }
+        assert!(!dir.join("1.db.tmp").exists() && !level1.join("4.db.bf").exists());
+        assert_eq!(
+            cleanup_orphans(dir, &LocalFs).await?,
+            CleanupReport::default()
+        );
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_prunes_the_archived_wal_files_past_their_age() -> Result<()> {
+        let tmpdir = TempDir::new("prune_archive")?;
+        let dir = tmpdir.path();
+        assert_eq!(
+            prune_archive(dir, Duration::ZERO, &LocalFs).await?,
+            Default::default()
+        );
+
+        let archive = dir.join(ARCHIVE_DIR_NAME);
+        fs::create_dir_all(&archive)?;
+        let hour = Duration::from_secs(3600);
+        write_aged(&archive.join("100-1.wal"), 2 * hour)?;
+        write_aged(&archive.join("200-2.wal"), MIN_CLEANUP_AGE / 2)?;
+
+        assert_eq!(
+            prune_archive(dir, 3 * hour, &LocalFs).await?,
+            Default::default()
+        );
+        // never the recent ones, whatever the age
+        let report = WriteAheadLog::prune_archive(dir, Duration::ZERO).await?;
+        assert_eq!(report.archived_wal_files_removed, 1);
+        assert_eq!(report.bytes_freed, 10);
+        assert!(!archive.join("100-1.wal").exists());
+        assert!(archive.join("200-2.wal").exists());
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1293 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{io, task::JoinSet};
+
+use crate::{
+    compression::Codec,
+    entries::DEFAULT_MAX_FIELD_LEN,
+    prelude::{Entry, Error},
+    sstable::{
+        get_bloom_filter_path, get_index_path, level_dir, list_level_files, IndexMode, Manifest,
+        SSTableOptions, SSTableQuerier, SSTableReader, SSTableReaderOptions,
+        SSTableReaderScanHandler, SSTableWriter,
+    },
+    storage::{self, AppendMode, LocalFs, Storage},
+    throttle::Throttle,
+    utils::{allocation_order, Clock, HybridClock},
+};
+
+mod strategy;
+
+pub use self::strategy::{CompactionStrategy, SizeFilter};
+
+/// Name of the file which marks a compaction running in the directory.
+const LOCK_FILE_NAME: &str = "compaction.lock";
+
+/// Held by a running compaction of a directory, removes its `compaction.lock` file once
+/// released, or in the background when dropped. See [`Compaction::try_lock`].
+pub struct CompactionLock {
+    path: PathBuf,
+    /// Where the lock file is, `None` once released
+    storage: Option<Arc<dyn Storage>>,
+}
+
+impl CompactionLock {
+    /// Remove the lock file, the next compaction of the directory may start as soon as this
+    /// returns.
+    pub async fn release(mut self) -> Result<()> {
+        let Some(storage) = self.storage.take() else {
+            return Ok(());
+        };
+        storage
+            .remove(&self.path)
+            .await
+            .with_context(|| format!("remove compaction lock {:?}", self.path))
+    }
+}
+
+impl std::fmt::Debug for CompactionLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompactionLock")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl Drop for CompactionLock {
+    fn drop(&mut self) {
+        let Some(storage) = self.storage.take() else {
+            return;
+        };
+        let path = self.path.clone();
+        let remove = async move {
+            if let Err(e) = storage.remove(&path).await {
+                tracing::error!("Failed to remove the compaction lock {:?}: {}", path, e);
+            }
+        };
+        // a lock left behind is removed when the database opens again
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn(remove)),
+            Err(_) => tracing::error!("No runtime to remove the compaction lock {:?}", self.path),
+        }
+    }
+}
+
+/// Outcome of [`Compaction::compact`].
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CompactionReport {
+    pub input_files: usize,
+    pub input_bytes: u64,
+    /// 0 when there was nothing to compact, more than 1 when the output rolled over, see
+    /// [`Compaction::max_output_file_size`].
+    pub output_files: usize,
+    /// The SSTables written, in key order.
+    pub output_paths: Vec<PathBuf>,
+    pub output_bytes: u64,
+    pub entries_written: u64,
+    /// Versions of a key left behind for a newer one.
+    pub duplicates_skipped: u64,
+    /// Tombstones dropped along with the versions they shadow.
+    pub tombstones_dropped: u64,
+    /// Tombstones written to the output, as they still shadow a version left out of the
+    /// compaction or are younger than the [`Compaction::tombstone_ttl`].
+    pub tombstones_retained: u64,
+    /// Entries a [`CompactionFilter`] dropped.
+    pub entries_filtered: u64,
+    /// Values dropped once past their [`Entry::expires_at`].
+    pub entries_expired: u64,
+    pub duration: Duration,
+}
+
+/// What [`Compaction::compact`] would do at the moment, see [`Compaction::plan`].
+#[derive(Debug, Clone, Default)]
+pub struct CompactionPlan {
+    /// The SSTables to merge and their size, newest first.
+    pub input_files: Vec<(PathBuf, u64)>,
+    pub input_bytes: u64,
+    pub entries_written: u64,
+    pub duplicates_skipped: u64,
+    pub tombstones_dropped: u64,
+    pub tombstones_retained: u64,
+    pub entries_filtered: u64,
+    pub entries_expired: u64,
+    /// The encoded length of the entries to write, without the index, bloom filter and footer
+    /// of the output SSTables.
+    pub estimated_output_bytes: u64,
+    /// What the compaction would free, the input bytes the estimated output leaves.
+    pub reclaimable_bytes: u64,
+}
+
+/// What a [`CompactionFilter`] does with an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    /// Delete the key, as if it was deleted when the entry was written.
+    Drop,
+    /// Keep the entry with another value and its original timestamp.
+    Replace(Vec<u8>),
+}
+
+/// Decides over the newest version of every live key a compaction writes, e.g. to expire the
+/// old entries without deleting them one by one. See [`Compaction::with_filter`].
+pub trait CompactionFilter: Send + Sync {
+    fn decide(&self, entry: &Entry) -> FilterDecision;
+}
+
+pub struct Compaction {
+    dir: PathBuf,
+    size_filter: SizeFilter,
+    ext: String,
+    sstable_options: SSTableOptions,
+    sstable_querier: Option<Arc<SSTableQuerier>>,
+    max_output_file_size: u64,
+    /// Applied in the order they were added
+    filters: Vec<Arc<dyn CompactionFilter>>,
+    throttle: Option<u64>,
+    strategy: CompactionStrategy,
+    tombstone_ttl: Duration,
+    max_field_len: usize,
+    clock: Arc<dyn Clock>,
+    storage: Arc<dyn Storage>,
+}
+
+impl Compaction {
+    pub fn new(dir: PathBuf, size: u64, ext: &str) -> Self {
+        Self {
+            dir,
+            size_filter: SizeFilter::SmallerThan(size),
+            ext: ext.into(),
+            sstable_options: SSTableOptions::default(),
+            sstable_querier: None,
+            max_output_file_size: u64::MAX,
+            filters: Vec::new(),
+            throttle: None,
+            strategy: CompactionStrategy::default(),
+            tombstone_ttl: Duration::ZERO,
+            max_field_len: DEFAULT_MAX_FIELD_LEN,
+            clock: Arc::new(HybridClock),
+            storage: Arc::new(LocalFs),
+        }
+    }
+
+    /// Keep the tombstones younger than `ttl` in the output, even when nothing is left for
+    /// them to shadow, in case an older version still shows up, e.g. from a slow flush. They
+    /// are dropped right away by default.
+    pub fn tombstone_ttl(mut self, ttl: Duration) -> Self {
+        self.tombstone_ttl = ttl;
+        self
+    }
+
+    /// The longest key or value read back from the input SSTables, a longer one is a
+    /// corruption. [`DEFAULT_MAX_FIELD_LEN`] by default.
+    pub fn max_field_len(mut self, max_field_len: usize) -> Self {
+        self.max_field_len = max_field_len;
+        self
+    }
+
+    /// Which SSTables to merge, every one smaller than `size` by default, see
+    /// [`CompactionStrategy`] and [`Compaction::size_filter`].
+    pub fn strategy(mut self, strategy: CompactionStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Which sizes of SSTables [`CompactionStrategy::All`] merges, smaller than the `size` of
+    /// [`Compaction::new`] by default.
+    pub fn size_filter(mut self, size_filter: SizeFilter) -> Self {
+        self.size_filter = size_filter;
+        self
+    }
+
+    /// Keep the bytes the compaction reads and writes under `bytes_per_sec`, so it leaves
+    /// the disk to the lookups. Unthrottled by default, or when `bytes_per_sec` is 0.
+    pub fn with_throttle(mut self, bytes_per_sec: u64) -> Self {
+        self.throttle = (bytes_per_sec > 0).then_some(bytes_per_sec);
+        self
+    }
+
+    /// Run every live entry written by the compaction through `filter`, after the filters
+    /// added before, see [`CompactionFilter`]. An entry one of them drops is not seen by the
+    /// next ones, which see the value another one replaced.
+    pub fn with_filter(mut self, filter: Arc<dyn CompactionFilter>) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Write the compacted SSTables following `options`, see
+    /// [`DatabaseBuilder`](crate::DatabaseBuilder).
+    pub(crate) fn sstable_options(mut self, options: SSTableOptions) -> Self {
+        self.sstable_options = options;
+        self
+    }
+
+    /// Name the compacted SSTables and expire the tombstones after the timestamps of `clock`,
+    /// see [`DatabaseBuilder::clock`](crate::DatabaseBuilder::clock).
+    pub(crate) fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Where the SSTables, the manifest and the lock are read from and written to, [`LocalFs`]
+    /// by default.
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Start a new output SSTable once the records written to the current one reach `size`
+    /// bytes, no limit by default. The outputs hold sorted, non overlapping key ranges.
+    pub fn max_output_file_size(mut self, size: u64) -> Self {
+        self.max_output_file_size = size;
+        self
+    }
+
+    /// Invalidate the files the compaction creates, rewrites or removes in `sstable_querier`,
+    /// see [`SSTableQuerier::invalidate`].
+    pub fn sstable_querier(mut self, sstable_querier: Arc<SSTableQuerier>) -> Self {
+        self.sstable_querier = Some(sstable_querier);
+        self
+    }
+
+    /// Compress the compacted SSTable in blocks, see [`Codec`].
+    pub fn sstable_compression(mut self, codec: Codec) -> Self {
+        self.sstable_options.compression = codec;
+        self
+    }
+
+    /// Only index every `n`th key of the compacted SSTable, see
+    /// [`DatabaseBuilder::index_every_n_keys`](crate::DatabaseBuilder::index_every_n_keys).
+    pub fn index_every_n_keys(mut self, n: u32) -> Self {
+        self.sstable_options.index_interval = n;
+        self
+    }
+
+    /// Lock `dir` against other compactions, [`Error::CompactionInProgress`] when one is
+    /// already running there.
+    pub async fn try_lock(dir: &Path) -> Result<CompactionLock> {
+        Self::try_lock_with_storage(dir, Arc::new(LocalFs)).await
+    }
+
+    /// [`Compaction::try_lock`] of `dir` in `storage`.
+    pub async fn try_lock_with_storage(
+        dir: &Path,
+        storage: Arc<dyn Storage>,
+    ) -> Result<CompactionLock> {
+        let path = dir.join(LOCK_FILE_NAME);
+        match storage.open_append(&path, AppendMode::CreateNew).await {
+            Ok(_) => Ok(CompactionLock {
+                path,
+                storage: Some(storage),
+            }),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Err(Error::CompactionInProgress(dir.to_owned()).into())
+            }
+            Err(e) => Err(e).with_context(|| format!("create compaction lock {:?}", path)),
+        }
+    }
+
+    /// Merge the SSTables the [`CompactionStrategy`] picks into new ones. The manifest of the
+    /// directory swaps the inputs for the outputs at once before the inputs are removed, so a
+    /// crash in between leaves one of the two sets live and never both.
+    ///
+    /// Fails with [`Error::CompactionInProgress`] when another compaction of the directory is
+    /// running.
+    #[tracing::instrument(skip_all, fields(dir = ?self.dir))]
+    pub async fn compact(&self) -> Result<CompactionReport> {
+        let lock = Self::try_lock_with_storage(&self.dir, Arc::clone(&self.storage)).await?;
+        let report = self.compact_locked().await;
+        let released = lock.release().await;
+        let report = report?;
+        released?;
+        Ok(report)
+    }
+
+    /// [`Compaction::compact`] once the directory is locked
+    async fn compact_locked(&self) -> Result<CompactionReport> {
+        let started_at = Instant::now();
+        let manifest = Manifest::load(&self.dir, self.storage.as_ref()).await?;
+        let files = self.input_files(manifest.as_ref()).await?;
+        if files.is_empty() {
+            tracing::info!("Skip compacting because no sstable files found");
+            return Ok(CompactionReport {
+                duration: started_at.elapsed(),
+                ..Default::default()
+            });
+        }
+        let mut report = CompactionReport {
+            input_files: files.len(),
+            ..Default::default()
+        };
+        for file in files.iter() {
+            report.input_bytes += storage::file_len(self.storage.as_ref(), file).await?;
+        }
+
+        let mut throttle = self.throttle.map(Throttle::new);
+        let latest_entries = self
+            .latest_entries(&files, &mut report, throttle.as_mut())
+            .await?;
+
+        // write in key order, which a sparse index relies on
+        let other_sstables = self.other_sstables(&files, manifest.as_ref()).await?;
+        let now = self.clock.now()?;
+        let mut last_timestamp = 0;
+        let mut writer = self.new_writer(&mut last_timestamp, &mut report).await?;
+        for entry in latest_entries.into_values() {
+            let Some(entry) = self.resolve(entry, &other_sstables, now, &mut report).await else {
+                continue;
+            };
+            if writer.bytes_written() >= self.max_output_file_size {
+                self.finish_writer(writer, &mut report).await?;
+                writer = self.new_writer(&mut last_timestamp, &mut report).await?;
+            }
+            let bytes_written = writer.bytes_written();
+            writer
+                .set(&entry)
+                .await
+                .context("write entry to new sstable")?;
+            if let Some(throttle) = throttle.as_mut() {
+                throttle
+                    .consume(writer.bytes_written() - bytes_written)
+                    .await;
+            }
+        }
+        self.finish_writer(writer, &mut report).await?;
+        report.output_files = report.output_paths.len();
+        Manifest::update(
+            &self.dir,
+            &report.output_paths,
+            &files,
+            self.storage.as_ref(),
+        )
+        .await
+        .context("swap the compacted sstables in the manifest")?;
+        for path in report.output_paths.iter() {
+            self.invalidate(path);
+        }
+
+        // delete the old files, their bloom filters and the idx files of version 1 SSTables
+        let mut remove_file_fn_set = files.iter().fold(JoinSet::new(), |mut fn_set, file| {
+            let mut remove = |path: PathBuf| {
+                let storage = Arc::clone(&self.storage);
+                fn_set.spawn(async move { storage.remove(&path).await });
+            };
+            if let Ok(bloom_filter_path) = get_bloom_filter_path(file) {
+                remove(bloom_filter_path);
+            }
+            // only version 1 SSTables have one, the removal of a missing file is ignored
+            if let Ok(index_path) = get_index_path(file) {
+                remove(index_path);
+            }
+            remove(file.clone());
+            fn_set
+        });
+        while let Some(res) = remove_file_fn_set.join_next().await {
+            if let Err(e) = res {
+                tracing::error!("Failed to remove old sstable file: {}", e);
+            }
+        }
+        for file in files.iter() {
+            self.invalidate(file);
+        }
+
+        report.duration = started_at.elapsed();
+        tracing::info!(
+            "Compacted {} sstable files ({} bytes) into {:?} ({} bytes): {} entries, {} duplicates skipped, {} tombstones dropped, {} retained in {:?}",
+            report.input_files,
+            report.input_bytes,
+            report.output_paths,
+            report.output_bytes,
+            report.entries_written,
+            report.duplicates_skipped,
+            report.tombstones_dropped,
+            report.tombstones_retained,
+            report.duration
+        );
+        Ok(report)
+    }
+
+    /// Go through the merge of [`Compaction::compact`] without writing or removing any file,
+    /// to see what it would do. It does not take the lock of the directory, a compaction
+    /// running meanwhile may remove the files it reads.
+    pub async fn plan(&self) -> Result<CompactionPlan> {
+        let manifest = Manifest::load(&self.dir, self.storage.as_ref()).await?;
+        let files = self.input_files(manifest.as_ref()).await?;
+        let mut plan = CompactionPlan::default();
+        if files.is_empty() {
+            return Ok(plan);
+        }
+        for file in files.iter() {
+            let size = storage::file_len(self.storage.as_ref(), file).await?;
+            plan.input_bytes += size;
+            plan.input_files.push((file.clone(), size));
+        }
+
+        let mut throttle = self.throttle.map(Throttle::new);
+        let mut report = CompactionReport::default();
+        let latest_entries = self
+            .latest_entries(&files, &mut report, throttle.as_mut())
+            .await?;
+        let other_sstables = self.other_sstables(&files, manifest.as_ref()).await?;
+        let now = self.clock.now()?;
+        for entry in latest_entries.into_values() {
+            if let Some(entry) = self.resolve(entry, &other_sstables, now, &mut report).await {
+                plan.entries_written += 1;
+                plan.estimated_output_bytes += entry.encoded_len_v2() as u64;
+            }
+        }
+        plan.duplicates_skipped = report.duplicates_skipped;
+        plan.tombstones_dropped = report.tombstones_dropped;
+        plan.tombstones_retained = report.tombstones_retained;
+        plan.entries_filtered = report.entries_filtered;
+        plan.entries_expired = report.entries_expired;
+        plan.reclaimable_bytes = plan.input_bytes.saturating_sub(plan.estimated_output_bytes);
+        Ok(plan)
+    }
+
+    /// The newest version of every key of `files`, scanned in turn
+    async fn latest_entries(
+        &self,
+        files: &[PathBuf],
+        report: &mut CompactionReport,
+        mut throttle: Option<&mut Throttle>,
+    ) -> Result<BTreeMap<Vec<u8>, Entry>> {
+        let mut latest_entries = BTreeMap::new();
+        for file in files {
+            let options = SSTableReaderOptions {
+                max_field_len: self.max_field_len,
+                ..Default::default()
+            };
+            let reader = SSTableReader::with_storage(file, options, self.storage.as_ref()).await?;
+            reader
+                .scan(SSTableScanHandler::new(
+                    &mut latest_entries,
+                    &mut report.duplicates_skipped,
+                    throttle.as_deref_mut(),
+                ))
+                .await?;
+        }
+        Ok(latest_entries)
+    }
+
+    /// The entry to write for the newest version of a key, if any. A tombstone past its ttl is
+    /// dropped along with the versions it shadows, unless one of them is in a file left out of
+    /// the compaction. An entry the filter drops, or expired by `now`, turns into a tombstone kept
+    /// only for the latter.
+    async fn resolve(
+        &self,
+        mut entry: Entry,
+        other_sstables: &[SSTableReader],
+        now: u128,
+        report: &mut CompactionReport,
+    ) -> Option<Entry> {
+        let tombstone_expiry = now.saturating_sub(self.tombstone_ttl.as_micros());
+        let mut filtered = false;
+        if entry.is_expired(now) {
+            report.entries_expired += 1;
+            filtered = true;
+            entry.value = None;
+        }
+        for filter in self.filters.iter() {
+            if entry.is_deleted() {
+                break;
+            }
+            // the filter sees the value as written, a corrupted one is left for the reads
+            let decision = match entry.value_codec {
+                Codec::None => filter.decide(&entry),
+                _ => match entry.clone().decompressed() {
+                    Ok(decompressed) => filter.decide(&decompressed),
+                    Err(_) => FilterDecision::Keep,
+                },
+            };
+            match decision {
+                FilterDecision::Keep => {}
+                FilterDecision::Drop => {
+                    report.entries_filtered += 1;
+                    filtered = true;
+                    entry.value = None;
+                }
+                FilterDecision::Replace(value) => {
+                    entry.value = Some(value.into());
+                    entry.value_codec = Codec::None;
+                }
+            }
+        }
+        if entry.is_deleted() && !filtered {
+            if entry.timestamp < tombstone_expiry
+                && !shadows_older_version(other_sstables, &entry).await
+            {
+                report.tombstones_dropped += 1;
+                return None;
+            }
+            report.tombstones_retained += 1;
+        } else if filtered && !shadows_older_version(other_sstables, &entry).await {
+            return None;
+        }
+        Some(entry)
+    }
+
+    /// The live SSTables the strategy picks for the compaction, along with the level 1 files
+    /// they overlap for [`CompactionStrategy::Leveled`], newest first as they are scanned
+    async fn input_files(&self, manifest: Option<&Manifest>) -> Result<Vec<PathBuf>> {
+        let [level0, level1] =
+            list_level_files(&self.dir, self.ext.as_str(), self.storage.as_ref())
+                .await?
+                .map(|files| {
+                    files
+                        .into_iter()
+                        .filter(|file| manifest.is_none_or(|m| m.contains(file)))
+                        .collect::<Vec<_>>()
+                });
+        let mut sizes = Vec::with_capacity(level0.len());
+        for file in level0 {
+            let size = storage::file_len(self.storage.as_ref(), &file).await?;
+            sizes.push((file, size));
+        }
+        let mut files = self.strategy.pick(sizes, self.size_filter);
+        if matches!(self.strategy, CompactionStrategy::Leveled { .. }) && !files.is_empty() {
+            files.extend(overlapping_files(&files, level1, self.storage.as_ref()).await);
+        }
+        files.sort_by(|a, b| allocation_order(b).cmp(&allocation_order(a)));
+        Ok(files)
+    }
+
+    /// Where the compacted SSTables go, level 1 for [`CompactionStrategy::Leveled`]
+    fn output_dir(&self) -> PathBuf {
+        match self.strategy {
+            CompactionStrategy::Leveled { .. } => level_dir(&self.dir, 1),
+            _ => self.dir.clone(),
+        }
+    }
+
+    /// A writer to the next output SSTable, named after a timestamp greater than the ones before
+    async fn new_writer(
+        &self,
+        last_timestamp: &mut u128,
+        report: &mut CompactionReport,
+    ) -> Result<SSTableWriter> {
+        *last_timestamp = self.clock.now()?.max(*last_timestamp + 1);
+        let output_dir = self.output_dir();
+        self.storage.create_dir_all(&output_dir).await?;
+        let mut writer =
+            SSTableWriter::create(&output_dir, *last_timestamp, Arc::clone(&self.storage)).await?;
+        writer.set_options(self.sstable_options);
+        report.output_paths.push(writer.path().to_path_buf());
+        Ok(writer)
+    }
+
+    /// Persist the output SSTable of `writer`, the last one of `report`
+    async fn finish_writer(
+        &self,
+        mut writer: SSTableWriter,
+        report: &mut CompactionReport,
+    ) -> Result<()> {
+        writer.flush().await.context("flush new sstable to disk")?;
+        let path = report.output_paths.last().context("no output sstable")?;
+        report.output_bytes += storage::file_len(self.storage.as_ref(), path).await?;
+        report.entries_written += writer.entries_written();
+        Ok(())
+    }
+
+    /// The live SSTable files of the directory left out of the compaction of `files`, opened
+    /// with a lazy index as only a few keys are looked up in them
+    async fn other_sstables(
+        &self,
+        files: &[PathBuf],
+        manifest: Option<&Manifest>,
+    ) -> Result<Vec<SSTableReader>> {
+        let options = SSTableReaderOptions {
+            index_mode: IndexMode::Lazy,
+            max_field_len: self.max_field_len,
+            ..Default::default()
+        };
+        let mut readers = Vec::new();
+        for file in list_level_files(&self.dir, self.ext.as_str(), self.storage.as_ref())
+            .await?
+            .concat()
+        {
+            if !files.contains(&file) && manifest.is_none_or(|m| m.contains(&file)) {
+                let reader =
+                    SSTableReader::with_storage(&file, options, self.storage.as_ref()).await?;
+                readers.push(reader);
+            }
+        }
+        Ok(readers)
+    }
+
+    fn invalidate(&self, path: &Path) {
+        if let Some(sstable_querier) = self.sstable_querier.as_ref() {
+            sstable_querier.invalidate(path);
+        }
+    }
+}
+
+/// Remove the lock a compaction interrupted by a crash left in `dir` of `storage`, see
+/// [`Compaction::try_lock`].
+pub(crate) async fn remove_stale_lock(dir: &Path, storage: &dyn Storage) -> Result<()> {
+    let path = dir.join(LOCK_FILE_NAME);
+    match storage.remove(&path).await {
+        Ok(()) => tracing::warn!("Removed the stale compaction lock {:?}", path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("remove compaction lock {:?}", path)),
+    }
+    Ok(())
+}
+
+/// The files of `level1` whose key range overlaps the one of `files`, all of them when the
+/// range of one of `files` is unknown
+async fn overlapping_files(
+    files: &[PathBuf],
+    level1: Vec<PathBuf>,
+    storage: &dyn Storage,
+) -> Vec<PathBuf> {
+    let mut key_range: Option<(Vec<u8>, Vec<u8>)> = None;
+    for file in files {
+        let Some((min_key, max_key)) = SSTableReader::footer_key_range(file, storage).await else {
+            return level1;
+        };
+        key_range = Some(match key_range {
+            Some((min, max)) => (min.min(min_key), max.max(max_key)),
+            None => (min_key, max_key),
+        });
+    }
+    let Some((min_key, max_key)) = key_range else {
+        return Vec::new();
+    };
+    let bounds = (
+        Bound::Included(min_key.as_slice()),
+        Bound::Included(max_key.as_slice()),
+    );
+    let mut overlapping = Vec::new();
+    for file in level1 {
+        if SSTableReader::may_overlap(&file, bounds, storage).await {
+            overlapping.push(file);
+        }
+    }
+    overlapping
+}
+
+/// Whether one of the `sstables` holds a version of the key of `tombstone` older than it
+async fn shadows_older_version(sstables: &[SSTableReader], tombstone: &Entry) -> bool {
+    let key = tombstone.key.as_slice();
+    for sstable in sstables {
+        if !sstable.overlaps((Bound::Included(key), Bound::Included(key))) {
+            continue;
+        }
+        match sstable.try_get(key).await {
+            Ok(Some(entry)) if entry.timestamp > tombstone.timestamp => {}
+            Ok(None) => {}
+            // an unreadable version may be an older one
+            _ => return true,
+        }
+    }
+    false
+}
+
+/// Collects the version with the greatest timestamp of every key, a tombstone included. The
+/// file names do not order the versions, so every one of them is compared.
+struct SSTableScanHandler<'a> {
+    latest_entries: &'a mut BTreeMap<Vec<u8>, Entry>,
+    duplicates_skipped: &'a mut u64,
+    throttle: Option<&'a mut Throttle>,
+}
+
+impl<'a> SSTableScanHandler<'a> {
+    fn new(
+        latest_entries: &'a mut BTreeMap<Vec<u8>, Entry>,
+        duplicates_skipped: &'a mut u64,
+        throttle: Option<&'a mut Throttle>,
+    ) -> Self {
+        Self {
+            latest_entries,
+            duplicates_skipped,
+            throttle,
+        }
+    }
+}
+
+#[async_trait]
+impl SSTableReaderScanHandler for SSTableScanHandler<'_> {
+    async fn handle(&mut self, entry: Entry) -> Result<()> {
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.consume(entry.encoded_len() as u64).await;
+        }
+        match self.latest_entries.get(&entry.key) {
+            // on a tie the file scanned first, the newest one, wins
+            Some(latest) if latest.timestamp >= entry.timestamp => *self.duplicates_skipped += 1,
+            _ => {
+                if self
+                    .latest_entries
+                    .insert(entry.key.clone(), entry)
+                    .is_some()
+                {
+                    *self.duplicates_skipped += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use anyhow::Result;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::utils::{get_files_with_ext, micros_now};
+
+    // Helper function to create a dummy SSTable file for testing
+    async fn create_dummy_sstable_file(dir: &Path, filename: &str, entry: &Entry) -> Result<()> {
+        let file_path = dir.join(filename);
+        let mut writer = SSTableWriter::new(&file_path)
+            .await
+            .context("init sstable writer")?;
+        writer
+            .set(entry)
+            .await
+            .context("insert record")?
+            .flush()
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact() -> Result<()> {
+        // Setup test directory and files
+        let tmpdir = TempDir::new("test_compact")?;
+        let test_dir = tmpdir.path();
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello").map(|i| i.to_vec()), 1);
+        let entry_2 = Entry::new(b"test2".to_vec(), Some(b"hello").map(|i| i.to_vec()), 2);
+        create_dummy_sstable_file(test_dir, "test1.db", &entry_1).await?;
+        create_dummy_sstable_file(test_dir, "test2.db", &entry_2).await?;
+
+        let querier = Arc::new(SSTableQuerier::new(test_dir).await?);
+        assert!(querier.query(entry_1.key.as_slice()).await.is_some());
+
+        // Initialize Compaction
+        let compaction = Compaction::new(test_dir.to_path_buf(), 200, "db")
+            .sstable_querier(Arc::clone(&querier));
+
+        // Perform compaction
+        compaction.compact().await.context("Failed to compact")?;
+
+        // Check results
+        // 1. check if the old files are deleted
+        assert!(!test_dir.join("test1.db").exists());
+        assert!(!test_dir.join("test2.db").exists());
+        assert!(!test_dir.join("test1.db.bf").exists());
+        assert!(!test_dir.join("test2.db.bf").exists());
+        assert!(get_files_with_ext(test_dir, "idx")?.is_empty());
+        // 2. check if the new file is created
+        let files = get_files_with_ext(test_dir, "db")?;
+        assert_eq!(files.len(), 1);
+
+        // 3. check if the data in the new file are correct
+        let new_file = files.first().unwrap();
+        let sstable_reader = SSTableReader::new(new_file).await?;
+        assert!(sstable_reader.get(entry_1.key.as_slice()).await.is_some());
+        assert!(sstable_reader.get(entry_2.key.as_slice()).await.is_some());
+
+        // 4. check if the querier reads the new file
+        assert!(querier.query(entry_1.key.as_slice()).await.is_some());
+        assert!(querier.query(entry_2.key.as_slice()).await.is_some());
+
+        // Cleanup
+        tmpdir.close().context("remove the test folders")?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_keeps_the_newest_version() -> Result<()> {
+        let tmpdir = TempDir::new("test_compact_newest")?;
+        let test_dir = tmpdir.path();
+
+        // the older file holds the newer version
+        let newer = Entry::new(b"test1".to_vec(), Some(b"newer".to_vec()), 5);
+        create_dummy_sstable_file(test_dir, "test1.db", &newer).await?;
+        let older = Entry::new(b"test1".to_vec(), Some(b"older".to_vec()), 2);
+        create_dummy_sstable_file(test_dir, "test2.db", &older).await?;
+        // and a value newer than the tombstone of the next compaction
+        let tombstone = Entry::new(b"test2".to_vec(), None, 3);
+        create_dummy_sstable_file(test_dir, "test3.db", &tombstone).await?;
+        let mut writer = SSTableWriter::new(&test_dir.join("0.db")).await?;
+        writer
+            .set(&Entry::new(b"padding".to_vec(), Some(vec![0; 200]), 1))
+            .await?
+            .set(&Entry::new(
+                b"test2".to_vec(),
+                Some(b"rewritten".to_vec()),
+                4,
+            ))
+            .await?
+            .flush()
+            .await?;
+
+        Compaction::new(test_dir.to_path_buf(), 200, "db")
+            .compact()
+            .await
+            .context("Failed to compact")?;
+
+        let querier = SSTableQuerier::new(test_dir).await?;
+        let entry = querier.query(b"test1").await.unwrap();
+        assert_eq!(entry.value.as_deref(), Some(&b"newer"[..]));
+        let entry = querier.query(b"test2").await.unwrap();
+        assert_eq!(entry.value.as_deref(), Some(&b"rewritten"[..]));
+        let reader = SSTableReader::new(&test_dir.join("0.db")).await?;
+        assert!(reader.get(b"test2").await.is_some());
+
+        tmpdir.close().context("remove the test folders")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_keeps_the_tombstones_of_the_files_left_out() -> Result<()> {
+        let tmpdir = TempDir::new("test_compact_tombstones")?;
+        let test_dir = tmpdir.path();
+
+        // a value in a large file, left out of the compaction
+        let mut writer = SSTableWriter::new(&test_dir.join("0.db")).await?;
+        writer
+            .set(&Entry::new(b"padding".to_vec(), Some(vec![0; 200]), 1))
+            .await?
+            .set(&Entry::new(b"test1".to_vec(), Some(b"deleted".to_vec()), 1))
+            .await?
+            .flush()
+            .await?;
+        let tombstone = Entry::new(b"test1".to_vec(), None, 2);
+        create_dummy_sstable_file(test_dir, "test1.db", &tombstone).await?;
+        // nothing to shadow in the large file
+        let tombstone = Entry::new(b"test2".to_vec(), None, 3);
+        create_dummy_sstable_file(test_dir, "test2.db", &tombstone).await?;
+
+        let report = Compaction::new(test_dir.to_path_buf(), 200, "db")
+            .compact()
+            .await
+            .context("Failed to compact")?;
+        assert_eq!(report.tombstones_dropped, 1);
+        assert_eq!(report.tombstones_retained, 1);
+
+        let files = get_files_with_ext(test_dir, "db")?;
+        assert_eq!(files.len(), 2);
+        let new_file = files.iter().find(|file| !file.ends_with("0.db")).unwrap();
+        let reader = SSTableReader::new(new_file).await?;
+        assert!(reader.get(b"test1").await.unwrap().is_deleted());
+        assert!(reader.get(b"test2").await.is_none());
+
+        let db = crate::DatabaseBuilder::new(test_dir.to_path_buf())
+            .build()
+            .await?;
+        assert!(db.get(b"test1").await.is_none());
+        assert!(db.get(b"padding").await.is_some());
+
+        tmpdir.close().context("remove the test folders")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_with_tombstone_ttl() -> Result<()> {
+        let tmpdir = TempDir::new("test_compact_tombstone_ttl")?;
+        let test_dir = tmpdir.path();
+        let expired = Entry::new(b"test1".to_vec(), None, 1);
+        create_dummy_sstable_file(test_dir, "test1.db", &expired).await?;
+        let recent = Entry::new(b"test2".to_vec(), None, micros_now()?);
+        create_dummy_sstable_file(test_dir, "test2.db", &recent).await?;
+
+        let report = Compaction::new(test_dir.to_path_buf(), 200, "db")
+            .tombstone_ttl(Duration::from_secs(60 * 60))
+            .compact()
+            .await?;
+        assert_eq!(report.tombstones_dropped, 1);
+        assert_eq!(report.tombstones_retained, 1);
+
+        let reader = SSTableReader::new(&report.output_paths[0]).await?;
+        assert!(reader.get(b"test1").await.is_none());
+        assert!(reader.get(b"test2").await.unwrap().is_deleted());
+
+        tmpdir.close().context("remove the test folders")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_report() -> Result<()> {
+        let tmpdir = TempDir::new("test_compact_report")?;
+        let test_dir = tmpdir.path();
+
+        // nothing to compact
+        let compaction = Compaction::new(test_dir.to_path_buf(), 1000, "db");
+        let report = compaction.compact().await?;
+        assert_eq!((report.input_files, report.output_files), (0, 0));
+
+        let mut writer = SSTableWriter::new(&test_dir.join("test1.db")).await?;
+        writer
+            .set(&Entry::new(b"key1".to_vec(), Some(b"old".to_vec()), 1))
+            .await?
+            .set(&Entry::new(b"key2".to_vec(), Some(b"value".to_vec()), 1))
+            .await?
+            .set(&Entry::new(b"key3".to_vec(), Some(b"deleted".to_vec()), 1))
+            .await?
+            .flush()
+            .await?;
+        let mut writer = SSTableWriter::new(&test_dir.join("test2.db")).await?;
+        writer
+            .set(&Entry::new(b"key1".to_vec(), Some(b"new".to_vec()), 2))
+            .await?
+            .set(&Entry::new(b"key3".to_vec(), None, 2))
+            .await?
+            .set(&Entry::new(b"key4".to_vec(), Some(b"value".to_vec()), 2))
+            .await?
+            .flush()
+            .await?;
+        let input_bytes = std::fs::metadata(test_dir.join("test1.db"))?.len()
+            + std::fs::metadata(test_dir.join("test2.db"))?.len();
+
+        let report = compaction.compact().await?;
+        assert_eq!(report.input_files, 2);
+        assert_eq!(report.input_bytes, input_bytes);
+        assert_eq!(report.output_files, 1);
+        let files = get_files_with_ext(test_dir, "db")?;
+        assert_eq!(report.output_bytes, std::fs::metadata(&files[0])?.len());
+        // key1, key2 and key4
+        assert_eq!(report.entries_written, 3);
+        // the old key1 and the value of key3
+        assert_eq!(report.duplicates_skipped, 2);
+        assert_eq!(report.tombstones_dropped, 1);
+
+        tmpdir.close().context("remove the test folders")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_plan() -> Result<()> {
+        let tmpdir = TempDir::new("test_compact_plan")?;
+        let test_dir = tmpdir.path();
+        let compaction = Compaction::new(test_dir.to_path_buf(), 1000, "db");
+        assert!(compaction.plan().await?.input_files.is_empty());
+
+        let mut writer = SSTableWriter::new(&test_dir.join("test1.db")).await?;
+        writer
+            .set(&Entry::new(b"key1".to_vec(), Some(b"old".to_vec()), 1))
+            .await?
+            .set(&Entry::new(b"key2".to_vec(), Some(b"deleted".to_vec()), 1))
+            .await?
+            .flush()
+            .await?;
+        let new_entry = Entry::new(b"key1".to_vec(), Some(b"new".to_vec()), 2);
+        let mut writer = SSTableWriter::new(&test_dir.join("test2.db")).await?;
+        writer
+            .set(&new_entry)
+            .await?
+            .set(&Entry::new(b"key2".to_vec(), None, 2))
+            .await?
+            .flush()
+            .await?;
+
+        let plan = compaction.plan().await?;
+        let input_paths = plan
+            .input_files
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            input_paths,
+            vec![test_dir.join("test2.db"), test_dir.join("test1.db")]
+        );
+        assert_eq!(plan.entries_written, 1);
+        assert_eq!(plan.duplicates_skipped, 2);
+        assert_eq!(plan.tombstones_dropped, 1);
+        assert_eq!(
+            plan.estimated_output_bytes,
+            new_entry.encoded_len_v2() as u64
+        );
+        assert_eq!(
+            plan.reclaimable_bytes,
+            plan.input_bytes - plan.estimated_output_bytes
+        );
+        // nothing changed on disk
+        assert_eq!(get_files_with_ext(test_dir, "db")?.len(), 2);
+
+        let report = compaction.compact().await?;
+        assert_eq!(report.input_bytes, plan.input_bytes);
+        assert_eq!(report.entries_written, plan.entries_written);
+        assert_eq!(report.duplicates_skipped, plan.duplicates_skipped);
+        assert_eq!(report.tombstones_dropped, plan.tombstones_dropped);
+
+        tmpdir.close().context("remove the test folders")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_survives_a_crash() -> Result<()> {
+        let tmpdir = TempDir::new("test_compact_crash")?;
+        let test_dir = tmpdir.path();
+        let older = Entry::new(b"test1".to_vec(), Some(b"older".to_vec()), 1);
+        create_dummy_sstable_file(test_dir, "test1.db", &older).await?;
+        let db = crate::DatabaseBuilder::new(test_dir.to_path_buf())
+            .build()
+            .await?;
+        drop(db);
+
+        // a crash before the manifest swap leaves the output out of it
+        let unlisted = Entry::new(b"test1".to_vec(), Some(b"unlisted".to_vec()), 2);
+        create_dummy_sstable_file(test_dir, "test2.db", &unlisted).await?;
+        let querier = SSTableQuerier::new(test_dir).await?;
+        let entry = querier.query(b"test1").await.unwrap();
+        assert_eq!(entry.value, older.value);
+        let report = Compaction::new(test_dir.to_path_buf(), 200, "db")
+            .compact()
+            .await?;
+        assert_eq!(report.input_files, 1);
+        assert!(test_dir.join("test2.db").exists());
+
+        // a crash after it leaves the inputs behind
+        let output = report.output_paths[0].clone();
+        let newer = Entry::new(b"test1".to_vec(), Some(b"newer".to_vec()), 3);
+        create_dummy_sstable_file(test_dir, "test3.db", &newer).await?;
+        Manifest::update(
+            test_dir,
+            &[test_dir.join("test3.db")],
+            std::slice::from_ref(&output),
+            &LocalFs,
+        )
+        .await?;
+
+        let db = crate::DatabaseBuilder::new(test_dir.to_path_buf())
+            .build()
+            .await?;
+        assert_eq!(db.get(b"test1").await.unwrap().value, &b"newer"[..]);
+        assert!(!test_dir.join("test2.db").exists());
+        assert!(!output.exists());
+        assert_eq!(get_files_with_ext(test_dir, "db")?.len(), 1);
+
+        tmpdir.close().context("remove the test folders")?;
+        Ok(())
+    }
+
+    struct TestFilter;
+
+    impl CompactionFilter for TestFilter {
+        fn decide(&self, entry: &Entry) -> FilterDecision {
+            match entry.key.as_slice() {
+                b"drop1" | b"drop2" => FilterDecision::Drop,
+                b"replace" => FilterDecision::Replace(b"replaced".to_vec()),
+                _ => FilterDecision::Keep,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compact_with_filter() -> Result<()> {
+        let tmpdir = TempDir::new("test_compact_filter")?;
+        let test_dir = tmpdir.path();
+
+        // an older version of drop2 in a large file, left out of the compaction
+        let mut writer = SSTableWriter::new(&test_dir.join("0.db")).await?;
+        writer
+            .set(&Entry::new(b"drop2".to_vec(), Some(b"older".to_vec()), 1))
+            .await?
+            .set(&Entry::new(b"padding".to_vec(), Some(vec![0; 200]), 1))
+            .await?
+            .flush()
+            .await?;
+        for (i, key) in [&b"drop1"[..], b"drop2", b"keep", b"replace"]
+            .into_iter()
+            .enumerate()
+        {
+            let entry = Entry::new(key.to_vec(), Some(b"value".to_vec()), 2);
+            create_dummy_sstable_file(test_dir, &format!("test{}.db", i), &entry).await?;
+        }
+
+        let db = crate::DatabaseBuilder::new(test_dir.to_path_buf())
+            .compaction_filter(Arc::new(TestFilter))
+            .build()
+            .await?;
+        let report = db.compact(200).await?;
+        assert_eq!(report.input_files, 4);
+        assert_eq!(report.entries_filtered, 2);
+        assert_eq!(report.tombstones_dropped, 0);
+        // keep, replace and the tombstone of drop2
+        assert_eq!(report.entries_written, 3);
+
+        assert!(db.get(b"drop1").await.is_none());
+        assert!(db.get(b"drop2").await.is_none());
+        assert_eq!(db.get(b"keep").await.unwrap().value, &b"value"[..]);
+        let replaced = db.get(b"replace").await.unwrap();
+        assert_eq!(replaced.value, &b"replaced"[..]);
+        assert_eq!(replaced.timestamp, 2);
+
+        // an extra filter sees the values the one of the database replaced
+        struct DropReplaced;
+        impl CompactionFilter for DropReplaced {
+            fn decide(&self, entry: &Entry) -> FilterDecision {
+                match entry.value.as_deref() == Some(b"replaced") {
+                    true => FilterDecision::Drop,
+                    false => FilterDecision::Keep,
+                }
+            }
+        }
+        let report = db
+            .compact_with(u64::MAX, Some(Arc::new(DropReplaced)), 0)
+            .await?;
+        assert_eq!(report.entries_filtered, 1);
+        assert!(db.get(b"replace").await.is_none());
+        assert_eq!(db.get(b"keep").await.unwrap().value, &b"value"[..]);
+
+        tmpdir.close().context("remove the test folders")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_with_throttle() -> Result<()> {
+        let tmpdir = TempDir::new("test_compact_throttle")?;
+        let test_dir = tmpdir.path();
+        for i in 0..20u32 {
+            let entry = Entry::new(format!("key{:03}", i).into_bytes(), Some(vec![0; 100]), 1);
+            create_dummy_sstable_file(test_dir, &format!("test{}.db", i), &entry).await?;
+        }
+
+        // about 2.5 KB to read and as much to write, one second worth of it goes through at once
+        let report = Compaction::new(test_dir.to_path_buf(), 1000, "db")
+            .with_throttle(4000)
+            .compact()
+            .await?;
+        assert_eq!(report.entries_written, 20);
+        assert!(report.duration >= Duration::from_millis(200));
+
+        tmpdir.close().context("remove the test folders")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_is_locked() -> Result<()> {
+        let tmpdir = TempDir::new("test_compact_lock")?;
+        let test_dir = tmpdir.path();
+        let entry = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        create_dummy_sstable_file(test_dir, "test1.db", &entry).await?;
+        let compaction = Compaction::new(test_dir.to_path_buf(), 200, "db");
+
+        let lock = Compaction::try_lock(test_dir).await?;
+        let err = compaction.compact().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::CompactionInProgress(_))
+        ));
+        assert!(test_dir.join("test1.db").exists());
+
+        lock.release().await?;
+        assert_eq!(compaction.compact().await?.input_files, 1);
+        assert!(!test_dir.join(LOCK_FILE_NAME).exists());
+
+        // a crash leaves the lock behind, opening the database removes it
+        std::mem::forget(Compaction::try_lock(test_dir).await?);
+        crate::DatabaseBuilder::new(test_dir.to_path_buf())
+            .build()
+            .await?;
+        assert!(!test_dir.join(LOCK_FILE_NAME).exists());
+
+        tmpdir.close().context("remove the test folders")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_rolls_over_to_multiple_files() -> Result<()> {
+        let tmpdir = TempDir::new("test_compact_roll_over")?;
+        let test_dir = tmpdir.path();
+        for i in 0..50u32 {
+            let entry = Entry::new(format!("key{:03}", i).into_bytes(), Some(vec![0; 20]), 1);
+            create_dummy_sstable_file(test_dir, &format!("test{}.db", i), &entry).await?;
+        }
+
+        let querier = Arc::new(SSTableQuerier::new(test_dir).await?);
+        let report = Compaction::new(test_dir.to_path_buf(), 200, "db")
+            .max_output_file_size(500)
+            .sstable_querier(Arc::clone(&querier))
+            .compact()
+            .await?;
+        assert_eq!(report.input_files, 50);
+        assert_eq!(report.entries_written, 50);
+        assert!(report.output_files > 1);
+        assert_eq!(report.output_paths.len(), report.output_files);
+        let mut files = get_files_with_ext(test_dir, "db")?;
+        files.sort();
+        assert_eq!(files, report.output_paths);
+
+        // the outputs are sorted and do not overlap
+        let mut last_max_key = None;
+        for path in report.output_paths.iter() {
+            let reader = SSTableReader::new(path).await?;
+            let range = reader.key_range().unwrap();
+            assert!(last_max_key.is_none_or(|max_key: Vec<u8>| max_key.as_slice() < *range.start()));
+            last_max_key = Some(range.end().to_vec());
+        }
+        for i in 0..50u32 {
+            let key = format!("key{:03}", i).into_bytes();
+            assert!(querier.query(&key).await.is_some());
+        }
+
+        tmpdir.close().context("remove the test folders")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_with_sparse_index() -> Result<()> {
+        let tmpdir = TempDir::new("test_compact_sparse")?;
+        let test_dir = tmpdir.path();
+        let deleted_key = b"deleted".to_vec();
+
+        // a large file, left out of the compaction, with a sparse index
+        let mut writer = SSTableWriter::new(&test_dir.join("0.db")).await?;
+        writer.set_index_interval(4);
+        writer
+            .set(&Entry::new(deleted_key.clone(), Some(b"old".to_vec()), 1))
+            .await?;
+        for i in 0..50u32 {
+            let key = format!("key{:03}", i).into_bytes();
+            writer
+                .set(&Entry::new(key, Some(b"hello".to_vec()), 1))
+                .await?;
+        }
+        writer.flush().await?;
+
+        // the tombstone is newer than the value
+        let value = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 2);
+        create_dummy_sstable_file(test_dir, "test1.db", &value).await?;
+        let tombstone = Entry::new(b"test1".to_vec(), None, 3);
+        create_dummy_sstable_file(test_dir, "test2.db", &tombstone).await?;
+        let tombstone = Entry::new(deleted_key.clone(), None, 4);
+        create_dummy_sstable_file(test_dir, "test3.db", &tombstone).await?;
+
+        Compaction::new(test_dir.to_path_buf(), 200, "db")
+            .index_every_n_keys(4)
+            .compact()
+            .await
+            .context("Failed to compact")?;
+
+        let files = get_files_with_ext(test_dir, "db")?;
+        assert_eq!(files.len(), 2);
+        for file in files {
+            let reader = SSTableReader::new(&file).await?;
+            assert_eq!(reader.index_interval(), 4);
+            assert!(reader.get(b"test1").await.is_none());
+        }
+
+        // the large file keeps its old value, shadowed by the tombstone
+        let reader = SSTableReader::new(&test_dir.join("0.db")).await?;
+        assert!(reader.get(&deleted_key).await.is_some());
+        let querier = SSTableQuerier::new(test_dir).await?;
+        assert!(querier.query(&deleted_key).await.unwrap().is_deleted());
+
+        tmpdir.close().context("remove the test folders")?;
+        Ok(())
+    }
+}
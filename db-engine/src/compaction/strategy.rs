@@ -0,0 +1,221 @@
+use std::path::PathBuf;
+
+/// A file of a size tier may be up to this many times the average size of the smaller files of
+/// the tier.
+const TIER_SIZE_RATIO: f64 = 1.5;
+
+/// Which sizes of SSTables a [`CompactionStrategy::All`] compaction merges, see
+/// [`Compaction::size_filter`](super::Compaction::size_filter), or of files
+/// [`get_files_with_ext_filtered`](crate::get_files_with_ext_filtered) lists. The bounds compose without
+/// overlap: a file of `limit` bytes is `AtLeast(limit)`, not `SmallerThan(limit)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizeFilter {
+    /// Strictly smaller than the limit
+    SmallerThan(u64),
+    /// The limit or larger
+    AtLeast(u64),
+    /// From the first limit, included, to the second one, excluded
+    Between(u64, u64),
+    #[default]
+    Any,
+}
+
+impl SizeFilter {
+    pub fn matches(&self, size: u64) -> bool {
+        match *self {
+            Self::SmallerThan(limit) => size < limit,
+            Self::AtLeast(limit) => size >= limit,
+            Self::Between(min, max) => (min..max).contains(&size),
+            Self::Any => true,
+        }
+    }
+}
+
+/// Which SSTables a [`Compaction`](super::Compaction) merges, the others are left untouched.
+/// Only the level 0 files are picked, along with the level 1 files they overlap for
+/// [`CompactionStrategy::Leveled`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompactionStrategy {
+    /// Every file matching the [`Compaction::size_filter`](super::Compaction::size_filter),
+    /// smaller than the size of [`Compaction::new`](super::Compaction::new) by default.
+    #[default]
+    All,
+    /// Group the files of similar size into tiers and merge the `max_merge` smallest files of
+    /// the smallest tier holding at least `min_merge`. A merged file joins a larger tier, so
+    /// every record is rewritten about once per tier instead of at every compaction, and the
+    /// large files are merged as well once enough of them pile up. Ignores the
+    /// [`Compaction::size_filter`](super::Compaction::size_filter).
+    SizeTiered { min_merge: usize, max_merge: usize },
+    /// Once the level 0 files add up to `level0_max_size` bytes, merge them all with the
+    /// level 1 files they overlap into level 1, see [`level_dir`](crate::sstable::level_dir).
+    /// Cap the size of the level 1 files with
+    /// [`Compaction::max_output_file_size`](super::Compaction::max_output_file_size).
+    Leveled { level0_max_size: u64 },
+}
+
+impl CompactionStrategy {
+    /// Pick the inputs of the next compaction among the level 0 `files` and their sizes,
+    /// matching `size_filter` for [`CompactionStrategy::All`].
+    pub(crate) fn pick(&self, files: Vec<(PathBuf, u64)>, size_filter: SizeFilter) -> Vec<PathBuf> {
+        match *self {
+            Self::All => files
+                .into_iter()
+                .filter(|(_, size)| size_filter.matches(*size))
+                .map(|(path, _)| path)
+                .collect(),
+            Self::SizeTiered {
+                min_merge,
+                max_merge,
+            } => size_tiers(files)
+                .into_iter()
+                .find(|tier| tier.len() >= min_merge.max(1))
+                .map(|tier| tier.into_iter().take(max_merge).map(|(path, _)| path))
+                .into_iter()
+                .flatten()
+                .collect(),
+            Self::Leveled { level0_max_size } => {
+                match files.iter().map(|(_, size)| size).sum::<u64>() >= level0_max_size {
+                    true => files.into_iter().map(|(path, _)| path).collect(),
+                    false => Vec::new(),
+                }
+            }
+        }
+    }
+}
+
+/// The `files` grouped by size, the smallest first
+fn size_tiers(mut files: Vec<(PathBuf, u64)>) -> Vec<Vec<(PathBuf, u64)>> {
+    files.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    let mut tiers: Vec<Vec<(PathBuf, u64)>> = Vec::new();
+    for file in files {
+        match tiers.last_mut() {
+            Some(tier) if file.1 as f64 <= average_size(tier) * TIER_SIZE_RATIO => tier.push(file),
+            _ => tiers.push(vec![file]),
+        }
+    }
+    tiers
+}
+
+fn average_size(tier: &[(PathBuf, u64)]) -> f64 {
+    tier.iter().map(|(_, size)| *size as f64).sum::<f64>() / tier.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    use super::{super::Compaction, *};
+
+    async fn picked(dir: &Path, strategy: CompactionStrategy) -> Result<Vec<String>> {
+        let compaction = Compaction::new(dir.to_path_buf(), 500, "db").strategy(strategy);
+        let mut names = compaction
+            .input_files(None)
+            .await?
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+
+    #[tokio::test]
+    async fn it_picks_the_files() -> Result<()> {
+        let temp_dir = TempDir::new("compaction_strategy")?;
+        let dir = temp_dir.path();
+        for (name, size) in [
+            ("a.db", 100),
+            ("b.db", 110),
+            ("c.db", 140),
+            ("d.db", 1000),
+            ("e.db", 1200),
+            ("f.db", 10000),
+        ] {
+            std::fs::write(dir.join(name), vec![0; size])?;
+        }
+
+        assert_eq!(
+            picked(dir, CompactionStrategy::All).await?,
+            ["a.db", "b.db", "c.db"]
+        );
+        let size_tiered = |min_merge, max_merge| CompactionStrategy::SizeTiered {
+            min_merge,
+            max_merge,
+        };
+        assert_eq!(
+            picked(dir, size_tiered(3, 4)).await?,
+            ["a.db", "b.db", "c.db"]
+        );
+        // the smallest files of the tier
+        assert_eq!(picked(dir, size_tiered(2, 2)).await?, ["a.db", "b.db"]);
+        // no tier is large enough
+        assert!(picked(dir, size_tiered(4, 4)).await?.is_empty());
+
+        // the large files are merged as well
+        for name in ["a.db", "b.db", "c.db"] {
+            std::fs::remove_file(dir.join(name))?;
+        }
+        assert_eq!(picked(dir, size_tiered(2, 4)).await?, ["d.db", "e.db"]);
+        assert!(picked(dir, CompactionStrategy::All).await?.is_empty());
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn it_matches_the_sizes_at_the_limits() {
+        let cases = [
+            (SizeFilter::SmallerThan(100), [true, false, false]),
+            (SizeFilter::AtLeast(100), [false, true, true]),
+            (SizeFilter::Between(100, 101), [false, true, false]),
+            (SizeFilter::Between(100, 100), [false, false, false]),
+            (SizeFilter::Any, [true, true, true]),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!([99, 100, 101].map(|size| filter.matches(size)), expected);
+        }
+        assert!(SizeFilter::SmallerThan(u64::MAX).matches(u64::MAX - 1));
+        assert!(!SizeFilter::SmallerThan(0).matches(0));
+        assert!(SizeFilter::AtLeast(0).matches(0));
+    }
+
+    #[tokio::test]
+    async fn it_picks_the_files_by_size() -> Result<()> {
+        let temp_dir = TempDir::new("compaction_strategy")?;
+        let dir = temp_dir.path();
+        for (name, size) in [("a.db", 499), ("b.db", 500), ("c.db", 501)] {
+            std::fs::write(dir.join(name), vec![0; size])?;
+        }
+        let picked = |size_filter| async move {
+            let compaction = Compaction::new(dir.to_path_buf(), 500, "db");
+            let compaction = match size_filter {
+                Some(size_filter) => compaction.size_filter(size_filter),
+                None => compaction,
+            };
+            let mut names = compaction
+                .input_files(None)
+                .await?
+                .iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            names.sort();
+            anyhow::Ok(names)
+        };
+
+        // a file exactly at the size of `Compaction::new` is left alone
+        assert_eq!(picked(None).await?, ["a.db"]);
+        assert_eq!(
+            picked(Some(SizeFilter::AtLeast(500))).await?,
+            ["b.db", "c.db"]
+        );
+        assert_eq!(picked(Some(SizeFilter::Between(500, 501))).await?, ["b.db"]);
+        assert_eq!(
+            picked(Some(SizeFilter::Any)).await?,
+            ["a.db", "b.db", "c.db"]
+        );
+
+        temp_dir.close()?;
+        Ok(())
+    }
+}
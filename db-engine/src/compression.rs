@@ -0,0 +1,78 @@
+use std::io;
+
+/// Compression codec for the stored values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+/// Zstd level used for the values, the library default.
+const ZSTD_LEVEL: i32 = 3;
+
+impl Codec {
+    /// The tag stored next to the compressed bytes.
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Lz4),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compress the bytes, returns `None` when the codec does not make them smaller so the
+    /// caller can store them raw.
+    pub(crate) fn compress(self, bytes: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Codec::None => return None,
+            Codec::Lz4 => lz4_flex::compress_prepend_size(bytes),
+            Codec::Zstd => zstd::bulk::compress(bytes, ZSTD_LEVEL).ok()?,
+        };
+        (compressed.len() < bytes.len()).then_some(compressed)
+    }
+
+    pub(crate) fn decompress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(bytes.to_vec()),
+            Codec::Lz4 => lz4_flex::decompress_size_prepended(bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Codec::Zstd => zstd::stream::decode_all(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Codec;
+
+    #[test]
+    fn it_round_trips_compressible_values() {
+        let value = br#"{"name":"apple","kind":"fruit"}"#.repeat(32);
+        for codec in [Codec::Lz4, Codec::Zstd] {
+            let compressed = codec.compress(&value).unwrap();
+            assert!(compressed.len() < value.len());
+            assert_eq!(codec.decompress(&compressed).unwrap(), value);
+            assert_eq!(Codec::from_tag(codec.tag()), Some(codec));
+        }
+    }
+
+    #[test]
+    fn it_keeps_uncompressible_values_raw() {
+        let value = b"x".to_vec();
+        assert!(Codec::None.compress(&value).is_none());
+        assert!(Codec::Lz4.compress(&value).is_none());
+        assert!(Codec::Zstd.compress(&value).is_none());
+        assert!(Codec::from_tag(42).is_none());
+    }
+}
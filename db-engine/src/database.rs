@@ -1,104 +1,704 @@
 use anyhow::{Context, Result};
-use std::path::PathBuf;
-use tokio::fs::remove_file;
+use std::{
+    collections::BTreeMap,
+    mem,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
+    cdc::{
+        is_not_found, prune_archive, read_sstable_history, read_wal_history, retire_wal_files,
+        CdcCursors, CdcStream, History,
+    },
+    cleanup::{cleanup_orphans, CleanupReport},
+    compaction::{Compaction, CompactionFilter, CompactionReport, CompactionStrategy},
+    compression::Codec,
+    entries::DEFAULT_MAX_FIELD_LEN,
+    events::ChangeEvent,
     mem_table::MemTable,
+    metrics::{Metrics, MetricsSnapshot, Operation, OperationTimer},
     prelude::*,
-    sstable::{SSTableQuerier, SSTableWriter},
+    read_cache::ReadCache,
+    sstable::{
+        level_dir, list_level_files, remove_orphaned_index_files, remove_tmp_files, IndexMode,
+        Manifest, SSTableOptions, SSTableQuerier, SSTableReader, SSTableWriter, LEVEL_COUNT,
+    },
+    stats::{DatabaseStats, WriteStall},
+    storage::{self, LocalFs, Storage},
     utils::*,
-    wal::WriteAheadLog,
+    verify::{verify_sstables, VerifyReport},
+    wal::{RecoveryMode, RecoveryReport, RestoreProgress, SyncPolicy, WriteAheadLog},
+    write_batch::WriteBatch,
 };
 
 const DEFAULT_MAX_MEM_TABLE_SIZE: usize = 10 * 1024 * 1024;
 
+const DEFAULT_LEVEL0_MAX_SIZE: u64 = 4 * DEFAULT_MAX_MEM_TABLE_SIZE as u64;
+
+const DEFAULT_LEVEL1_FILE_SIZE: u64 = 2 * DEFAULT_MAX_MEM_TABLE_SIZE as u64;
+
+const DEFAULT_CHANGE_EVENTS_CAPACITY: usize = 1024;
+
+/// Delay of a write for every level 0 SSTable at or over the
+/// [`DatabaseBuilder::slowdown_sstable_count`]
+const SLOWDOWN_DELAY_PER_SSTABLE: Duration = Duration::from_millis(1);
+
+/// Listings of the SSTables for the history of a [`Database::cdc_stream`] before giving up on
+/// the compactions replacing them
+const CDC_HISTORY_ATTEMPTS: usize = 3;
+
 pub struct Database {
     dir: PathBuf,
     wal: WriteAheadLog,
+    /// Older WAL files which still back the active MemTable after a restore, they are removed
+    /// together with the active WAL once the MemTable is flushed.
+    wal_segments: Vec<PathBuf>,
     mem_table: MemTable,
+    immutable_mem_table: Option<ImmutableMemTable>,
+    flush_task: Option<JoinHandle<Result<()>>>,
     max_mem_table_size: usize,
+    sync_policy: SyncPolicy,
+    wal_compression: Codec,
+    value_compression: Option<(Codec, usize)>,
+    read_cache: Option<ReadCache>,
+    /// See [`DatabaseStats::logical_value_bytes`]
+    logical_value_bytes: u64,
+    /// See [`DatabaseStats::stored_value_bytes`]
+    stored_value_bytes: u64,
+    sstable_options: SSTableOptions,
+    sstable_querier: Arc<SSTableQuerier>,
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    level0_max_size: u64,
+    level1_file_size: u64,
+    tombstone_ttl: Duration,
+    max_field_len: usize,
+    metrics: Option<Arc<Metrics>>,
+    clock: Arc<dyn Clock>,
+    storage: Arc<dyn Storage>,
+    max_disk_usage: Option<u64>,
+    slowdown_sstable_count: Option<usize>,
+    stop_sstable_count: Option<usize>,
+    max_pending_immutable_memtables: Option<usize>,
+    /// See [`DatabaseStats::write_stall`]
+    write_stall: Mutex<WriteStall>,
+    /// See [`DatabaseStats::flush_failing`]
+    flush_failing: bool,
+    /// Number of the level 0 SSTables when last counted, on open and after a flush or a
+    /// compaction, see [`DatabaseBuilder::stop_sstable_count`]
+    level0_sstables: AtomicUsize,
+    change_events: broadcast::Sender<ChangeEvent>,
+    cdc_cursors: Arc<CdcCursors>,
+    replica: bool,
+    /// See [`Database::last_applied_timestamp`]
+    last_applied_timestamp: u128,
+    /// See [`Database::recovery_report`]
+    recovery_report: RecoveryReport,
+    /// Bytes taken by the files when last measured on open, flush or compaction, plus the WAL
+    /// bytes appended since, see [`DatabaseStats::disk_usage`]
+    disk_usage: AtomicU64,
+    _dir_lock: DirLock,
+}
+
+/// A frozen MemTable waiting to be flushed to SSTable, together with the WAL files backing it.
+struct ImmutableMemTable {
+    mem_table: Arc<MemTable>,
+    wal_paths: Vec<PathBuf>,
 }
 
-pub struct DatabaseBuilder(Database);
+pub struct DatabaseBuilder {
+    dir: PathBuf,
+    max_mem_table_size: usize,
+    sync_policy: SyncPolicy,
+    wal_compression: Codec,
+    value_compression: Option<(Codec, usize)>,
+    read_cache_capacity: Option<u64>,
+    sstable_options: SSTableOptions,
+    index_mode: IndexMode,
+    rebuild_corrupt_index: bool,
+    recovery_mode: RecoveryMode,
+    progress: Option<mpsc::Sender<RestoreProgress>>,
+    cancellation: CancellationToken,
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    level0_max_size: u64,
+    level1_file_size: u64,
+    tombstone_ttl: Duration,
+    max_field_len: usize,
+    enable_metrics: bool,
+    clock: Arc<dyn Clock>,
+    storage: Arc<dyn Storage>,
+    max_disk_usage: Option<u64>,
+    slowdown_sstable_count: Option<usize>,
+    stop_sstable_count: Option<usize>,
+    max_pending_immutable_memtables: Option<usize>,
+    change_events_capacity: usize,
+    replica: bool,
+}
 
 impl DatabaseBuilder {
-    pub async fn new(dir: PathBuf) -> Result<Self> {
-        let (wal, mem_table) = WriteAheadLog::restore_from_dir(&dir).await?;
-
-        let db = Database {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
             dir,
-            wal,
-            mem_table,
             max_mem_table_size: DEFAULT_MAX_MEM_TABLE_SIZE,
-        };
-        Ok(Self(db))
+            sync_policy: SyncPolicy::default(),
+            wal_compression: Codec::default(),
+            value_compression: None,
+            read_cache_capacity: None,
+            sstable_options: SSTableOptions::default(),
+            index_mode: IndexMode::default(),
+            rebuild_corrupt_index: false,
+            recovery_mode: RecoveryMode::default(),
+            progress: None,
+            cancellation: CancellationToken::new(),
+            compaction_filter: None,
+            level0_max_size: DEFAULT_LEVEL0_MAX_SIZE,
+            level1_file_size: DEFAULT_LEVEL1_FILE_SIZE,
+            tombstone_ttl: Duration::ZERO,
+            max_field_len: DEFAULT_MAX_FIELD_LEN,
+            enable_metrics: false,
+            clock: Arc::new(HybridClock),
+            storage: Arc::new(LocalFs),
+            max_disk_usage: None,
+            slowdown_sstable_count: None,
+            stop_sstable_count: None,
+            max_pending_immutable_memtables: None,
+            change_events_capacity: DEFAULT_CHANGE_EVENTS_CAPACITY,
+            replica: false,
+        }
+    }
+
+    /// Like [`DatabaseBuilder::new`], the WAL replay of [`DatabaseBuilder::build`] is reported
+    /// to `progress` and stops before the next WAL file once `cancellation` is cancelled.
+    pub fn new_with_progress(
+        dir: PathBuf,
+        progress: mpsc::Sender<RestoreProgress>,
+        cancellation: CancellationToken,
+    ) -> Self {
+        Self {
+            progress: Some(progress),
+            cancellation,
+            ..Self::new(dir)
+        }
     }
 
     pub fn max_mem_table_size(mut self, max_mem_table_size: usize) -> Self {
-        self.0.max_mem_table_size = max_mem_table_size;
+        self.max_mem_table_size = max_mem_table_size;
+        self
+    }
+
+    /// When the WAL is synced to disk, see [`SyncPolicy`].
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Compress the values written to the WAL, see [`Codec`].
+    pub fn wal_compression(mut self, codec: Codec) -> Self {
+        self.wal_compression = codec;
+        self
+    }
+
+    /// Compress the values longer than `min_size` bytes one by one before they are written, see
+    /// [`Codec`]. The codec is kept with every value, the reads decompress them whatever the
+    /// setting, and the compactions carry them over as they are.
+    pub fn value_compression(mut self, codec: Codec, min_size: usize) -> Self {
+        self.value_compression = (codec != Codec::None).then_some((codec, min_size));
+        self
+    }
+
+    /// Cache the newest version of up to `capacity` bytes of the most recently read keys, for
+    /// the [`Database::get`] of the keys found in the SSTables alone. A write forgets the
+    /// cached version of its key, the compactions of [`Database::compact`] and
+    /// [`Database::compact_levels`] the whole cache. Off by default.
+    pub fn read_cache_capacity(mut self, capacity: u64) -> Self {
+        self.read_cache_capacity = Some(capacity);
+        self
+    }
+
+    /// Compress the SSTable files in blocks, see [`Codec`]. Files written without compression
+    /// stay readable.
+    pub fn sstable_compression(mut self, codec: Codec) -> Self {
+        self.sstable_options.compression = codec;
+        self
+    }
+
+    /// Only index every `n`th key of the SSTable files, a lookup then walks the records from
+    /// the closest indexed key. Trades some read latency for much smaller indexes, every key is
+    /// indexed by default.
+    pub fn index_every_n_keys(mut self, n: u32) -> Self {
+        self.sstable_options.index_interval = n;
+        self
+    }
+
+    /// Keep the indexes of the SSTable files on disk and binary search them on every lookup
+    /// instead of loading them, see [`IndexMode`]. Caps the memory the indexes take at the cost
+    /// of a few more reads per lookup.
+    pub fn index_mode(mut self, index_mode: IndexMode) -> Self {
+        self.index_mode = index_mode;
+        self
+    }
+
+    /// Rebuild a corrupt SSTable index from the data records of its file instead of failing the
+    /// lookups that need it. Off by default, the corruption is reported instead.
+    pub fn rebuild_corrupt_index(mut self, rebuild: bool) -> Self {
+        self.rebuild_corrupt_index = rebuild;
+        self
+    }
+
+    /// Run the entries through `filter` in [`Database::compact`], see [`CompactionFilter`].
+    pub fn compaction_filter(mut self, filter: Arc<dyn CompactionFilter>) -> Self {
+        self.compaction_filter = Some(filter);
+        self
+    }
+
+    /// How many bytes of level 0 SSTables [`Database::compact_levels`] lets pile up before
+    /// merging them into level 1, 4 MemTables by default.
+    pub fn level0_max_size(mut self, size: u64) -> Self {
+        self.level0_max_size = size;
+        self
+    }
+
+    /// The size of the level 1 SSTables [`Database::compact_levels`] writes, 2 MemTables by
+    /// default. A lookup reads a single one of them, however many there are.
+    pub fn level1_file_size(mut self, size: u64) -> Self {
+        self.level1_file_size = size;
+        self
+    }
+
+    /// How long the compactions keep a tombstone which shadows nothing anymore, see
+    /// [`Compaction::tombstone_ttl`]. Not at all by default.
+    pub fn tombstone_ttl(mut self, ttl: Duration) -> Self {
+        self.tombstone_ttl = ttl;
+        self
+    }
+
+    /// The longest key, value or content type, [`DEFAULT_MAX_FIELD_LEN`] by default. A longer
+    /// one is refused with [`Error::FieldTooLong`], and a longer length prefix read back from a
+    /// WAL or SSTable file is a corrupted record rather than an allocation: lowering it makes
+    /// the records of the longer fields already written unreadable.
+    pub fn max_field_len(mut self, max_field_len: usize) -> Self {
+        self.max_field_len = max_field_len;
+        self
+    }
+
+    /// Record the latency of the reads, writes and flushes, see [`Database::metrics_snapshot`].
+    /// Off by default.
+    pub fn enable_metrics(mut self, enable: bool) -> Self {
+        self.enable_metrics = enable;
+        self
+    }
+
+    /// Take the timestamps of the writes and the names of the new WAL and SSTable files from
+    /// `clock`, e.g. a deterministic one in tests. A [`HybridClock`] by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Keep the WAL files, the SSTables, their indexes and bloom filters in `storage`, see
+    /// [`Storage`]. [`LocalFs`] by default.
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Refuse the writes of values with [`Error::DiskBudgetExceeded`] once the SSTable, index,
+    /// bloom filter and WAL files take `bytes`. The deletes and the compactions, which reclaim
+    /// space, still go through. No budget by default.
+    pub fn max_disk_usage(mut self, bytes: u64) -> Self {
+        self.max_disk_usage = Some(bytes);
+        self
+    }
+
+    /// Delay every write once there are `count` level 0 SSTables, by a millisecond for each one
+    /// at or over `count`, so the compactions catch up before the writes stop. Off by default.
+    pub fn slowdown_sstable_count(mut self, count: usize) -> Self {
+        self.slowdown_sstable_count = Some(count);
+        self
+    }
+
+    /// Refuse the writes with [`Error::WriteStalled`] once there are `count` level 0 SSTables,
+    /// until a compaction merges them. Off by default.
+    pub fn stop_sstable_count(mut self, count: usize) -> Self {
+        self.stop_sstable_count = Some(count);
+        self
+    }
+
+    /// Refuse the writes with [`Error::WriteStalled`] while more than `count` MemTables wait
+    /// for their flush: the one flushed in the background, and the active one once full. With 1
+    /// the writes go on during a flush until the active MemTable is full as well, with 0 they
+    /// stop during every flush. Only one MemTable is flushed at a time, so over 1 the writes
+    /// wait for the flush in flight, as they do by default.
+    pub fn max_pending_immutable_memtables(mut self, count: usize) -> Self {
+        self.max_pending_immutable_memtables = Some(count);
         self
     }
 
-    pub fn build(self) -> Database {
-        self.0
+    /// How many [`ChangeEvent`]s a subscriber may fall behind before it misses the oldest ones,
+    /// see [`Database::subscribe`]. 1024 by default.
+    pub fn change_events_capacity(mut self, capacity: usize) -> Self {
+        self.change_events_capacity = capacity;
+        self
+    }
+
+    /// Open the database as the replica of a primary: it only takes the writes of
+    /// [`Database::apply_replicated`], the others fail with [`Error::ReadOnlyReplica`].
+    pub fn replica(mut self, replica: bool) -> Self {
+        self.replica = replica;
+        self
+    }
+
+    /// What to do with a corrupted WAL file while opening the database, see [`RecoveryMode`].
+    pub fn on_corruption(mut self, recovery_mode: RecoveryMode) -> Self {
+        self.recovery_mode = recovery_mode;
+        self
+    }
+
+    /// Restore the data from the directory and open the database. The directory stays locked
+    /// until the database is dropped, [`Error::DirectoryLocked`] while another one is open.
+    pub async fn build(self) -> Result<Database> {
+        let dir_lock = lock_dir(&self.dir)?;
+        let (wal, mem_table, wal_segments, recovery_report) =
+            WriteAheadLog::restore_from_dir_with_clock(
+                &self.dir,
+                self.recovery_mode,
+                self.progress,
+                self.cancellation,
+                self.clock.as_ref(),
+                Arc::clone(&self.storage),
+                self.max_field_len,
+            )
+            .await?;
+        let mut wal = wal.with_sync_policy(self.sync_policy).await?;
+        wal.set_compression(self.wal_compression);
+        for level in 0..LEVEL_COUNT {
+            self.storage
+                .create_dir_all(&level_dir(&self.dir, level))
+                .await
+                .context("create level dir")?;
+        }
+        remove_tmp_files(&self.dir, self.storage.as_ref()).await?;
+        remove_orphaned_index_files(&self.dir, self.storage.as_ref()).await?;
+        Manifest::recover(&self.dir, self.storage.as_ref()).await?;
+        crate::compaction::remove_stale_lock(&self.dir, self.storage.as_ref()).await?;
+        // no CDC cursor outlives the process
+        prune_archive(&self.dir, None, self.storage.as_ref()).await?;
+        let sstable_querier = SSTableQuerier::with_storage(&self.dir, Arc::clone(&self.storage))
+            .await?
+            .index_mode(self.index_mode)
+            .rebuild_corrupt_index(self.rebuild_corrupt_index)
+            .max_field_len(self.max_field_len);
+        let sstable_querier = Arc::new(sstable_querier);
+        let disk_usage = dir_size(&self.dir, self.storage.as_ref()).await?;
+        let level0_sstables = sstable_querier.level0_len().await?;
+        let last_applied_timestamp = match self.replica {
+            true => last_timestamp(&self.dir, &mem_table, self.storage.as_ref()).await?,
+            false => 0,
+        };
+
+        Ok(Database {
+            dir: self.dir,
+            wal,
+            wal_segments,
+            mem_table,
+            immutable_mem_table: None,
+            flush_task: None,
+            max_mem_table_size: self.max_mem_table_size,
+            sync_policy: self.sync_policy,
+            wal_compression: self.wal_compression,
+            value_compression: self.value_compression,
+            read_cache: self.read_cache_capacity.map(ReadCache::new),
+            logical_value_bytes: 0,
+            stored_value_bytes: 0,
+            sstable_options: self.sstable_options,
+            sstable_querier,
+            compaction_filter: self.compaction_filter,
+            level0_max_size: self.level0_max_size,
+            level1_file_size: self.level1_file_size,
+            tombstone_ttl: self.tombstone_ttl,
+            max_field_len: self.max_field_len,
+            metrics: self.enable_metrics.then(Default::default),
+            clock: self.clock,
+            storage: self.storage,
+            max_disk_usage: self.max_disk_usage,
+            slowdown_sstable_count: self.slowdown_sstable_count,
+            stop_sstable_count: self.stop_sstable_count,
+            max_pending_immutable_memtables: self.max_pending_immutable_memtables,
+            write_stall: Mutex::default(),
+            flush_failing: false,
+            level0_sstables: AtomicUsize::new(level0_sstables),
+            change_events: broadcast::channel(self.change_events_capacity).0,
+            cdc_cursors: Arc::default(),
+            replica: self.replica,
+            last_applied_timestamp,
+            recovery_report,
+            disk_usage: AtomicU64::new(disk_usage),
+            _dir_lock: dir_lock,
+        })
     }
 }
 
 impl Database {
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(key_len = key.len())))]
     pub async fn get(&self, key: &[u8]) -> Option<DbEntry> {
-        let mut entry_opt = self.mem_table.get(key).cloned();
-        if entry_opt.is_none() {
-            match SSTableQuerier::new(&self.dir) {
-                Ok(querier) => {
-                    entry_opt = querier.query(key).await;
-                }
-                Err(e) => {
-                    tracing::error!("SSTable querier error: {}", e);
+        let _timer = self.timer(Operation::Get);
+        // the newest version wins, the mem_table on a tie
+        let immutable = self
+            .immutable_mem_table
+            .as_ref()
+            .and_then(|immutable| immutable.mem_table.get(key));
+        let mem_entry = [immutable, self.mem_table.get(key)]
+            .into_iter()
+            .flatten()
+            .max_by_key(|entry| entry.timestamp)
+            .cloned();
+        // only the keys read out of the SSTables alone are cached
+        if let (None, Some(read_cache)) = (mem_entry.as_ref(), self.read_cache.as_ref()) {
+            let entry = match read_cache.get(key) {
+                Some(entry) => entry,
+                None => {
+                    let entry = self.sstable_querier.query(key).await;
+                    let entry = entry.and_then(|entry| db_entry(key, entry));
+                    read_cache.insert(key, entry.clone());
+                    entry
                 }
-            }
+            };
+            // an entry may expire once cached
+            return entry.filter(|entry| match entry.expires_at() {
+                Some(expires_at) => expires_at > self.clock.now().unwrap_or_default(),
+                None => true,
+            });
         }
+        let sstable_entry = self
+            .sstable_querier
+            .query_newer_than(key, mem_entry.as_ref().map(|entry| entry.timestamp))
+            .await;
 
-        let entry = entry_opt?;
-        if entry.is_deleted() {
+        let entry = sstable_entry.or(mem_entry)?;
+        if is_expired(&entry, &mut None, self.clock.as_ref()) {
             return None;
         }
+        db_entry(key, entry)
+    }
+
+    /// Scan the live Key-Value pairs whose key falls in `bounds`, in ascending key order.
+    ///
+    /// The keys are compared byte by byte, numbers and tuples keep their order once encoded
+    /// with the [`keys`](crate::keys) helpers:
+    ///
+    /// ```no_run
+    /// # async fn example(db: &db_engine::Database) -> anyhow::Result<()> {
+    /// use db_engine::keys::{decode_i64, encode_i64};
+    ///
+    /// let (start, end) = (encode_i64(-10), encode_i64(10));
+    /// for entry in db.scan(&start[..]..&end[..]).await? {
+    ///     println!("{}", decode_i64(&entry.key).unwrap());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn scan<'a>(&self, bounds: impl RangeBounds<&'a [u8]>) -> Result<Vec<DbEntry>> {
+        let bounds = (bounds.start_bound().cloned(), bounds.end_bound().cloned());
+        let (immutable, mem_table) = self.mem_table_snapshots();
+        let sstable_entries = self.sstable_querier.scan(bounds).await?;
+        let immutable_entries = immutable
+            .iter()
+            .flat_map(|immutable| immutable.range(bounds));
+        merge_scan(
+            sstable_entries,
+            immutable_entries.chain(mem_table.range(bounds)),
+            self.clock.as_ref(),
+        )
+    }
+
+    /// Scan the live Key-Value pairs whose key starts with `prefix`, in ascending key order.
+    ///
+    /// ```no_run
+    /// # async fn example(db: &db_engine::Database) -> anyhow::Result<()> {
+    /// use db_engine::keys::{decode_composite, encode_composite};
+    ///
+    /// // every order of the user, the ones of "alice2" are not under this prefix
+    /// let prefix = encode_composite(&[b"orders", b"alice"]);
+    /// for entry in db.scan_prefix(&prefix).await? {
+    ///     let parts = decode_composite(&entry.key).unwrap();
+    ///     println!("{:?}", parts[2]);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<DbEntry>> {
+        let upper_bound = prefix_upper_bound(prefix);
+        let bounds = (
+            Bound::Included(prefix),
+            upper_bound
+                .as_deref()
+                .map_or(Bound::Unbounded, Bound::Excluded),
+        );
+        let (immutable, mem_table) = self.mem_table_snapshots();
+        let sstable_entries = self.sstable_querier.scan(bounds).await?;
+        let immutable_entries = immutable
+            .iter()
+            .flat_map(|immutable| immutable.iter_prefix(prefix));
+        merge_scan(
+            sstable_entries,
+            immutable_entries.chain(mem_table.iter_prefix(prefix)),
+            self.clock.as_ref(),
+        )
+    }
 
-        let db_entry = DbEntry {
-            key: entry.key,
-            value: entry.value.unwrap(),
-            timestamp: entry.timestamp,
+    /// A page of [`Database::scan_prefix`]: the live pairs among the first `limit` keys with
+    /// `prefix` after `start_after`, tombstones included, so a page never reads more than
+    /// `limit` records of any MemTable or SSTable. Returns them with the last key looked at,
+    /// where the next page starts, `None` once there are no more keys.
+    pub async fn scan_prefix_page(
+        &self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<(Vec<DbEntry>, Option<Vec<u8>>)> {
+        let upper_bound = prefix_upper_bound(prefix);
+        let bounds = (
+            start_after
+                .filter(|start_after| *start_after >= prefix)
+                .map_or(Bound::Included(prefix), Bound::Excluded),
+            upper_bound
+                .as_deref()
+                .map_or(Bound::Unbounded, Bound::Excluded),
+        );
+        let (immutable, mem_table) = self.mem_table_snapshots();
+        let sstable_entries = self.sstable_querier.scan_limit(bounds, limit).await?;
+        let immutable_entries = immutable
+            .iter()
+            .flat_map(|immutable| immutable.range(bounds).take(limit));
+        let mut merged = merge_latest(
+            sstable_entries,
+            immutable_entries.chain(mem_table.range(bounds).take(limit)),
+        );
+        // the keys further on may have newer versions in the files not read as far
+        while merged.len() > limit {
+            merged.pop_last();
+        }
+        let next_start = match merged.len() < limit {
+            true => None,
+            false => merged.keys().next_back().cloned(),
         };
-        Some(db_entry)
+        Ok((live_entries(merged, self.clock.as_ref())?, next_start))
     }
 
-    pub async fn set(&mut self, key: &[u8], value: &[u8]) -> Result<usize> {
-        let timestamp = micros_now()?;
+    /// Snapshots of the immutable and the active MemTable, taken before any SSTable I/O so a
+    /// scan sees one consistent in-memory state.
+    fn mem_table_snapshots(&self) -> (Option<Arc<MemTable>>, Arc<MemTable>) {
+        let immutable = self
+            .immutable_mem_table
+            .as_ref()
+            .map(|immutable| Arc::clone(&immutable.mem_table));
+        (immutable, self.mem_table.snapshot())
+    }
 
-        // wal
-        self.wal
-            .set(key, value, timestamp)
-            .await
-            .context("write data to wal")?;
-        self.wal.flush().await.context("flash wal to file")?;
+    /// Set a Key-Value pair, returns the timestamp it is written with.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(key_len = key.len(), value_len = value.len())))]
+    pub async fn set(&mut self, key: &[u8], value: &[u8]) -> Result<u128> {
+        let _timer = self.timer(Operation::Set);
+        self.check_writable()?;
+        check_field_len("key", key, self.max_field_len)?;
+        check_field_len("value", value, self.max_field_len)?;
+        self.check_disk_budget()?;
+        self.check_write_stall().await?;
+        let timestamp = self.clock.now()?;
 
-        // mem_table
-        self.mem_table.set(key, value, timestamp);
+        // wal and mem_table
+        self.append_entry(Entry::new(key.to_vec(), Some(value.to_vec()), timestamp))
+            .await?;
 
         // persist to SSTable
         self.persist_to_sstable().await?;
 
-        Ok(1)
+        Ok(timestamp)
     }
 
+    /// Set a Key-Value pair tagged with the `content_type` of the value, which comes back with
+    /// it as [`DbEntry::content_type`]. Returns the timestamp it is written with.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(key_len = key.len(), value_len = value.len())))]
+    pub async fn set_typed(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        content_type: &str,
+    ) -> Result<u128> {
+        let _timer = self.timer(Operation::Set);
+        self.check_writable()?;
+        check_field_len("key", key, self.max_field_len)?;
+        check_field_len("value", value, self.max_field_len)?;
+        check_field_len("content type", content_type.as_bytes(), self.max_field_len)?;
+        self.check_disk_budget()?;
+        self.check_write_stall().await?;
+        let timestamp = self.clock.now()?;
+
+        // wal and mem_table
+        let entry = Entry::new(key.to_vec(), Some(value.to_vec()), timestamp)
+            .with_content_type(content_type);
+        self.append_entry(entry).await?;
+
+        // persist to SSTable
+        self.persist_to_sstable().await?;
+
+        Ok(timestamp)
+    }
+
+    /// Set a Key-Value pair which reads as deleted once `ttl` has passed after its timestamp,
+    /// see [`DbEntry::expires_at`]. The compactions drop it after that. Returns the timestamp
+    /// it is written with.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(key_len = key.len(), value_len = value.len())))]
+    pub async fn set_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<u128> {
+        let _timer = self.timer(Operation::Set);
+        self.check_writable()?;
+        check_field_len("key", key, self.max_field_len)?;
+        check_field_len("value", value, self.max_field_len)?;
+        self.check_disk_budget()?;
+        self.check_write_stall().await?;
+        let timestamp = self.clock.now()?;
+        let expires_at = timestamp.saturating_add(ttl.as_micros());
+
+        // wal and mem_table
+        let entry =
+            Entry::new(key.to_vec(), Some(value.to_vec()), timestamp).with_expires_at(expires_at);
+        self.append_entry(entry).await?;
+
+        // persist to SSTable
+        self.persist_to_sstable().await?;
+
+        Ok(timestamp)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(key_len = key.len())))]
     pub async fn delete(&mut self, key: &[u8]) -> Result<usize> {
-        let timestamp = micros_now()?;
+        let _timer = self.timer(Operation::Delete);
+        self.check_writable()?;
+        check_field_len("key", key, self.max_field_len)?;
+        self.check_write_stall().await?;
+        let timestamp = self.clock.now()?;
 
         // wal
+        let appended = self.wal.bytes_appended();
         self.wal.delete(key, timestamp).await?;
-        self.wal.flush().await?;
+        self.wal.flush_appended().await?;
+        self.add_disk_usage(self.wal.bytes_appended() - appended);
 
         // mem_table
+        self.invalidate_cached(key);
         self.mem_table.delete(key, timestamp);
+        self.publish(|| Entry::new(key.to_vec(), None, timestamp));
 
         // persist to SSTable
         self.persist_to_sstable().await?;
@@ -106,131 +706,2276 @@ impl Database {
         Ok(1)
     }
 
-    async fn persist_to_sstable(&mut self) -> Result<()> {
-        if self.mem_table.size() >= self.max_mem_table_size {
-            // flush the data to sstable
-            let sstable_path = self.dir.join(format!("{}.db", micros_now()?));
-            let mut writer = SSTableWriter::new(&sstable_path).await?;
-            for entry in self.mem_table.entries().iter() {
-                writer.set(entry).await.context("add entry to sstable")?;
-            }
-            writer
-                .flush()
-                .await
-                .context("flash sstable buffer to file")?;
+    /// Apply all the writes of the batch with one WAL append, returns the number of writes.
+    pub async fn write(&mut self, batch: WriteBatch) -> Result<usize> {
+        let _timer = self.timer(Operation::Write);
+        self.check_writable()?;
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        let timestamp = self.clock.now()?;
+        let entries = batch.into_entries(timestamp);
+        for entry in entries.iter() {
+            check_field_len("key", &entry.key, self.max_field_len)?;
+            check_field_len(
+                "value",
+                entry.value.as_deref().unwrap_or_default(),
+                self.max_field_len,
+            )?;
+            check_field_len(
+                "content type",
+                entry.content_type.as_deref().unwrap_or_default().as_bytes(),
+                self.max_field_len,
+            )?;
+        }
+        if entries.iter().any(|entry| entry.value.is_some()) {
+            self.check_disk_budget()?;
+        }
+        self.check_write_stall().await?;
+        let mut entries = entries;
+        let written = entries
+            .iter_mut()
+            .map(|entry| self.compress_value(entry))
+            .collect::<Vec<_>>();
 
-            // delete correspond wal file
-            remove_file(self.wal.path())
-                .await
-                .context("remove wal file")?;
-            // clear mem_table
-            *self = DatabaseBuilder::new(self.dir.clone())
-                .await?
-                .max_mem_table_size(self.max_mem_table_size)
-                .build();
+        // wal
+        let appended = self.wal.bytes_appended();
+        self.wal
+            .append_batch(&entries)
+            .await
+            .context("write batch to wal")?;
+        self.add_disk_usage(self.wal.bytes_appended() - appended);
+
+        // mem_table
+        let count = entries.len();
+        for (entry, written) in entries.into_iter().zip(written) {
+            self.publish(|| written.unwrap_or_else(|| entry.clone()));
+            self.invalidate_cached(&entry.key);
+            self.mem_table.put(entry);
         }
-        Ok(())
+
+        // persist to SSTable
+        self.persist_to_sstable().await?;
+
+        Ok(count)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use anyhow::Result;
-    use tempdir::TempDir;
+    /// The SSTable querier of the database, which reads its SSTables without the MemTables.
+    /// Compact the directory with [`Database::compact`] or [`Database::compact_with`] rather
+    /// than a [`Compaction`](crate::Compaction) of its own: they also forget the reads the
+    /// [`DatabaseBuilder::read_cache_capacity`] cached.
+    pub fn sstable_querier(&self) -> Arc<SSTableQuerier> {
+        Arc::clone(&self.sstable_querier)
+    }
 
-    use super::*;
+    /// Compact the SSTable files smaller than `size` bytes into new ones written like the
+    /// flushed ones, through the [`DatabaseBuilder::compaction_filter`] if any.
+    pub async fn compact(&self, size: u64) -> Result<CompactionReport> {
+        self.run_compaction(self.compaction(size)).await
+    }
 
-    #[tokio::test]
-    async fn it_works_with_mem_table() -> Result<()> {
-        let tmpdir = TempDir::new("mem_table_test")?;
-        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
-            .await?
-            .build();
+    /// [`Database::compact`] through `filter` as well, after the
+    /// [`DatabaseBuilder::compaction_filter`] if any, reading and writing at most
+    /// `throttle_bytes_per_sec`, 0 for no limit. E.g. for a scheduler with filters of its own.
+    pub async fn compact_with(
+        &self,
+        size: u64,
+        filter: Option<Arc<dyn CompactionFilter>>,
+        throttle_bytes_per_sec: u64,
+    ) -> Result<CompactionReport> {
+        let mut compaction = self.compaction(size).with_throttle(throttle_bytes_per_sec);
+        if let Some(filter) = filter {
+            compaction = compaction.with_filter(filter);
+        }
+        self.run_compaction(compaction).await
+    }
 
-        assert!(db.get(b"test").await.is_none());
-        assert_eq!(db.mem_table.size(), 0);
-        assert_eq!(db.mem_table.entries().len(), 0);
+    /// Merge the level 0 SSTables into level 1 once they reach
+    /// [`DatabaseBuilder::level0_max_size`], see [`CompactionStrategy::Leveled`].
+    pub async fn compact_levels(&self) -> Result<CompactionReport> {
+        let compaction = self
+            .compaction(0)
+            .strategy(CompactionStrategy::Leveled {
+                level0_max_size: self.level0_max_size,
+            })
+            .max_output_file_size(self.level1_file_size);
+        self.run_compaction(compaction).await
+    }
 
-        let result = db.set(b"test", b"hello").await?;
-        assert_eq!(result, 1);
-        assert_ne!(db.mem_table.size(), 0);
-        assert_eq!(db.mem_table.entries().len(), 1);
+    /// Run `compaction`, then measure the files again
+    async fn run_compaction(&self, compaction: Compaction) -> Result<CompactionReport> {
+        let report = compaction.compact().await;
+        self.invalidate_read_cache();
+        let report = report?;
+        self.measure_disk_usage().await?;
+        self.count_level0_sstables().await?;
+        self.measure_write_stall();
+        Ok(report)
+    }
 
-        let entry = db.get(b"test").await.unwrap();
-        assert_eq!(entry.key, b"test");
-        assert_eq!(entry.value, b"hello");
+    /// Forget every cached key once a compaction may have dropped or replaced their values,
+    /// even a failed one.
+    fn invalidate_read_cache(&self) {
+        if let Some(read_cache) = self.read_cache.as_ref() {
+            read_cache.invalidate_all();
+        }
+    }
 
-        db.delete(b"test").await?;
-        assert!(db.get(b"test").await.is_none());
+    /// A compaction of the SSTables smaller than `size`, written like the flushed ones
+    fn compaction(&self, size: u64) -> Compaction {
+        let mut compaction = Compaction::new(self.dir.clone(), size, "db")
+            .clock(Arc::clone(&self.clock))
+            .storage(Arc::clone(&self.storage))
+            .sstable_querier(Arc::clone(&self.sstable_querier))
+            .sstable_options(self.sstable_options)
+            .tombstone_ttl(self.tombstone_ttl)
+            .max_field_len(self.max_field_len);
+        if let Some(filter) = self.compaction_filter.as_ref() {
+            compaction = compaction.with_filter(Arc::clone(filter));
+        }
+        compaction
+    }
 
-        tmpdir.close()?;
-        Ok(())
+    /// The latency histograms of the operations so far, empty unless
+    /// [`DatabaseBuilder::enable_metrics`] is set.
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.metrics
+            .as_deref()
+            .map_or_else(|| Metrics::default().snapshot(), Metrics::snapshot)
     }
 
-    #[tokio::test]
-    async fn it_works_with_wal_files() -> Result<()> {
-        let tmpdir = TempDir::new("wal_test")?;
-        let dir = tmpdir.path().to_path_buf();
+    /// Receive a [`ChangeEvent`] for every set and delete from now on, in the order they are
+    /// applied. A subscriber more than [`DatabaseBuilder::change_events_capacity`] events
+    /// behind misses the oldest ones and gets [`broadcast::error::RecvError::Lagged`], the
+    /// writes never wait for it.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.change_events.subscribe()
+    }
 
-        // seed
-        DatabaseBuilder::new(dir.clone())
-            .await?
-            .build()
-            .set(b"hello", b"world")
-            .await?;
+    /// Apply a write of the primary on this replica, with the timestamp it was written with
+    /// there, e.g. one of its [`Database::cdc_stream`]. Applying it again is harmless, the
+    /// newest timestamp of a key wins. Fails with [`Error::NotAReplica`] unless the database
+    /// is opened with [`DatabaseBuilder::replica`].
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(key_len = entry.key.len(), timestamp = entry.timestamp)))]
+    pub async fn apply_replicated(&mut self, entry: Entry) -> Result<()> {
+        if !self.replica {
+            return Err(Error::NotAReplica.into());
+        }
+        check_field_len("key", &entry.key, self.max_field_len)?;
+        check_field_len(
+            "value",
+            entry.value.as_deref().unwrap_or_default(),
+            self.max_field_len,
+        )?;
+        check_field_len(
+            "content type",
+            entry.content_type.as_deref().unwrap_or_default().as_bytes(),
+            self.max_field_len,
+        )?;
+        if entry.value.is_some() {
+            self.check_disk_budget()?;
+        }
 
-        // load data in existing wal file
-        let db = DatabaseBuilder::new(dir).await?.build();
-        assert!(db.get(b"test").await.is_none());
-        assert!(db.get(b"hello").await.is_some());
+        // wal
+        let appended = self.wal.bytes_appended();
+        self.wal
+            .put(&entry)
+            .await
+            .context("write replicated entry to wal")?;
+        self.wal
+            .flush_appended()
+            .await
+            .context("flash wal to file")?;
+        self.add_disk_usage(self.wal.bytes_appended() - appended);
 
-        tmpdir.close()?;
-        Ok(())
+        // mem_table
+        self.last_applied_timestamp = self.last_applied_timestamp.max(entry.timestamp);
+        self.publish(|| entry.clone());
+        self.invalidate_cached(&entry.key);
+        self.mem_table.put(entry);
+
+        // persist to SSTable
+        self.persist_to_sstable().await
     }
 
-    #[tokio::test]
-    async fn it_works_with_sstable() -> Result<()> {
-        let tmpdir = TempDir::new("sstable_test")?;
-        let dir = tmpdir.path().to_path_buf();
+    /// Whether the database is opened with [`DatabaseBuilder::replica`]
+    pub fn is_replica(&self) -> bool {
+        self.replica
+    }
 
-        // seed
-        let seed_entry = Entry::new(b"test1".to_vec(), Some(b"hello").map(|i| i.to_vec()), 1);
-        SSTableWriter::new(&dir.join("test.db"))
-            .await?
-            .set(&seed_entry)
-            .await?
-            .flush()
-            .await?;
+    /// The newest timestamp applied by [`Database::apply_replicated`], restored from the files
+    /// on a restart: the replication resumes after it. 0 on a primary, or on a replica which
+    /// has applied nothing yet.
+    pub fn last_applied_timestamp(&self) -> u128 {
+        self.last_applied_timestamp
+    }
 
-        // test
-        let db = DatabaseBuilder::new(dir).await?.build();
-        let result = db.get(b"test1").await;
-        assert!(result.is_some());
-        assert_eq!(result.unwrap().value, b"hello");
-        assert!(db.get(b"test").await.is_none());
+    /// How the WAL files were replayed when the database was opened.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
 
-        tmpdir.close()?;
+    /// Stream the changes with a timestamp after `from_timestamp`: first the ones still in
+    /// the WAL and the SSTables, then the live writes, see [`CdcStream`]. The history is read
+    /// right away and held in memory. The WAL files the stream has not read past are kept in
+    /// the `cdc` directory until it is dropped.
+    ///
+    /// The SSTables hold the latest version of each key only once a compaction or a restart
+    /// has gone by, with the tombstones it dropped gone. A stream more than
+    /// [`DatabaseBuilder::change_events_capacity`] writes behind ends with
+    /// [`CdcError::Lagged`](crate::CdcError::Lagged).
+    pub async fn cdc_stream(&self, from_timestamp: u128) -> Result<CdcStream> {
+        // the cursor holds on to the WAL files before they are listed, the subscription
+        // catches the writes once the history ends
+        let cursor = self.cdc_cursors.open(from_timestamp);
+        let live = BroadcastStream::new(self.subscribe());
+
+        let mut history = History::new();
+        let mut wal_paths = self.wal_segments.clone();
+        if let Some(immutable) = self.immutable_mem_table.as_ref() {
+            wal_paths.extend(immutable.wal_paths.iter().cloned());
+        }
+        wal_paths.push(self.wal.path());
+        let storage = self.storage.as_ref();
+        let max_field_len = self.max_field_len;
+        read_wal_history(
+            &self.dir,
+            wal_paths,
+            from_timestamp,
+            &mut history,
+            storage,
+            max_field_len,
+        )
+        .await?;
+        let mut attempts = 0;
+        loop {
+            let files = self.sstable_files().await?;
+            match read_sstable_history(
+                files.into_iter().map(|(path, _)| path),
+                from_timestamp,
+                &mut history,
+                storage,
+                max_field_len,
+            )
+            .await
+            {
+                Ok(()) => break,
+                // a compaction replaced a file, its output holds the same entries
+                Err(e) if is_not_found(&e) && attempts < CDC_HISTORY_ATTEMPTS => attempts += 1,
+                Err(e) => return Err(e.context("read the cdc history")),
+            }
+        }
+        Ok(CdcStream::new(history, live, from_timestamp, cursor))
+    }
+
+    /// Tell the subscribers about the write of the entry, built only when there are any.
+    /// Append the entry to the WAL and the MemTable, with its value compressed by the
+    /// [`DatabaseBuilder::value_compression`] if any, and publish it as written.
+    async fn append_entry(&mut self, mut entry: Entry) -> Result<()> {
+        let written = self.compress_value(&mut entry);
+        let appended = self.wal.bytes_appended();
+        self.wal.put(&entry).await.context("write data to wal")?;
+        self.wal
+            .flush_appended()
+            .await
+            .context("flash wal to file")?;
+        self.add_disk_usage(self.wal.bytes_appended() - appended);
+        self.publish(|| written.unwrap_or_else(|| entry.clone()));
+        self.invalidate_cached(&entry.key);
+        self.mem_table.put(entry);
         Ok(())
     }
 
-    #[tokio::test]
-    async fn it_persists_data_to_sstable_when_reached_the_max_limitation() -> Result<()> {
-        let tmpdir = TempDir::new("persist_to_sstable").unwrap();
+    /// Forget the cached version of `key`, see [`DatabaseBuilder::read_cache_capacity`].
+    fn invalidate_cached(&self, key: &[u8]) {
+        if let Some(read_cache) = self.read_cache.as_ref() {
+            read_cache.invalidate(key);
+        }
+    }
 
-        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
-            .await?
-            .max_mem_table_size(64)
-            .build();
-        db.set(b"test", b"helloworld").await?;
-        db.set(b"test1", b"helloworld1").await?;
-        assert_eq!(db.mem_table.size(), 0);
-        assert_eq!(db.mem_table.entries().len(), 0);
+    /// Compress the value of `entry` with the [`DatabaseBuilder::value_compression`] when it is
+    /// longer than the minimum size and gets smaller, returns the entry as written then. Counts
+    /// the value bytes for the [`DatabaseStats`].
+    fn compress_value(&mut self, entry: &mut Entry) -> Option<Entry> {
+        let value = entry.value.as_deref()?;
+        self.logical_value_bytes += value.len() as u64;
+        let compressed = self
+            .value_compression
+            .filter(|(_, min_size)| value.len() > *min_size)
+            .and_then(|(codec, _)| Some((codec, codec.compress(value)?)));
+        let Some((codec, compressed)) = compressed else {
+            self.stored_value_bytes += value.len() as u64;
+            return None;
+        };
+        self.stored_value_bytes += compressed.len() as u64;
+        let written = entry.clone();
+        entry.value = Some(compressed.into());
+        entry.value_codec = codec;
+        Some(written)
+    }
 
-        let entry = db.get(b"test").await;
-        assert!(entry.is_some());
-        assert_eq!(entry.unwrap().value, b"helloworld");
+    fn publish(&self, entry: impl FnOnce() -> Entry) {
+        if self.change_events.receiver_count() > 0 {
+            let _ = self.change_events.send(ChangeEvent::from(entry()));
+        }
+    }
 
-        tmpdir.close()?;
+    /// Start timing an `operation` when the metrics are enabled.
+    fn timer(&self, operation: Operation) -> Option<OperationTimer> {
+        self.metrics
+            .as_ref()
+            .map(|metrics| metrics.timer(operation))
+    }
+
+    /// Current statistics of the database.
+    pub fn stats(&self) -> DatabaseStats {
+        DatabaseStats {
+            mem_table_len: self.mem_table.len(),
+            mem_table_live_count: self.mem_table.live_count(),
+            mem_table_tombstone_count: self.mem_table.tombstone_count(),
+            mem_table_size: self.mem_table.size(),
+            mem_table_memory_usage: self.mem_table.approximate_memory_usage(),
+            immutable_mem_table_memory_usage: self
+                .immutable_mem_table
+                .as_ref()
+                .map_or(0, |immutable| {
+                    immutable.mem_table.approximate_memory_usage()
+                }),
+            disk_usage: self.disk_usage.load(Ordering::Relaxed),
+            max_disk_usage: self.max_disk_usage,
+            write_stall: *self.write_stall.lock().unwrap(),
+            flush_failing: self.flush_failing,
+            logical_value_bytes: self.logical_value_bytes,
+            stored_value_bytes: self.stored_value_bytes,
+            read_cache_hits: self.read_cache.as_ref().map_or(0, ReadCache::hits),
+            read_cache_misses: self.read_cache.as_ref().map_or(0, ReadCache::misses),
+        }
+    }
+
+    /// The directory of the database
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The longest key, value or content type, see [`DatabaseBuilder::max_field_len`]
+    pub fn max_field_len(&self) -> usize {
+        self.max_field_len
+    }
+
+    /// The WAL the writes are appended to
+    pub fn wal_path(&self) -> PathBuf {
+        self.wal.path()
+    }
+
+    /// Bytes taken by the WAL the writes are appended to, see [`Database::wal_path`]. 0 when
+    /// the file is not there, e.g. rotated in the meantime.
+    pub async fn wal_size(&self) -> Result<u64> {
+        match storage::file_len(self.storage.as_ref(), &self.wal.path()).await {
+            Ok(len) => Ok(len),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The SSTable files of every level with their size, by path. A file a compaction removes
+    /// in the meantime is left out.
+    pub async fn sstable_files(&self) -> Result<Vec<(PathBuf, u64)>> {
+        let mut files = Vec::new();
+        for path in list_level_files(&self.dir, "db", self.storage.as_ref())
+            .await?
+            .into_iter()
+            .flatten()
+        {
+            match storage::file_len(self.storage.as_ref(), &path).await {
+                Ok(len) => files.push((path, len)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Read back every entry of the SSTables, see [`VerifyReport`]. The WAL is checked when
+    /// the database is opened already, see [`DatabaseBuilder::on_corruption`].
+    pub async fn verify(&self) -> Result<VerifyReport> {
+        let files = self.sstable_files().await?;
+        let files = files.into_iter().map(|(path, _)| path);
+        Ok(verify_sstables(files, self.storage.as_ref(), self.max_field_len).await)
+    }
+
+    /// Remove the files a crash left behind: the `.tmp` files of the flushes and compactions,
+    /// and the index and bloom filter files whose SSTable is gone. The files modified in the
+    /// last [`MIN_CLEANUP_AGE`](crate::MIN_CLEANUP_AGE) are kept, a flush or a compaction may
+    /// still be writing them.
+    pub async fn cleanup_orphans(&self) -> Result<CleanupReport> {
+        let report = cleanup_orphans(&self.dir, self.storage.as_ref()).await?;
+        if report.bytes_freed > 0 {
+            self.measure_disk_usage().await?;
+        }
+        Ok(report)
+    }
+
+    /// Measure the bytes the files take again, see [`DatabaseStats::disk_usage`]. Only done
+    /// when the files change as a whole: on a flush, a compaction or a cleanup.
+    async fn measure_disk_usage(&self) -> Result<u64> {
+        let used = dir_size(&self.dir, self.storage.as_ref()).await?;
+        self.disk_usage.store(used, Ordering::Relaxed);
+        Ok(used)
+    }
+
+    /// Count the `bytes` appended to the WAL until the next measure.
+    fn add_disk_usage(&self, bytes: u64) {
+        self.disk_usage.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Fail with [`Error::ReadOnlyReplica`] on a replica, which only takes the writes of its
+    /// primary.
+    fn check_writable(&self) -> Result<()> {
+        match self.replica {
+            true => Err(Error::ReadOnlyReplica.into()),
+            false => Ok(()),
+        }
+    }
+
+    /// Fail with [`Error::DiskBudgetExceeded`] when the files take the
+    /// [`DatabaseBuilder::max_disk_usage`] already.
+    fn check_disk_budget(&self) -> Result<()> {
+        let Some(limit) = self.max_disk_usage else {
+            return Ok(());
+        };
+        let used = self.disk_usage.load(Ordering::Relaxed);
+        if used >= limit {
+            return Err(Error::DiskBudgetExceeded { used, limit }.into());
+        }
+        Ok(())
+    }
+
+    /// Delay the write or refuse it with [`Error::WriteStalled`] while the flushes and the
+    /// compactions fall behind, see [`DatabaseBuilder::slowdown_sstable_count`]. A finished
+    /// background flush is taken in first, and a full MemTable frozen if it can be.
+    async fn check_write_stall(&mut self) -> Result<()> {
+        if self.slowdown_sstable_count.is_none()
+            && self.stop_sstable_count.is_none()
+            && self.max_pending_immutable_memtables.is_none()
+        {
+            return Ok(());
+        }
+        self.persist_to_sstable().await?;
+        let level0_sstables = self.measure_write_stall();
+        let write_stall = *self.write_stall.lock().unwrap();
+        match write_stall {
+            WriteStall::None => {}
+            WriteStall::Slowdown => {
+                let slowdown = self.slowdown_sstable_count.unwrap_or_default();
+                let overload = (level0_sstables + 1).saturating_sub(slowdown);
+                let delay = SLOWDOWN_DELAY_PER_SSTABLE * overload as u32;
+                tracing::debug!("Slowing down a write by {:?}", delay);
+                tokio::time::sleep(delay).await;
+            }
+            WriteStall::Stopped => {
+                return Err(Error::WriteStalled {
+                    level0_sstables,
+                    pending_mem_tables: self.pending_mem_tables(),
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Count the level 0 SSTables again, see [`Database::measure_write_stall`]. Only done when
+    /// the manifest changes: on a flush or a compaction.
+    async fn count_level0_sstables(&self) -> Result<()> {
+        let level0_sstables = self.sstable_querier.level0_len().await?;
+        self.level0_sstables
+            .store(level0_sstables, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Measure whether the writes are held back again, see [`DatabaseStats::write_stall`].
+    /// Returns the number of level 0 SSTables.
+    fn measure_write_stall(&self) -> usize {
+        let level0_sstables = self.level0_sstables.load(Ordering::Relaxed);
+        let over = |limit: Option<usize>, count: usize| limit.is_some_and(|limit| count >= limit);
+        let write_stall = if over(self.stop_sstable_count, level0_sstables)
+            || self
+                .max_pending_immutable_memtables
+                .is_some_and(|max| self.pending_mem_tables() > max)
+        {
+            WriteStall::Stopped
+        } else if over(self.slowdown_sstable_count, level0_sstables) {
+            WriteStall::Slowdown
+        } else {
+            WriteStall::None
+        };
+        *self.write_stall.lock().unwrap() = write_stall;
+        level0_sstables
+    }
+
+    /// The MemTables waiting for a flush: the one flushed in the background, and the active
+    /// one once full
+    fn pending_mem_tables(&self) -> usize {
+        let full = self.mem_table.approximate_memory_usage() >= self.max_mem_table_size;
+        usize::from(self.immutable_mem_table.is_some()) + usize::from(full)
+    }
+
+    /// Wait for the in-flight background flush (if any) to finish.
+    pub async fn wait_for_flush(&mut self) -> Result<()> {
+        let Some(flush_task) = self.flush_task.take() else {
+            return Ok(());
+        };
+
+        match flush_task.await.context("join mem_table flush task")? {
+            Ok(()) => {
+                self.immutable_mem_table = None;
+                self.flush_failing = false;
+                // the SSTable is in, the WAL files are gone
+                self.measure_disk_usage().await?;
+                self.count_level0_sstables().await?;
+                Ok(())
+            }
+            Err(e) => {
+                self.flush_failing = true;
+                // keep the immutable mem_table readable and try again in the background,
+                // its WAL files are still on disk so nothing is lost if we crash meanwhile.
+                if let Some(immutable) = self.immutable_mem_table.as_ref() {
+                    self.flush_task = Some(tokio::spawn(flush_mem_table(
+                        self.dir.clone(),
+                        self.sstable_options,
+                        Arc::clone(&self.sstable_querier),
+                        Arc::clone(&immutable.mem_table),
+                        immutable.wal_paths.clone(),
+                        Arc::clone(&self.cdc_cursors),
+                        Arc::clone(&self.clock),
+                        Arc::clone(&self.storage),
+                        self.timer(Operation::Flush),
+                    )));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// [`Database::wait_for_flush`] on behalf of a write, `false` when the flush failed. The
+    /// write is in the WAL already, so the failure is only logged, not returned: the flush is
+    /// retried in the background meanwhile, see [`DatabaseStats::flush_failing`].
+    async fn wait_for_flush_of_write(&mut self) -> bool {
+        match self.wait_for_flush().await {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!("Fail to flush a mem_table, retrying: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Flush the active MemTable to a new SSTable right away and start a fresh WAL, waiting
+    /// for the in-flight background flush first. Returns the path of the new SSTable, an empty
+    /// MemTable does not produce a file.
+    pub async fn flush(&mut self) -> Result<Option<PathBuf>> {
+        self.wait_for_flush().await?;
+        if self.mem_table.is_empty() {
+            return Ok(None);
+        }
+        let _timer = self.timer(Operation::Flush);
+
+        let new_wal = self.new_wal().await?;
+        let wal = mem::replace(&mut self.wal, new_wal);
+        let entries = mem::take(&mut self.mem_table).drain_sorted();
+
+        let sstable_path = match write_sstable(
+            &self.dir,
+            self.sstable_options,
+            self.clock.as_ref(),
+            Arc::clone(&self.storage),
+            entries.iter(),
+        )
+        .await
+        {
+            Ok(sstable_path) => sstable_path,
+            Err(e) => {
+                // put the data back, it is still backed by the old WAL which we keep appending to
+                self.mem_table = entries.into_iter().collect();
+                let new_wal = mem::replace(&mut self.wal, wal);
+                self.storage
+                    .remove(&new_wal.path())
+                    .await
+                    .context("remove unused wal file")?;
+                return Err(e);
+            }
+        };
+        self.sstable_querier.invalidate(&sstable_path);
+
+        // delete correspond wal files
+        let mut wal_paths = mem::take(&mut self.wal_segments);
+        wal_paths.push(wal.path());
+        let max_timestamp = entries.iter().map(|entry| entry.timestamp).max();
+        retire_wal_files(
+            &self.dir,
+            wal_paths,
+            max_timestamp,
+            &self.cdc_cursors,
+            self.storage.as_ref(),
+        )
+        .await?;
+        self.measure_disk_usage().await?;
+        self.count_level0_sstables().await?;
+        self.measure_write_stall();
+        Ok(Some(sstable_path))
+    }
+
+    /// Flush the MemTable to an SSTable and sync the fresh WAL to disk, before the process
+    /// exits: nothing is left to replay on the next start. The database stays usable.
+    pub async fn close(&mut self) -> Result<()> {
+        self.flush().await.context("flush mem_table")?;
+        self.wal.sync().await.context("sync wal")?;
+        Ok(())
+    }
+
+    /// Create a new WAL file following the configured sync policy and compression.
+    async fn new_wal(&self) -> Result<WriteAheadLog> {
+        let mut wal = WriteAheadLog::new_with_clock(
+            &self.dir,
+            self.clock.as_ref(),
+            Arc::clone(&self.storage),
+        )
+        .await?
+        .with_sync_policy(self.sync_policy)
+        .await?;
+        wal.set_compression(self.wal_compression);
+        Ok(wal)
+    }
+
+    /// Freeze the MemTable once it reaches the limitation and flush it to SSTable in the
+    /// background. Only one immutable MemTable can be pending at a time, so this waits for
+    /// the previous flush when it is still in flight.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(mem_table_usage = self.mem_table.approximate_memory_usage())))]
+    async fn persist_to_sstable(&mut self) -> Result<()> {
+        if self
+            .flush_task
+            .as_ref()
+            .is_some_and(|flush_task| flush_task.is_finished())
+        {
+            self.wait_for_flush_of_write().await;
+        }
+
+        if self.mem_table.approximate_memory_usage() < self.max_mem_table_size {
+            return Ok(());
+        }
+        // with a limit the writes stall rather than wait for the flush in flight
+        if self.flush_task.is_some()
+            && self
+                .max_pending_immutable_memtables
+                .is_some_and(|max| self.pending_mem_tables() > max)
+        {
+            return Ok(());
+        }
+
+        // the active mem_table keeps growing until the retry goes through
+        if !self.wait_for_flush_of_write().await {
+            return Ok(());
+        }
+
+        // swap in a fresh mem_table and WAL, so the writes can continue right away
+        let new_wal = self.new_wal().await?;
+        let wal = mem::replace(&mut self.wal, new_wal);
+        let mem_table = Arc::new(mem::take(&mut self.mem_table));
+        let mut wal_paths = mem::take(&mut self.wal_segments);
+        wal_paths.push(wal.path());
+
+        self.flush_task = Some(tokio::spawn(flush_mem_table(
+            self.dir.clone(),
+            self.sstable_options,
+            Arc::clone(&self.sstable_querier),
+            Arc::clone(&mem_table),
+            wal_paths.clone(),
+            Arc::clone(&self.cdc_cursors),
+            Arc::clone(&self.clock),
+            Arc::clone(&self.storage),
+            self.timer(Operation::Flush),
+        )));
+        self.immutable_mem_table = Some(ImmutableMemTable {
+            mem_table,
+            wal_paths,
+        });
+
+        Ok(())
+    }
+}
+
+/// Write the MemTable to a new SSTable and then remove the WAL files backing it, see
+/// [`retire_wal_files`].
+#[allow(clippy::too_many_arguments)]
+async fn flush_mem_table(
+    dir: PathBuf,
+    options: SSTableOptions,
+    sstable_querier: Arc<SSTableQuerier>,
+    mem_table: Arc<MemTable>,
+    wal_paths: Vec<PathBuf>,
+    cdc_cursors: Arc<CdcCursors>,
+    clock: Arc<dyn Clock>,
+    storage: Arc<dyn Storage>,
+    _timer: Option<OperationTimer>,
+) -> Result<()> {
+    let sstable_path = write_sstable(
+        &dir,
+        options,
+        clock.as_ref(),
+        Arc::clone(&storage),
+        mem_table.iter(),
+    )
+    .await?;
+    sstable_querier.invalidate(&sstable_path);
+
+    // delete correspond wal files
+    let max_timestamp = mem_table.iter().map(|entry| entry.timestamp).max();
+    retire_wal_files(
+        &dir,
+        wal_paths,
+        max_timestamp,
+        &cdc_cursors,
+        storage.as_ref(),
+    )
+    .await
+}
+
+/// Write the sorted entries to a new level 0 SSTable of `dir` named after a timestamp of
+/// `clock` in `storage`, following the `options`, returns its path.
+async fn write_sstable<'a>(
+    dir: &Path,
+    options: SSTableOptions,
+    clock: &dyn Clock,
+    storage: Arc<dyn Storage>,
+    entries: impl ExactSizeIterator<Item = &'a Entry>,
+) -> Result<PathBuf> {
+    let mut writer =
+        SSTableWriter::create(&level_dir(dir, 0), clock.now()?, Arc::clone(&storage)).await?;
+    let sstable_path = writer.path().to_path_buf();
+    tracing::info!(
+        "Flushing {} entries from mem_table to {:?}",
+        entries.len(),
+        sstable_path
+    );
+    writer.set_options(options);
+    for entry in entries {
+        writer.set(entry).await.context("add entry to sstable")?;
+    }
+    writer
+        .flush()
+        .await
+        .context("flash sstable buffer to file")?;
+    Manifest::update(
+        dir,
+        std::slice::from_ref(&sstable_path),
+        &[],
+        storage.as_ref(),
+    )
+    .await
+    .context("add sstable to manifest")?;
+    tracing::info!(
+        "Flushed {} entries, {} bytes to {:?}",
+        writer.entries_written(),
+        writer.bytes_written(),
+        sstable_path
+    );
+
+    Ok(sstable_path)
+}
+
+/// The newest timestamp of the restored `mem_table` and the SSTables of `dir`, 0 without any
+/// entry.
+async fn last_timestamp(dir: &Path, mem_table: &MemTable, storage: &dyn Storage) -> Result<u128> {
+    let mut last = mem_table.iter().map(|entry| entry.timestamp).max();
+    for path in list_level_files(dir, "db", storage)
+        .await?
+        .into_iter()
+        .flatten()
+    {
+        let max_timestamp = SSTableReader::footer_max_timestamp(&path, storage)
+            .await
+            .with_context(|| format!("read the footer of {:?}", path))?;
+        last = last.max(max_timestamp);
+    }
+    Ok(last.unwrap_or_default())
+}
+
+/// Refuse a key or value longer than `max`, which could not be read back.
+fn check_field_len(field: &'static str, bytes: &[u8], max: usize) -> Result<()> {
+    if bytes.len() > max {
+        return Err(Error::FieldTooLong {
+            field,
+            len: bytes.len(),
+            max,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Merge the SSTable scan result with the MemTable entries, the newest version wins
+/// and the deleted keys are dropped.
+fn merge_scan<'a>(
+    sstable_entries: Vec<Entry>,
+    mem_table_entries: impl Iterator<Item = &'a Entry>,
+    clock: &dyn Clock,
+) -> Result<Vec<DbEntry>> {
+    live_entries(merge_latest(sstable_entries, mem_table_entries), clock)
+}
+
+/// The newest version of every key of the SSTable and the MemTable entries, by key
+fn merge_latest<'a>(
+    sstable_entries: Vec<Entry>,
+    mem_table_entries: impl Iterator<Item = &'a Entry>,
+) -> BTreeMap<Vec<u8>, Entry> {
+    let mut merged = sstable_entries
+        .into_iter()
+        .map(|entry| (entry.key.clone(), entry))
+        .collect::<BTreeMap<_, _>>();
+    for entry in mem_table_entries {
+        match merged.get(&entry.key) {
+            Some(existing) if existing.timestamp > entry.timestamp => {}
+            _ => {
+                merged.insert(entry.key.clone(), entry.clone());
+            }
+        }
+    }
+    merged
+}
+
+/// The live entry of `key` with its value decompressed, `None` for a tombstone or a value which
+/// does not decompress
+fn db_entry(key: &[u8], entry: Entry) -> Option<DbEntry> {
+    match entry.decompressed() {
+        Ok(entry) => DbEntry::try_from(entry).ok(),
+        Err(e) => {
+            tracing::error!(
+                "Fail to decompress the value of a key of {} bytes: {:?}",
+                key.len(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// The values of `merged` decompressed, without the tombstones nor the values expired by the
+/// time of `clock`
+fn live_entries(merged: BTreeMap<Vec<u8>, Entry>, clock: &dyn Clock) -> Result<Vec<DbEntry>> {
+    let mut now = None;
+    let mut entries = Vec::new();
+    for entry in merged.into_values() {
+        if is_expired(&entry, &mut now, clock) {
+            continue;
+        }
+        let entry = entry.decompressed().context("decompress the value")?;
+        entries.extend(DbEntry::try_from(entry).ok());
+    }
+    Ok(entries)
+}
+
+/// Whether the value of `entry` expired by `now`, taken from `clock` the first time an entry
+/// with an expiry needs it: the reads of the other entries leave the clock alone.
+fn is_expired(entry: &Entry, now: &mut Option<u128>, clock: &dyn Clock) -> bool {
+    entry.expires_at.is_some()
+        && entry.is_expired(*now.get_or_insert_with(|| clock.now().unwrap_or_default()))
+}
+
+/// The smallest key which is greater than every key starting with `prefix`.
+/// Return None if there is no such key (empty prefix or all bytes are 0xFF).
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper_bound = prefix.to_vec();
+    while let Some(last) = upper_bound.pop() {
+        if last < u8::MAX {
+            upper_bound.push(last + 1);
+            return Some(upper_bound);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tempdir::TempDir;
+    use tokio::{fs::create_dir_all, io::AsyncWriteExt};
+
+    use super::*;
+    use crate::{entries::DEFAULT_MAX_FIELD_LEN, events::ChangeKind, sstable::get_level_files};
+
+    #[tokio::test]
+    async fn it_works_with_mem_table() -> Result<()> {
+        let tmpdir = TempDir::new("mem_table_test")?;
+        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .build()
+            .await?;
+
+        assert!(db.get(b"test").await.is_none());
+        assert_eq!(db.mem_table.size(), 0);
+        assert_eq!(db.mem_table.len(), 0);
+
+        let timestamp = db.set(b"test", b"hello").await?;
+        assert_ne!(db.mem_table.size(), 0);
+        assert_eq!(db.mem_table.len(), 1);
+
+        let stats = db.stats();
+        assert_eq!(stats.mem_table_len, 1);
+        assert_eq!(stats.mem_table_live_count, 1);
+        assert_eq!(stats.mem_table_tombstone_count, 0);
+        assert_eq!(stats.mem_table_size, db.mem_table.size());
+        assert!(stats.mem_table_memory_usage > stats.mem_table_size);
+        assert_eq!(stats.immutable_mem_table_memory_usage, 0);
+
+        let entry = db.get(b"test").await.unwrap();
+        assert_eq!(entry.key, b"test");
+        assert_eq!(entry.value, &b"hello"[..]);
+        assert_eq!(entry.timestamp, timestamp);
+
+        db.delete(b"test").await?;
+        assert!(db.get(b"test").await.is_none());
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_refuses_the_fields_it_could_not_read_back() -> Result<()> {
+        let tmpdir = TempDir::new("field_len_test")?;
+        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .build()
+            .await?;
+
+        let too_long = vec![0; DEFAULT_MAX_FIELD_LEN + 1];
+        let err = db.set(b"test", &too_long).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::FieldTooLong { field: "value", .. })
+        ));
+        let mut batch = WriteBatch::new();
+        batch.set(b"test", b"hello").delete(&too_long);
+        let err = db.write(batch).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::FieldTooLong { field: "key", .. })
+        ));
+        assert!(db.get(b"test").await.is_none());
+        db.set(b"test", &[0; 100]).await?;
+        db.flush().await?;
+        drop(db);
+
+        // a lower limit applies to the writes and to the records read back
+        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .max_field_len(64)
+            .build()
+            .await?;
+        assert_eq!(db.max_field_len(), 64);
+        let err = db.set(b"test", &[0; 65]).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::FieldTooLong { max: 64, .. })
+        ));
+        let report = db.verify().await?;
+        assert_eq!(report.corruptions.len(), 1);
+        assert!(report.corruptions[0].reason.contains("Field length 100"));
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_refuses_the_writes_over_the_disk_budget() -> Result<()> {
+        let tmpdir = TempDir::new("disk_budget_test")?;
+        let dir = tmpdir.path().to_path_buf();
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .max_disk_usage(1000)
+            .build()
+            .await?;
+        assert_eq!(db.stats().max_disk_usage, Some(1000));
+
+        let mut written = 0;
+        let err = loop {
+            let key = format!("key{}", written);
+            match db.set(key.as_bytes(), &[0; 100]).await {
+                Ok(_) => written += 1,
+                Err(err) => break err,
+            }
+            assert!(written < 20, "the budget is never enforced");
+        };
+        let used = match err.downcast_ref::<Error>() {
+            Some(Error::DiskBudgetExceeded { used, limit: 1000 }) => *used,
+            _ => panic!("unexpected error {:?}", err),
+        };
+        assert!(used >= 1000);
+        assert_eq!(db.stats().disk_usage, used);
+        assert!(db.set_typed(b"key", b"{}", "text/plain").await.is_err());
+        let mut batch = WriteBatch::new();
+        batch.set(b"key", b"value");
+        assert!(db.write(batch).await.is_err());
+
+        // the deletes still go through and give the space back once flushed
+        for i in 0..written {
+            db.delete(format!("key{}", i).as_bytes()).await?;
+        }
+        db.flush().await?;
+        db.compact(u64::MAX).await?;
+        assert!(db.stats().disk_usage < used);
+        db.set(b"key", b"value").await?;
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_stalls_the_writes_while_the_compactions_fall_behind() -> Result<()> {
+        let tmpdir = TempDir::new("write_stall_test")?;
+        let dir = tmpdir.path().to_path_buf();
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .slowdown_sstable_count(2)
+            .stop_sstable_count(3)
+            .build()
+            .await?;
+
+        for (i, write_stall) in [WriteStall::None, WriteStall::Slowdown, WriteStall::Stopped]
+            .into_iter()
+            .enumerate()
+        {
+            db.set(format!("key{}", i).as_bytes(), b"value").await?;
+            db.flush().await?;
+            assert_eq!(db.stats().write_stall, write_stall);
+        }
+        let err = db.set(b"key", b"value").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::WriteStalled {
+                level0_sstables: 3,
+                pending_mem_tables: 0
+            })
+        ));
+        assert!(db.delete(b"key0").await.is_err());
+        let mut batch = WriteBatch::new();
+        batch.set(b"key", b"value");
+        assert!(db.write(batch).await.is_err());
+
+        // a compaction merges the level 0 files, the writes go through again
+        db.compact(u64::MAX).await?;
+        assert_eq!(db.stats().write_stall, WriteStall::None);
+        db.set(b"key", b"value").await?;
+        assert_eq!(db.scan(..).await?.len(), 4);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    /// Pretend a MemTable is being flushed until [`finish_flush`]
+    fn start_flush(db: &mut Database) {
+        db.immutable_mem_table = Some(ImmutableMemTable {
+            mem_table: Arc::default(),
+            wal_paths: Vec::new(),
+        });
+        db.flush_task = Some(tokio::spawn(std::future::pending()));
+    }
+
+    async fn finish_flush(db: &mut Database) {
+        let flush_task = tokio::spawn(async { Ok(()) });
+        while !flush_task.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        db.flush_task = Some(flush_task);
+    }
+
+    #[tokio::test]
+    async fn it_stalls_the_writes_while_the_flushes_fall_behind() -> Result<()> {
+        let tmpdir = TempDir::new("write_stall_test")?;
+        let dir = tmpdir.path().to_path_buf();
+        let stalled = |result: Result<u128>| match result.unwrap_err().downcast_ref::<Error>() {
+            Some(Error::WriteStalled {
+                pending_mem_tables, ..
+            }) => *pending_mem_tables,
+            _ => panic!("not stalled"),
+        };
+
+        // 1: the writes go on during a flush until the active MemTable is full as well
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .max_mem_table_size(1024)
+            .max_pending_immutable_memtables(1)
+            .build()
+            .await?;
+        start_flush(&mut db);
+        db.set(b"a", b"value").await?;
+        assert_eq!(db.stats().write_stall, WriteStall::None);
+        db.set(b"b", &[0; 2048]).await?;
+        assert_eq!(stalled(db.set(b"c", b"value").await), 2);
+        assert_eq!(db.stats().write_stall, WriteStall::Stopped);
+
+        // the full MemTable is flushed in turn, the writes go on meanwhile
+        finish_flush(&mut db).await;
+        db.set(b"c", b"value").await?;
+        assert_eq!(db.stats().write_stall, WriteStall::None);
+        db.wait_for_flush().await?;
+        assert_eq!(db.scan(..).await?.len(), 3);
+        drop(db);
+
+        // 0: the writes stop during every flush
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .max_pending_immutable_memtables(0)
+            .build()
+            .await?;
+        db.set(b"d", b"value").await?;
+        start_flush(&mut db);
+        assert_eq!(stalled(db.set(b"e", b"value").await), 1);
+        finish_flush(&mut db).await;
+        db.set(b"e", b"value").await?;
+        assert_eq!(db.stats().write_stall, WriteStall::None);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_content_type_of_the_values() -> Result<()> {
+        let tmpdir = TempDir::new("content_type_test")?;
+        let dir = tmpdir.path().to_path_buf();
+        let mut db = DatabaseBuilder::new(dir.clone()).build().await?;
+        db.set_typed(b"json", b"{}", "application/json").await?;
+        db.set(b"plain", b"hello").await?;
+
+        let entry = db.get(b"json").await.unwrap();
+        assert_eq!(entry.value, &b"{}"[..]);
+        assert_eq!(entry.content_type(), Some("application/json"));
+        assert_eq!(db.get(b"plain").await.unwrap().content_type(), None);
+
+        // replayed from the WAL
+        drop(db);
+        let mut db = DatabaseBuilder::new(dir.clone()).build().await?;
+        let entry = db.get(b"json").await.unwrap();
+        assert_eq!(entry.content_type(), Some("application/json"));
+
+        // read from the SSTable
+        db.flush().await?;
+        assert_eq!(db.mem_table.len(), 0);
+        let entry = db.get(b"json").await.unwrap();
+        assert_eq!(entry.content_type(), Some("application/json"));
+        let entries = db.scan(..).await?;
+        assert_eq!(entries[0].content_type(), Some("application/json"));
+        assert_eq!(entries[1].content_type(), None);
+
+        // an untyped write drops it
+        db.set(b"json", b"[]").await?;
+        assert_eq!(db.get(b"json").await.unwrap().content_type(), None);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_records_the_latencies_when_enabled() -> Result<()> {
+        let tmpdir = TempDir::new("metrics_test")?;
+        let dir = tmpdir.path().to_path_buf();
+        let mut db = DatabaseBuilder::new(dir.clone()).build().await?;
+        db.set(b"test", b"hello").await?;
+        assert_eq!(db.metrics_snapshot().set.count, 0);
+        drop(db);
+
+        let mut db = DatabaseBuilder::new(dir)
+            .enable_metrics(true)
+            .build()
+            .await?;
+        db.set(b"test", b"hello").await?;
+        db.set_typed(b"json", b"{}", "application/json").await?;
+        db.get(b"test").await.unwrap();
+        assert!(db.get(b"missing").await.is_none());
+        db.delete(b"test").await?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"batch", b"value");
+        db.write(batch).await?;
+        db.flush().await?;
+
+        let snapshot = db.metrics_snapshot();
+        assert_eq!(snapshot.set.count, 2);
+        assert_eq!(snapshot.get.count, 2);
+        assert_eq!(snapshot.delete.count, 1);
+        assert_eq!(snapshot.flush.count, 1);
+        assert_eq!(snapshot.write.count, 1);
+        assert_eq!(snapshot.get.buckets.last().unwrap().1, 2);
+        assert!(snapshot.flush.quantile(0.99).is_some());
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    /// Counts from 1000 by steps of 10.
+    #[derive(Default)]
+    struct StepClock(std::sync::atomic::AtomicU64);
+
+    impl Clock for StepClock {
+        fn now(&self) -> Result<u128> {
+            let step = self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(u128::from(1000 + step * 10))
+        }
+    }
+
+    #[tokio::test]
+    async fn it_takes_the_timestamps_from_the_clock() -> Result<()> {
+        let tmpdir = TempDir::new("clock_test")?;
+        let dir = tmpdir.path().to_path_buf();
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .clock(Arc::new(StepClock::default()))
+            .build()
+            .await?;
+        // the first timestamp names the WAL
+        assert_eq!(db.wal.path(), dir.join("1000.wal"));
+
+        db.set(b"a", b"1").await?;
+        db.set(b"a", b"2").await?;
+        db.set(b"b", b"3").await?;
+        assert_eq!(db.get(b"a").await.unwrap().timestamp, 1020);
+        assert_eq!(db.get(b"b").await.unwrap().timestamp, 1030);
+        let wal_size = db.wal_size().await?;
+        assert!(wal_size > 0);
+        assert_eq!(wal_size, std::fs::metadata(db.wal_path())?.len());
+
+        assert_eq!(db.flush().await?, Some(level_dir(&dir, 0).join("1050.db")));
+        assert_eq!(db.flush().await?, None);
+        assert_eq!(db.wal_path(), dir.join("1040.wal"));
+        let files = db.sstable_files().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, level_dir(&dir, 0).join("1050.db"));
+        assert!(files[0].1 > 0);
+        assert_eq!(
+            get_level_files(&dir, "db")?[0],
+            [level_dir(&dir, 0).join("1050.db")]
+        );
+        assert_eq!(db.get(b"a").await.unwrap().value, &b"2"[..]);
+
+        // a WAL gone in the meantime, e.g. rotated, takes no space
+        std::fs::remove_file(db.wal_path())?;
+        assert_eq!(db.wal_size().await?, 0);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_expires_the_values_after_their_ttl() -> Result<()> {
+        let tmpdir = TempDir::new("ttl_test")?;
+        let dir = tmpdir.path().to_path_buf();
+        let clock = Arc::new(StepClock::default());
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .clock(Arc::clone(&clock) as Arc<dyn Clock>)
+            .build()
+            .await?;
+        db.set(b"b", b"2").await?;
+        db.flush().await?;
+        let timestamp = db
+            .set_with_ttl(b"a", b"1", Duration::from_micros(1_000))
+            .await?;
+        let entry = db.get(b"a").await.unwrap();
+        assert_eq!(entry.expires_at(), Some(timestamp + 1_000));
+        assert_eq!(db.get(b"b").await.unwrap().expires_at(), None);
+
+        // replayed from the WAL, then read from an SSTable
+        drop(db);
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .clock(Arc::clone(&clock) as Arc<dyn Clock>)
+            .build()
+            .await?;
+        assert_eq!(
+            db.get(b"a").await.unwrap().expires_at(),
+            Some(timestamp + 1_000)
+        );
+        db.flush().await?;
+        assert_eq!(db.get(b"a").await.unwrap(), entry);
+        assert_eq!(db.scan_prefix(b"").await?.len(), 2);
+
+        // 100 steps of the clock later
+        clock.0.fetch_add(100, std::sync::atomic::Ordering::Relaxed);
+        assert!(db.get(b"a").await.is_none());
+        let entries = db.scan_prefix(b"").await?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, b"b");
+        let (page, _) = db.scan_prefix_page(b"", None, 10).await?;
+        assert_eq!(page, entries);
+
+        let report = db.compact(u64::MAX).await?;
+        assert_eq!(report.entries_expired, 1);
+        assert_eq!(report.entries_written, 1);
+        assert!(db.get(b"a").await.is_none());
+        assert_eq!(db.get(b"b").await.unwrap().value, &b"2"[..]);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_leaves_nothing_to_replay_once_closed() -> Result<()> {
+        let tmpdir = TempDir::new("close_test")?;
+        for sync_policy in [SyncPolicy::Never, SyncPolicy::Always] {
+            let dir = tmpdir.path().join(format!("{:?}", sync_policy));
+            create_dir_all(&dir).await?;
+            let mut db = DatabaseBuilder::new(dir.clone())
+                .sync_policy(sync_policy)
+                .build()
+                .await?;
+            db.set(b"key", b"value").await?;
+            db.close().await?;
+            assert_eq!(db.stats().mem_table_len, 0);
+            assert_eq!(db.sstable_files().await?.len(), 1);
+            // an empty MemTable has nothing to flush
+            db.close().await?;
+            drop(db);
+
+            let db = DatabaseBuilder::new(dir.clone()).build().await?;
+            assert_eq!(db.stats().mem_table_len, 0);
+            assert_eq!(db.get(b"key").await.unwrap().value, &b"value"[..]);
+        }
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    /// Always the same timestamp
+    struct FixedClock;
+
+    impl Clock for FixedClock {
+        fn now(&self) -> Result<u128> {
+            Ok(1000)
+        }
+    }
+
+    #[tokio::test]
+    async fn it_never_overwrites_a_file_of_the_same_timestamp() -> Result<()> {
+        let tmpdir = TempDir::new("collision_test")?;
+        let dir = tmpdir.path().to_path_buf();
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .clock(Arc::new(FixedClock))
+            .build()
+            .await?;
+        db.set(b"a", b"1").await?;
+        db.set(b"b", b"1").await?;
+        db.flush().await?;
+        db.set(b"a", b"2").await?;
+        db.flush().await?;
+        // the flushed WAL files are gone, which frees their names again
+        assert_eq!(get_files_with_ext(&dir, "wal")?, [db.wal.path()]);
+        let mut level0 = get_level_files(&dir, "db")?[0].clone();
+        level0.sort();
+        assert_eq!(
+            level0,
+            [
+                level_dir(&dir, 0).join("1000-1.db"),
+                level_dir(&dir, 0).join("1000.db"),
+            ]
+        );
+
+        // the file of the suffix is the newer one
+        assert_eq!(db.get(b"a").await.unwrap().value, &b"2"[..]);
+        assert_eq!(db.get(b"b").await.unwrap().value, &b"1"[..]);
+        db.compact(u64::MAX).await?;
+        assert_eq!(db.get(b"a").await.unwrap().value, &b"2"[..]);
+        assert_eq!(db.get(b"b").await.unwrap().value, &b"1"[..]);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_works_with_wal_files() -> Result<()> {
+        let tmpdir = TempDir::new("wal_test")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        // seed
+        DatabaseBuilder::new(dir.clone())
+            .build()
+            .await?
+            .set(b"hello", b"world")
+            .await?;
+
+        // load data in existing wal file
+        let db = DatabaseBuilder::new(dir).build().await?;
+        assert!(db.get(b"test").await.is_none());
+        assert!(db.get(b"hello").await.is_some());
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_restored_wal_files_until_flushed() -> Result<()> {
+        let tmpdir = TempDir::new("restored_wal_files")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        // seed two wal files, as left behind by a crash during a background flush
+        let mut wal = WriteAheadLog::new(&dir).await?;
+        wal.set(b"hello", b"world", 1).await?;
+        wal.flush().await?;
+        let mut wal = WriteAheadLog::new(&dir).await?;
+        wal.set(b"test", b"helloworld", 2).await?;
+        wal.flush().await?;
+
+        // restore appends to the newest file and copies nothing
+        let mut db = DatabaseBuilder::new(dir.clone()).build().await?;
+        assert_eq!(db.wal.path(), wal.path());
+        assert_eq!(db.wal_segments.len(), 1);
+        assert_eq!(get_files_with_ext(&dir, "wal")?.len(), 2);
+
+        db.flush().await?;
+        assert!(db.wal_segments.is_empty());
+        assert_eq!(get_files_with_ext(&dir, "wal")?, vec![db.wal.path()]);
+        assert_eq!(db.get(b"hello").await.unwrap().value, &b"world"[..]);
+        assert_eq!(db.get(b"test").await.unwrap().value, &b"helloworld"[..]);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_works_with_sstable() -> Result<()> {
+        let tmpdir = TempDir::new("sstable_test")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        // seed
+        let seed_entry = Entry::new(b"test1".to_vec(), Some(b"hello").map(|i| i.to_vec()), 1);
+        SSTableWriter::new(&dir.join("test.db"))
+            .await?
+            .set(&seed_entry)
+            .await?
+            .flush()
+            .await?;
+
+        // test
+        let db = DatabaseBuilder::new(dir).build().await?;
+        let result = db.get(b"test1").await;
+        assert!(result.is_some());
+        assert_eq!(result.unwrap().value, &b"hello"[..]);
+        assert!(db.get(b"test").await.is_none());
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_gets_the_newest_version_from_the_mem_table_or_the_sstables() -> Result<()> {
+        let tmpdir = TempDir::new("newest_version")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        // written with a timestamp ahead of the clock
+        let newer = micros_now()? + 60_000_000;
+        let seed_entry = Entry::new(b"test1".to_vec(), Some(b"sstable".to_vec()), newer);
+        SSTableWriter::new(&dir.join("test.db"))
+            .await?
+            .set(&seed_entry)
+            .await?
+            .flush()
+            .await?;
+
+        let mut db = DatabaseBuilder::new(dir).build().await?;
+        db.set(b"test1", b"mem_table").await?;
+        db.set(b"test2", b"mem_table").await?;
+        assert_eq!(db.get(b"test1").await.unwrap().value, &b"sstable"[..]);
+        assert_eq!(db.get(b"test2").await.unwrap().value, &b"mem_table"[..]);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_persists_data_to_sstable_when_reached_the_max_limitation() -> Result<()> {
+        let tmpdir = TempDir::new("persist_to_sstable").unwrap();
+
+        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .max_mem_table_size(64)
+            .build()
+            .await?;
+        db.set(b"test", b"helloworld").await?;
+        db.set(b"test1", b"helloworld1").await?;
+        assert_eq!(db.mem_table.size(), 0);
+        assert_eq!(db.mem_table.len(), 0);
+
+        let entry = db.get(b"test").await;
+        assert!(entry.is_some());
+        assert_eq!(entry.unwrap().value, &b"helloworld"[..]);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_flushes_the_immutable_mem_table_in_the_background() -> Result<()> {
+        let tmpdir = TempDir::new("background_flush")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        // one entry takes about 270 bytes, so every second write triggers a flush
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .max_mem_table_size(300)
+            .build()
+            .await?;
+        db.set(b"test", b"helloworld").await?;
+        assert!(db.immutable_mem_table.is_none());
+        db.set(b"test1", b"helloworld1").await?;
+        let frozen_wal_path = db.immutable_mem_table.as_ref().unwrap().wal_paths[0].clone();
+
+        // readable while the flush is in flight
+        assert_eq!(db.get(b"test1").await.unwrap().value, &b"helloworld1"[..]);
+        assert_eq!(db.scan_prefix(b"test").await?.len(), 2);
+
+        db.wait_for_flush().await?;
+        assert!(db.immutable_mem_table.is_none());
+        assert!(!frozen_wal_path.exists());
+        assert_eq!(get_level_files(&dir, "db")?.concat().len(), 1);
+        assert_eq!(db.get(b"test1").await.unwrap().value, &b"helloworld1"[..]);
+
+        // the options are kept after a flush
+        assert_eq!(db.max_mem_table_size, 300);
+        db.set(b"test2", b"helloworld2").await?;
+        db.set(b"test3", b"helloworld3").await?;
+        db.wait_for_flush().await?;
+        assert_eq!(get_level_files(&dir, "db")?.concat().len(), 2);
+        assert_eq!(db.scan_prefix(b"test").await?.len(), 4);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    async fn flush_finished(db: &Database) {
+        while !db.flush_task.as_ref().unwrap().is_finished() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_writes_going_while_a_flush_fails() -> Result<()> {
+        let tmpdir = TempDir::new("failed_flush")?;
+        let dir = tmpdir.path().to_path_buf();
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .max_mem_table_size(300)
+            .build()
+            .await?;
+        // no SSTable can be created in level 0
+        std::fs::remove_dir(level_dir(&dir, 0))?;
+        std::fs::write(level_dir(&dir, 0), b"")?;
+
+        db.set(b"test", b"helloworld").await?;
+        db.set(b"test1", b"helloworld1").await?;
+        flush_finished(&db).await;
+        assert!(!db.stats().flush_failing);
+
+        // the failure is not the one of the next write, which is in the WAL
+        db.set(b"test2", b"helloworld2").await?;
+        assert!(db.stats().flush_failing);
+        assert!(db.immutable_mem_table.is_some());
+        flush_finished(&db).await;
+        db.set(b"test3", b"helloworld3").await?;
+        assert_eq!(db.scan_prefix(b"test").await?.len(), 4);
+
+        // the retry goes through once the SSTable can be written
+        flush_finished(&db).await;
+        std::fs::remove_file(level_dir(&dir, 0))?;
+        std::fs::create_dir(level_dir(&dir, 0))?;
+        db.set(b"test4", b"helloworld4").await?;
+        db.wait_for_flush().await?;
+        assert!(!db.stats().flush_failing);
+        assert!(!get_level_files(&dir, "db")?[0].is_empty());
+
+        drop(db);
+        let db = DatabaseBuilder::new(dir.clone()).build().await?;
+        assert_eq!(db.scan_prefix(b"test").await?.len(), 5);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_scans_mem_table_and_sstable() -> Result<()> {
+        let tmpdir = TempDir::new("scan_test")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        // seed
+        let apple = Entry::new(b"apple".to_vec(), Some(b"old apple".to_vec()), 1);
+        let apricot = Entry::new(b"apricot".to_vec(), Some(b"apricot".to_vec()), 1);
+        let banana = Entry::new(b"banana".to_vec(), Some(b"banana".to_vec()), 1);
+        SSTableWriter::new(&dir.join("1.db"))
+            .await?
+            .set(&apple)
+            .await?
+            .set(&apricot)
+            .await?
+            .flush()
+            .await?;
+        SSTableWriter::new(&dir.join("2.db"))
+            .await?
+            .set(&banana)
+            .await?
+            .flush()
+            .await?;
+
+        let mut db = DatabaseBuilder::new(dir).build().await?;
+        db.set(b"apple", b"new apple").await?;
+        db.delete(b"apricot").await?;
+        db.set(b"avocado", b"avocado").await?;
+
+        let entries = db.scan_prefix(b"a").await?;
+        let keys = entries.iter().map(|e| e.key.as_slice()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![&b"apple"[..], b"avocado"]);
+        assert_eq!(entries[0].value, &b"new apple"[..]);
+
+        let entries = db.scan(&b"apricot"[..]..).await?;
+        let keys = entries.iter().map(|e| e.key.as_slice()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![&b"avocado"[..], b"banana"]);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_scans_by_page() -> Result<()> {
+        let tmpdir = TempDir::new("scan_page_test")?;
+        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .build()
+            .await?;
+        for i in 0..30 {
+            db.set(format!("key{:02}", i).as_bytes(), b"old").await?;
+        }
+        db.set(b"other", b"value").await?;
+        db.flush().await?;
+        // newer versions and tombstones in the MemTable, over the SSTable
+        for i in (0..30).step_by(3) {
+            db.delete(format!("key{:02}", i).as_bytes()).await?;
+        }
+        db.set(b"key10", b"new").await?;
+
+        let mut keys = Vec::new();
+        let mut start_after = None;
+        loop {
+            let (entries, next_start) = db
+                .scan_prefix_page(b"key", start_after.as_deref(), 7)
+                .await?;
+            assert!(entries.len() <= 7);
+            keys.extend(entries.iter().map(|entry| entry.key.clone()));
+            if let Some(entry) = entries.iter().find(|entry| entry.key == b"key10") {
+                assert_eq!(entry.value, &b"new"[..]);
+            }
+            match next_start {
+                Some(next_start) => start_after = Some(next_start),
+                None => break,
+            }
+        }
+        let expected = db.scan_prefix(b"key").await?;
+        assert_eq!(expected.len(), 20);
+        assert_eq!(
+            keys,
+            expected
+                .into_iter()
+                .map(|entry| entry.key)
+                .collect::<Vec<_>>()
+        );
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn it_computes_prefix_upper_bound() {
+        assert_eq!(prefix_upper_bound(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_upper_bound(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_upper_bound(b"\xff\xff"), None);
+        assert_eq!(prefix_upper_bound(b""), None);
+    }
+
+    #[tokio::test]
+    async fn it_flushes_the_mem_table_on_demand() -> Result<()> {
+        let tmpdir = TempDir::new("flush_on_demand")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .max_mem_table_size(4096)
+            .build()
+            .await?;
+        db.set(b"test", b"hello").await?;
+        db.delete(b"test1").await?;
+        let old_wal_path = db.wal.path();
+
+        db.flush().await?;
+        assert_eq!(db.mem_table.len(), 0);
+        assert!(!old_wal_path.exists());
+        assert!(db.wal.path().exists());
+        assert_eq!(get_level_files(&dir, "db")?.concat().len(), 1);
+        assert_eq!(db.max_mem_table_size, 4096);
+        assert_eq!(db.get(b"test").await.unwrap().value, &b"hello"[..]);
+
+        // an empty mem_table does not create any file
+        db.flush().await?;
+        assert_eq!(get_level_files(&dir, "db")?.concat().len(), 1);
+        assert_eq!(get_level_files(&dir, "idx")?.concat().len(), 0);
+
+        // nothing is replayed when reopening
+        drop(db);
+        let db = DatabaseBuilder::new(dir).build().await?;
+        assert_eq!(db.mem_table.len(), 0);
+        assert_eq!(db.get(b"test").await.unwrap().value, &b"hello"[..]);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_works_with_sync_policy_always() -> Result<()> {
+        let tmpdir = TempDir::new("sync_policy_always")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .max_mem_table_size(200)
+            .sync_policy(SyncPolicy::Always)
+            .build()
+            .await?;
+        assert_eq!(get_files_with_ext(&dir, "wal")?.len(), 1);
+        db.set(b"test", b"helloworld").await?;
+        db.set(b"test1", b"helloworld1").await?;
+        db.delete(b"test").await?;
+        db.wait_for_flush().await?;
+
+        // reopen without a clean shutdown
+        drop(db);
+        let db = DatabaseBuilder::new(dir).build().await?;
+        assert!(db.get(b"test").await.is_none());
+        assert_eq!(db.get(b"test1").await.unwrap().value, &b"helloworld1"[..]);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_applies_a_write_batch() -> Result<()> {
+        let tmpdir = TempDir::new("write_batch")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let mut db = DatabaseBuilder::new(dir.clone()).build().await?;
+        db.set(b"test", b"hello").await?;
+        db.set_typed(b"typed", b"{}", "application/json").await?;
+        let typed = db.get(b"typed").await.unwrap();
+        db.delete(b"typed").await?;
+
+        let mut batch = WriteBatch::new();
+        batch
+            .set(b"test1", b"helloworld1")
+            .delete(b"test")
+            .set(b"test2", b"helloworld2")
+            .put(&typed);
+        assert_eq!(db.write(batch).await?, 4);
+        assert_eq!(db.write(WriteBatch::new()).await?, 0);
+
+        assert!(db.get(b"test").await.is_none());
+        assert_eq!(db.get(b"test1").await.unwrap().value, &b"helloworld1"[..]);
+
+        // replayed from the WAL
+        drop(db);
+        let db = DatabaseBuilder::new(dir).build().await?;
+        assert!(db.get(b"test").await.is_none());
+        assert_eq!(db.get(b"test2").await.unwrap().value, &b"helloworld2"[..]);
+        let entry = db.get(b"typed").await.unwrap();
+        assert_eq!(entry.content_type(), Some("application/json"));
+        assert!(entry.timestamp > typed.timestamp);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_works_with_wal_compression() -> Result<()> {
+        let tmpdir = TempDir::new("wal_compression")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let json = br#"{"name":"apple","kind":"fruit"}"#.repeat(32);
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .wal_compression(Codec::Zstd)
+            .build()
+            .await?;
+        db.set(b"test", &json).await?;
+        db.set(b"test1", b"tiny").await?;
+        drop(db);
+
+        // a plain database reads the compressed records back
+        let db = DatabaseBuilder::new(dir).build().await?;
+        assert_eq!(db.get(b"test").await.unwrap().value, json);
+        assert_eq!(db.get(b"test1").await.unwrap().value, &b"tiny"[..]);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_works_with_sstable_compression() -> Result<()> {
+        let tmpdir = TempDir::new("sstable_compression")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let json = br#"{"name":"apple","kind":"fruit"}"#.repeat(32);
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .sstable_compression(Codec::Zstd)
+            .build()
+            .await?;
+        db.set(b"test", &json).await?;
+        db.flush().await?;
+        drop(db);
+
+        // compressed and plain SSTables side by side
+        let mut db = DatabaseBuilder::new(dir.clone()).build().await?;
+        db.set(b"test1", b"plain").await?;
+        db.flush().await?;
+        assert_eq!(get_level_files(&dir, "db")?.concat().len(), 2);
+        assert_eq!(db.get(b"test").await.unwrap().value, json);
+        assert_eq!(db.get(b"test1").await.unwrap().value, &b"plain"[..]);
+        assert_eq!(db.scan(..).await?.len(), 2);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_works_with_value_compression() -> Result<()> {
+        let tmpdir = TempDir::new("value_compression")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let json = br#"{"name":"apple","kind":"fruit"}"#.repeat(32);
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .value_compression(Codec::Lz4, 64)
+            .wal_compression(Codec::Zstd)
+            .build()
+            .await?;
+        let mut events = db.subscribe();
+        db.set(b"a", &json).await?;
+        db.set(b"b", b"tiny").await?;
+        db.set_typed(b"c", &json, "application/json").await?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"d", &json);
+        db.write(batch).await?;
+
+        // the values are published and read back as written
+        assert_eq!(events.recv().await?.value.unwrap(), json);
+        assert_eq!(db.get(b"a").await.unwrap().value, json);
+        assert_eq!(db.get(b"b").await.unwrap().value, &b"tiny"[..]);
+        let entries = db.scan(..).await?;
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[2].value, json);
+        assert_eq!(entries[2].content_type(), Some("application/json"));
+        assert_eq!(entries[3].value, json);
+        let stats = db.stats();
+        assert_eq!(stats.logical_value_bytes, 3 * json.len() as u64 + 4);
+        assert!(stats.stored_value_bytes < json.len() as u64);
+
+        // the compactions carry the compressed values over
+        db.flush().await?;
+        db.set(b"e", &json).await?;
+        db.flush().await?;
+        db.compact(u64::MAX).await?;
+        assert_eq!(get_level_files(&dir, "db")?.concat().len(), 1);
+        let stored = db.sstable_querier().query(b"a").await.unwrap();
+        assert_eq!(stored.value_codec, Codec::Lz4);
+        assert!(stored.value.unwrap().len() < json.len());
+        assert_eq!(db.get(b"e").await.unwrap().value, json);
+        drop(db);
+
+        // a plain database reads them back, and writes its values as before
+        let mut db = DatabaseBuilder::new(dir).build().await?;
+        db.set(b"f", &json).await?;
+        assert_eq!(db.stats().stored_value_bytes, json.len() as u64);
+        assert_eq!(db.get(b"a").await.unwrap().value, json);
+        assert_eq!(db.scan_prefix(b"").await?.len(), 6);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_caches_the_keys_read_out_of_the_sstables() -> Result<()> {
+        let tmpdir = TempDir::new("read_cache")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .read_cache_capacity(1 << 20)
+            .build()
+            .await?;
+        db.set(b"a", b"1").await?;
+        db.set(b"b", b"2").await?;
+        db.flush().await?;
+        let counters = |db: &Database| {
+            let stats = db.stats();
+            (stats.read_cache_hits, stats.read_cache_misses)
+        };
+
+        assert_eq!(db.get(b"a").await.unwrap().value, &b"1"[..]);
+        assert_eq!(db.get(b"a").await.unwrap().value, &b"1"[..]);
+        assert!(db.get(b"c").await.is_none());
+        assert!(db.get(b"c").await.is_none());
+        assert_eq!(counters(&db), (2, 2));
+
+        // a write forgets the key, also once flushed
+        db.set(b"a", b"3").await?;
+        db.delete(b"b").await?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"c", b"4");
+        db.write(batch).await?;
+        assert_eq!(db.get(b"a").await.unwrap().value, &b"3"[..]);
+        db.flush().await?;
+        assert_eq!(db.get(b"a").await.unwrap().value, &b"3"[..]);
+        assert!(db.get(b"b").await.is_none());
+        assert_eq!(db.get(b"c").await.unwrap().value, &b"4"[..]);
+        assert_eq!(counters(&db), (2, 5));
+
+        // and a compaction the whole cache
+        assert_eq!(db.get(b"a").await.unwrap().value, &b"3"[..]);
+        db.compact(u64::MAX).await?;
+        assert_eq!(db.get(b"a").await.unwrap().value, &b"3"[..]);
+        assert_eq!(counters(&db), (3, 6));
+
+        // off by default
+        drop(db);
+        let db = DatabaseBuilder::new(dir).build().await?;
+        assert_eq!(db.get(b"a").await.unwrap().value, &b"3"[..]);
+        assert_eq!(counters(&db), (0, 0));
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_works_with_a_sparse_sstable_index() -> Result<()> {
+        let tmpdir = TempDir::new("sparse_sstable_index")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .index_every_n_keys(8)
+            .build()
+            .await?;
+        for i in (0..100u32).rev() {
+            db.set(format!("key{:03}", i).as_bytes(), b"value").await?;
+        }
+        db.delete(b"key042").await?;
+        db.flush().await?;
+
+        assert_eq!(get_level_files(&dir, "db")?.concat().len(), 1);
+        assert_eq!(db.get(b"key000").await.unwrap().value, &b"value"[..]);
+        assert_eq!(db.get(b"key099").await.unwrap().value, &b"value"[..]);
+        assert!(db.get(b"key042").await.is_none());
+        assert!(db.get(b"key100").await.is_none());
+        assert_eq!(db.scan(&b"key040"[..]..&b"key050"[..]).await?.len(), 9);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_compacts_the_levels() -> Result<()> {
+        let tmpdir = TempDir::new("compact_levels")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .level0_max_size(1)
+            .level1_file_size(500)
+            .build()
+            .await?;
+        for round in 0..3u32 {
+            for i in (round * 20..100).step_by(2) {
+                let value = format!("value{}", round);
+                db.set(format!("key{:03}", i).as_bytes(), value.as_bytes())
+                    .await?;
+            }
+            db.delete(format!("key{:03}", round * 30).as_bytes())
+                .await?;
+            db.flush().await?;
+
+            let report = db.compact_levels().await?;
+            let [level0, level1] = get_level_files(&dir, "db")?;
+            assert!(level0.is_empty());
+            assert!(report.output_paths.iter().all(|path| level1.contains(path)));
+        }
+        assert!(get_level_files(&dir, "db")?[1].len() > 1);
+
+        // the level 1 files do not overlap
+        let mut key_ranges = Vec::new();
+        for path in get_level_files(&dir, "db")?[1].iter() {
+            key_ranges.push(
+                SSTableReader::footer_key_range(path, &LocalFs)
+                    .await
+                    .unwrap(),
+            );
+        }
+        key_ranges.sort();
+        assert!(key_ranges.windows(2).all(|pair| pair[0].1 < pair[1].0));
+
+        // every version and deletion survives, a lookup reads a single level 1 file
+        let querier = SSTableQuerier::new(&dir).await?;
+        assert!(querier.query(b"key058").await.is_some());
+        assert_eq!(querier.files_opened(), 1);
+        drop(db);
+        let db = DatabaseBuilder::new(dir.clone()).build().await?;
+        assert_eq!(db.get(b"key004").await.unwrap().value, &b"value0"[..]);
+        assert_eq!(db.get(b"key024").await.unwrap().value, &b"value1"[..]);
+        assert_eq!(db.get(b"key042").await.unwrap().value, &b"value2"[..]);
+        assert_eq!(db.get(b"key098").await.unwrap().value, &b"value2"[..]);
+        assert!(db.get(b"key030").await.is_none());
+        assert!(db.get(b"key060").await.is_none());
+        assert_eq!(db.scan(..).await?.len(), 47);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_locks_the_directory() -> Result<()> {
+        let tmpdir = TempDir::new("dir_lock")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let db = DatabaseBuilder::new(dir.clone()).build().await?;
+        let err = DatabaseBuilder::new(dir.clone())
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::DirectoryLocked(locked)) if *locked == dir
+        ));
+
+        // released along with the database
+        drop(db);
+        DatabaseBuilder::new(dir).build().await?;
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_works_with_a_lazy_sstable_index() -> Result<()> {
+        let tmpdir = TempDir::new("lazy_sstable_index")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let mut db = DatabaseBuilder::new(dir.clone())
+            .index_mode(IndexMode::Lazy)
+            .build()
+            .await?;
+        for i in 0..100u32 {
+            db.set(format!("key{:03}", i).as_bytes(), b"value").await?;
+        }
+        db.delete(b"key042").await?;
+        db.flush().await?;
+
+        assert_eq!(get_level_files(&dir, "db")?.concat().len(), 1);
+        assert_eq!(db.get(b"key000").await.unwrap().value, &b"value"[..]);
+        assert_eq!(db.get(b"key099").await.unwrap().value, &b"value"[..]);
+        assert!(db.get(b"key042").await.is_none());
+        assert!(db.get(b"key100").await.is_none());
+        assert_eq!(db.scan(&b"key040"[..]..&b"key050"[..]).await?.len(), 9);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_repairs_a_corrupted_wal_on_open() -> Result<()> {
+        let tmpdir = TempDir::new("repair_on_open")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        // garbage between two valid records
+        let mut wal = WriteAheadLog::new(&dir).await?;
+        wal.set(b"hello", b"world", 1).await?;
+        wal.flush().await?;
+        tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(wal.path())
+            .await?
+            .write_all(&[7; 20])
+            .await?;
+        let mut wal = WriteAheadLog::from_path(&wal.path()).await?;
+        wal.set(b"test", b"helloworld", 2).await?;
+        wal.flush().await?;
+
+        let db = DatabaseBuilder::new(dir)
+            .on_corruption(RecoveryMode::Repair)
+            .build()
+            .await?;
+        assert_eq!(db.get(b"hello").await.unwrap().value, &b"world"[..]);
+        assert_eq!(db.get(b"test").await.unwrap().value, &b"helloworld"[..]);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_reports_the_recovery() -> Result<()> {
+        let tmpdir = TempDir::new("recovery_report")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let mut db = DatabaseBuilder::new(dir.clone()).build().await?;
+        assert_eq!(db.recovery_report().wal_files_replayed, 0);
+        db.set(b"a", b"1").await?;
+        db.delete(b"b").await?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"c", b"3").delete(b"d");
+        db.write(batch).await?;
+        let wal_path = db.wal_path();
+        drop(db);
+        let wal_len = tokio::fs::metadata(&wal_path).await?.len();
+        // a torn record at the end
+        tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&wal_path)
+            .await?
+            .write_all(&[1, 2, 3])
+            .await?;
+
+        let db = DatabaseBuilder::new(dir).build().await?;
+        let report = db.recovery_report();
+        assert_eq!(report.wal_files_replayed, 1);
+        assert_eq!(report.records_applied, 4);
+        assert_eq!(report.tombstones, 2);
+        assert_eq!(report.bytes_read, wal_len + 3);
+        assert_eq!(report.truncated_tail_bytes, 3);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_reports_the_restore_progress() -> Result<()> {
+        let tmpdir = TempDir::new("restore_progress")?;
+        let dir = tmpdir.path().to_path_buf();
+
+        let mut wal = WriteAheadLog::new(&dir).await?;
+        wal.set(b"hello", b"world", 1).await?;
+        wal.flush().await?;
+        let mut wal = WriteAheadLog::new(&dir).await?;
+        wal.set(b"test", b"helloworld", 2).await?;
+        wal.delete(b"hello", 3).await?;
+        wal.flush().await?;
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let db = DatabaseBuilder::new_with_progress(dir.clone(), sender, CancellationToken::new())
+            .build()
+            .await?;
+        assert!(db.get(b"hello").await.is_none());
+
+        let mut reports = Vec::new();
+        while let Ok(progress) = receiver.try_recv() {
+            reports.push(progress);
+        }
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|progress| progress.done));
+        assert!(reports
+            .iter()
+            .all(|progress| progress.bytes_processed == progress.file_len));
+        assert_eq!(reports[1].file, wal.path());
+        assert_eq!(reports[1].file_index, 1);
+        assert_eq!(reports[1].total_files, 2);
+        assert_eq!(reports[1].records_applied, 2);
+
+        // cancelled before the first file, nothing is touched
+        drop(db);
+        let (sender, _receiver) = mpsc::channel(16);
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let result = DatabaseBuilder::new_with_progress(dir.clone(), sender, cancellation)
+            .build()
+            .await;
+        assert!(matches!(
+            result.err().unwrap().downcast_ref::<Error>(),
+            Some(Error::RestoreCancelled)
+        ));
+        assert_eq!(get_files_with_ext(&dir, "wal")?.len(), 2);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_publishes_the_writes_to_the_subscribers() -> Result<()> {
+        let tmpdir = TempDir::new("change_events")?;
+        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .change_events_capacity(2)
+            .build()
+            .await?;
+        db.set(b"unseen", b"value").await?;
+
+        let mut events = db.subscribe();
+        let timestamp = db.set(b"hello", b"world").await?;
+        db.delete(b"hello").await?;
+        let first = events.recv().await?;
+        assert_eq!(first.kind, ChangeKind::Set);
+        assert_eq!(first.key, b"hello");
+        assert_eq!(first.value.as_deref(), Some(&b"world"[..]));
+        assert_eq!(first.timestamp, timestamp);
+        assert_eq!(events.recv().await?.kind, ChangeKind::Delete);
+
+        // a slow subscriber misses the oldest events, the writes go on
+        let mut batch = WriteBatch::new();
+        batch.set(b"a", b"1").set(b"b", b"2").delete(b"c");
+        db.write(batch).await?;
+        assert!(matches!(
+            events.recv().await,
+            Err(broadcast::error::RecvError::Lagged(1))
+        ));
+        assert_eq!(events.recv().await?.key, b"b");
+        assert_eq!(events.recv().await?.key, b"c");
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_applies_the_writes_of_the_primary_on_a_replica() -> Result<()> {
+        let (primary_dir, replica_dir) = (TempDir::new("primary")?, TempDir::new("replica")?);
+        let mut primary = DatabaseBuilder::new(primary_dir.path().to_path_buf())
+            .build()
+            .await?;
+        let open_replica = || {
+            DatabaseBuilder::new(replica_dir.path().to_path_buf())
+                .replica(true)
+                .build()
+        };
+        let mut replica = open_replica().await?;
+        assert!(matches!(
+            replica.set(b"a", b"1").await.unwrap_err().downcast_ref(),
+            Some(Error::ReadOnlyReplica)
+        ));
+        assert!(matches!(
+            primary
+                .apply_replicated(Entry::new(b"a".to_vec(), None, 1))
+                .await
+                .unwrap_err()
+                .downcast_ref(),
+            Some(Error::NotAReplica)
+        ));
+        assert_eq!(replica.last_applied_timestamp(), 0);
+
+        primary.set(b"a", b"1").await?;
+        let typed = primary.set_typed(b"b", b"{}", "application/json").await?;
+        primary.delete(b"a").await?;
+        let last = primary
+            .set_with_ttl(b"c", b"3", Duration::from_secs(60))
+            .await?;
+        let mut events = primary.cdc_stream(0).await?;
+        for _ in 0..4 {
+            let event = tokio_stream::StreamExt::next(&mut events).await.unwrap()?;
+            replica.apply_replicated(Entry::from(event)).await?;
+        }
+
+        let b = replica.get(b"b").await.unwrap();
+        assert_eq!(
+            (b.timestamp, b.content_type()),
+            (typed, Some("application/json"))
+        );
+        assert!(replica.get(b"a").await.is_none());
+        assert!(replica.get(b"c").await.unwrap().expires_at().is_some());
+        assert_eq!(replica.last_applied_timestamp(), last);
+
+        // the replication resumes after the last one, from the WAL or the SSTables
+        drop(replica);
+        let mut replica = open_replica().await?;
+        assert_eq!(replica.last_applied_timestamp(), last);
+        replica.flush().await?;
+        drop(replica);
+        assert_eq!(open_replica().await?.last_applied_timestamp(), last);
+
+        primary_dir.close()?;
+        replica_dir.close()?;
         Ok(())
     }
 }
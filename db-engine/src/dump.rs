@@ -0,0 +1,88 @@
+use std::{io::SeekFrom, path::Path};
+
+use anyhow::{Context, Result};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::{compression::Codec, entries::Entry, sstable::SSTABLE_MAGIC, wal::WAL_MAGIC};
+
+/// Outcome of [`SSTableReader::dump`](crate::SSTableReader::dump) and
+/// [`WriteAheadLog::dump`](crate::WriteAheadLog::dump).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DumpSummary {
+    /// Records which read back intact
+    pub records: u64,
+    /// Records which did not, each one reported with its offset
+    pub corruptions: u64,
+}
+
+/// The kind of a file of a database directory, see [`FileKind::detect`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    SSTable,
+    Wal,
+}
+
+impl FileKind {
+    /// Tell an SSTable from a WAL by the magic of the file, by its extension for the older
+    /// formats without one. `None` for any other file.
+    pub async fn detect(path: &Path) -> Result<Option<Self>> {
+        let mut file = File::open(path)
+            .await
+            .with_context(|| format!("open {:?}", path))?;
+        let len = file.metadata().await?.len();
+        let mut magic = [0; 8];
+        if len >= magic.len() as u64 {
+            file.read_exact(&mut magic).await?;
+            if magic == WAL_MAGIC {
+                return Ok(Some(Self::Wal));
+            }
+            file.seek(SeekFrom::End(-(magic.len() as i64))).await?;
+            file.read_exact(&mut magic).await?;
+            if magic == *SSTABLE_MAGIC {
+                return Ok(Some(Self::SSTable));
+            }
+        }
+        Ok(match path.extension().and_then(|ext| ext.to_str()) {
+            Some("wal") => Some(Self::Wal),
+            Some("db") => Some(Self::SSTable),
+            _ => None,
+        })
+    }
+}
+
+/// A line of a dump for the `entry` at `offset`, with its value in hex when `raw` is set
+pub(crate) fn entry_line(offset: &str, entry: &Entry, raw: bool) -> String {
+    let mut line = format!(
+        "{:>12}  key \"{}\" timestamp {}",
+        offset,
+        entry.key.escape_ascii(),
+        entry.timestamp
+    );
+    match &entry.value {
+        Some(value) => line.push_str(&format!(" value {} bytes", value.len())),
+        None => line.push_str(" tombstone"),
+    }
+    if entry.value.is_some() && entry.value_codec != Codec::None {
+        line.push_str(&format!(" compressed with {:?}", entry.value_codec));
+    }
+    if let Some(content_type) = &entry.content_type {
+        line.push_str(&format!(" content type {}", content_type));
+    }
+    if let Some(expires_at) = entry.expires_at {
+        line.push_str(&format!(" expires at {}", expires_at));
+    }
+    if let Some(value) = entry.value.as_ref().filter(|_| raw) {
+        line.push_str("\n              ");
+        line.extend(value.iter().map(|byte| format!("{:02x}", byte)));
+    }
+    line.push('\n');
+    line
+}
+
+/// A line of a dump for the corrupted record at `offset`
+pub(crate) fn corruption_line(offset: &str, e: &dyn std::fmt::Display) -> String {
+    format!("{:>12}  CORRUPTED {}\n", offset, e)
+}
@@ -1,78 +1,522 @@
-use tokio::{
-    fs::File,
-    io::{self, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-};
-/// Database Entry
+use bytes::Bytes;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{compression::Codec, errors::WalReadError};
+
+/// Default of [`DatabaseBuilder::max_field_len`](crate::DatabaseBuilder::max_field_len), 16 MB.
+pub const DEFAULT_MAX_FIELD_LEN: usize = 16 * 1024 * 1024;
+
+/// Longest varint, the one of a u128.
+const MAX_VARINT_LEN: usize = 19;
+
+/// Bit of the flags byte after the key of a varint Entry: it is a tombstone. Nothing else was
+/// ever written there before the metadata section.
+const FLAG_DELETED: u8 = 0x01;
+
+/// Bit of the flags byte: the value is followed by a metadata section.
+const FLAG_METADATA: u8 = 0x02;
+
+/// Bits of the flags byte holding the tag of the [`Codec`] the value is compressed with, see
+/// [`Entry::value_codec`]. They were always clear before, an uncompressed value.
+const FLAG_VALUE_CODEC: u8 = 0x0c;
+
+/// Position of the lowest bit of [`FLAG_VALUE_CODEC`].
+const FLAG_VALUE_CODEC_SHIFT: u8 = 2;
+
+/// Bit of the flags byte opening the metadata section: a content type follows.
+const METADATA_CONTENT_TYPE: u8 = 0x01;
+
+/// Bit of the flags byte opening the metadata section: a varint expiry timestamp follows,
+/// after the content type if any.
+const METADATA_EXPIRES_AT: u8 = 0x02;
+
+/// How an Entry is laid out, set by the format version of the WAL or SSTable file holding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EntryEncoding {
+    /// 8 byte lengths and a 16 byte timestamp, see [`Entry::write_to`].
+    Fixed,
+    /// Varint lengths and timestamp, see [`Entry::write_to_v2`].
+    Varint,
+}
+
+/// Database Entry, a live key with its value. With the `serde` feature the key and value are
+/// base64 strings in the human-readable formats, and bytes in the others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DbEntry {
+    #[cfg_attr(feature = "serde", serde(with = "base64_bytes"))]
     pub key: Vec<u8>,
-    pub value: Vec<u8>,
+    /// Shared with the entry it was read from rather than copied.
+    #[cfg_attr(feature = "serde", serde(with = "base64_bytes"))]
+    pub value: Bytes,
     pub timestamp: u128,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    content_type: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    expires_at: Option<u128>,
+}
+
+impl DbEntry {
+    /// The content type the value was stored with by [`crate::Database::set_typed`].
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// When the value set by [`crate::Database::set_with_ttl`] expires, in microseconds since
+    /// the Unix epoch.
+    pub fn expires_at(&self) -> Option<u128> {
+        self.expires_at
+    }
+}
+
+impl From<DbEntry> for Entry {
+    fn from(entry: DbEntry) -> Self {
+        Self {
+            key: entry.key,
+            value: Some(entry.value),
+            timestamp: entry.timestamp,
+            content_type: entry.content_type,
+            expires_at: entry.expires_at,
+            value_codec: Codec::None,
+        }
+    }
+}
+
+/// Only a live Entry is a DbEntry, a tombstone is handed back. The value is taken as is, see
+/// [`Entry::decompressed`].
+impl TryFrom<Entry> for DbEntry {
+    type Error = Entry;
+
+    fn try_from(entry: Entry) -> Result<Self, Self::Error> {
+        match entry.value {
+            Some(value) => Ok(Self {
+                key: entry.key,
+                value,
+                timestamp: entry.timestamp,
+                content_type: entry.content_type,
+                expires_at: entry.expires_at,
+            }),
+            None => Err(entry),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+pub(crate) mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        bytes: impl AsRef<[u8]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let bytes = bytes.as_ref();
+        match serializer.is_human_readable() {
+            true => serializer.serialize_str(&STANDARD.encode(bytes)),
+            false => serializer.serialize_bytes(bytes),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>, T: From<Vec<u8>>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let bytes = match deserializer.is_human_readable() {
+            true => {
+                let encoded = String::deserialize(deserializer)?;
+                STANDARD.decode(encoded).map_err(D::Error::custom)?
+            }
+            false => Vec::deserialize(deserializer)?,
+        };
+        Ok(bytes.into())
+    }
+
+    /// The same for an optional field, e.g. the value of a delete
+    pub(crate) mod option {
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        struct Encoded<'a>(&'a [u8]);
+
+        impl Serialize for Encoded<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                super::serialize(self.0, serializer)
+            }
+        }
+
+        struct Decoded(Vec<u8>);
+
+        impl<'de> Deserialize<'de> for Decoded {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                super::deserialize(deserializer).map(Self)
+            }
+        }
+
+        pub(crate) fn serialize<S: Serializer>(
+            bytes: &Option<impl AsRef<[u8]>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            bytes
+                .as_ref()
+                .map(|bytes| Encoded(bytes.as_ref()))
+                .serialize(serializer)
+        }
+
+        pub(crate) fn deserialize<'de, D: Deserializer<'de>, T: From<Vec<u8>>>(
+            deserializer: D,
+        ) -> Result<Option<T>, D::Error> {
+            let decoded = Option::<Decoded>::deserialize(deserializer)?;
+            Ok(decoded.map(|Decoded(bytes)| bytes.into()))
+        }
+    }
 }
 
 /// Data Entry
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Entry {
     pub key: Vec<u8>,
-    pub value: Option<Vec<u8>>, // the vaule will be None when the entry is deleted
+    pub value: Option<Bytes>, // the vaule will be None when the entry is deleted
     pub timestamp: u128,
+    /// Content type of the value, only kept by [`Entry::write_to_v2`] and never on a
+    /// tombstone.
+    pub content_type: Option<String>,
+    /// Microseconds since the Unix epoch from which the entry reads as deleted, only kept by
+    /// [`Entry::write_to_v2`] and never on a tombstone.
+    pub expires_at: Option<u128>,
+    /// Codec the value is compressed with, see
+    /// [`DatabaseBuilder::value_compression`](crate::DatabaseBuilder::value_compression). Only
+    /// kept by [`Entry::write_to_v2`] and never on a tombstone.
+    pub value_codec: Codec,
 }
 
 impl Entry {
     pub fn new(key: Vec<u8>, value: Option<Vec<u8>>, timestamp: u128) -> Self {
         Self {
             key,
-            value,
+            value: value.map(Bytes::from),
             timestamp,
+            content_type: None,
+            expires_at: None,
+            value_codec: Codec::None,
+        }
+    }
+
+    /// Tag the value with its `content_type`.
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Expire the value at `expires_at`, in microseconds since the Unix epoch.
+    pub fn with_expires_at(mut self, expires_at: u128) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    /// Whether the value expired by `now`, in microseconds since the Unix epoch.
+    pub fn is_expired(&self, now: u128) -> bool {
+        self.stored_expires_at()
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// The entry with its value decompressed, the way it was written to the
+    /// [`Database`](crate::Database).
+    pub fn decompressed(mut self) -> io::Result<Self> {
+        if let Some(value) = self.value.as_ref() {
+            if self.value_codec != Codec::None {
+                self.value = Some(self.value_codec.decompress(value)?.into());
+            }
+        }
+        self.value_codec = Codec::None;
+        Ok(self)
+    }
+
+    /// The codec written to the flags byte, the one of a live entry.
+    fn stored_value_codec(&self) -> Codec {
+        match self.value {
+            Some(_) => self.value_codec,
+            None => Codec::None,
         }
     }
 
-    /// Get the Entry object from BufReader.
-    pub async fn read_from(reader: &mut BufReader<File>) -> Option<Self> {
+    /// The content type written to the metadata section, the one of a live entry.
+    fn stored_content_type(&self) -> Option<&str> {
+        self.value.as_ref().and(self.content_type.as_deref())
+    }
+
+    /// The expiry written to the metadata section, the one of a live entry.
+    fn stored_expires_at(&self) -> Option<u128> {
+        self.value.as_ref().and(self.expires_at)
+    }
+
+    /// Whether a metadata section follows the value, see [`Entry::write_to_v2`].
+    fn has_metadata(&self) -> bool {
+        self.stored_content_type().is_some() || self.stored_expires_at().is_some()
+    }
+
+    /// Get the Entry object followed by its CRC32 checksum, as written by
+    /// [`Entry::write_checksummed_to`], a mismatch is [`WalReadError::ChecksumMismatch`] at
+    /// `offset`. The input has `remaining` bytes left, see [`Entry::try_read_bounded`].
+    pub(crate) async fn try_read_checksummed<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        offset: u64,
+        remaining: u64,
+        encoding: EntryEncoding,
+        max_field_len: usize,
+    ) -> Result<Option<Self>, WalReadError> {
+        let Some(entry) = Self::read_with(encoding, reader, remaining, max_field_len).await? else {
+            return Ok(None);
+        };
+        verify_checksum(reader, entry.checksum(), offset).await?;
+        Ok(Some(entry))
+    }
+
+    /// [`Entry::try_read_checksummed`] from the start of `buf`, the value of a varint record
+    /// is a slice of `buf` rather than a copy, so it keeps the whole buffer alive.
+    pub(crate) async fn try_read_checksummed_from(
+        buf: &Bytes,
+        offset: u64,
+        encoding: EntryEncoding,
+        max_field_len: usize,
+    ) -> Result<Option<Self>, WalReadError> {
+        let mut input = buf.as_ref();
+        if encoding == EntryEncoding::Fixed {
+            let remaining = buf.len() as u64;
+            return Self::try_read_checksummed(
+                &mut input,
+                offset,
+                remaining,
+                encoding,
+                max_field_len,
+            )
+            .await;
+        }
+
         // key
-        let mut key_len_buffers = [0; 8];
-        if reader.read_exact(&mut key_len_buffers).await.is_err() {
-            return None;
+        let Some(key_len) = read_varint(&mut input, true).await? else {
+            return Ok(None);
+        };
+        let mut key = alloc_field(key_len, input.len() as u64, max_field_len)?;
+        read_field(&mut input, &mut key, false).await?;
+
+        // flags
+        let (is_deleted, has_metadata, value_codec) = read_flags(&mut input).await?;
+
+        // value
+        let mut value = None;
+        if !is_deleted {
+            let value_len = read_varint(&mut input, false).await?.unwrap_or_default();
+            let value_len = check_field_len(value_len, input.len() as u64, max_field_len)?;
+            let start = buf.len() - input.len();
+            value = Some(buf.slice(start..start + value_len));
+            input = &input[value_len..];
         }
-        let key_len = usize::from_le_bytes(key_len_buffers);
-        let mut key = vec![0; key_len];
-        if reader.read_exact(&mut key).await.is_err() {
-            return None;
+
+        // metadata
+        let (mut content_type, mut expires_at) = (None, None);
+        if has_metadata {
+            (content_type, expires_at) =
+                read_metadata(&mut input, buf.len() as u64, max_field_len).await?;
         }
 
+        // timestamp
+        let timestamp = read_varint(&mut input, false).await?.unwrap_or_default();
+
+        let entry = Self {
+            key,
+            value,
+            timestamp,
+            content_type,
+            expires_at,
+            value_codec,
+        };
+        verify_checksum(&mut input, entry.checksum(), offset).await?;
+        Ok(Some(entry))
+    }
+
+    /// Get the Entry object from the reader, telling a clean end of file (`Ok(None)`) apart
+    /// from a partially written record or an I/O error.
+    ///
+    /// A key or value longer than the `remaining` bytes of the input is reported as
+    /// [`WalReadError::UnexpectedEof`] before anything is allocated for it, so a garbage length
+    /// prefix cannot exhaust the memory. One longer than `max_field_len` is
+    /// [`WalReadError::FieldTooLong`].
+    pub(crate) async fn try_read_bounded<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        remaining: u64,
+        max_field_len: usize,
+    ) -> Result<Option<Self>, WalReadError> {
+        // key
+        let mut key_len_buffers = [0; 8];
+        if read_field(reader, &mut key_len_buffers, true).await? == 0 {
+            return Ok(None);
+        }
+        let key_len = u64::from_le_bytes(key_len_buffers).into();
+        let mut key = alloc_field(key_len, remaining, max_field_len)?;
+        read_field(reader, &mut key, false).await?;
+
         // is_deleted
         let mut bool_buffers = [0; 1];
-        if reader.read_exact(&mut bool_buffers).await.is_err() {
-            return None;
-        }
+        read_field(reader, &mut bool_buffers, false).await?;
         let is_deleted = bool_buffers[0] != 0;
 
         // value
         let mut value = None;
         if !is_deleted {
             let mut value_len_buffers = [0; 8];
-            if reader.read_exact(&mut value_len_buffers).await.is_err() {
-                return None;
-            }
-            let value_len = usize::from_le_bytes(value_len_buffers);
-            let mut value_buf = vec![0; value_len];
-            if reader.read_exact(&mut value_buf).await.is_err() {
-                return None;
-            }
-            value = Some(value_buf);
+            read_field(reader, &mut value_len_buffers, false).await?;
+            let value_len = u64::from_le_bytes(value_len_buffers).into();
+            let mut value_buf = alloc_field(value_len, remaining, max_field_len)?;
+            read_field(reader, &mut value_buf, false).await?;
+            value = Some(value_buf.into());
         }
 
         // timestamp
         let mut timestamp_buffers = [0; 16];
-        if reader.read_exact(&mut timestamp_buffers).await.is_err() {
-            return None;
-        }
+        read_field(reader, &mut timestamp_buffers, false).await?;
         let timestamp = u128::from_le_bytes(timestamp_buffers);
 
-        Some(Self {
+        Ok(Some(Self {
             key,
             value,
             timestamp,
-        })
+            content_type: None,
+            expires_at: None,
+            value_codec: Codec::None,
+        }))
+    }
+
+    /// Get the Entry object written by [`Entry::write_to_v2`], like
+    /// [`Entry::try_read_bounded`]. A varint longer than the one of a u128 is
+    /// [`WalReadError::InvalidVarint`].
+    pub(crate) async fn read_from_v2<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        remaining: u64,
+        max_field_len: usize,
+    ) -> Result<Option<Self>, WalReadError> {
+        // key
+        let Some(key_len) = read_varint(reader, true).await? else {
+            return Ok(None);
+        };
+        let mut key = alloc_field(key_len, remaining, max_field_len)?;
+        read_field(reader, &mut key, false).await?;
+
+        // flags
+        let (is_deleted, has_metadata, value_codec) = read_flags(reader).await?;
+
+        // value
+        let mut value = None;
+        if !is_deleted {
+            let value_len = read_varint(reader, false).await?.unwrap_or_default();
+            let mut value_buf = alloc_field(value_len, remaining, max_field_len)?;
+            read_field(reader, &mut value_buf, false).await?;
+            value = Some(value_buf.into());
+        }
+
+        // metadata
+        let (mut content_type, mut expires_at) = (None, None);
+        if has_metadata {
+            (content_type, expires_at) = read_metadata(reader, remaining, max_field_len).await?;
+        }
+
+        // timestamp
+        let timestamp = read_varint(reader, false).await?.unwrap_or_default();
+
+        Ok(Some(Self {
+            key,
+            value,
+            timestamp,
+            content_type,
+            expires_at,
+            value_codec,
+        }))
+    }
+
+    /// Get the Entry object laid out with `encoding`.
+    pub(crate) async fn read_with<R: AsyncRead + Unpin>(
+        encoding: EntryEncoding,
+        reader: &mut R,
+        remaining: u64,
+        max_field_len: usize,
+    ) -> Result<Option<Self>, WalReadError> {
+        match encoding {
+            EntryEncoding::Fixed => Self::try_read_bounded(reader, remaining, max_field_len).await,
+            EntryEncoding::Varint => Self::read_from_v2(reader, remaining, max_field_len).await,
+        }
+    }
+
+    /// Length of the Entry once written with [`Entry::write_to`].
+    pub fn encoded_len(&self) -> usize {
+        let value_len = self.value.as_ref().map_or(0, |val| 8 + val.len());
+        8 + self.key.len() + 1 + value_len + 16
+    }
+
+    /// Length of the Entry once written with [`Entry::write_to_v2`].
+    pub fn encoded_len_v2(&self) -> usize {
+        let value_len = self
+            .value
+            .as_ref()
+            .map_or(0, |val| varint_len(val.len() as u128) + val.len());
+        let content_type_len = self.stored_content_type().map_or(0, |content_type| {
+            varint_len(content_type.len() as u128) + content_type.len()
+        });
+        let expires_at_len = self.stored_expires_at().map_or(0, varint_len);
+        let metadata_len = match self.has_metadata() {
+            true => 1 + content_type_len + expires_at_len,
+            false => 0,
+        };
+        varint_len(self.key.len() as u128)
+            + self.key.len()
+            + 1
+            + value_len
+            + metadata_len
+            + varint_len(self.timestamp)
+    }
+
+    /// Length of the Entry once laid out with `encoding`.
+    pub(crate) fn encoded_len_with(&self, encoding: EntryEncoding) -> usize {
+        match encoding {
+            EntryEncoding::Fixed => self.encoded_len(),
+            EntryEncoding::Varint => self.encoded_len_v2(),
+        }
+    }
+
+    /// CRC32 over the encoded key, tombstone flag, value, value codec, content type, expiry and
+    /// timestamp.
+    pub fn checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        self.hash_into(&mut hasher);
+        hasher.finalize()
+    }
+
+    /// Feed the encoded fields into the CRC32 hasher.
+    pub(crate) fn hash_into(&self, hasher: &mut crc32fast::Hasher) {
+        hasher.update(&self.key.len().to_le_bytes());
+        hasher.update(&self.key);
+        hasher.update(&[u8::from(self.is_deleted())]);
+        if let Some(val) = &self.value {
+            hasher.update(&val.len().to_le_bytes());
+            hasher.update(val);
+        }
+        // left out when uncompressed, as before there was a codec
+        let value_codec = self.stored_value_codec();
+        if value_codec != Codec::None {
+            hasher.update(&[value_codec.tag()]);
+        }
+        if let Some(content_type) = self.stored_content_type() {
+            hasher.update(&[METADATA_CONTENT_TYPE]);
+            hasher.update(&content_type.len().to_le_bytes());
+            hasher.update(content_type.as_bytes());
+        }
+        if let Some(expires_at) = self.stored_expires_at() {
+            hasher.update(&[METADATA_EXPIRES_AT]);
+            hasher.update(&expires_at.to_le_bytes());
+        }
+        hasher.update(&self.timestamp.to_le_bytes());
     }
 
     /// To check if the entry is marked as deleted.
@@ -80,8 +524,36 @@ impl Entry {
         self.value.is_none()
     }
 
-    /// Write the Entry object to BufWriter.
-    pub async fn write_to(&self, writer: &mut BufWriter<File>) -> io::Result<()> {
+    /// Write the Entry object followed by its CRC32 checksum to the writer.
+    pub async fn write_checksummed_to<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        self.write_checksummed_with(EntryEncoding::Fixed, writer)
+            .await
+    }
+
+    /// Write the Entry object laid out with `encoding`, followed by its CRC32 checksum, which
+    /// is the same for both encodings.
+    pub(crate) async fn write_checksummed_with<W: AsyncWrite + Unpin>(
+        &self,
+        encoding: EntryEncoding,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        self.write_with(encoding, writer).await?;
+        writer.write_all(&self.checksum().to_le_bytes()).await
+    }
+
+    /// Write the Entry object to the writer, which cannot hold a content type, an expiry nor a
+    /// compressed value.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        if self.has_metadata() || self.stored_value_codec() != Codec::None {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the fixed width encoding has no room for a content type, an expiry or a codec",
+            ));
+        }
+
         // key
         writer.write_all(&self.key.len().to_le_bytes()).await?;
         writer.write_all(&self.key).await?;
@@ -101,4 +573,688 @@ impl Entry {
 
         Ok(())
     }
+
+    /// Write the Entry object with its lengths and timestamp as LEB128 varints, which saves
+    /// most of the 32 bytes [`Entry::write_to`] spends on them.
+    ///
+    /// A content type or an expiry sets a bit of the flags byte and goes to a metadata section
+    /// after the value, the Entries without either are written as before it existed. So does
+    /// the tag of the codec of a compressed value, in two more bits of the flags byte.
+    pub async fn write_to_v2<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(self.encoded_len_v2());
+        put_varint(&mut bytes, self.key.len() as u128);
+        bytes.extend_from_slice(&self.key);
+        let content_type = self.stored_content_type();
+        let expires_at = self.stored_expires_at();
+        let mut flags = u8::from(self.is_deleted());
+        if self.has_metadata() {
+            flags |= FLAG_METADATA;
+        }
+        flags |= self.stored_value_codec().tag() << FLAG_VALUE_CODEC_SHIFT;
+        bytes.push(flags);
+        if let Some(val) = &self.value {
+            put_varint(&mut bytes, val.len() as u128);
+            bytes.extend_from_slice(val);
+        }
+        if self.has_metadata() {
+            let mut metadata_flags = 0;
+            if content_type.is_some() {
+                metadata_flags |= METADATA_CONTENT_TYPE;
+            }
+            if expires_at.is_some() {
+                metadata_flags |= METADATA_EXPIRES_AT;
+            }
+            bytes.push(metadata_flags);
+        }
+        if let Some(content_type) = content_type {
+            put_varint(&mut bytes, content_type.len() as u128);
+            bytes.extend_from_slice(content_type.as_bytes());
+        }
+        if let Some(expires_at) = expires_at {
+            put_varint(&mut bytes, expires_at);
+        }
+        put_varint(&mut bytes, self.timestamp);
+        writer.write_all(&bytes).await
+    }
+
+    /// Write the Entry object laid out with `encoding`.
+    pub(crate) async fn write_with<W: AsyncWrite + Unpin>(
+        &self,
+        encoding: EntryEncoding,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        match encoding {
+            EntryEncoding::Fixed => self.write_to(writer).await,
+            EntryEncoding::Varint => self.write_to_v2(writer).await,
+        }
+    }
+}
+
+/// Append `value` as an unsigned LEB128 varint: 7 bits per byte, low bits first, the high bit
+/// set on every byte but the last.
+fn put_varint(bytes: &mut Vec<u8>, mut value: u128) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn varint_len(value: u128) -> usize {
+    (128 - value.leading_zeros() as usize).max(1).div_ceil(7)
+}
+
+/// Read a varint written by [`put_varint`], `Ok(None)` at a clean end of file when
+/// `eof_allowed` is set, see [`read_field`].
+async fn read_varint<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    eof_allowed: bool,
+) -> Result<Option<u128>, WalReadError> {
+    let mut value = 0_u128;
+    let mut byte_buffers = [0; 1];
+    for i in 0..MAX_VARINT_LEN {
+        if read_field(reader, &mut byte_buffers, eof_allowed && i == 0).await? == 0 {
+            return Ok(None);
+        }
+        let byte = byte_buffers[0];
+        let bits = u128::from(byte & 0x7f);
+        // the last byte of a u128 only carries 2 bits
+        if i == MAX_VARINT_LEN - 1 && bits > 0b11 {
+            return Err(WalReadError::InvalidVarint);
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(WalReadError::InvalidVarint)
+}
+
+/// Read the flags byte after the key of a varint Entry: whether it is a tombstone, whether a
+/// metadata section follows its value, and the codec of its value.
+async fn read_flags<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<(bool, bool, Codec), WalReadError> {
+    let mut flags_buffers = [0; 1];
+    read_field(reader, &mut flags_buffers, false).await?;
+    let flags = flags_buffers[0];
+    let is_deleted = flags & FLAG_DELETED != 0;
+    let has_metadata = flags & FLAG_METADATA != 0;
+    let value_codec = Codec::from_tag((flags & FLAG_VALUE_CODEC) >> FLAG_VALUE_CODEC_SHIFT)
+        .ok_or(WalReadError::InvalidMetadata)?;
+    if flags & !(FLAG_DELETED | FLAG_METADATA | FLAG_VALUE_CODEC) != 0
+        || (is_deleted && (has_metadata || value_codec != Codec::None))
+    {
+        return Err(WalReadError::InvalidMetadata);
+    }
+    Ok((is_deleted, has_metadata, value_codec))
+}
+
+/// Read the metadata section following the value of a varint Entry: its flags byte, then the
+/// content type as a varint length and UTF-8 bytes and the expiry as a varint, when the flags
+/// say so.
+async fn read_metadata<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    remaining: u64,
+    max_field_len: usize,
+) -> Result<(Option<String>, Option<u128>), WalReadError> {
+    let mut flags_buffers = [0; 1];
+    read_field(reader, &mut flags_buffers, false).await?;
+    let flags = flags_buffers[0];
+    if flags & !(METADATA_CONTENT_TYPE | METADATA_EXPIRES_AT) != 0 {
+        return Err(WalReadError::InvalidMetadata);
+    }
+    let mut content_type = None;
+    if flags & METADATA_CONTENT_TYPE != 0 {
+        let len = read_varint(reader, false).await?.unwrap_or_default();
+        let mut bytes = alloc_field(len, remaining, max_field_len)?;
+        read_field(reader, &mut bytes, false).await?;
+        content_type = Some(String::from_utf8(bytes).map_err(|_| WalReadError::InvalidMetadata)?);
+    }
+    let mut expires_at = None;
+    if flags & METADATA_EXPIRES_AT != 0 {
+        expires_at = read_varint(reader, false).await?;
+    }
+    Ok((content_type, expires_at))
+}
+
+/// Read the CRC32 checksum trailing a record which starts at `offset` and compare it with the
+/// `expected` one.
+pub(crate) async fn verify_checksum<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    expected: u32,
+    offset: u64,
+) -> Result<(), WalReadError> {
+    let mut checksum_buffers = [0; 4];
+    read_field(reader, &mut checksum_buffers, false).await?;
+    if u32::from_le_bytes(checksum_buffers) != expected {
+        return Err(WalReadError::ChecksumMismatch { offset });
+    }
+    Ok(())
+}
+
+/// The buffer of a field with the length prefix `len`, see [`check_field_len`]. Failing to
+/// allocate it is an [`io::ErrorKind::OutOfMemory`] error rather than an abort.
+fn alloc_field(len: u128, remaining: u64, max: usize) -> Result<Vec<u8>, WalReadError> {
+    let len = check_field_len(len, remaining, max)?;
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len)
+        .map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))?;
+    buf.resize(len, 0);
+    Ok(buf)
+}
+
+/// The length prefix `len` of a field, which cannot be longer than `max` nor the `remaining`
+/// bytes of the input.
+fn check_field_len(len: u128, remaining: u64, max: usize) -> Result<usize, WalReadError> {
+    if len > max as u128 {
+        return Err(WalReadError::FieldTooLong {
+            len: u64::try_from(len).unwrap_or(u64::MAX),
+            max,
+        });
+    }
+    if len > u128::from(remaining) {
+        return Err(WalReadError::UnexpectedEof {
+            missing: usize::try_from(len - u128::from(remaining)).unwrap_or(usize::MAX),
+        });
+    }
+
+    Ok(len as usize)
+}
+
+/// Fill `buf` from the reader and return the number of bytes read.
+///
+/// Reaching the end of file before anything was read is fine when `eof_allowed` is set
+/// (`Ok(0)`), any other short read is reported as [`WalReadError::UnexpectedEof`].
+pub(crate) async fn read_field<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+    eof_allowed: bool,
+) -> Result<usize, WalReadError> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]).await {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if read == buf.len() || (read == 0 && eof_allowed) {
+        Ok(read)
+    } else {
+        Err(WalReadError::UnexpectedEof {
+            missing: buf.len() - read,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        let mut entries = vec![
+            Entry::new(Vec::new(), Some(Vec::new()), 0),
+            Entry::new(b"key".to_vec(), Some(Vec::new()), 1),
+            Entry::new(b"key".to_vec(), None, u128::MAX),
+            Entry::new(
+                vec![0xff; 1 << 20],
+                Some(b"value".to_vec()),
+                1_700_000_000_000_000,
+            ),
+            Entry::new(b"key".to_vec(), Some(vec![7; 70_000]), u64::MAX.into()),
+        ];
+        // the lengths around the varint byte boundaries
+        for len in [127, 128, 16_383, 16_384] {
+            entries.push(Entry::new(vec![1; len], Some(vec![2; len]), len as u128));
+        }
+        entries
+    }
+
+    #[tokio::test]
+    async fn it_round_trips_both_encodings() {
+        for encoding in [EntryEncoding::Fixed, EntryEncoding::Varint] {
+            for entry in entries() {
+                let mut bytes = Vec::new();
+                entry
+                    .write_checksummed_with(encoding, &mut bytes)
+                    .await
+                    .unwrap();
+                assert_eq!(bytes.len(), entry.encoded_len_with(encoding) + 4);
+
+                let mut input = bytes.as_slice();
+                let decoded = Entry::try_read_checksummed(
+                    &mut input,
+                    0,
+                    bytes.len() as u64,
+                    encoding,
+                    DEFAULT_MAX_FIELD_LEN,
+                )
+                .await
+                .unwrap()
+                .unwrap();
+                assert!(input.is_empty());
+                assert_eq!(
+                    (decoded.key, decoded.value, decoded.timestamp),
+                    (entry.key.clone(), entry.value.clone(), entry.timestamp)
+                );
+
+                // a clean end of file, then a record cut short
+                let mut input = &bytes[..0];
+                assert!(
+                    Entry::read_with(encoding, &mut input, 0, DEFAULT_MAX_FIELD_LEN)
+                        .await
+                        .unwrap()
+                        .is_none()
+                );
+                let cut = &bytes[..bytes.len() - 5];
+                let err = Entry::read_with(
+                    encoding,
+                    &mut &cut[..],
+                    cut.len() as u64,
+                    DEFAULT_MAX_FIELD_LEN,
+                )
+                .await
+                .unwrap_err();
+                assert!(matches!(err, WalReadError::UnexpectedEof { .. }));
+            }
+        }
+    }
+
+    #[test]
+    fn it_converts_between_entries() {
+        let db_entry = DbEntry {
+            key: b"key".to_vec(),
+            value: Bytes::from_static(b"value"),
+            timestamp: 1,
+            content_type: Some("text/plain".to_owned()),
+            expires_at: None,
+        };
+        let entry = Entry::from(db_entry.clone());
+        assert_eq!(
+            entry,
+            Entry::new(b"key".to_vec(), Some(b"value".to_vec()), 1).with_content_type("text/plain")
+        );
+        assert_eq!(DbEntry::try_from(entry), Ok(db_entry));
+
+        let tombstone = Entry::new(b"key".to_vec(), None, 2);
+        assert_eq!(DbEntry::try_from(tombstone.clone()), Err(tombstone));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_serializes_the_bytes_as_base64() {
+        let db_entry = DbEntry {
+            key: b"key".to_vec(),
+            value: Bytes::from_static(&[0, 255]),
+            timestamp: 1,
+            content_type: None,
+            expires_at: None,
+        };
+        let json = serde_json::to_string(&db_entry).unwrap();
+        assert_eq!(json, r#"{"key":"a2V5","value":"AP8=","timestamp":1}"#);
+        assert_eq!(serde_json::from_str::<DbEntry>(&json).unwrap(), db_entry);
+        assert!(
+            serde_json::from_str::<DbEntry>(r#"{"key":"!","value":"","timestamp":1}"#).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn it_round_trips_through_a_cursor() {
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        for entry in entries() {
+            entry.write_checksummed_to(&mut cursor).await.unwrap();
+            entry.write_to_v2(&mut cursor).await.unwrap();
+        }
+
+        cursor.set_position(0);
+        let len = cursor.get_ref().len() as u64;
+        for entry in entries() {
+            let fixed = Entry::try_read_checksummed(
+                &mut cursor,
+                0,
+                len,
+                EntryEncoding::Fixed,
+                DEFAULT_MAX_FIELD_LEN,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            let varint = Entry::read_from_v2(&mut cursor, len, DEFAULT_MAX_FIELD_LEN)
+                .await
+                .unwrap()
+                .unwrap();
+            for decoded in [fixed, varint] {
+                assert_eq!(decoded.key, entry.key);
+                assert_eq!(decoded.value, entry.value);
+                assert_eq!(decoded.timestamp, entry.timestamp);
+            }
+        }
+        assert!(
+            Entry::try_read_bounded(&mut cursor, 0, DEFAULT_MAX_FIELD_LEN)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_content_type_in_a_metadata_section() {
+        // the entries without one are laid out as before
+        let mut plain = Vec::new();
+        let entry = Entry::new(b"k".to_vec(), Some(b"v".to_vec()), 1);
+        entry.write_to_v2(&mut plain).await.unwrap();
+        assert_eq!(plain, [1, b'k', 0, 1, b'v', 1]);
+        let mut tombstone = Vec::new();
+        let entry = Entry::new(b"k".to_vec(), None, 1).with_content_type("text/plain");
+        entry.write_to_v2(&mut tombstone).await.unwrap();
+        assert_eq!(tombstone, [1, b'k', 1, 1]);
+
+        let entry = Entry::new(b"k".to_vec(), Some(b"{}".to_vec()), 1)
+            .with_content_type("application/json");
+        let mut buf = Vec::new();
+        entry
+            .write_checksummed_with(EntryEncoding::Varint, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf.len(), entry.encoded_len_v2() + 4);
+        assert_eq!(buf[2], FLAG_METADATA);
+        assert_ne!(
+            entry.checksum(),
+            Entry {
+                content_type: None,
+                ..entry.clone()
+            }
+            .checksum()
+        );
+
+        let len = buf.len() as u64;
+        let read = Entry::try_read_checksummed(
+            &mut buf.as_slice(),
+            0,
+            len,
+            EntryEncoding::Varint,
+            DEFAULT_MAX_FIELD_LEN,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(read, entry);
+        let shared = Entry::try_read_checksummed_from(
+            &buf.clone().into(),
+            0,
+            EntryEncoding::Varint,
+            DEFAULT_MAX_FIELD_LEN,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(shared, entry);
+        let db_entry = DbEntry::try_from(shared).unwrap();
+        assert_eq!(db_entry.content_type(), Some("application/json"));
+
+        // the fixed width encoding has no room for it
+        let err = entry.write_to(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // nor is there any other flag
+        buf[2] = 0x10;
+        let err = Entry::read_from_v2(&mut buf.as_slice(), len, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, WalReadError::InvalidMetadata));
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_expiry_in_the_metadata_section() {
+        let expiring = Entry::new(b"k".to_vec(), Some(b"v".to_vec()), 1).with_expires_at(300);
+        let typed = expiring.clone().with_content_type("text/plain");
+        for entry in [expiring, typed] {
+            let mut buf = Vec::new();
+            entry
+                .write_checksummed_with(EntryEncoding::Varint, &mut buf)
+                .await
+                .unwrap();
+            assert_eq!(buf.len(), entry.encoded_len_v2() + 4);
+            let len = buf.len() as u64;
+            let read = Entry::try_read_checksummed(
+                &mut buf.as_slice(),
+                0,
+                len,
+                EntryEncoding::Varint,
+                DEFAULT_MAX_FIELD_LEN,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(read, entry);
+            let shared = Entry::try_read_checksummed_from(
+                &buf.into(),
+                0,
+                EntryEncoding::Varint,
+                DEFAULT_MAX_FIELD_LEN,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(shared, entry);
+            assert_eq!(DbEntry::try_from(shared).unwrap().expires_at(), Some(300));
+            let err = entry.write_to(&mut Vec::new()).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        let entry = Entry::new(b"k".to_vec(), Some(b"v".to_vec()), 1).with_expires_at(300);
+        assert!(!entry.is_expired(299));
+        assert!(entry.is_expired(300));
+        let tombstone = Entry::new(b"k".to_vec(), None, 1).with_expires_at(300);
+        assert!(!tombstone.is_expired(300));
+        assert_ne!(
+            entry.checksum(),
+            Entry {
+                expires_at: None,
+                ..entry.clone()
+            }
+            .checksum()
+        );
+
+        // no other bit of the metadata flags
+        let mut buf = Vec::new();
+        entry.write_to_v2(&mut buf).await.unwrap();
+        assert_eq!(buf[5], METADATA_EXPIRES_AT);
+        buf[5] = 0x04;
+        let err = Entry::read_from_v2(&mut buf.as_slice(), 64, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, WalReadError::InvalidMetadata));
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_value_codec_in_the_flags() {
+        let value = b"compressible ".repeat(16);
+        let compressed = Codec::Lz4.compress(&value).unwrap();
+        let entry = Entry {
+            value_codec: Codec::Lz4,
+            ..Entry::new(b"k".to_vec(), Some(compressed), 1).with_content_type("text/plain")
+        };
+        let mut buf = Vec::new();
+        entry
+            .write_checksummed_with(EntryEncoding::Varint, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf.len(), entry.encoded_len_v2() + 4);
+        assert_eq!(buf[2], FLAG_METADATA | Codec::Lz4.tag() << 2);
+        assert_ne!(
+            entry.checksum(),
+            Entry {
+                value_codec: Codec::None,
+                ..entry.clone()
+            }
+            .checksum()
+        );
+        let len = buf.len() as u64;
+        let read = Entry::try_read_checksummed(
+            &mut buf.as_slice(),
+            0,
+            len,
+            EntryEncoding::Varint,
+            DEFAULT_MAX_FIELD_LEN,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(read, entry);
+        let decompressed = read.decompressed().unwrap();
+        assert_eq!(decompressed.value.as_deref(), Some(&value[..]));
+        assert_eq!(decompressed.value_codec, Codec::None);
+        let err = entry.write_to(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // a tombstone has no value to compress
+        let mut tombstone = Vec::new();
+        Entry {
+            value: None,
+            ..entry.clone()
+        }
+        .write_to_v2(&mut tombstone)
+        .await
+        .unwrap();
+        assert_eq!(tombstone, [1, b'k', FLAG_DELETED, 1]);
+        tombstone[2] |= Codec::Zstd.tag() << 2;
+        let err = Entry::read_from_v2(&mut tombstone.as_slice(), 4, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, WalReadError::InvalidMetadata));
+
+        // nor is there a codec with the last tag
+        buf[2] |= FLAG_VALUE_CODEC;
+        let err = Entry::read_from_v2(&mut buf.as_slice(), len, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, WalReadError::InvalidMetadata));
+    }
+
+    #[tokio::test]
+    async fn it_slices_the_value_out_of_the_buffer() {
+        let entry = Entry::new(b"key".to_vec(), Some(vec![7; 1024]), 42);
+        let mut buf = Vec::new();
+        entry
+            .write_checksummed_with(EntryEncoding::Varint, &mut buf)
+            .await
+            .unwrap();
+        let buf = Bytes::from(buf);
+
+        let decoded =
+            Entry::try_read_checksummed_from(&buf, 0, EntryEncoding::Varint, DEFAULT_MAX_FIELD_LEN)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(decoded, entry);
+        let value = decoded.value.unwrap();
+        assert!(buf.as_ptr_range().contains(&value.as_ptr()));
+
+        // a damaged record is still caught
+        let mut damaged = buf.to_vec();
+        damaged[10] ^= 1;
+        let err = Entry::try_read_checksummed_from(
+            &damaged.into(),
+            0,
+            EntryEncoding::Varint,
+            DEFAULT_MAX_FIELD_LEN,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, WalReadError::ChecksumMismatch { offset: 0 }));
+    }
+
+    #[tokio::test]
+    async fn it_saves_the_fixed_width_fields() {
+        let entry = Entry::new(
+            b"user:42".to_vec(),
+            Some(b"12345678901234567890".to_vec()),
+            1,
+        );
+        assert_eq!(entry.encoded_len() - entry.encoded_len_v2(), 29);
+
+        let entry = Entry::new(b"key".to_vec(), None, 1_700_000_000_000_000);
+        let mut bytes = Vec::new();
+        entry.write_to_v2(&mut bytes).await.unwrap();
+        assert_eq!(bytes[..5], [3, b'k', b'e', b'y', 1]);
+        assert_eq!(bytes.len(), 5 + 8);
+    }
+
+    #[tokio::test]
+    async fn it_rejects_absurd_length_prefixes() {
+        let too_long = |err| matches!(err, WalReadError::FieldTooLong { len, max } if len == 1 << 60 && max == DEFAULT_MAX_FIELD_LEN);
+
+        // a key, then a value length of the fixed encoding, the input looks long enough
+        let bytes = (1_u64 << 60).to_le_bytes();
+        let err = Entry::try_read_bounded(&mut bytes.as_slice(), u64::MAX, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap_err();
+        assert!(too_long(err));
+        let mut bytes = 1_u64.to_le_bytes().to_vec();
+        bytes.extend_from_slice(b"k\0");
+        bytes.extend_from_slice(&(1_u64 << 60).to_le_bytes());
+        let err = Entry::try_read_bounded(&mut bytes.as_slice(), u64::MAX, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap_err();
+        assert!(too_long(err));
+
+        // and of the varint one
+        let mut bytes = Vec::new();
+        put_varint(&mut bytes, 1 << 60);
+        let err = Entry::read_from_v2(&mut bytes.as_slice(), u64::MAX, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap_err();
+        assert!(too_long(err));
+        let mut bytes = vec![1, b'k', 0];
+        put_varint(&mut bytes, 1 << 60);
+        let err = Entry::read_from_v2(&mut bytes.as_slice(), u64::MAX, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap_err();
+        assert!(too_long(err));
+    }
+
+    #[tokio::test]
+    async fn it_rejects_malformed_varints() {
+        // a key length longer than the input
+        let mut bytes = Vec::new();
+        put_varint(&mut bytes, 1000);
+        let err = Entry::read_from_v2(
+            &mut bytes.as_slice(),
+            bytes.len() as u64,
+            DEFAULT_MAX_FIELD_LEN,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, WalReadError::UnexpectedEof { .. }));
+
+        // a timestamp overflowing a u128
+        let mut bytes = vec![0, 1];
+        bytes.extend_from_slice(&[0xff; MAX_VARINT_LEN - 1]);
+        bytes.push(0x04);
+        let err = Entry::read_from_v2(
+            &mut bytes.as_slice(),
+            bytes.len() as u64,
+            DEFAULT_MAX_FIELD_LEN,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, WalReadError::InvalidVarint));
+
+        let mut bytes = vec![0, 1];
+        put_varint(&mut bytes, u128::MAX);
+        assert_eq!(bytes.len(), 2 + MAX_VARINT_LEN);
+        let entry = Entry::read_from_v2(
+            &mut bytes.as_slice(),
+            bytes.len() as u64,
+            DEFAULT_MAX_FIELD_LEN,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(entry.timestamp, u128::MAX);
+    }
 }
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{io, path::PathBuf};
 
 use thiserror::Error;
 
@@ -6,4 +6,116 @@ use thiserror::Error;
 pub enum Error {
     #[error("Invalid Path: {0}")]
     InvalidPath(PathBuf),
+
+    #[error("Unsupported WAL version {found}, supported version is {supported}")]
+    UnsupportedWalVersion { found: u16, supported: u16 },
+
+    #[error("Unsupported SSTable version {found}, supported version is {supported}")]
+    UnsupportedSSTableVersion { found: u16, supported: u16 },
+
+    #[error("Restore cancelled")]
+    RestoreCancelled,
+
+    #[error(
+        "Out of order key {:?} after {:?}",
+        String::from_utf8_lossy(next),
+        String::from_utf8_lossy(prev)
+    )]
+    OutOfOrderKey { prev: Vec<u8>, next: Vec<u8> },
+
+    #[error("The database in {0:?} is already open, e.g. by a running server")]
+    DirectoryLocked(PathBuf),
+
+    #[error("A compaction of {0:?} is already running")]
+    CompactionInProgress(PathBuf),
+
+    #[error("Corrupt index of {0:?}")]
+    CorruptIndex(PathBuf),
+
+    #[error("The {field} is {len} bytes long, the longest one is {max} bytes")]
+    FieldTooLong {
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+
+    #[error("The files take {used} bytes, the disk budget is {limit} bytes")]
+    DiskBudgetExceeded { used: u64, limit: u64 },
+
+    #[error(
+        "The writes stall until the flushes and compactions catch up: {level0_sstables} level 0 \
+         SSTables, {pending_mem_tables} MemTables waiting for a flush"
+    )]
+    WriteStalled {
+        level0_sstables: usize,
+        pending_mem_tables: usize,
+    },
+
+    #[error("The database is a read-only replica, the writes go to its primary")]
+    ReadOnlyReplica,
+
+    #[error("Only a replica applies the writes of a primary")]
+    NotAReplica,
+
+    #[error("Corruption in {path:?} at offset {offset}: {reason}")]
+    Corruption {
+        path: PathBuf,
+        offset: u64,
+        reason: String,
+    },
+}
+
+/// Errors while reading records back from a WAL file.
+#[derive(Error, Debug)]
+pub enum WalReadError {
+    /// The file ended in the middle of a record, at least `missing` bytes are absent.
+    #[error("Unexpected EOF: at least {missing} bytes missing")]
+    UnexpectedEof { missing: usize },
+
+    /// The record at `offset` does not match its checksum.
+    #[error("Checksum mismatch at offset {offset}")]
+    ChecksumMismatch { offset: u64 },
+
+    /// The record at `offset` is tagged with a codec this build does not know.
+    #[error("Unknown codec {tag} at offset {offset}")]
+    UnknownCodec { tag: u8, offset: u64 },
+
+    /// The value of the record at `offset` cannot be decompressed.
+    #[error("Failed to decompress the record at offset {offset}: {source}")]
+    Decompress { offset: u64, source: io::Error },
+
+    /// The record at `offset` has an unknown type, or one which does not match its content.
+    #[error("Invalid record type {record_type} at offset {offset}")]
+    InvalidRecordType { record_type: u8, offset: u64 },
+
+    /// A key or value length prefix is greater than the
+    /// [`DatabaseBuilder::max_field_len`](crate::DatabaseBuilder::max_field_len).
+    #[error("Field length {len} is greater than the maximum {max}")]
+    FieldTooLong { len: u64, max: usize },
+
+    /// A length or timestamp of a record is encoded with more bytes than a u128 needs.
+    #[error("Invalid varint")]
+    InvalidVarint,
+
+    /// The flags of a record or its metadata section have unknown bits set, or its content
+    /// type is not UTF-8.
+    #[error("Invalid entry metadata")]
+    InvalidMetadata,
+
+    /// The batch marker at `offset` does not close the open batch, or opens a second one.
+    #[error("Invalid batch marker at offset {offset}")]
+    InvalidBatch { offset: u64 },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Errors of a [`CdcStream`](crate::CdcStream).
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CdcError {
+    /// The consumer fell more than [`crate::DatabaseBuilder::change_events_capacity`] events
+    /// behind the writes and missed some. The stream ends, a new one from `resume_from` goes
+    /// on without a gap.
+    #[error("The CDC stream lagged behind the writes, resume from timestamp {resume_from}")]
+    Lagged { resume_from: u128 },
 }
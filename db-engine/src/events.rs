@@ -0,0 +1,78 @@
+use bytes::Bytes;
+
+use crate::{compression::Codec, entries::Entry};
+
+/// The kind of write of a [`ChangeEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ChangeKind {
+    Set,
+    Delete,
+}
+
+/// A write of a [`Database`](crate::Database), published once it is in the WAL and the
+/// MemTable, see [`Database::subscribe`](crate::Database::subscribe) and
+/// [`Database::cdc_stream`](crate::Database::cdc_stream). With the `serde` feature the key
+/// and value are base64 like the ones of a [`DbEntry`](crate::DbEntry).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    #[cfg_attr(feature = "serde", serde(with = "crate::entries::base64_bytes"))]
+    pub key: Vec<u8>,
+    /// The value of a set, `None` for a delete
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "crate::entries::base64_bytes::option"
+        )
+    )]
+    pub value: Option<Bytes>,
+    /// See [`Database::set_typed`](crate::Database::set_typed)
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub content_type: Option<String>,
+    /// See [`Database::set_with_ttl`](crate::Database::set_with_ttl)
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub expires_at: Option<u128>,
+    /// The timestamp the write is stored with
+    pub timestamp: u128,
+}
+
+impl From<Entry> for ChangeEvent {
+    fn from(entry: Entry) -> Self {
+        Self {
+            kind: match entry.value {
+                Some(_) => ChangeKind::Set,
+                None => ChangeKind::Delete,
+            },
+            key: entry.key,
+            value: entry.value,
+            content_type: entry.content_type,
+            expires_at: entry.expires_at,
+            timestamp: entry.timestamp,
+        }
+    }
+}
+
+impl From<ChangeEvent> for Entry {
+    /// The entry as written, e.g. to apply it on a replica
+    fn from(event: ChangeEvent) -> Self {
+        Self {
+            key: event.key,
+            value: event.value,
+            timestamp: event.timestamp,
+            content_type: event.content_type,
+            expires_at: event.expires_at,
+            value_codec: Codec::None,
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Key encodings which keep the order of what they encode.
+//!
+//! The MemTable and the SSTables sort keys byte by byte, so a [`Database::scan`] only walks
+//! numbers in order when they are big-endian, and tuples when their parts cannot run into each
+//! other. The decoders return `None` for bytes their encoder did not produce.
+//!
+//! [`Database::scan`]: crate::Database::scan
+
+/// Escapes a 0x00 inside a part of a composite key.
+const ESCAPE: u8 = 0xff;
+
+/// Ends every part of a composite key, after a 0x00.
+const TERMINATOR: u8 = 0x01;
+
+/// Big-endian bytes of `value`, which sort like the numbers.
+pub fn encode_u64(value: u64) -> [u8; 8] {
+    value.to_be_bytes()
+}
+
+/// The number of a key written by [`encode_u64`].
+pub fn decode_u64(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Big-endian bytes of `value` with the sign bit flipped, so the negative numbers come first.
+pub fn encode_i64(value: i64) -> [u8; 8] {
+    encode_u64((value as u64) ^ (1 << 63))
+}
+
+/// The number of a key written by [`encode_i64`].
+pub fn decode_i64(bytes: &[u8]) -> Option<i64> {
+    Some((decode_u64(bytes)? ^ (1 << 63)) as i64)
+}
+
+/// Join `parts` into one key which sorts like the list of parts: every 0x00 of a part is
+/// followed by 0xff and every part ends with 0x00 0x01. The key of the first parts of a list
+/// is a prefix of the key of the whole list, which makes it a [`Database::scan_prefix`]
+/// prefix.
+///
+/// [`Database::scan_prefix`]: crate::Database::scan_prefix
+pub fn encode_composite(parts: &[&[u8]]) -> Vec<u8> {
+    let len = parts.iter().map(|part| part.len() + 2).sum();
+    let mut bytes = Vec::with_capacity(len);
+    for part in parts {
+        for &byte in part.iter() {
+            bytes.push(byte);
+            if byte == 0 {
+                bytes.push(ESCAPE);
+            }
+        }
+        bytes.extend_from_slice(&[0, TERMINATOR]);
+    }
+    bytes
+}
+
+/// The parts of a key written by [`encode_composite`].
+pub fn decode_composite(bytes: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut parts = Vec::new();
+    let mut part = Vec::new();
+    let mut bytes = bytes.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != 0 {
+            part.push(byte);
+            continue;
+        }
+        match *bytes.next()? {
+            ESCAPE => part.push(0),
+            TERMINATOR => parts.push(std::mem::take(&mut part)),
+            _ => return None,
+        }
+    }
+    part.is_empty().then_some(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::mem_table::MemTable;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn it_keeps_the_order_of_u64(a: u64, b: u64) {
+            prop_assert_eq!(encode_u64(a).cmp(&encode_u64(b)), a.cmp(&b));
+            prop_assert_eq!(decode_u64(&encode_u64(a)), Some(a));
+        }
+
+        #[test]
+        fn it_keeps_the_order_of_i64(a: i64, b: i64) {
+            prop_assert_eq!(encode_i64(a).cmp(&encode_i64(b)), a.cmp(&b));
+            prop_assert_eq!(decode_i64(&encode_i64(a)), Some(a));
+        }
+
+        #[test]
+        fn it_keeps_the_order_of_composite_keys(
+            // few distinct bytes, so the parts often share prefixes and hold 0x00
+            a in prop::collection::vec(prop::collection::vec(0_u8..3, 0..4), 0..4),
+            b in prop::collection::vec(prop::collection::vec(0_u8..3, 0..4), 0..4),
+        ) {
+            let encode = |parts: &[Vec<u8>]| {
+                encode_composite(&parts.iter().map(Vec::as_slice).collect::<Vec<_>>())
+            };
+            prop_assert_eq!(encode(&a).cmp(&encode(&b)), a.cmp(&b));
+            prop_assert_eq!(decode_composite(&encode(&a)), Some(a));
+        }
+
+        #[test]
+        fn it_sorts_the_keys_like_the_mem_table(
+            mut values in prop::collection::vec(any::<i64>(), 0..50),
+        ) {
+            let mut mem_table = MemTable::new();
+            for value in values.iter() {
+                mem_table.set(&encode_i64(*value), b"", 0);
+            }
+            let sorted = mem_table
+                .iter()
+                .map(|entry| decode_i64(&entry.key).unwrap())
+                .collect::<Vec<_>>();
+            values.sort();
+            values.dedup();
+            prop_assert_eq!(sorted, values);
+        }
+    }
+
+    #[test]
+    fn it_rejects_what_it_did_not_encode() {
+        assert_eq!(decode_u64(&[1, 2, 3]), None);
+        assert_eq!(decode_i64(&[0; 9]), None);
+        // unterminated part, unknown escape
+        assert_eq!(decode_composite(b"a"), None);
+        assert_eq!(decode_composite(b"a\0"), None);
+        assert_eq!(decode_composite(b"a\0\x02"), None);
+        assert_eq!(decode_composite(b""), Some(vec![]));
+        assert_eq!(
+            decode_composite(b"a\0\xff\0\x01\0\x01"),
+            Some(vec![b"a\0".to_vec(), vec![]])
+        );
+    }
+}
@@ -1,14 +1,56 @@
+mod cdc;
+mod cleanup;
 mod compaction;
+mod compression;
 mod database;
+mod dump;
 mod entries;
 mod errors;
+mod events;
+pub mod keys;
 mod mem_table;
+mod metrics;
+mod point_in_time;
 mod prelude;
+mod read_cache;
 mod sstable;
+mod stats;
+mod storage;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+mod throttle;
 mod utils;
+mod verify;
 mod wal;
+mod write_batch;
 
-pub use crate::compaction::Compaction;
+pub use crate::cdc::CdcStream;
+pub use crate::cleanup::{CleanupReport, MIN_CLEANUP_AGE};
+pub use crate::compaction::{
+    Compaction, CompactionFilter, CompactionLock, CompactionPlan, CompactionReport,
+    CompactionStrategy, FilterDecision, SizeFilter,
+};
+pub use crate::compression::Codec;
 pub use crate::database::Database;
 pub use crate::database::DatabaseBuilder;
-pub use crate::entries::DbEntry;
+pub use crate::dump::{DumpSummary, FileKind};
+pub use crate::entries::{DbEntry, Entry, DEFAULT_MAX_FIELD_LEN};
+pub use crate::errors::{CdcError, Error};
+pub use crate::events::{ChangeEvent, ChangeKind};
+#[cfg(feature = "testutil")]
+pub use crate::mem_table::MemTable;
+pub use crate::metrics::{HistogramSnapshot, MetricsSnapshot};
+pub use crate::point_in_time::PointInTimeReport;
+pub use crate::sstable::{IndexMode, SSTableQuerier, SSTableReader, SSTableReaderOptions};
+pub use crate::stats::{DatabaseStats, WriteStall};
+#[cfg(feature = "testutil")]
+pub use crate::storage::FaultyFs;
+pub use crate::storage::{AppendMode, LocalFs, Metadata, ReadableFile, Storage, WritableFile};
+#[allow(deprecated)]
+pub use crate::utils::get_files_with_ext_and_size;
+pub use crate::utils::{get_files_with_ext_filtered, Clock, HybridClock};
+pub use crate::verify::{Corruption, VerifyReport};
+pub use crate::wal::{
+    RecoveryMode, RecoveryReport, RepairReport, RestoreProgress, SyncPolicy, WriteAheadLog,
+};
+pub use crate::write_batch::WriteBatch;
@@ -1,3 +1,10 @@
+use std::{
+    collections::BTreeMap,
+    mem::size_of,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
 use crate::prelude::*;
 
 /// Timestamp size (16 bytes)
@@ -6,17 +13,29 @@ const TIMESTAMP_SIZE: usize = 16;
 /// Tombstone size (1 byte)
 const TOMBSTONE_SIZE: usize = 1;
 
+/// Approximate heap overhead of a single BTreeMap slot (key `Vec` header + `Entry` struct).
+/// The nodes are between half and completely full, so count 1.5 slots per entry.
+const ENTRY_OVERHEAD: usize = (size_of::<Vec<u8>>() + size_of::<Entry>()) * 3 / 2;
+
 /// MemTable holds a sorted list of the latest writes.
+///
+/// The entries live behind an `Arc` and are copied on write, so reads only need `&self`
+/// and [`MemTable::snapshot`] can hand out the current state without copying it.
+#[derive(Clone)]
 pub struct MemTable {
-    entries: Vec<Entry>,
+    entries: Arc<BTreeMap<Vec<u8>, Entry>>,
     size: usize,
+    memory_usage: usize,
+    tombstone_count: usize,
 }
 
 impl MemTable {
     pub fn new() -> Self {
         Self {
-            entries: Vec::new(),
+            entries: Arc::new(BTreeMap::new()),
             size: 0,
+            memory_usage: 0,
+            tombstone_count: 0,
         }
     }
 
@@ -24,77 +43,188 @@ impl MemTable {
     ///
     /// Return None if no record is being found.
     pub fn get(&self, key: &[u8]) -> Option<&Entry> {
-        if let Ok(idx) = self.get_index(key) {
-            return Some(&self.entries[idx]);
-        }
-
-        None
+        self.entries.get(key)
     }
 
     /// Set Key-Value pair in MemTable.
+    #[cfg(any(test, feature = "testutil"))]
     pub fn set(&mut self, key: &[u8], value: &[u8], timestamp: u128) {
         let entry = Entry::new(key.to_vec(), Some(value.to_vec()), timestamp);
-        let key_size = key.len();
-        let value_size = value.len();
-
-        match self.get_index(key) {
-            Ok(idx) => {
-                // update exists entry
-                if let Some(v) = self.entries[idx].value.as_ref() {
-                    self.size -= v.len();
-                }
-                self.size += value_size;
-                self.entries[idx] = entry;
-            }
-            Err(idx) => {
-                // create new entry
-                self.entries.insert(idx, entry);
-                self.size += key_size + value_size + TIMESTAMP_SIZE + TOMBSTONE_SIZE;
-            }
-        }
+        self.insert(entry);
+    }
+
+    /// Set the Entry as is, with its content type and expiry, e.g. one replayed from the WAL.
+    pub fn put(&mut self, entry: Entry) {
+        self.insert(entry);
     }
 
     /// Delete Key-Value pair in MemTable.
     /// The deletion is done by Tombstone.
     pub fn delete(&mut self, key: &[u8], timestamp: u128) {
-        let entry = Entry {
-            key: key.to_vec(),
-            value: None,
-            timestamp,
-        };
-        let key_size = key.len();
-
-        match self.get_index(key) {
-            Ok(idx) => {
-                // update exists entry
-                if let Some(v) = self.entries[idx].value.as_ref() {
-                    self.size -= v.len();
-                }
-                self.entries[idx] = entry;
-            }
-            Err(idx) => {
-                // create new entry
-                self.entries.insert(idx, entry);
-                self.size += key_size + TIMESTAMP_SIZE + TOMBSTONE_SIZE;
-            }
-        }
+        let entry = Entry::new(key.to_vec(), None, timestamp);
+        self.insert(entry);
     }
 
+    /// Logical size of the MemTable: the encoded bytes of the keys, values, timestamps and
+    /// tombstones.
     pub fn size(&self) -> usize {
         self.size
     }
 
-    pub fn entries(&self) -> &[Entry] {
-        &self.entries
+    /// Approximate heap footprint of the MemTable, including the capacity of every key and
+    /// value buffer, the `Entry` structs and the container's own node allocations.
+    pub fn approximate_memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+
+    /// Number of entries (including tombstones) in the MemTable.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of deleted entries (tombstones) in the MemTable.
+    pub fn tombstone_count(&self) -> usize {
+        self.tombstone_count
+    }
+
+    /// Number of entries holding a value in the MemTable.
+    pub fn live_count(&self) -> usize {
+        self.len() - self.tombstone_count
+    }
+
+    /// Iterate over the entries in ascending key order.
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &Entry> {
+        self.entries.values()
+    }
+
+    /// Take a cheap, immutable snapshot of the current state. Later writes to this MemTable
+    /// are not visible through the snapshot.
+    pub fn snapshot(&self) -> Arc<MemTable> {
+        Arc::new(self.clone())
+    }
+
+    /// Consume the MemTable and return its entries in ascending key order, without copying
+    /// unless a snapshot still shares them.
+    pub fn drain_sorted(self) -> Vec<Entry> {
+        self.into_iter().collect()
+    }
+
+    /// Iterate over the entries (including tombstones) whose key falls in `bounds`,
+    /// in ascending key order.
+    pub fn range<'a>(&self, bounds: impl RangeBounds<&'a [u8]>) -> impl Iterator<Item = &Entry> {
+        let start = bounds.start_bound().cloned();
+        let end = bounds.end_bound().cloned();
+
+        // `BTreeMap::range` panics on inverted bounds, treat them as an empty range instead
+        let is_empty = match (start, end) {
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            (Bound::Included(s), Bound::Excluded(e))
+            | (Bound::Excluded(s), Bound::Included(e))
+            | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            _ => false,
+        };
+        let range = if is_empty {
+            None
+        } else {
+            Some(self.entries.range::<[u8], _>((start, end)))
+        };
+
+        range.into_iter().flatten().map(|(_, entry)| entry)
+    }
+
+    /// Insert the entry and keep the size accounting in sync, returning the replaced entry.
+    ///
+    /// The counters are adjusted by the delta between the full footprint of the new entry
+    /// and the one it replaces, so overwrites and tombstones can never drift them.
+    fn insert(&mut self, entry: Entry) -> Option<Entry> {
+        let map_key = entry.key.clone();
+        self.size += entry_size(&entry);
+        self.tombstone_count += usize::from(entry.is_deleted());
+        self.memory_usage += entry_memory_usage(&map_key, &entry);
+        // only copies the map when a snapshot of it is still alive
+        let old_entry = Arc::make_mut(&mut self.entries).insert(map_key, entry);
+        if let Some(old_entry) = old_entry.as_ref() {
+            self.size -= entry_size(old_entry);
+            self.tombstone_count -= usize::from(old_entry.is_deleted());
+            // the map keeps the original key buffer, release the one we just allocated instead
+            self.memory_usage -= entry_memory_usage(&old_entry.key, old_entry);
+        }
+        old_entry
+    }
+
+    /// Recompute the logical size from scratch, to cross-check the incremental accounting.
+    #[cfg(test)]
+    fn recompute_size(&self) -> usize {
+        self.entries.values().map(entry_size).sum()
     }
 
-    /// Perform the binary search to find the index of the key
-    fn get_index(&self, key: &[u8]) -> Result<usize, usize> {
+    /// Iterate over the entries (including tombstones) whose key starts with `prefix`,
+    /// in ascending key order.
+    pub fn iter_prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = &'a Entry> {
         self.entries
-            .binary_search_by_key(&key, |entry| entry.key.as_slice())
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .map(|(_, entry)| entry)
+            .take_while(move |entry| entry.key.starts_with(prefix))
+    }
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntoIterator for MemTable {
+    type Item = Entry;
+    type IntoIter = std::collections::btree_map::IntoValues<Vec<u8>, Entry>;
+
+    /// Consume the MemTable, yielding the entries in ascending key order.
+    fn into_iter(self) -> Self::IntoIter {
+        Arc::try_unwrap(self.entries)
+            .unwrap_or_else(|entries| (*entries).clone())
+            .into_values()
+    }
+}
+
+impl FromIterator<Entry> for MemTable {
+    fn from_iter<T: IntoIterator<Item = Entry>>(iter: T) -> Self {
+        let mut mem_table = MemTable::new();
+        for entry in iter {
+            mem_table.insert(entry);
+        }
+        mem_table
     }
 }
 
+/// Logical size of an entry: key + value + content type + expiry + timestamp + tombstone.
+fn entry_size(entry: &Entry) -> usize {
+    entry.key.len()
+        + entry.value.as_ref().map_or(0, |value| value.len())
+        + entry
+            .content_type
+            .as_ref()
+            .map_or(0, |content_type| content_type.len())
+        + entry.expires_at.map_or(0, |_| TIMESTAMP_SIZE)
+        + TIMESTAMP_SIZE
+        + TOMBSTONE_SIZE
+}
+
+/// Heap bytes used by one MemTable slot holding `entry` under `map_key`.
+fn entry_memory_usage(map_key: &Vec<u8>, entry: &Entry) -> usize {
+    ENTRY_OVERHEAD
+        + map_key.capacity()
+        + entry.key.capacity()
+        + entry.value.as_ref().map_or(0, |value| value.len())
+        + entry
+            .content_type
+            .as_ref()
+            .map_or(0, |content_type| content_type.capacity())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -107,18 +237,19 @@ mod tests {
 
         table.set(b"Apple", b"Apple Smoothie", 20); // 19 + 16 + 1
 
-        assert_eq!(table.entries[0].key, b"Apple");
-        assert_eq!(table.entries[0].value.as_ref().unwrap(), b"Apple Smoothie");
-        assert_eq!(table.entries[0].timestamp, 20);
-        assert!(!table.entries[0].is_deleted());
-        assert_eq!(table.entries[1].key, b"Lime");
-        assert_eq!(table.entries[1].value.as_ref().unwrap(), b"Lime Smoothie");
-        assert_eq!(table.entries[1].timestamp, 0);
-        assert!(!table.entries[1].is_deleted());
-        assert_eq!(table.entries[2].key, b"Orange");
-        assert_eq!(table.entries[2].value.as_ref().unwrap(), b"Orange Smoothie");
-        assert_eq!(table.entries[2].timestamp, 10);
-        assert!(!table.entries[2].is_deleted());
+        let entries = table.iter().collect::<Vec<_>>();
+        assert_eq!(entries[0].key, b"Apple");
+        assert_eq!(entries[0].value.as_ref().unwrap(), &b"Apple Smoothie"[..]);
+        assert_eq!(entries[0].timestamp, 20);
+        assert!(!entries[0].is_deleted());
+        assert_eq!(entries[1].key, b"Lime");
+        assert_eq!(entries[1].value.as_ref().unwrap(), &b"Lime Smoothie"[..]);
+        assert_eq!(entries[1].timestamp, 0);
+        assert!(!entries[1].is_deleted());
+        assert_eq!(entries[2].key, b"Orange");
+        assert_eq!(entries[2].value.as_ref().unwrap(), &b"Orange Smoothie"[..]);
+        assert_eq!(entries[2].timestamp, 10);
+        assert!(!entries[2].is_deleted());
 
         assert_eq!(table.size, 108);
     }
@@ -131,18 +262,19 @@ mod tests {
 
         table.set(b"Lime", b"Lime Smoothie", 20);
 
-        assert_eq!(table.entries[0].key, b"Apple");
-        assert_eq!(table.entries[0].value.as_ref().unwrap(), b"Apple Smoothie");
-        assert_eq!(table.entries[0].timestamp, 0);
-        assert!(!table.entries[0].is_deleted());
-        assert_eq!(table.entries[1].key, b"Lime");
-        assert_eq!(table.entries[1].value.as_ref().unwrap(), b"Lime Smoothie");
-        assert_eq!(table.entries[1].timestamp, 20);
-        assert!(!table.entries[1].is_deleted());
-        assert_eq!(table.entries[2].key, b"Orange");
-        assert_eq!(table.entries[2].value.as_ref().unwrap(), b"Orange Smoothie");
-        assert_eq!(table.entries[2].timestamp, 10);
-        assert!(!table.entries[2].is_deleted());
+        let entries = table.iter().collect::<Vec<_>>();
+        assert_eq!(entries[0].key, b"Apple");
+        assert_eq!(entries[0].value.as_ref().unwrap(), &b"Apple Smoothie"[..]);
+        assert_eq!(entries[0].timestamp, 0);
+        assert!(!entries[0].is_deleted());
+        assert_eq!(entries[1].key, b"Lime");
+        assert_eq!(entries[1].value.as_ref().unwrap(), &b"Lime Smoothie"[..]);
+        assert_eq!(entries[1].timestamp, 20);
+        assert!(!entries[1].is_deleted());
+        assert_eq!(entries[2].key, b"Orange");
+        assert_eq!(entries[2].value.as_ref().unwrap(), &b"Orange Smoothie"[..]);
+        assert_eq!(entries[2].timestamp, 10);
+        assert!(!entries[2].is_deleted());
 
         assert_eq!(table.size, 108);
     }
@@ -155,18 +287,19 @@ mod tests {
 
         table.set(b"Orange", b"Orange Smoothie", 20);
 
-        assert_eq!(table.entries[0].key, b"Apple");
-        assert_eq!(table.entries[0].value.as_ref().unwrap(), b"Apple Smoothie");
-        assert_eq!(table.entries[0].timestamp, 0);
-        assert!(!table.entries[0].is_deleted());
-        assert_eq!(table.entries[1].key, b"Lime");
-        assert_eq!(table.entries[1].value.as_ref().unwrap(), b"Lime Smoothie");
-        assert_eq!(table.entries[1].timestamp, 10);
-        assert!(!table.entries[1].is_deleted());
-        assert_eq!(table.entries[2].key, b"Orange");
-        assert_eq!(table.entries[2].value.as_ref().unwrap(), b"Orange Smoothie");
-        assert_eq!(table.entries[2].timestamp, 20);
-        assert!(!table.entries[2].is_deleted());
+        let entries = table.iter().collect::<Vec<_>>();
+        assert_eq!(entries[0].key, b"Apple");
+        assert_eq!(entries[0].value.as_ref().unwrap(), &b"Apple Smoothie"[..]);
+        assert_eq!(entries[0].timestamp, 0);
+        assert!(!entries[0].is_deleted());
+        assert_eq!(entries[1].key, b"Lime");
+        assert_eq!(entries[1].value.as_ref().unwrap(), &b"Lime Smoothie"[..]);
+        assert_eq!(entries[1].timestamp, 10);
+        assert!(!entries[1].is_deleted());
+        assert_eq!(entries[2].key, b"Orange");
+        assert_eq!(entries[2].value.as_ref().unwrap(), &b"Orange Smoothie"[..]);
+        assert_eq!(entries[2].timestamp, 20);
+        assert!(!entries[2].is_deleted());
 
         assert_eq!(table.size, 108);
     }
@@ -180,18 +313,19 @@ mod tests {
 
         table.set(b"Lime", b"A sour fruit", 30);
 
-        assert_eq!(table.entries[0].key, b"Apple");
-        assert_eq!(table.entries[0].value.as_ref().unwrap(), b"Apple Smoothie");
-        assert_eq!(table.entries[0].timestamp, 0);
-        assert!(!table.entries[0].is_deleted());
-        assert_eq!(table.entries[1].key, b"Lime");
-        assert_eq!(table.entries[1].value.as_ref().unwrap(), b"A sour fruit");
-        assert_eq!(table.entries[1].timestamp, 30);
-        assert!(!table.entries[1].is_deleted());
-        assert_eq!(table.entries[2].key, b"Orange");
-        assert_eq!(table.entries[2].value.as_ref().unwrap(), b"Orange Smoothie");
-        assert_eq!(table.entries[2].timestamp, 20);
-        assert!(!table.entries[2].is_deleted());
+        let entries = table.iter().collect::<Vec<_>>();
+        assert_eq!(entries[0].key, b"Apple");
+        assert_eq!(entries[0].value.as_ref().unwrap(), &b"Apple Smoothie"[..]);
+        assert_eq!(entries[0].timestamp, 0);
+        assert!(!entries[0].is_deleted());
+        assert_eq!(entries[1].key, b"Lime");
+        assert_eq!(entries[1].value.as_ref().unwrap(), &b"A sour fruit"[..]);
+        assert_eq!(entries[1].timestamp, 30);
+        assert!(!entries[1].is_deleted());
+        assert_eq!(entries[2].key, b"Orange");
+        assert_eq!(entries[2].value.as_ref().unwrap(), &b"Orange Smoothie"[..]);
+        assert_eq!(entries[2].timestamp, 20);
+        assert!(!entries[2].is_deleted());
 
         assert_eq!(table.size, 107);
     }
@@ -206,7 +340,7 @@ mod tests {
         let entry = table.get(b"Orange").unwrap();
 
         assert_eq!(entry.key, b"Orange");
-        assert_eq!(entry.value.as_ref().unwrap(), b"Orange Smoothie");
+        assert_eq!(entry.value.as_ref().unwrap(), &b"Orange Smoothie"[..]);
         assert_eq!(entry.timestamp, 20);
     }
 
@@ -234,10 +368,11 @@ mod tests {
         assert_eq!(res.timestamp, 10);
         assert!(res.is_deleted());
 
-        assert_eq!(table.entries[0].key, b"Apple");
-        assert_eq!(table.entries[0].value, None);
-        assert_eq!(table.entries[0].timestamp, 10);
-        assert!(table.entries[0].is_deleted());
+        let entries = table.iter().collect::<Vec<_>>();
+        assert_eq!(entries[0].key, b"Apple");
+        assert_eq!(entries[0].value, None);
+        assert_eq!(entries[0].timestamp, 10);
+        assert!(entries[0].is_deleted());
 
         assert_eq!(table.size, 22);
     }
@@ -254,11 +389,259 @@ mod tests {
         assert_eq!(res.timestamp, 10);
         assert!(res.is_deleted());
 
-        assert_eq!(table.entries[0].key, b"Apple");
-        assert_eq!(table.entries[0].value, None);
-        assert_eq!(table.entries[0].timestamp, 10);
-        assert!(table.entries[0].is_deleted());
+        let entries = table.iter().collect::<Vec<_>>();
+        assert_eq!(entries[0].key, b"Apple");
+        assert_eq!(entries[0].value, None);
+        assert_eq!(entries[0].timestamp, 10);
+        assert!(entries[0].is_deleted());
+
+        assert_eq!(table.size, 22);
+    }
+
+    fn seed_fruits() -> MemTable {
+        let mut table = MemTable::new();
+        table.set(b"Apple", b"Apple Smoothie", 0);
+        table.set(b"Apricot", b"Apricot Smoothie", 10);
+        table.set(b"Lime", b"Lime Smoothie", 20);
+        table.set(b"Orange", b"Orange Smoothie", 30);
+        table.delete(b"Banana", 40);
+        table
+    }
+
+    fn keys<'a>(entries: impl Iterator<Item = &'a Entry>) -> Vec<&'a [u8]> {
+        entries.map(|entry| entry.key.as_slice()).collect()
+    }
+
+    #[test]
+    fn test_mem_table_range_with_bounds_between_keys() {
+        let table = seed_fruits();
+
+        let range = table.range(&b"B"[..]..&b"M"[..]);
+        assert_eq!(keys(range), vec![&b"Banana"[..], b"Lime"]);
+
+        let range = table.range(&b"Apricot"[..]..=&b"Lime"[..]);
+        assert_eq!(keys(range), vec![&b"Apricot"[..], b"Banana", b"Lime"]);
+
+        let range = table.range(..&b"Apricot"[..]);
+        assert_eq!(keys(range), vec![&b"Apple"[..]]);
+
+        let range = table.range(&b"M"[..]..);
+        assert_eq!(keys(range), vec![&b"Orange"[..]]);
+    }
+
+    #[test]
+    fn test_mem_table_range_includes_tombstones() {
+        let table = seed_fruits();
+
+        let entries = table
+            .range(&b"Banana"[..]..=&b"Banana"[..])
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].is_deleted());
+    }
+
+    #[test]
+    fn test_mem_table_range_empty() {
+        let table = seed_fruits();
+
+        assert_eq!(table.range(&b"P"[..]..&b"Z"[..]).count(), 0);
+        assert_eq!(table.range(&b"Z"[..]..&b"A"[..]).count(), 0);
+        assert_eq!(table.range(&b"Lime"[..]..&b"Lime"[..]).count(), 0);
+        assert_eq!(MemTable::new().range(&b""[..]..).count(), 0);
+    }
+
+    #[test]
+    fn test_mem_table_iter_prefix() {
+        let table = seed_fruits();
+
+        assert_eq!(
+            keys(table.iter_prefix(b"Ap")),
+            vec![&b"Apple"[..], b"Apricot"]
+        );
+        assert_eq!(keys(table.iter_prefix(b"Lime")), vec![&b"Lime"[..]]);
+        assert_eq!(table.iter_prefix(b"").count(), 5);
+    }
+
+    #[test]
+    fn test_mem_table_iter_prefix_longer_than_any_key() {
+        let table = seed_fruits();
+
+        assert_eq!(table.iter_prefix(b"Apple Smoothie Deluxe").count(), 0);
+        assert_eq!(table.iter_prefix(b"Zucchini").count(), 0);
+    }
+
+    #[test]
+    fn test_mem_table_approximate_memory_usage() {
+        let mut table = MemTable::new();
+        assert_eq!(table.approximate_memory_usage(), 0);
+
+        table.set(b"Apple", b"Apple Smoothie", 0);
+        let one_entry = table.approximate_memory_usage();
+        assert!(one_entry >= ENTRY_OVERHEAD + 5 + 5 + 14);
+        assert!(one_entry > table.size());
+
+        // overwrite with a shorter value releases the old value's capacity
+        table.set(b"Apple", b"Apple", 10);
+        assert_eq!(table.approximate_memory_usage(), one_entry - 9);
+
+        // a tombstone releases the value buffer entirely
+        table.delete(b"Apple", 20);
+        assert_eq!(table.approximate_memory_usage(), one_entry - 14);
+
+        table.set(b"Apple", b"Apple Smoothie", 30);
+        assert_eq!(table.approximate_memory_usage(), one_entry);
+
+        table.set(b"Lime", b"Lime Smoothie", 40);
+        assert!(table.approximate_memory_usage() > one_entry);
+    }
+
+    #[test]
+    fn test_mem_table_size_after_interleaved_writes() {
+        let mut table = MemTable::new();
 
+        table.set(b"Apple", b"Apple Smoothie", 0);
+        table.delete(b"Apple", 10);
         assert_eq!(table.size, 22);
+        assert_eq!(table.size, table.recompute_size());
+
+        // set over a tombstone
+        table.set(b"Apple", b"Apple Pie", 20);
+        assert_eq!(table.size, 31);
+        assert_eq!(table.size, table.recompute_size());
+
+        // delete over a tombstone
+        table.delete(b"Lime", 30);
+        table.delete(b"Lime", 40);
+        assert_eq!(table.size, 31 + 21);
+        assert_eq!(table.size, table.recompute_size());
+
+        // repeated cycles must not drift
+        for i in 0..100u128 {
+            let value = vec![b'x'; (i % 7) as usize];
+            match i % 3 {
+                0 => table.set(b"Orange", &value, i),
+                1 => table.delete(b"Orange", i),
+                _ => table.set(b"Apple", &value, i),
+            }
+            assert_eq!(table.size, table.recompute_size());
+        }
+
+        table.delete(b"Apple", 200);
+        table.delete(b"Orange", 200);
+        table.delete(b"Lime", 200);
+        assert_eq!(table.size, 22 + 23 + 21);
+        assert_eq!(table.size, table.recompute_size());
+    }
+
+    #[test]
+    fn test_mem_table_drain_sorted() {
+        let mut table = MemTable::new();
+        table.set(b"Orange", b"Orange Smoothie", 0);
+        table.delete(b"Lime", 10);
+        table.set(b"Apple", b"Apple Smoothie", 20);
+
+        let entries = table.drain_sorted();
+        let keys = entries.iter().map(|e| e.key.as_slice()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![&b"Apple"[..], b"Lime", b"Orange"]);
+        assert!(entries[1].is_deleted());
+
+        // and back again
+        let table = entries.into_iter().collect::<MemTable>();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.size(), table.recompute_size());
+        assert_eq!(table.get(b"Apple").unwrap().timestamp, 20);
+    }
+
+    #[test]
+    fn test_mem_table_snapshot() {
+        let mut table = MemTable::new();
+        table.set(b"Apple", b"Apple Smoothie", 0);
+        table.set(b"Lime", b"Lime Smoothie", 10);
+
+        let snapshot = table.snapshot();
+        table.set(b"Apple", b"Apple Pie", 20);
+        table.delete(b"Lime", 30);
+        table.set(b"Orange", b"Orange Smoothie", 40);
+
+        // the snapshot keeps the state at the time it was taken
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(
+            snapshot.get(b"Apple").unwrap().value.as_deref(),
+            Some(&b"Apple Smoothie"[..])
+        );
+        assert!(!snapshot.get(b"Lime").unwrap().is_deleted());
+        assert!(snapshot.get(b"Orange").is_none());
+        assert_eq!(snapshot.size(), snapshot.recompute_size());
+
+        assert_eq!(table.len(), 3);
+        assert_eq!(
+            table.get(b"Apple").unwrap().value.as_deref(),
+            Some(&b"Apple Pie"[..])
+        );
+        assert!(table.get(b"Lime").unwrap().is_deleted());
+
+        // draining while a snapshot is alive leaves the snapshot intact
+        assert_eq!(table.drain_sorted().len(), 3);
+        assert_eq!(snapshot.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_mem_table_concurrent_readers() {
+        let mut table = MemTable::new();
+        for i in 0..1000u32 {
+            table.set(&i.to_be_bytes(), b"value", i as u128);
+        }
+
+        let snapshot = table.snapshot();
+        let readers = (0..8)
+            .map(|_| {
+                let snapshot = Arc::clone(&snapshot);
+                std::thread::spawn(move || {
+                    (0..1000u32)
+                        .filter(|i| snapshot.get(&i.to_be_bytes()).is_some())
+                        .count()
+                })
+            })
+            .collect::<Vec<_>>();
+        table.delete(&0u32.to_be_bytes(), 1000);
+
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), 1000);
+        }
+    }
+
+    #[test]
+    fn test_mem_table_counts() {
+        let mut table = MemTable::new();
+        assert!(table.is_empty());
+        assert_eq!(table.tombstone_count(), 0);
+        assert_eq!(table.live_count(), 0);
+
+        table.set(b"Apple", b"Apple Smoothie", 0);
+        table.set(b"Lime", b"Lime Smoothie", 10);
+        table.delete(b"Orange", 20);
+        assert!(!table.is_empty());
+        assert_eq!(table.tombstone_count(), 1);
+        assert_eq!(table.live_count(), 2);
+
+        // live -> tombstone
+        table.delete(b"Apple", 30);
+        assert_eq!(table.tombstone_count(), 2);
+        assert_eq!(table.live_count(), 1);
+
+        // tombstone -> tombstone
+        table.delete(b"Apple", 40);
+        assert_eq!(table.tombstone_count(), 2);
+
+        // tombstone -> live
+        table.set(b"Orange", b"Orange Smoothie", 50);
+        assert_eq!(table.tombstone_count(), 1);
+        assert_eq!(table.live_count(), 2);
+
+        // live -> live
+        table.set(b"Lime", b"A sour fruit", 60);
+        assert_eq!(table.tombstone_count(), 1);
+        assert_eq!(table.live_count(), 2);
+        assert_eq!(table.len(), 3);
     }
 }
@@ -0,0 +1,191 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Number of buckets of a latency histogram: the upper bounds double from 1 µs to 2^30 µs
+/// (about 18 minutes), and the last bucket holds the slower operations.
+const BUCKET_COUNT: usize = 32;
+
+/// An operation of the Database whose latency is recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Get,
+    Set,
+    Delete,
+    Flush,
+    Write,
+}
+
+/// Latency histograms of the operations of a Database. Recording one only updates atomics, it
+/// never allocates nor locks.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    histograms: [Histogram; 5],
+}
+
+impl Metrics {
+    /// Start timing an `operation`, it is recorded when the timer is dropped.
+    pub(crate) fn timer(self: &Arc<Self>, operation: Operation) -> OperationTimer {
+        OperationTimer {
+            metrics: Arc::clone(self),
+            operation,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub(crate) fn record(&self, operation: Operation, elapsed: Duration) {
+        self.histograms[operation as usize].record(elapsed);
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        let [get, set, delete, flush, write] = &self.histograms;
+        MetricsSnapshot {
+            get: get.snapshot(),
+            set: set.snapshot(),
+            delete: delete.snapshot(),
+            flush: flush.snapshot(),
+            write: write.snapshot(),
+        }
+    }
+}
+
+/// Records the time from its creation to its drop, see [`Metrics::timer`].
+pub(crate) struct OperationTimer {
+    metrics: Arc<Metrics>,
+    operation: Operation,
+    started_at: Instant,
+}
+
+impl Drop for OperationTimer {
+    fn drop(&mut self) {
+        self.metrics
+            .record(self.operation, self.started_at.elapsed());
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKET_COUNT],
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        // the bucket of the smallest power of two not below `micros`
+        let bucket = (u64::BITS - micros.saturating_sub(1).leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKET_COUNT - 1)].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut count = 0;
+        let buckets = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, bucket)| {
+                count += bucket.load(Ordering::Relaxed);
+                let upper_bound = match i < BUCKET_COUNT - 1 {
+                    true => Duration::from_micros(1 << i),
+                    false => Duration::MAX,
+                };
+                (upper_bound, count)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count,
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Latency histograms of the [`Database`](crate::Database) operations, see
+/// [`Database::metrics_snapshot`](crate::Database::metrics_snapshot).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub get: HistogramSnapshot,
+    pub set: HistogramSnapshot,
+    pub delete: HistogramSnapshot,
+    /// The MemTable flushes to SSTable, in the background or not
+    pub flush: HistogramSnapshot,
+    /// The [`WriteBatch`](crate::WriteBatch)es, whatever their number of entries
+    pub write: HistogramSnapshot,
+}
+
+/// Latencies of one operation, laid out like a Prometheus histogram.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Upper bound of every bucket with the number of operations which took at most that long,
+    /// the last bound is `Duration::MAX` (`+Inf`)
+    pub buckets: Vec<(Duration, u64)>,
+    /// Number of operations recorded
+    pub count: u64,
+    /// Total time of the operations recorded
+    pub sum: Duration,
+}
+
+impl HistogramSnapshot {
+    /// Upper bound of the bucket holding the `quantile` (0.5 for the median) of the latencies,
+    /// `None` before any operation.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (quantile.clamp(0.0, 1.0) * self.count as f64)
+            .ceil()
+            .max(1.0) as u64;
+        self.buckets
+            .iter()
+            .find(|(_, count)| *count >= rank)
+            .map(|(upper_bound, _)| *upper_bound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let metrics = Arc::new(Metrics::default());
+        for micros in [0, 1, 2, 3, 100, 100, 100, 100, 100, 5_000] {
+            metrics.record(Operation::Get, Duration::from_micros(micros));
+        }
+        metrics.record(Operation::Set, Duration::from_secs(3600));
+        drop(metrics.timer(Operation::Delete));
+
+        let snapshot = metrics.snapshot();
+        let get = snapshot.get;
+        assert_eq!(get.count, 10);
+        assert_eq!(get.sum, Duration::from_micros(5_506));
+        assert_eq!(get.buckets.len(), BUCKET_COUNT);
+        // cumulative counts: 0 and 1 µs, 2 µs, 3 µs in the 4 µs bucket, 100 µs in the 128 µs one
+        assert_eq!(get.buckets[0], (Duration::from_micros(1), 2));
+        assert_eq!(get.buckets[1], (Duration::from_micros(2), 3));
+        assert_eq!(get.buckets[2], (Duration::from_micros(4), 4));
+        assert_eq!(get.buckets[7], (Duration::from_micros(128), 9));
+        assert_eq!(get.quantile(0.5), Some(Duration::from_micros(128)));
+        assert_eq!(get.quantile(0.99), Some(Duration::from_micros(8_192)));
+
+        // an hour only fits in the last bucket
+        assert_eq!(snapshot.set.buckets[BUCKET_COUNT - 2].1, 0);
+        assert_eq!(snapshot.set.quantile(0.5), Some(Duration::MAX));
+        assert_eq!(snapshot.delete.count, 1);
+        assert_eq!(snapshot.flush.quantile(0.5), None);
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::fs::{copy, create_dir_all, read_dir};
+use tokio_stream::StreamExt;
+
+use crate::{
+    cdc::ARCHIVE_DIR_NAME,
+    database::Database,
+    entries::Entry,
+    errors::WalReadError,
+    mem_table::MemTable,
+    sstable::{list_level_files, SSTableReader},
+    storage::LocalFs,
+    utils::{allocation_order, list_files_with_ext, HybridClock, DIR_LOCK_FILE_NAME},
+    wal::{WALIterator, WalRecord, WriteAheadLog},
+};
+
+/// Outcome of [`Database::restore_to`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PointInTimeReport {
+    /// WAL records written at or before the point in time, replayed into the target
+    pub applied: u64,
+    /// WAL records written after it, and the ones of the batches cut short before their commit
+    pub skipped: u64,
+}
+
+impl Database {
+    /// Build the database as it was at `as_of` in the new `target_dir`: the files of the
+    /// `checkpoint`, a copy of the directory of a closed database taken at or before `as_of`,
+    /// then the records of its WAL files and of the ones in `wal_archive` written at or before
+    /// `as_of`. The replayed entries go to a WAL of the target, open it with a
+    /// [`DatabaseBuilder`](crate::DatabaseBuilder) afterwards.
+    ///
+    /// The WAL files may overlap the checkpoint and each other, the newest version of each
+    /// key wins. Fails when an SSTable of the checkpoint holds a write after `as_of`.
+    pub async fn restore_to(
+        target_dir: &Path,
+        checkpoint: &Path,
+        wal_archive: &Path,
+        as_of: u128,
+    ) -> Result<PointInTimeReport> {
+        if is_non_empty_dir(target_dir).await? {
+            bail!("the target of the restore {:?} is not empty", target_dir);
+        }
+        for path in list_level_files(checkpoint, "db", &LocalFs)
+            .await?
+            .iter()
+            .flatten()
+        {
+            let max_timestamp = SSTableReader::footer_max_timestamp(path, &LocalFs)
+                .await
+                .with_context(|| format!("read the footer of {:?}", path))?;
+            if max_timestamp.is_none_or(|max_timestamp| max_timestamp > as_of) {
+                bail!(
+                    "the checkpoint {:?} holds writes after {}, in {:?}",
+                    checkpoint,
+                    as_of,
+                    path
+                );
+            }
+        }
+        copy_checkpoint(checkpoint, target_dir).await?;
+
+        let mut wal_files = list_files_with_ext(checkpoint, "wal", &LocalFs).await?;
+        wal_files.extend(list_files_with_ext(wal_archive, "wal", &LocalFs).await?);
+        wal_files.sort_by(|a, b| allocation_order(a).cmp(&allocation_order(b)));
+        let mut mem_table = MemTable::new();
+        let mut report = PointInTimeReport::default();
+        for wal_file in wal_files {
+            replay_until(&wal_file, as_of, &mut mem_table, &mut report).await?;
+        }
+
+        let mut wal =
+            WriteAheadLog::new_with_clock(target_dir, &HybridClock, Arc::new(LocalFs)).await?;
+        for entry in mem_table.iter() {
+            wal.put(entry).await?;
+        }
+        wal.sync().await.context("sync the restored wal")?;
+        Ok(report)
+    }
+}
+
+async fn is_non_empty_dir(dir: &Path) -> Result<bool> {
+    match read_dir(dir).await {
+        Ok(mut entries) => Ok(entries.next_entry().await?.is_some()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("list {:?}", dir)),
+    }
+}
+
+/// Copy the files of the database in `checkpoint` to `target`, but its WAL files which are
+/// replayed instead, its lock and the WAL files kept for its CDC streams
+async fn copy_checkpoint(checkpoint: &Path, target: &Path) -> Result<()> {
+    let mut dirs: Vec<(PathBuf, PathBuf)> = vec![(checkpoint.to_path_buf(), target.to_path_buf())];
+    while let Some((from, to)) = dirs.pop() {
+        create_dir_all(&to)
+            .await
+            .with_context(|| format!("create {:?}", to))?;
+        let mut entries = read_dir(&from)
+            .await
+            .with_context(|| format!("list {:?}", from))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let (path, file_name) = (entry.path(), entry.file_name());
+            if entry.file_type().await?.is_dir() {
+                if from != checkpoint || file_name != ARCHIVE_DIR_NAME {
+                    dirs.push((path, to.join(file_name)));
+                }
+            } else if file_name != DIR_LOCK_FILE_NAME
+                && path.extension().is_none_or(|ext| ext != "wal")
+            {
+                copy(&path, to.join(file_name))
+                    .await
+                    .with_context(|| format!("copy {:?}", path))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Apply the records of the WAL file written at or before `as_of` to the MemTable, the newest
+/// version of a key wins. The entries of a batch count once its commit record is read, the
+/// file ends at the first bad record like on a restore.
+async fn replay_until(
+    path: &Path,
+    as_of: u128,
+    mem_table: &mut MemTable,
+    report: &mut PointInTimeReport,
+) -> Result<()> {
+    let mut apply = |entry: Entry, report: &mut PointInTimeReport| {
+        if entry.timestamp > as_of {
+            report.skipped += 1;
+            return;
+        }
+        report.applied += 1;
+        if mem_table
+            .get(&entry.key)
+            .is_none_or(|newest| newest.timestamp <= entry.timestamp)
+        {
+            mem_table.put(entry);
+        }
+    };
+
+    let mut wal_iter = WALIterator::new(path.to_path_buf())
+        .await
+        .with_context(|| format!("open wal file {:?}", path))?;
+    let mut batch: Option<Vec<Entry>> = None;
+    while let Some(record) = wal_iter.next().await {
+        match record {
+            Ok(WalRecord::Entry(entry)) => match batch.as_mut() {
+                Some(batch) => batch.push(entry),
+                None => apply(entry, report),
+            },
+            Ok(WalRecord::BatchBegin { .. }) => batch = Some(Vec::new()),
+            Ok(WalRecord::BatchCommit { .. }) => {
+                for entry in batch.take().unwrap_or_default() {
+                    apply(entry, report);
+                }
+            }
+            Err(WalReadError::Io(e)) => {
+                return Err(e).with_context(|| format!("read wal file {:?}", path));
+            }
+            Err(e) => {
+                tracing::warn!("Stop replaying {:?} at a bad record: {}", path, e);
+                break;
+            }
+        }
+    }
+    report.skipped += batch.map_or(0, |batch| batch.len() as u64);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{utils::Clock, DatabaseBuilder, WriteBatch};
+
+    /// Hands out the timestamp set last, then the following ones
+    #[derive(Default)]
+    struct SetClock(AtomicU64);
+
+    impl SetClock {
+        fn set(&self, timestamp: u64) {
+            self.0.store(timestamp, Ordering::Relaxed);
+        }
+    }
+
+    impl Clock for SetClock {
+        fn now(&self) -> Result<u128> {
+            Ok(u128::from(self.0.fetch_add(1, Ordering::Relaxed)))
+        }
+    }
+
+    #[tokio::test]
+    async fn it_restores_the_database_as_of_a_timestamp() -> Result<()> {
+        let tmpdir = TempDir::new("point_in_time_test")?;
+        let (dir, checkpoint) = (tmpdir.path().join("db"), tmpdir.path().join("checkpoint"));
+        std::fs::create_dir(&dir)?;
+        let clock = Arc::new(SetClock::default());
+        let open = || {
+            DatabaseBuilder::new(dir.clone())
+                .clock(Arc::clone(&clock) as Arc<dyn Clock>)
+                .build()
+        };
+
+        clock.set(100);
+        let mut db = open().await?;
+        db.set(b"a", b"v1").await?;
+        db.close().await?;
+        drop(db);
+        copy_checkpoint(&dir, &checkpoint).await?;
+
+        let mut db = open().await?;
+        clock.set(200);
+        db.set(b"b", b"v1").await?;
+        clock.set(300);
+        let mut batch = WriteBatch::new();
+        batch.set(b"c", b"v1").set(b"a", b"v2");
+        db.write(batch).await?;
+        clock.set(400);
+        db.delete(b"b").await?;
+        clock.set(500);
+        db.set(b"d", b"v1").await?;
+        clock.set(600);
+        let mut batch = WriteBatch::new();
+        batch.set(b"e", b"v1").set(b"f", b"v1");
+        db.write(batch).await?;
+        // the last batch never committed
+        let wal_path = db.wal_path();
+        drop(db);
+        let wal_len = std::fs::metadata(&wal_path)?.len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&wal_path)?
+            .set_len(wal_len - 9)?;
+
+        let target = tmpdir.path().join("restored");
+        let report = Database::restore_to(&target, &checkpoint, &dir, 350).await?;
+        // b@200 and the batch@300 in, the delete@400, d@500 and the cut batch out
+        assert_eq!(
+            report,
+            PointInTimeReport {
+                applied: 3,
+                skipped: 4
+            }
+        );
+        let restored = DatabaseBuilder::new(target.clone()).build().await?;
+        assert_eq!(&restored.get(b"a").await.unwrap().value[..], b"v2");
+        assert_eq!(restored.get(b"a").await.unwrap().timestamp, 300);
+        assert_eq!(restored.get(b"b").await.unwrap().timestamp, 200);
+        assert!(restored.get(b"c").await.is_some());
+        assert!(restored.get(b"d").await.is_none());
+        assert!(restored.get(b"e").await.is_none());
+        drop(restored);
+
+        // the target must be new, the checkpoint older than the point in time
+        let err = Database::restore_to(&target, &checkpoint, &dir, 350)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not empty"));
+        let err = Database::restore_to(&tmpdir.path().join("early"), &checkpoint, &dir, 50)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("holds writes after 50"));
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
@@ -4,3 +4,4 @@ pub use crate::entries::Entry;
 
 /// Re-expose the Error
 pub use crate::errors::Error;
+pub use crate::errors::WalReadError;
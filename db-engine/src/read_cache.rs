@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use moka::sync::Cache;
+
+use crate::entries::DbEntry;
+
+/// Bytes counted for a cached key besides its key and value.
+const SLOT_OVERHEAD: usize = 64;
+
+/// The newest version of the keys read out of the SSTables, by key: the live entry, or `None`
+/// for a deleted or missing key. Shared by the concurrent reads without a global lock, the
+/// least recently used keys go first once it holds more than its capacity in bytes.
+pub(crate) struct ReadCache {
+    entries: Cache<Vec<u8>, Option<DbEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ReadCache {
+    pub(crate) fn new(capacity: u64) -> Self {
+        let entries = Cache::builder()
+            .max_capacity(capacity)
+            .weigher(|key: &Vec<u8>, entry: &Option<DbEntry>| {
+                let len = SLOT_OVERHEAD
+                    + key.len()
+                    + entry.as_ref().map_or(0, |entry| {
+                        entry.value.len() + entry.content_type().map_or(0, str::len)
+                    });
+                u32::try_from(len).unwrap_or(u32::MAX)
+            })
+            .build();
+        Self {
+            entries,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The cached version of `key`, counted as a hit or a miss.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Option<DbEntry>> {
+        let entry = self.entries.get(key);
+        let counter = match entry {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        entry
+    }
+
+    pub(crate) fn insert(&self, key: &[u8], entry: Option<DbEntry>) {
+        self.entries.insert(key.to_vec(), entry);
+    }
+
+    /// Forget `key`, written since it was cached.
+    pub(crate) fn invalidate(&self, key: &[u8]) {
+        self.entries.invalidate(key);
+    }
+
+    /// Forget every key, e.g. once a compaction dropped or replaced some values.
+    pub(crate) fn invalidate_all(&self) {
+        self.entries.invalidate_all();
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
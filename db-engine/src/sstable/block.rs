@@ -0,0 +1,105 @@
+use tokio::io::AsyncRead;
+
+use crate::{
+    compression::Codec,
+    entries::{read_field, verify_checksum},
+    errors::WalReadError,
+};
+
+/// Records are gathered into blocks of about this many bytes before being compressed.
+pub(crate) const BLOCK_SIZE: usize = 4096;
+
+/// The index of a block based SSTable points at a record with the offset of its block in the
+/// high bits and the offset within the decompressed block in the low ones. A record always
+/// starts within the first [`BLOCK_SIZE`] bytes of its block, so they fit.
+const INNER_OFFSET_BITS: u32 = 16;
+
+/// Codec tag (u8) and the length of the stored records (u32) ahead of them.
+const BLOCK_HEADER_LEN: usize = 1 + 4;
+
+pub(crate) fn pack_offset(block_offset: u64, inner_offset: usize) -> u64 {
+    (block_offset << INNER_OFFSET_BITS) | inner_offset as u64
+}
+
+pub(crate) fn unpack_offset(offset: u64) -> (u64, usize) {
+    let inner_offset = offset & ((1 << INNER_OFFSET_BITS) - 1);
+    (offset >> INNER_OFFSET_BITS, inner_offset as usize)
+}
+
+/// Encode the records of a block: the codec tag, the length of the stored bytes, the records
+/// compressed with `codec` (kept raw when it does not shrink them) and the CRC32 checksum of
+/// all of it.
+pub(crate) fn encode_block(codec: Codec, records: &[u8]) -> Vec<u8> {
+    let (codec, stored) = match codec.compress(records) {
+        Some(compressed) => (codec, compressed),
+        None => (Codec::None, records.to_vec()),
+    };
+
+    let mut block = Vec::with_capacity(BLOCK_HEADER_LEN + stored.len() + 4);
+    block.push(codec.tag());
+    block.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+    block.extend_from_slice(&stored);
+    block.extend_from_slice(&crc32fast::hash(&block).to_le_bytes());
+    block
+}
+
+/// Read the block at `offset`, verify its checksum and decompress the records in it, returns
+/// them together with the length of the block in the input.
+///
+/// `remaining` is the number of bytes left in the input from `offset`.
+pub(crate) async fn read_block<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    offset: u64,
+    remaining: u64,
+) -> Result<(Vec<u8>, u64), WalReadError> {
+    let mut header = [0; BLOCK_HEADER_LEN];
+    read_field(reader, &mut header, false).await?;
+    let tag = header[0];
+    let stored_len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as u64;
+    let available = remaining.saturating_sub(BLOCK_HEADER_LEN as u64);
+    if stored_len > available {
+        return Err(WalReadError::UnexpectedEof {
+            missing: (stored_len - available) as usize,
+        });
+    }
+
+    let mut stored = vec![0; stored_len as usize];
+    read_field(reader, &mut stored, false).await?;
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header);
+    hasher.update(&stored);
+    verify_checksum(reader, hasher.finalize(), offset).await?;
+
+    let codec = Codec::from_tag(tag).ok_or(WalReadError::UnknownCodec { tag, offset })?;
+    let records = codec
+        .decompress(&stored)
+        .map_err(|source| WalReadError::Decompress { offset, source })?;
+    Ok((records, (BLOCK_HEADER_LEN + stored.len() + 4) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_works() {
+        let records = b"records ".repeat(64);
+        for codec in [Codec::None, Codec::Lz4, Codec::Zstd] {
+            let block = encode_block(codec, &records);
+            assert_eq!(block[0], codec.tag());
+            let decoded = read_block(&mut block.as_slice(), 0, block.len() as u64)
+                .await
+                .unwrap();
+            assert_eq!(decoded, (records.clone(), block.len() as u64));
+
+            let mut damaged = block.clone();
+            damaged[BLOCK_HEADER_LEN + 1] ^= 1;
+            let err = read_block(&mut damaged.as_slice(), 7, damaged.len() as u64)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, WalReadError::ChecksumMismatch { offset: 7 }));
+        }
+
+        assert_eq!(unpack_offset(pack_offset(123_456, 4095)), (123_456, 4095));
+    }
+}
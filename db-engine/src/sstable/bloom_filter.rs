@@ -0,0 +1,155 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+use tokio::io;
+
+use crate::storage::{self, Storage};
+
+/// False positive rate the filter is sized for.
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// Bloom filter over the keys of an SSTable, persisted next to it in a `.bf` file.
+///
+/// File layout: number of hash functions (u32), the bit array, CRC32 of both (u32), all
+/// little endian.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_count: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `entry_count` keys.
+    pub fn with_capacity(entry_count: usize) -> Self {
+        let n = entry_count.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bit_count = (-n * FALSE_POSITIVE_RATE.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0);
+        let hash_count = (bit_count / n * ln2).round().clamp(1.0, 30.0) as u32;
+        Self {
+            bits: vec![0; (bit_count as usize).div_ceil(8)],
+            hash_count,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        self.insert_hash(hash_key(key));
+    }
+
+    /// Insert a key by its [`hash_key`], for callers which cannot keep the keys around.
+    pub fn insert_hash(&mut self, hash: u64) {
+        for bit in self.bit_positions(hash) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// `false` when the key is definitely not in the SSTable.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_positions(hash_key(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Double hashing over the two halves of one 64 bit hash.
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let (h1, h2) = (hash as u32, (hash >> 32) as u32);
+        let bit_count = (self.bits.len() * 8) as u64;
+        (0..self.hash_count)
+            .map(move |i| (u64::from(h1.wrapping_add(i.wrapping_mul(h2))) % bit_count) as usize)
+    }
+
+    /// Write the filter to `path` of `storage`, replacing the previous one.
+    pub async fn persist(&self, path: &Path, storage: &dyn Storage) -> Result<()> {
+        let mut bytes = Vec::with_capacity(self.bits.len() + 8);
+        bytes.extend_from_slice(&self.hash_count.to_le_bytes());
+        bytes.extend_from_slice(&self.bits);
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_le_bytes());
+
+        storage::write(storage, path, &bytes)
+            .await
+            .context("write bloom filter to file")
+    }
+
+    /// Load the filter from `path` of `storage`, `None` when there is none (an SSTable written
+    /// before the filters existed) or it is damaged, either way the SSTable may contain any key.
+    pub async fn load(path: &Path, storage: &dyn Storage) -> Option<Self> {
+        let bytes = match storage::read(storage, path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                tracing::warn!("Failed to read bloom filter {:?}: {}", path, e);
+                return None;
+            }
+        };
+        if bytes.len() < 9 {
+            tracing::warn!("Truncated bloom filter {:?}", path);
+            return None;
+        }
+        let (content, checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32fast::hash(content).to_le_bytes() != checksum {
+            tracing::warn!("Corrupted bloom filter {:?}", path);
+            return None;
+        }
+        let (hash_count, bits) = content.split_at(4);
+        Some(Self {
+            bits: bits.to_vec(),
+            hash_count: u32::from_le_bytes(hash_count.try_into().ok()?),
+        })
+    }
+}
+
+/// FNV-1a followed by the splitmix64 finalizer, stable across builds so persisted filters
+/// stay valid.
+pub(super) fn hash_key(key: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for &byte in key {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use tokio::fs;
+
+    use super::*;
+    use crate::storage::LocalFs;
+
+    #[tokio::test]
+    async fn it_works() -> Result<()> {
+        let temp_dir = TempDir::new("bloom_filter")?;
+        let path = temp_dir.path().join("test.db.bf");
+
+        let mut filter = BloomFilter::with_capacity(1000);
+        for i in 0..1000 {
+            filter.insert(format!("key{}", i).as_bytes());
+        }
+        filter.persist(&path, &LocalFs).await?;
+
+        let filter = BloomFilter::load(&path, &LocalFs).await.unwrap();
+        assert!((0..1000).all(|i| filter.may_contain(format!("key{}", i).as_bytes())));
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(format!("missing{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        // a missing or damaged filter is no filter
+        assert!(
+            BloomFilter::load(&temp_dir.path().join("none.bf"), &LocalFs)
+                .await
+                .is_none()
+        );
+        let mut bytes = fs::read(&path).await?;
+        bytes[10] ^= 1;
+        fs::write(&path, bytes).await?;
+        assert!(BloomFilter::load(&path, &LocalFs).await.is_none());
+
+        temp_dir.close()?;
+        Ok(())
+    }
+}
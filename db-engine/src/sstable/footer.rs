@@ -0,0 +1,285 @@
+use crate::{entries::EntryEncoding, prelude::*, storage::ReadableFile};
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use super::sstable_index::IndexFormat;
+
+/// Marks an SSTable file which carries its own index, found at the very end of the file.
+pub(crate) const SSTABLE_MAGIC: &[u8; 8] = b"SDBSST\0\x01";
+
+/// The latest format, files without the magic are version 1 and keep their index in a `.idx`
+/// file next to them.
+pub(crate) const SSTABLE_VERSION: u16 = 6;
+
+/// The first version with the index block and the footer.
+pub(crate) const SSTABLE_VERSION_FOOTER: u16 = 2;
+
+/// The first version where every record is followed by its CRC32 checksum.
+pub(crate) const SSTABLE_VERSION_CHECKSUM: u16 = 3;
+
+/// The first version where the records are gathered into compressed blocks, see
+/// [`super::block`].
+pub(crate) const SSTABLE_VERSION_BLOCKS: u16 = 4;
+
+/// The first version where the entries have varint lengths and timestamp, see
+/// [`Entry::write_to_v2`], its records are not in blocks.
+pub(crate) const SSTABLE_VERSION_VARINT: u16 = 5;
+
+/// The records of version 5 gathered into blocks like the ones of version 4.
+pub(crate) const SSTABLE_VERSION_VARINT_BLOCKS: u16 = 6;
+
+/// Whether the records of an SSTable of `version` are gathered into blocks.
+pub(crate) fn is_block_format(version: u16) -> bool {
+    matches!(
+        version,
+        SSTABLE_VERSION_BLOCKS | SSTABLE_VERSION_VARINT_BLOCKS
+    )
+}
+
+/// How the entries of an SSTable of `version` are laid out.
+pub(crate) fn entry_encoding(version: u16) -> EntryEncoding {
+    match version >= SSTABLE_VERSION_VARINT {
+        true => EntryEncoding::Varint,
+        false => EntryEncoding::Fixed,
+    }
+}
+
+/// Footer length (u32), CRC32 of the footer (u32), version (u16) and the magic.
+const TRAILER_LEN: u64 = 4 + 4 + 2 + SSTABLE_MAGIC.len() as u64;
+
+/// Footer of an SSTable file, following the data records and the index block.
+///
+/// Layout: index offset (u64), index length (u64), entry count (u64), min key length (u32),
+/// min key, max key length (u32), max key, index interval (u32, absent from the footers
+/// written before the sparse index, meaning a dense one), index format (u8, absent from the
+/// footers written before the sorted index, meaning bincode), max timestamp (u128, absent when
+/// unknown), then the fixed size trailer, all little endian.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SSTableFooter {
+    /// Format of the data records, see [`SSTABLE_VERSION`].
+    pub(crate) version: u16,
+    pub(crate) index_offset: u64,
+    pub(crate) index_len: u64,
+    pub(crate) entry_count: u64,
+    pub(crate) min_key: Vec<u8>,
+    pub(crate) max_key: Vec<u8>,
+    /// Only every `index_interval`th key is in the index, 1 for a dense index.
+    pub(crate) index_interval: u32,
+    pub(crate) index_format: IndexFormat,
+    /// The greatest timestamp of the entries, `None` for the SSTables written before it was
+    /// kept track of
+    pub(crate) max_timestamp: Option<u128>,
+}
+
+impl SSTableFooter {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut footer =
+            Vec::with_capacity(37 + self.min_key.len() + self.max_key.len() + TRAILER_LEN as usize);
+        footer.extend_from_slice(&self.index_offset.to_le_bytes());
+        footer.extend_from_slice(&self.index_len.to_le_bytes());
+        footer.extend_from_slice(&self.entry_count.to_le_bytes());
+        footer.extend_from_slice(&(self.min_key.len() as u32).to_le_bytes());
+        footer.extend_from_slice(&self.min_key);
+        footer.extend_from_slice(&(self.max_key.len() as u32).to_le_bytes());
+        footer.extend_from_slice(&self.max_key);
+        footer.extend_from_slice(&self.index_interval.to_le_bytes());
+        footer.push(self.index_format.tag());
+        if let Some(max_timestamp) = self.max_timestamp {
+            footer.extend_from_slice(&max_timestamp.to_le_bytes());
+        }
+
+        let checksum = crc32fast::hash(&footer);
+        footer.extend_from_slice(&(footer.len() as u32).to_le_bytes());
+        footer.extend_from_slice(&checksum.to_le_bytes());
+        footer.extend_from_slice(&self.version.to_le_bytes());
+        footer.extend_from_slice(SSTABLE_MAGIC);
+        footer
+    }
+
+    /// Read the footer at the end of the SSTable `file`, `None` when the file has no magic (a
+    /// version 1 SSTable).
+    pub(crate) async fn read_from(path: &Path, file: &dyn ReadableFile) -> Result<Option<Self>> {
+        let file_len = file.size().await?;
+        if file_len < TRAILER_LEN {
+            return Ok(None);
+        }
+
+        let trailer = file
+            .read_at(file_len - TRAILER_LEN, TRAILER_LEN)
+            .await
+            .context("read sstable trailer")?;
+        if &trailer[10..] != SSTABLE_MAGIC {
+            return Ok(None);
+        }
+        let footer_len = u32::from_le_bytes(trailer[..4].try_into()?) as u64;
+        let checksum = u32::from_le_bytes(trailer[4..8].try_into()?);
+        let version = u16::from_le_bytes(trailer[8..10].try_into()?);
+        if !(SSTABLE_VERSION_FOOTER..=SSTABLE_VERSION).contains(&version) {
+            return Err(Error::UnsupportedSSTableVersion {
+                found: version,
+                supported: SSTABLE_VERSION,
+            }
+            .into());
+        }
+
+        let footer_offset = (file_len - TRAILER_LEN)
+            .checked_sub(footer_len)
+            .ok_or_else(|| corruption(path, 0, "footer length exceeds the file"))?;
+        let footer = file
+            .read_at(footer_offset, footer_len)
+            .await
+            .context("read sstable footer")?;
+        if crc32fast::hash(&footer) != checksum {
+            return Err(corruption(path, footer_offset, "footer checksum mismatch").into());
+        }
+
+        Self::decode(version, &footer)
+            .filter(|f| f.index_offset.checked_add(f.index_len) == Some(footer_offset))
+            .map(Some)
+            .ok_or_else(|| corruption(path, footer_offset, "malformed footer").into())
+    }
+
+    /// The footer at the very end of `bytes`, `None` when there is none or it is damaged. Finds
+    /// the footers an append leaves behind amid the records.
+    pub(crate) fn from_trailing_bytes(bytes: &[u8]) -> Option<Self> {
+        let (rest, trailer) = bytes.split_last_chunk::<{ TRAILER_LEN as usize }>()?;
+        if &trailer[10..] != SSTABLE_MAGIC {
+            return None;
+        }
+        let footer_len = u32::from_le_bytes(trailer[..4].try_into().ok()?) as usize;
+        let checksum = u32::from_le_bytes(trailer[4..8].try_into().ok()?);
+        let version = u16::from_le_bytes(trailer[8..10].try_into().ok()?);
+        let footer_offset = rest.len().checked_sub(footer_len)?;
+        let footer = &rest[footer_offset..];
+        if crc32fast::hash(footer) != checksum {
+            return None;
+        }
+        Self::decode(version, footer)
+            .filter(|f| f.index_offset.checked_add(f.index_len) == Some(footer_offset as u64))
+    }
+
+    fn decode(version: u16, mut bytes: &[u8]) -> Option<Self> {
+        let index_offset = u64::from_le_bytes(take(&mut bytes, 8)?.try_into().ok()?);
+        let index_len = u64::from_le_bytes(take(&mut bytes, 8)?.try_into().ok()?);
+        let entry_count = u64::from_le_bytes(take(&mut bytes, 8)?.try_into().ok()?);
+        let min_key_len = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+        let min_key = take(&mut bytes, min_key_len as usize)?.to_vec();
+        let max_key_len = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+        let max_key = take(&mut bytes, max_key_len as usize)?.to_vec();
+        let index_interval = match bytes.is_empty() {
+            true => 1,
+            false => u32::from_le_bytes(take(&mut bytes, 4)?.try_into().ok()?),
+        };
+        let index_format = match bytes.is_empty() {
+            true => IndexFormat::Bincode,
+            false => IndexFormat::from_tag(take(&mut bytes, 1)?[0])?,
+        };
+        let max_timestamp = match bytes.is_empty() {
+            true => None,
+            false => Some(u128::from_le_bytes(take(&mut bytes, 16)?.try_into().ok()?)),
+        };
+        (bytes.is_empty() && index_interval > 0).then_some(Self {
+            version,
+            index_offset,
+            index_len,
+            entry_count,
+            min_key,
+            max_key,
+            index_interval,
+            index_format,
+            max_timestamp,
+        })
+    }
+
+    /// Read the index block the footer points at.
+    pub(crate) async fn read_index_block(&self, file: &dyn ReadableFile) -> Result<Vec<u8>> {
+        file.read_at(self.index_offset, self.index_len)
+            .await
+            .context("read sstable index block")
+    }
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if bytes.len() < len {
+        return None;
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Some(head)
+}
+
+pub(crate) fn corruption(path: &Path, offset: u64, reason: &str) -> Error {
+    Error::Corruption {
+        path: path.to_owned(),
+        offset,
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::storage::{LocalFs, Storage};
+
+    #[tokio::test]
+    async fn it_works() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_footer")?;
+        let path = temp_dir.path().join("test.db");
+
+        let footer = SSTableFooter {
+            version: SSTABLE_VERSION,
+            index_offset: 5,
+            index_len: 3,
+            entry_count: 1,
+            min_key: b"a".to_vec(),
+            max_key: b"abc".to_vec(),
+            index_interval: 16,
+            index_format: IndexFormat::Sorted,
+            max_timestamp: Some(7),
+        };
+        let mut bytes = b"data_idx".to_vec();
+        bytes.extend_from_slice(&footer.encode());
+        tokio::fs::write(&path, &bytes).await?;
+
+        let file = LocalFs.open_read(&path).await?;
+        assert_eq!(
+            SSTableFooter::read_from(&path, file.as_ref()).await?,
+            Some(footer)
+        );
+
+        // a file without the magic is a version 1 SSTable
+        tokio::fs::write(&path, b"no footer here at all").await?;
+        let file = LocalFs.open_read(&path).await?;
+        assert_eq!(SSTableFooter::read_from(&path, file.as_ref()).await?, None);
+
+        // a damaged footer is corruption
+        bytes[10] ^= 1;
+        tokio::fs::write(&path, &bytes).await?;
+        let file = LocalFs.open_read(&path).await?;
+        let err = SSTableFooter::read_from(&path, file.as_ref())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Corruption { .. })
+        ));
+
+        // as is a newer version
+        let version_offset = bytes.len() - SSTABLE_MAGIC.len() - 2;
+        bytes[version_offset] = SSTABLE_VERSION as u8 + 1;
+        tokio::fs::write(&path, &bytes).await?;
+        let file = LocalFs.open_read(&path).await?;
+        let err = SSTableFooter::read_from(&path, file.as_ref())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::UnsupportedSSTableVersion { found, .. }) if *found == SSTABLE_VERSION + 1
+        ));
+
+        temp_dir.close()?;
+        Ok(())
+    }
+}
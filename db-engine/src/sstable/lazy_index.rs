@@ -0,0 +1,153 @@
+use anyhow::Result;
+use std::{
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{prelude::*, storage::ReadableFile};
+
+use super::{
+    footer::{corruption, SSTableFooter},
+    sstable_index::{decode_sorted_entry, SORTED_INDEX_HEADER_LEN, SORTED_INDEX_POSITION_LEN},
+};
+
+/// An index block of the [sorted format](super::sstable_index::IndexFormat::Sorted) left in the
+/// file, a lookup binary searches it with O(log n) reads instead of loading it.
+pub(crate) struct LazyIndex {
+    path: PathBuf,
+    file: Arc<dyn ReadableFile>,
+    /// Where the index block starts in the file
+    offset: u64,
+    block_len: u64,
+    count: u64,
+}
+
+impl LazyIndex {
+    /// Open the index block `footer` points at, checking its entry count and its first and last
+    /// key against the footer.
+    pub(crate) async fn open(
+        path: &Path,
+        file: Arc<dyn ReadableFile>,
+        footer: &SSTableFooter,
+    ) -> Result<Self> {
+        let mut index = Self {
+            path: path.to_owned(),
+            file,
+            offset: footer.index_offset,
+            block_len: footer.index_len,
+            count: 0,
+        };
+        if index.block_len < SORTED_INDEX_HEADER_LEN {
+            return Err(Error::CorruptIndex(index.path).into());
+        }
+        let header = index
+            .file
+            .read_at(index.offset, SORTED_INDEX_HEADER_LEN)
+            .await?;
+        index.count = u64::from_le_bytes(header.try_into().unwrap_or_default());
+
+        // a sparse index holds the first key and every `index_interval`th one after it
+        let expected_count = footer.entry_count.div_ceil(footer.index_interval.into());
+        let table_len = index
+            .count
+            .checked_mul(SORTED_INDEX_POSITION_LEN)
+            .and_then(|len| len.checked_add(SORTED_INDEX_HEADER_LEN));
+        if index.count != expected_count || table_len.is_none_or(|len| len > index.block_len) {
+            return Err(Error::CorruptIndex(index.path).into());
+        }
+        if index.count > 0 {
+            let (min_key, _) = index.entry(0).await?;
+            let (max_key, _) = index.entry(index.count - 1).await?;
+            if min_key != footer.min_key
+                || (footer.index_interval == 1 && max_key != footer.max_key)
+            {
+                return Err(Error::CorruptIndex(index.path).into());
+            }
+        }
+
+        Ok(index)
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.count
+    }
+
+    /// The key and the offset of the `position`th entry
+    pub(crate) async fn entry(&self, position: u64) -> Result<(Vec<u8>, u64)> {
+        // the position of the next entry, or the end of the block, is where this one ends
+        let table_offset = SORTED_INDEX_HEADER_LEN + position * SORTED_INDEX_POSITION_LEN;
+        let is_last = position + 1 >= self.count;
+        let table_len = SORTED_INDEX_POSITION_LEN * if is_last { 1 } else { 2 };
+        let table = self
+            .file
+            .read_at(self.offset + table_offset, table_len)
+            .await?;
+        let start = u64::from_le_bytes(table[..8].try_into()?);
+        let end = match is_last {
+            true => self.block_len,
+            false => u64::from_le_bytes(table[8..].try_into()?),
+        };
+        if start > end || end > self.block_len {
+            return Err(self.corruption(table_offset, "index entry out of the index block"));
+        }
+
+        let entry = self.file.read_at(self.offset + start, end - start).await?;
+        match decode_sorted_entry(&entry) {
+            Some((key, offset)) if 4 + key.len() + 8 == entry.len() => Ok((key.to_vec(), offset)),
+            _ => Err(self.corruption(start, "malformed index entry")),
+        }
+    }
+
+    /// Position of the first key not before `start`
+    pub(crate) async fn position(&self, start: Bound<&[u8]>) -> Result<u64> {
+        let (key, inclusive) = match start {
+            Bound::Included(key) => (key, true),
+            Bound::Excluded(key) => (key, false),
+            Bound::Unbounded => return Ok(0),
+        };
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let (mid_key, _) = self.entry(mid).await?;
+            let before = match inclusive {
+                true => mid_key.as_slice() < key,
+                false => mid_key.as_slice() <= key,
+            };
+            match before {
+                true => low = mid + 1,
+                false => high = mid,
+            }
+        }
+        Ok(low)
+    }
+
+    /// The offset of the record of `key`
+    pub(crate) async fn get(&self, key: &[u8]) -> Result<Option<u64>> {
+        let position = self.position(Bound::Included(key)).await?;
+        if position >= self.count {
+            return Ok(None);
+        }
+        let (found, offset) = self.entry(position).await?;
+        Ok((found == key).then_some(offset))
+    }
+
+    /// The offset of the greatest key not after `start`, or of the first key
+    pub(crate) async fn start_offset(&self, start: Bound<&[u8]>) -> Result<Option<u64>> {
+        if self.count == 0 {
+            return Ok(None);
+        }
+        let position = match start {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                self.position(Bound::Excluded(key)).await?.saturating_sub(1)
+            }
+            Bound::Unbounded => 0,
+        };
+        Ok(Some(self.entry(position).await?.1))
+    }
+
+    /// `offset` is within the index block
+    fn corruption(&self, offset: u64, reason: &str) -> anyhow::Error {
+        corruption(&self.path, self.offset + offset, reason).into()
+    }
+}
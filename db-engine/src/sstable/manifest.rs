@@ -0,0 +1,242 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::sync::Mutex;
+
+use crate::storage::{self, Storage};
+
+use super::{
+    footer::corruption, get_bloom_filter_path, get_index_path, list_level_files, with_tmp_suffix,
+};
+
+/// Name of the file listing the live SSTables of a database directory.
+pub(crate) const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+const MANIFEST_MAGIC: &[u8; 8] = b"SDBMAN\0\x01";
+
+const MANIFEST_VERSION: u16 = 1;
+
+/// Serializes the updates of the manifests, a flush and a compaction both read, change and
+/// write one.
+static MANIFEST_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// The SSTable files of a directory which hold its data, the others on disk are the leftovers
+/// of a flush or a compaction interrupted before it updated the manifest. A directory without
+/// one, which the database has not opened yet, takes every SSTable file as live.
+///
+/// Layout: the magic, version (u16), file count (u32), then the length (u32) and the path of
+/// every file relative to the directory, followed by a CRC32 of all that, little endian. It is
+/// replaced by writing a temporary file and renaming it over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    dir: PathBuf,
+    file_names: BTreeSet<String>,
+}
+
+impl Manifest {
+    /// The manifest of `dir` in `storage`, `None` when it has none.
+    pub(crate) async fn load(dir: &Path, storage: &dyn Storage) -> Result<Option<Self>> {
+        let path = dir.join(MANIFEST_FILE_NAME);
+        let bytes = match storage::read(storage, &path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("read manifest {:?}", path)),
+        };
+        let Some((content, checksum)) = bytes.split_last_chunk::<4>() else {
+            return Err(corruption(&path, 0, "truncated manifest").into());
+        };
+        if crc32fast::hash(content).to_le_bytes() != *checksum {
+            return Err(corruption(&path, 0, "manifest checksum mismatch").into());
+        }
+        let file_names =
+            decode(content).ok_or_else(|| corruption(&path, 0, "malformed manifest"))?;
+        Ok(Some(Self {
+            dir: dir.to_owned(),
+            file_names,
+        }))
+    }
+
+    /// Whether the SSTable at `path` is live
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        self.file_names.contains(&relative_name(&self.dir, path))
+    }
+
+    /// Swap the SSTables `removed` for `added` in the manifest of `dir`, nothing to do when it
+    /// has none.
+    pub(crate) async fn update(
+        dir: &Path,
+        added: &[PathBuf],
+        removed: &[PathBuf],
+        storage: &dyn Storage,
+    ) -> Result<()> {
+        let _lock = MANIFEST_LOCK.lock().await;
+        let Some(mut manifest) = Self::load(dir, storage).await? else {
+            return Ok(());
+        };
+        for path in removed {
+            manifest.file_names.remove(&relative_name(dir, path));
+        }
+        manifest
+            .file_names
+            .extend(added.iter().map(|path| relative_name(dir, path)));
+        manifest.persist(storage).await
+    }
+
+    /// Check the SSTables of `dir` against its manifest when the database opens: the ones
+    /// missing from it are removed with their bloom filter and `.idx` file. A directory without
+    /// a manifest gets one listing all its SSTables.
+    pub(crate) async fn recover(dir: &Path, storage: &dyn Storage) -> Result<()> {
+        let _lock = MANIFEST_LOCK.lock().await;
+        let files = list_level_files(dir, "db", storage).await?.concat();
+        let Some(mut manifest) = Self::load(dir, storage).await? else {
+            tracing::info!("Creating the manifest of {:?}", dir);
+            let manifest = Self {
+                dir: dir.to_owned(),
+                file_names: files.iter().map(|path| relative_name(dir, path)).collect(),
+            };
+            return manifest.persist(storage).await;
+        };
+
+        for path in files.iter().filter(|path| !manifest.contains(path)) {
+            tracing::warn!("Removing the SSTable {:?}, it is not in the manifest", path);
+            for related_path in [get_bloom_filter_path(path)?, get_index_path(path)?] {
+                if storage::exists(storage, &related_path).await? {
+                    storage.remove(&related_path).await?;
+                }
+            }
+            storage.remove(path).await?;
+        }
+        let mut missing = Vec::new();
+        for name in manifest.file_names.iter() {
+            if !storage::exists(storage, &dir.join(name)).await? {
+                missing.push(name.clone());
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        tracing::error!("The SSTables {:?} of the manifest are missing", missing);
+        for name in missing {
+            manifest.file_names.remove(&name);
+        }
+        manifest.persist(storage).await
+    }
+
+    async fn persist(&self, storage: &dyn Storage) -> Result<()> {
+        let mut bytes = Vec::from(*MANIFEST_MAGIC);
+        bytes.extend_from_slice(&MANIFEST_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.file_names.len() as u32).to_le_bytes());
+        for name in self.file_names.iter() {
+            bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(name.as_bytes());
+        }
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_le_bytes());
+
+        let path = self.dir.join(MANIFEST_FILE_NAME);
+        let tmp_path = with_tmp_suffix(&path);
+        storage::write(storage, &tmp_path, &bytes)
+            .await
+            .context("write manifest to file")?;
+        storage
+            .rename(&tmp_path, &path)
+            .await
+            .context("rename manifest into place")?;
+        storage.sync(&self.dir).await.context("sync database dir")?;
+        Ok(())
+    }
+}
+
+/// `path` relative to `dir` with `/` separators, the same on every platform
+fn relative_name(dir: &Path, path: &Path) -> String {
+    path.strip_prefix(dir)
+        .unwrap_or(path)
+        .iter()
+        .map(|component| component.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn decode(bytes: &[u8]) -> Option<BTreeSet<String>> {
+    let bytes = bytes.strip_prefix(MANIFEST_MAGIC)?;
+    let (version, bytes) = bytes.split_first_chunk::<2>()?;
+    if u16::from_le_bytes(*version) != MANIFEST_VERSION {
+        return None;
+    }
+    let (count, mut bytes) = bytes.split_first_chunk::<4>()?;
+    let mut file_names = BTreeSet::new();
+    for _ in 0..u32::from_le_bytes(*count) {
+        let (len, rest) = bytes.split_first_chunk::<4>()?;
+        let len = u32::from_le_bytes(*len) as usize;
+        let name = rest.get(..len)?;
+        file_names.insert(String::from_utf8(name.to_vec()).ok()?);
+        bytes = &rest[len..];
+    }
+    bytes.is_empty().then_some(file_names)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use crate::{prelude::*, storage::LocalFs};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_works() -> Result<()> {
+        let temp_dir = TempDir::new("manifest")?;
+        let dir = temp_dir.path();
+        for name in ["1.db", "2.db", "3.db"] {
+            std::fs::write(dir.join(name), b"sstable")?;
+        }
+
+        // nothing to update without a manifest
+        Manifest::update(dir, &[dir.join("4.db")], &[], &LocalFs).await?;
+        assert_eq!(Manifest::load(dir, &LocalFs).await?, None);
+
+        // which lists every SSTable once created
+        Manifest::recover(dir, &LocalFs).await?;
+        let manifest = Manifest::load(dir, &LocalFs).await?.unwrap();
+        assert!(["1.db", "2.db", "3.db"]
+            .iter()
+            .all(|name| manifest.contains(&dir.join(name))));
+
+        Manifest::update(
+            dir,
+            &[dir.join("4.db")],
+            &[dir.join("1.db"), dir.join("2.db")],
+            &LocalFs,
+        )
+        .await?;
+        let manifest = Manifest::load(dir, &LocalFs).await?.unwrap();
+        assert!(!manifest.contains(&dir.join("1.db")));
+        assert!(manifest.contains(&dir.join("4.db")));
+
+        // the files left out of it are removed, the missing ones dropped from it
+        std::fs::write(dir.join("1.db.bf"), b"bloom filter")?;
+        Manifest::recover(dir, &LocalFs).await?;
+        assert!(!dir.join("1.db").exists() && !dir.join("1.db.bf").exists());
+        assert!(!dir.join("2.db").exists());
+        assert!(dir.join("3.db").exists());
+        let manifest = Manifest::load(dir, &LocalFs).await?.unwrap();
+        assert!(!manifest.contains(&dir.join("4.db")));
+        assert!(manifest.contains(&dir.join("3.db")));
+
+        // a damaged manifest is corruption
+        let path = dir.join(MANIFEST_FILE_NAME);
+        let mut bytes = std::fs::read(&path)?;
+        bytes[10] ^= 1;
+        std::fs::write(&path, bytes)?;
+        let err = Manifest::load(dir, &LocalFs).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Corruption { .. })
+        ));
+
+        temp_dir.close()?;
+        Ok(())
+    }
+}
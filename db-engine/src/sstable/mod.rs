@@ -1,3 +1,8 @@
+mod block;
+mod bloom_filter;
+mod footer;
+mod lazy_index;
+mod manifest;
 mod sstable_index;
 mod sstable_querier;
 mod sstable_reader;
@@ -8,11 +13,68 @@ pub use self::sstable_querier::*;
 pub use self::sstable_reader::*;
 pub use self::sstable_writer::*;
 
-use crate::prelude::*;
+pub(crate) use self::manifest::Manifest;
+
+use crate::{
+    prelude::*,
+    storage::{self, ReadableFile, Storage},
+};
+use anyhow::Result;
+use std::ops::Bound;
 use std::path::Path;
 use std::path::PathBuf;
+use tokio::io;
+
+use self::footer::SSTableFooter;
+pub(crate) use self::footer::SSTABLE_MAGIC;
+
+/// Number of levels of a database directory, see [`level_dir`].
+pub(crate) const LEVEL_COUNT: usize = 2;
+
+/// The subdirectory of `dir` holding the SSTables of `level`. The MemTables are flushed to
+/// level 0, whose files may overlap each other, and a leveled compaction merges them into the
+/// files of level 1, which do not.
+pub(crate) fn level_dir(dir: &Path, level: usize) -> PathBuf {
+    dir.join(format!("L{}", level))
+}
+
+/// The files with `ext` of every level of `dir` in `storage`. The ones at the root of `dir`,
+/// flushed before there were levels or written by a compaction of level 0, belong to level 0.
+pub(crate) async fn list_level_files(
+    dir: &Path,
+    ext: &str,
+    storage: &dyn Storage,
+) -> Result<[Vec<PathBuf>; LEVEL_COUNT]> {
+    let mut levels: [Vec<PathBuf>; LEVEL_COUNT] = Default::default();
+    levels[0] = crate::utils::list_files_with_ext(dir, ext, storage).await?;
+    for (level, files) in levels.iter_mut().enumerate() {
+        let level_dir = level_dir(dir, level);
+        if storage
+            .metadata(&level_dir)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir)
+        {
+            files.extend(crate::utils::list_files_with_ext(&level_dir, ext, storage).await?);
+        }
+    }
+    Ok(levels)
+}
+
+/// [`list_level_files`], blocking.
+#[cfg(test)]
+pub(crate) fn get_level_files(dir: &Path, ext: &str) -> Result<[Vec<PathBuf>; LEVEL_COUNT]> {
+    let mut levels: [Vec<PathBuf>; LEVEL_COUNT] = Default::default();
+    levels[0] = crate::utils::get_files_with_ext(dir, ext)?;
+    for (level, files) in levels.iter_mut().enumerate() {
+        let level_dir = level_dir(dir, level);
+        if level_dir.is_dir() {
+            files.extend(crate::utils::get_files_with_ext(&level_dir, ext)?);
+        }
+    }
+    Ok(levels)
+}
 
-fn get_index_path(db_path: &Path) -> anyhow::Result<PathBuf> {
+pub(crate) fn get_index_path(db_path: &Path) -> anyhow::Result<PathBuf> {
     let base_path = db_path
         .parent()
         .ok_or(Error::InvalidPath(db_path.to_path_buf()))?;
@@ -23,12 +85,159 @@ fn get_index_path(db_path: &Path) -> anyhow::Result<PathBuf> {
     Ok(index_path)
 }
 
+/// Load the index of the SSTable `file` together with its footer, the index is embedded in
+/// the file since version 2 and read from the `.idx` file next to it for version 1, which has
+/// no footer
+async fn load_index(
+    path: &Path,
+    file: &dyn ReadableFile,
+    storage: &dyn Storage,
+) -> Result<(SSTableIndex, Option<SSTableFooter>)> {
+    let builder = SSTableIndexBuilder::new(get_index_path(path)?);
+    let Some(footer) = SSTableFooter::read_from(path, file).await? else {
+        return Ok((builder.indexes(storage).await?.build(), None));
+    };
+
+    let index = builder
+        .block(&footer.read_index_block(file).await?, footer.index_format)
+        .map_err(|_| Error::CorruptIndex(path.to_owned()))?
+        .build();
+    if !index_matches_footer(&index, &footer) {
+        return Err(Error::CorruptIndex(path.to_owned()).into());
+    }
+
+    Ok((index, Some(footer)))
+}
+
+/// Whether `index` holds as many keys as `footer` counts, starting and ending with its keys
+fn index_matches_footer(index: &SSTableIndex, footer: &SSTableFooter) -> bool {
+    let min_key = index.first_key().unwrap_or_default();
+    let max_key = index.last_key().unwrap_or_default();
+    // a sparse index holds the first key and every `index_interval`th one after it
+    let matches_footer = match footer.index_interval {
+        1 => index.len() as u64 == footer.entry_count && max_key == footer.max_key,
+        interval => index.len() as u64 == footer.entry_count.div_ceil(interval.into()),
+    };
+    matches_footer && min_key == footer.min_key
+}
+
+/// Whether the keys `min..=max` of an SSTable overlap `bounds`
+fn key_range_overlaps(min: &[u8], max: &[u8], bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> bool {
+    let after_start = match bounds.0 {
+        Bound::Included(start) => max >= start,
+        Bound::Excluded(start) => max > start,
+        Bound::Unbounded => true,
+    };
+    let before_end = match bounds.1 {
+        Bound::Included(end) => min <= end,
+        Bound::Excluded(end) => min < end,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+/// `path` with `.tmp` appended, where a file is written before being renamed into place. The
+/// SSTables are listed by their extension, so these are never picked up.
+pub(crate) fn with_tmp_suffix(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".tmp");
+    path.with_file_name(file_name)
+}
+
+/// Remove the `.tmp` files an interrupted flush or compaction left behind in `dir`.
+pub(crate) async fn remove_tmp_files(dir: &Path, storage: &dyn Storage) -> Result<()> {
+    for path in list_level_files(dir, "tmp", storage)
+        .await?
+        .into_iter()
+        .flatten()
+    {
+        tracing::info!("Removing the unfinished SSTable file {:?}", path);
+        storage.remove(&path).await?;
+    }
+    Ok(())
+}
+
+/// Remove the `.idx` files in `dir` whose SSTable is gone, which the compactions before they
+/// were cleaned up with their SSTables left behind.
+pub(crate) async fn remove_orphaned_index_files(dir: &Path, storage: &dyn Storage) -> Result<()> {
+    for path in crate::utils::list_files_with_ext(dir, "idx", storage).await? {
+        let db_path = path.with_extension("");
+        if db_path.extension().is_some_and(|e| e == "db")
+            && !storage::exists(storage, &db_path).await?
+        {
+            tracing::warn!("Removing the index file {:?}, its SSTable is gone", path);
+            storage.remove(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the SSTable at `db_path` of `storage` has an index, embedded in the file since
+/// version 2 and in the `.idx` file next to it for version 1
+pub(crate) async fn has_index(db_path: &Path, storage: &dyn Storage) -> bool {
+    let embedded = async {
+        let file = storage.open_read(db_path).await?;
+        let Some(offset) = file.size().await?.checked_sub(SSTABLE_MAGIC.len() as u64) else {
+            return Ok(false);
+        };
+        let magic = file.read_at(offset, SSTABLE_MAGIC.len() as u64).await?;
+        io::Result::Ok(magic == SSTABLE_MAGIC)
+    };
+    if embedded.await.unwrap_or(false) {
+        return true;
+    }
+    match get_index_path(db_path) {
+        Ok(path) => storage::exists(storage, &path).await.unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+pub(crate) fn get_bloom_filter_path(db_path: &Path) -> anyhow::Result<PathBuf> {
+    let db_file_name = db_path
+        .file_name()
+        .ok_or(Error::InvalidPath(db_path.to_path_buf()))?;
+    Ok(db_path.with_file_name(format!("{}.bf", db_file_name.to_string_lossy())))
+}
+
 #[cfg(test)]
 mod tests {
     use tempdir::TempDir;
 
-    use super::{sstable_reader::SSTableReader, sstable_writer::SSTableWriter, *};
+    use super::{
+        sstable_index::IndexFormat,
+        sstable_querier::SSTableQuerier,
+        sstable_reader::{IndexMode, SSTableReader, SSTableReaderOptions},
+        sstable_writer::SSTableWriter,
+        *,
+    };
+    use crate::{
+        compression::Codec,
+        entries::DEFAULT_MAX_FIELD_LEN,
+        storage::{LocalFs, Storage},
+    };
     use anyhow::Result;
+    use tokio::{fs::File, io::AsyncWriteExt};
+    use tokio_stream::StreamExt;
+
+    const LAZY: SSTableReaderOptions = SSTableReaderOptions {
+        index_mode: IndexMode::Lazy,
+        rebuild_corrupt_index: false,
+        max_field_len: DEFAULT_MAX_FIELD_LEN,
+    };
+
+    /// Write the entries as a version 1 SSTable, with its index in a `.idx` file
+    pub(crate) async fn write_legacy_sstable(path: &Path, entries: &[Entry]) -> Result<()> {
+        let mut file = File::create(path).await?;
+        let mut index = SSTableIndexBuilder::new(get_index_path(path)?).build();
+        let mut offset = 0;
+        for entry in entries {
+            entry.write_to(&mut file).await?;
+            index.insert(&entry.key, offset);
+            offset += entry.encoded_len() as u64;
+        }
+        file.flush().await?;
+        index.persist().await
+    }
 
     #[tokio::test]
     async fn it_creates_new_sstable_file() -> Result<()> {
@@ -49,7 +258,7 @@ mod tests {
             .await?;
 
         // persist to file
-        let mut sst_reader = SSTableReader::new(&path).await?;
+        let sst_reader = SSTableReader::new(&path).await?;
         assert_entry(&sst_reader.get(b"test1").await.unwrap(), &entry_1);
         assert_entry(&sst_reader.get(b"test2").await.unwrap(), &entry_2);
         assert!(sst_reader.get(b"test3").await.is_none());
@@ -67,14 +276,14 @@ mod tests {
         let mut sst_writer = SSTableWriter::new(&path).await?;
         let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello").map(|i| i.to_vec()), 1);
         sst_writer.set(&entry_1).await?.flush().await?;
-        let mut sst_reader = SSTableReader::new(&path).await?;
+        let sst_reader = SSTableReader::new(&path).await?;
         assert_entry(&sst_reader.get(b"test1").await.unwrap(), &entry_1);
 
         // load from existing file
         let mut new_sst_writer = SSTableWriter::new(&path).await?;
         let entry_2 = Entry::new(b"test2".to_vec(), Some(b"world").map(|i| i.to_vec()), 2);
         new_sst_writer.set(&entry_2).await?.flush().await?;
-        let mut new_sst_reader = SSTableReader::new(&path).await?;
+        let new_sst_reader = SSTableReader::new(&path).await?;
         assert_entry(&new_sst_reader.get(b"test1").await.unwrap(), &entry_1);
         assert_entry(&new_sst_reader.get(b"test2").await.unwrap(), &entry_2);
         assert!(new_sst_reader.get(b"test3").await.is_none());
@@ -82,6 +291,852 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn it_reads_back_every_key_after_appending() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_append")?;
+        let path = temp_dir.path().join("test.db");
+
+        let entries = (0..8u8)
+            .map(|i| {
+                let value = (i % 3 != 2).then(|| vec![b'v'; i as usize * 7]);
+                Entry::new(format!("key{}", i).into_bytes(), value, i as u128)
+            })
+            .collect::<Vec<_>>();
+
+        // several entries in one go
+        let mut sst_writer = SSTableWriter::new(&path).await?;
+        for entry in entries[..5].iter() {
+            sst_writer.set(entry).await?;
+        }
+        sst_writer.flush().await?;
+
+        // appended to the existing file
+        let mut sst_writer = SSTableWriter::new(&path).await?;
+        for entry in entries[5..].iter() {
+            sst_writer.set(entry).await?;
+        }
+        sst_writer.flush().await?;
+
+        let sst_reader = SSTableReader::new(&path).await?;
+        for entry in entries.iter() {
+            assert_entry(&sst_reader.get(&entry.key).await.unwrap(), entry);
+        }
+        let ranged = sst_reader
+            .range((std::ops::Bound::Unbounded, std::ops::Bound::Unbounded))
+            .await;
+        assert_eq!(ranged.len(), entries.len());
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_does_not_serve_another_key_from_a_corrupted_index() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_corrupted_index")?;
+        let path = temp_dir.path().join("test.db");
+
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        let entry_2 = Entry::new(b"test2".to_vec(), Some(b"world".to_vec()), 2);
+        write_legacy_sstable(&path, &[entry_1.clone(), entry_2]).await?;
+
+        // point test2 at the entry of test1
+        let mut index = SSTableIndexBuilder::new(get_index_path(&path)?)
+            .indexes(&LocalFs)
+            .await?
+            .build();
+        index.insert(b"test2", 0);
+        index.persist().await?;
+
+        let sst_reader = SSTableReader::new(&path).await?;
+        assert_entry(&sst_reader.get(b"test1").await.unwrap(), &entry_1);
+        assert!(sst_reader.get(b"test2").await.is_none());
+        let err = sst_reader.try_get(b"test2").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Corruption { offset: 0, .. })
+        ));
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_rejects_keys_out_of_order() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_key_order")?;
+        let path = temp_dir.path().join("test.db");
+
+        let entry_a = Entry::new(b"a".to_vec(), Some(b"hello".to_vec()), 1);
+        let entry_b = Entry::new(b"b".to_vec(), Some(b"world".to_vec()), 2);
+        let mut sst_writer = SSTableWriter::new(&path).await?;
+        sst_writer.set(&entry_b).await?;
+        for entry in [&entry_a, &entry_b] {
+            let err = sst_writer.set(entry).await.err().unwrap();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::OutOfOrderKey { prev, next }) if prev == b"b" && *next == entry.key
+            ));
+        }
+        assert_eq!(sst_writer.entries_written(), 1);
+        assert_eq!(
+            sst_writer.bytes_written(),
+            entry_b.encoded_len_v2() as u64 + 4
+        );
+
+        // a duplicate key is let through on request, the later record wins
+        let updated_b = Entry::new(b"b".to_vec(), None, 3);
+        sst_writer.set_allow_duplicates(true);
+        sst_writer.set(&updated_b).await?;
+        assert!(sst_writer.set(&entry_a).await.is_err());
+        sst_writer.flush().await?;
+        assert_eq!(sst_writer.entries_written(), 2);
+
+        let sst_reader = SSTableReader::new(&path).await?;
+        assert_entry(&sst_reader.get(b"b").await.unwrap(), &updated_b);
+        assert!(sst_reader.get(b"a").await.is_none());
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_embeds_the_index_in_the_file() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_v2")?;
+        let path = temp_dir.path().join("test.db");
+
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        let entry_2 = Entry::new(b"test2".to_vec(), None, 2);
+        SSTableWriter::new(&path)
+            .await?
+            .set(&entry_1)
+            .await?
+            .set(&entry_2)
+            .await?
+            .flush()
+            .await?;
+        assert!(!get_index_path(&path)?.exists());
+
+        let file = LocalFs.open_read(&path).await?;
+        let footer = SSTableFooter::read_from(&path, file.as_ref())
+            .await?
+            .unwrap();
+        // every record is followed by its checksum
+        assert_eq!(footer.version, footer::SSTABLE_VERSION_VARINT);
+        assert_eq!(
+            footer.index_offset,
+            (entry_1.encoded_len_v2() + entry_2.encoded_len_v2() + 8) as u64
+        );
+        assert_eq!(footer.entry_count, 2);
+        assert_eq!(footer.min_key, b"test1");
+        assert_eq!(footer.max_key, b"test2");
+
+        let sst_reader = SSTableReader::new(&path).await?;
+        assert_entry(&sst_reader.get(b"test1").await.unwrap(), &entry_1);
+        assert_entry(&sst_reader.get(b"test2").await.unwrap(), &entry_2);
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_reads_and_upgrades_version_1_files() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_v1")?;
+        let path = temp_dir.path().join("test.db");
+
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        write_legacy_sstable(&path, std::slice::from_ref(&entry_1)).await?;
+        let sst_reader = SSTableReader::new(&path).await?;
+        assert_entry(&sst_reader.get(b"test1").await.unwrap(), &entry_1);
+
+        // appending writes the index into the file and drops the idx file
+        let entry_2 = Entry::new(b"test2".to_vec(), Some(b"world".to_vec()), 2);
+        SSTableWriter::new(&path)
+            .await?
+            .set(&entry_2)
+            .await?
+            .flush()
+            .await?;
+        assert!(!get_index_path(&path)?.exists());
+
+        let sst_reader = SSTableReader::new(&path).await?;
+        assert_entry(&sst_reader.get(b"test1").await.unwrap(), &entry_1);
+        assert_entry(&sst_reader.get(b"test2").await.unwrap(), &entry_2);
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_reads_the_fixed_width_entries_of_older_versions() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_fixed_width")?;
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        let entry_2 = Entry::new(b"test2".to_vec(), None, 2);
+        let entry_3 = Entry::new(b"test3".to_vec(), Some(b"appended".to_vec()), 3);
+
+        for (version, codec) in [
+            (footer::SSTABLE_VERSION_CHECKSUM, Codec::None),
+            (footer::SSTABLE_VERSION_BLOCKS, Codec::Lz4),
+        ] {
+            let path = temp_dir.path().join(format!("{}.db", version));
+            let mut sst_writer = SSTableWriter::new(&path).await?;
+            sst_writer.set_compression(codec);
+            sst_writer.set_version(version);
+            sst_writer
+                .set(&entry_1)
+                .await?
+                .set(&entry_2)
+                .await?
+                .flush()
+                .await?;
+            // appending keeps the version
+            SSTableWriter::new(&path)
+                .await?
+                .set(&entry_3)
+                .await?
+                .flush()
+                .await?;
+
+            let file = LocalFs.open_read(&path).await?;
+            let footer = SSTableFooter::read_from(&path, file.as_ref())
+                .await?
+                .unwrap();
+            assert_eq!(footer.version, version);
+            let sst_reader = SSTableReader::new(&path).await?;
+            for entry in [&entry_1, &entry_2, &entry_3] {
+                assert_entry(&sst_reader.get(&entry.key).await.unwrap(), entry);
+            }
+            let ranged = sst_reader
+                .range((std::ops::Bound::Unbounded, std::ops::Bound::Unbounded))
+                .await;
+            assert_eq!(ranged.len(), 3);
+            let rebuilt =
+                SSTableIndex::rebuild_from_data(&path, &LocalFs, DEFAULT_MAX_FIELD_LEN).await?;
+            assert_eq!(rebuilt.len(), 3);
+        }
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_rejects_absurd_length_prefixes() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_length_prefix")?;
+        let path = temp_dir.path().join("test.db");
+
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello world".to_vec()), 1);
+        let entry_2 = Entry::new(b"test2".to_vec(), Some(b"world".to_vec()), 2);
+        SSTableWriter::new(&path)
+            .await?
+            .set(&entry_1)
+            .await?
+            .set(&entry_2)
+            .await?
+            .flush()
+            .await?;
+
+        // a key length of 2^60 bytes
+        let mut bytes = tokio::fs::read(&path).await?;
+        bytes[..9].copy_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x10]);
+        tokio::fs::write(&path, bytes).await?;
+
+        let sst_reader = SSTableReader::new(&path).await?;
+        let err = sst_reader.try_get(b"test1").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Corruption { offset: 0, reason, .. }) if reason.contains("Field length")
+        ));
+        assert_entry(&sst_reader.get(b"test2").await.unwrap(), &entry_2);
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_detects_and_skips_corrupted_entries() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_checksum")?;
+        let path = temp_dir.path().join("test.db");
+
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        let entry_2 = Entry::new(b"test2".to_vec(), Some(b"world".to_vec()), 2);
+        SSTableWriter::new(&path)
+            .await?
+            .set(&entry_1)
+            .await?
+            .set(&entry_2)
+            .await?
+            .flush()
+            .await?;
+
+        // flip a bit in the middle of the first value
+        let mut bytes = tokio::fs::read(&path).await?;
+        let value_offset = 1 + entry_1.key.len() + 1 + 1;
+        bytes[value_offset + 2] ^= 1;
+        tokio::fs::write(&path, bytes).await?;
+
+        let sst_reader = SSTableReader::new(&path).await?;
+        let err = sst_reader.try_get(b"test1").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Corruption { offset: 0, .. })
+        ));
+        assert!(sst_reader.get(b"test1").await.is_none());
+        assert_entry(&sst_reader.get(b"test2").await.unwrap(), &entry_2);
+
+        // the scan goes on after the corrupted entry
+        struct Collect<'a>(&'a mut Vec<Entry>);
+        #[async_trait::async_trait]
+        impl SSTableReaderScanHandler for Collect<'_> {
+            async fn handle(&mut self, entry: Entry) -> Result<()> {
+                self.0.push(entry);
+                Ok(())
+            }
+        }
+        let mut scanned = Vec::new();
+        sst_reader.scan(Collect(&mut scanned)).await?;
+        assert_eq!(scanned.len(), 1);
+        assert_entry(&scanned[0], &entry_2);
+
+        // as does the iterator, which hands the error over
+        let iterated = sst_reader.iter().collect::<Vec<_>>().await;
+        assert_eq!(iterated.len(), 2);
+        assert!(iterated[0].is_err());
+        assert_entry(iterated[1].as_ref().unwrap(), &entry_2);
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_compresses_the_records_in_blocks() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_blocks")?;
+        let raw_path = temp_dir.path().join("raw.db");
+        let path = temp_dir.path().join("test.db");
+
+        let entries = (0..500u32)
+            .map(|i| {
+                let value = (i % 10 != 9).then(|| format!("value of key {:05}", i).into_bytes());
+                Entry::new(format!("key{:05}", i).into_bytes(), value, i as u128)
+            })
+            .collect::<Vec<_>>();
+        let mut raw_writer = SSTableWriter::new(&raw_path).await?;
+        let mut sst_writer = SSTableWriter::new(&path).await?;
+        sst_writer.set_compression(Codec::Lz4);
+        for entry in entries.iter() {
+            raw_writer.set(entry).await?;
+            sst_writer.set(entry).await?;
+        }
+        raw_writer.flush().await?;
+        sst_writer.flush().await?;
+
+        let file = LocalFs.open_read(&path).await?;
+        let footer = SSTableFooter::read_from(&path, file.as_ref())
+            .await?
+            .unwrap();
+        assert_eq!(footer.version, footer::SSTABLE_VERSION_VARINT_BLOCKS);
+        assert!(footer.index_offset * 2 < tokio::fs::metadata(&raw_path).await?.len());
+
+        let sst_reader = SSTableReader::new(&path).await?;
+        for entry in entries.iter().rev() {
+            assert_entry(&sst_reader.get(&entry.key).await.unwrap(), entry);
+        }
+        let ranged = sst_reader
+            .range((std::ops::Bound::Unbounded, std::ops::Bound::Unbounded))
+            .await;
+        assert_eq!(ranged.len(), entries.len());
+
+        // appending keeps the block format
+        let entry = Entry::new(b"key99999".to_vec(), Some(b"appended".to_vec()), 999);
+        SSTableWriter::new(&path)
+            .await?
+            .set(&entry)
+            .await?
+            .flush()
+            .await?;
+        let sst_reader = SSTableReader::new(&path).await?;
+        assert_entry(&sst_reader.get(b"key99999").await.unwrap(), &entry);
+        assert_entry(&sst_reader.get(b"key00001").await.unwrap(), &entries[1]);
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_only_indexes_every_nth_key() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_sparse")?;
+
+        let entries = (0..100u32)
+            .map(|i| {
+                let value = (i % 10 != 9).then(|| format!("value of key {:05}", i).into_bytes());
+                Entry::new(format!("key{:05}", i).into_bytes(), value, i as u128)
+            })
+            .collect::<Vec<_>>();
+        for codec in [Codec::None, Codec::Lz4] {
+            let path = temp_dir.path().join(format!("{:?}.db", codec));
+            let mut sst_writer = SSTableWriter::new(&path).await?;
+            sst_writer.set_options(SSTableOptions {
+                compression: codec,
+                index_interval: 16,
+                ..Default::default()
+            });
+            for entry in entries.iter() {
+                sst_writer.set(entry).await?;
+            }
+            // the reader walks forward from an indexed key, so the keys must be ascending
+            assert!(sst_writer.set(&entries[0]).await.is_err());
+            sst_writer.flush().await?;
+
+            let file = LocalFs.open_read(&path).await?;
+            let footer = SSTableFooter::read_from(&path, file.as_ref())
+                .await?
+                .unwrap();
+            assert_eq!((footer.entry_count, footer.index_interval), (100, 16));
+            assert_eq!(footer.max_key, b"key00099");
+
+            let sst_reader = SSTableReader::new(&path).await?;
+            assert_eq!(sst_reader.index_interval(), 16);
+            for entry in entries.iter().rev() {
+                assert_entry(&sst_reader.get(&entry.key).await.unwrap(), entry);
+            }
+            for missing in [&b"key"[..], b"key00050a", b"key99999"] {
+                assert!(sst_reader.get(missing).await.is_none());
+            }
+            let ranged = sst_reader
+                .range((
+                    std::ops::Bound::Excluded(&b"key00017"[..]),
+                    std::ops::Bound::Included(&b"key00040"[..]),
+                ))
+                .await;
+            assert_eq!(ranged.len(), 23);
+            assert_entry(&ranged[0], &entries[18]);
+            assert_entry(&ranged[22], &entries[40]);
+
+            // the index cannot take the new keys of an append
+            assert!(SSTableWriter::new(&path).await.is_err());
+        }
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_dumps_the_records_and_reports_the_corrupted_ones() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_dump")?;
+
+        let entries = (0..100u32)
+            .map(|i| {
+                let value = (i % 10 != 9).then(|| format!("value of key {:05}", i).into_bytes());
+                Entry::new(format!("key{:05}", i).into_bytes(), value, i as u128)
+            })
+            .collect::<Vec<_>>();
+        for (codec, index_interval) in [(Codec::None, 1), (Codec::Lz4, 1), (Codec::None, 16)] {
+            let path = temp_dir
+                .path()
+                .join(format!("{:?}-{}.db", codec, index_interval));
+            let mut sst_writer = SSTableWriter::new(&path).await?;
+            sst_writer.set_options(SSTableOptions {
+                compression: codec,
+                index_interval,
+                ..Default::default()
+            });
+            for entry in entries.iter() {
+                sst_writer.set(entry).await?;
+            }
+            sst_writer.flush().await?;
+
+            let mut dump = Vec::new();
+            let summary = SSTableReader::new(&path)
+                .await?
+                .dump(&mut dump, true)
+                .await?;
+            assert_eq!((summary.records, summary.corruptions), (100, 0));
+            let dump = String::from_utf8(dump)?;
+            assert_eq!(dump.matches(" tombstone").count(), 10);
+            assert!(dump.contains("key \"key00000\" timestamp 0 value 18 bytes"));
+            assert!(dump.contains(
+                &b"value of key 00000"
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+            ));
+        }
+
+        // flip a byte of a value, the other records are still dumped
+        let path = temp_dir.path().join("None-1.db");
+        let mut bytes = tokio::fs::read(&path).await?;
+        let at = bytes
+            .windows(18)
+            .position(|window| window == b"value of key 00042")
+            .unwrap();
+        bytes[at] ^= 0xff;
+        tokio::fs::write(&path, bytes).await?;
+        let mut dump = Vec::new();
+        let summary = SSTableReader::new(&path)
+            .await?
+            .dump(&mut dump, false)
+            .await?;
+        assert_eq!((summary.records, summary.corruptions), (99, 1));
+        let dump = String::from_utf8(dump)?;
+        assert_eq!(
+            dump.lines()
+                .filter(|line| line.contains("CORRUPTED"))
+                .count(),
+            1
+        );
+        assert!(dump.ends_with("99 records, 1 corrupted\n"));
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_shares_a_reader_between_concurrent_lookups() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_shared_reader")?;
+
+        // values longer than a read ahead and, compressed, than a block
+        let mut state = 7u32;
+        let noise = (0..3 * block::BLOCK_SIZE)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect::<Vec<_>>();
+        let entries = (0..64u32)
+            .map(|i| {
+                let value = match i % 8 {
+                    0 => noise.clone(),
+                    _ => format!("value of key {:05}", i).into_bytes(),
+                };
+                Entry::new(format!("key{:05}", i).into_bytes(), Some(value), i as u128)
+            })
+            .collect::<Vec<_>>();
+        for codec in [Codec::None, Codec::Zstd] {
+            let path = temp_dir.path().join(format!("{:?}.db", codec));
+            let mut sst_writer = SSTableWriter::new(&path).await?;
+            sst_writer.set_compression(codec);
+            for entry in entries.iter() {
+                sst_writer.set(entry).await?;
+            }
+            sst_writer.flush().await?;
+
+            let sst_reader = std::sync::Arc::new(SSTableReader::new(&path).await?);
+            let mut lookups = tokio::task::JoinSet::new();
+            for entry in entries.iter().cloned() {
+                let sst_reader = std::sync::Arc::clone(&sst_reader);
+                lookups.spawn(async move { (sst_reader.get(&entry.key).await, entry) });
+            }
+            while let Some(lookup) = lookups.join_next().await {
+                let (found, entry) = lookup?;
+                assert_entry(&found.unwrap(), &entry);
+            }
+            let ranged = sst_reader
+                .range((std::ops::Bound::Unbounded, std::ops::Bound::Unbounded))
+                .await;
+            assert_eq!(ranged.len(), entries.len());
+        }
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_publishes_the_sstable_on_flush_only() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_publish")?;
+        let path = temp_dir.path().join("test.db");
+        let tmp_path = temp_dir.path().join("test.db.tmp");
+
+        // a writer which never flushes leaves nothing but its tmp file
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        SSTableWriter::new(&path).await?.set(&entry_1).await?;
+        assert!(!path.exists());
+        assert!(tmp_path.exists());
+        let querier = SSTableQuerier::new(temp_dir.path()).await?;
+        assert!(querier.query(b"test1").await.is_none());
+
+        SSTableWriter::new(&path)
+            .await?
+            .set(&entry_1)
+            .await?
+            .flush()
+            .await?;
+        assert!(!tmp_path.exists());
+        assert!(!temp_dir.path().join("test.db.bf.tmp").exists());
+
+        // nor does an unfinished append touch the published file
+        let published = tokio::fs::read(&path).await?;
+        let entry_2 = Entry::new(b"test2".to_vec(), Some(b"world".to_vec()), 2);
+        SSTableWriter::new(&path).await?.set(&entry_2).await?;
+        assert_eq!(tokio::fs::read(&path).await?, published);
+
+        remove_tmp_files(temp_dir.path(), &LocalFs).await?;
+        assert!(!tmp_path.exists());
+        let sst_reader = SSTableReader::new(&path).await?;
+        assert_entry(&sst_reader.get(b"test1").await.unwrap(), &entry_1);
+        assert!(sst_reader.get(b"test2").await.is_none());
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_iterates_the_entries_in_key_order() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_iter")?;
+
+        let entries = (0..50u32)
+            .map(|i| {
+                let key = format!("key{:03}", i).into_bytes();
+                Entry::new(key, Some(b"value".to_vec()), i as u128)
+            })
+            .collect::<Vec<_>>();
+        for index_interval in [1, 8] {
+            let path = temp_dir.path().join(format!("{}.db", index_interval));
+            let mut sst_writer = SSTableWriter::new(&path).await?;
+            sst_writer.set_index_interval(index_interval);
+            for entry in entries.iter() {
+                sst_writer.set(entry).await?;
+            }
+            sst_writer.flush().await?;
+
+            let sst_reader = SSTableReader::new(&path).await?;
+            let iterated = sst_reader.iter().collect::<Result<Vec<_>>>().await?;
+            assert_eq!(iterated.len(), entries.len());
+            for (iterated, entry) in iterated.iter().zip(entries.iter()) {
+                assert_entry(iterated, entry);
+            }
+
+            let mut entries_in_range = sst_reader.iter_range((
+                std::ops::Bound::Excluded(&b"key010"[..]),
+                std::ops::Bound::Excluded(&b"key020"[..]),
+            ));
+            let first = entries_in_range.next().await.unwrap()?;
+            assert_entry(&first, &entries[11]);
+            assert_eq!(entries_in_range.collect::<Vec<_>>().await.len(), 8);
+
+            let past_the_end = sst_reader.iter_range((
+                std::ops::Bound::Included(&b"key100"[..]),
+                std::ops::Bound::Unbounded,
+            ));
+            assert_eq!(past_the_end.collect::<Vec<_>>().await.len(), 0);
+        }
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_binary_searches_a_lazy_index() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_lazy_index")?;
+
+        let entries = (0..100u32)
+            .map(|i| {
+                let value = (i % 10 != 9).then(|| format!("value of key {:05}", i).into_bytes());
+                Entry::new(format!("key{:05}", i).into_bytes(), value, i as u128)
+            })
+            .collect::<Vec<_>>();
+        for (codec, index_interval) in [(Codec::None, 1), (Codec::Lz4, 1), (Codec::Lz4, 16)] {
+            let path = temp_dir
+                .path()
+                .join(format!("{:?}_{}.db", codec, index_interval));
+            let mut sst_writer = SSTableWriter::new(&path).await?;
+            sst_writer.set_options(SSTableOptions {
+                compression: codec,
+                index_interval,
+                ..Default::default()
+            });
+            for entry in entries.iter() {
+                sst_writer.set(entry).await?;
+            }
+            sst_writer.flush().await?;
+
+            let sst_reader = SSTableReader::with_options(&path, LAZY).await?;
+            assert_eq!(sst_reader.index_mode(), IndexMode::Lazy);
+            for entry in entries.iter().rev() {
+                assert_entry(&sst_reader.get(&entry.key).await.unwrap(), entry);
+            }
+            for missing in [&b"key"[..], b"key00050a", b"key99999"] {
+                assert!(sst_reader.get(missing).await.is_none());
+            }
+            let ranged = sst_reader
+                .range((
+                    std::ops::Bound::Excluded(&b"key00017"[..]),
+                    std::ops::Bound::Included(&b"key00040"[..]),
+                ))
+                .await;
+            assert_eq!(ranged.len(), 23);
+            assert_entry(&ranged[0], &entries[18]);
+            assert_entry(&ranged[22], &entries[40]);
+            let iterated = sst_reader.iter().collect::<Result<Vec<_>>>().await?;
+            assert_eq!(iterated.len(), entries.len());
+        }
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_converts_a_bincode_index_to_the_sorted_format() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_bincode_index")?;
+        let path = temp_dir.path().join("test.db");
+
+        // a version 2 SSTable as written before the sorted index format
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        let entry_2 = Entry::new(b"test2".to_vec(), None, 2);
+        write_legacy_sstable(&path, &[entry_1.clone(), entry_2.clone()]).await?;
+        tokio::fs::remove_file(get_index_path(&path)?).await?;
+        let offsets = [
+            (entry_1.key.clone(), 0),
+            (entry_2.key.clone(), entry_1.encoded_len()),
+        ];
+        let index_block = bincode::serialize(&std::collections::BTreeMap::from(offsets))?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await?;
+        let index_offset = file.metadata().await?.len();
+        file.write_all(&index_block).await?;
+        let footer = SSTableFooter {
+            version: footer::SSTABLE_VERSION_FOOTER,
+            index_offset,
+            index_len: index_block.len() as u64,
+            entry_count: 2,
+            min_key: b"test1".to_vec(),
+            max_key: b"test2".to_vec(),
+            index_interval: 1,
+            index_format: IndexFormat::Bincode,
+            max_timestamp: None,
+        };
+        file.write_all(&footer.encode()).await?;
+        file.flush().await?;
+
+        // loaded eagerly, there is nothing to binary search
+        let sst_reader = SSTableReader::with_options(&path, LAZY).await?;
+        assert_eq!(sst_reader.index_mode(), IndexMode::Eager);
+        assert_entry(&sst_reader.get(b"test2").await.unwrap(), &entry_2);
+
+        // flushing a writer rewrites the index
+        SSTableWriter::new(&path).await?.flush().await?;
+        let file = LocalFs.open_read(&path).await?;
+        let footer = SSTableFooter::read_from(&path, file.as_ref())
+            .await?
+            .unwrap();
+        assert_eq!(footer.index_format, IndexFormat::Sorted);
+        let sst_reader = SSTableReader::with_options(&path, LAZY).await?;
+        assert_eq!(sst_reader.index_mode(), IndexMode::Lazy);
+        assert_entry(&sst_reader.get(b"test1").await.unwrap(), &entry_1);
+        assert_entry(&sst_reader.get(b"test2").await.unwrap(), &entry_2);
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_rebuilds_a_corrupt_index_from_the_data() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_file_rebuild_index")?;
+        let rebuild = SSTableReaderOptions {
+            rebuild_corrupt_index: true,
+            ..Default::default()
+        };
+
+        // a truncated idx file of a version 1 SSTable
+        let path = temp_dir.path().join("legacy.db");
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        let entry_2 = Entry::new(b"test2".to_vec(), None, 2);
+        write_legacy_sstable(&path, &[entry_1.clone(), entry_2.clone()]).await?;
+        let index_path = get_index_path(&path)?;
+        let index_len = tokio::fs::metadata(&index_path).await?.len();
+        tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&index_path)
+            .await?
+            .set_len(index_len - 3)
+            .await?;
+        let err = SSTableReader::new(&path).await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::CorruptIndex(p)) if *p == index_path
+        ));
+        let sst_reader = SSTableReader::with_options(&path, rebuild).await?;
+        assert_entry(&sst_reader.get(b"test1").await.unwrap(), &entry_1);
+        assert_entry(&sst_reader.get(b"test2").await.unwrap(), &entry_2);
+
+        // an appended SSTable, the previous index block and footer are skipped
+        for codec in [Codec::None, Codec::Lz4] {
+            let path = temp_dir.path().join(format!("{:?}.db", codec));
+            let mut sst_writer = SSTableWriter::new(&path).await?;
+            sst_writer.set_compression(codec);
+            for i in 0..50u32 {
+                let entry = Entry::new(format!("key{:03}", i).into_bytes(), None, i as u128);
+                sst_writer.set(&entry).await?;
+            }
+            sst_writer.flush().await?;
+            let updated = Entry::new(b"key010".to_vec(), Some(b"updated".to_vec()), 50);
+            SSTableWriter::new(&path)
+                .await?
+                .set(&updated)
+                .await?
+                .flush()
+                .await?;
+
+            let file = LocalFs.open_read(&path).await?;
+            let (index, _) = load_index(&path, file.as_ref(), &LocalFs).await?;
+            let rebuilt =
+                SSTableIndex::rebuild_from_data(&path, &LocalFs, DEFAULT_MAX_FIELD_LEN).await?;
+            assert_eq!(
+                rebuilt
+                    .range((Bound::Unbounded, Bound::Unbounded))
+                    .collect::<Vec<_>>(),
+                index
+                    .range((Bound::Unbounded, Bound::Unbounded))
+                    .collect::<Vec<_>>()
+            );
+
+            // the record of a removed key would come back
+            let mut sst_writer = SSTableWriter::new(&path).await?;
+            sst_writer.remove(b"key020");
+            sst_writer.flush().await?;
+            let err = SSTableIndex::rebuild_from_data(&path, &LocalFs, DEFAULT_MAX_FIELD_LEN)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::CorruptIndex(_))
+            ));
+        }
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_removes_the_orphaned_index_files() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_orphaned_index")?;
+        let dir = temp_dir.path();
+
+        let entry = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        let kept = dir.join("1.db");
+        write_legacy_sstable(&kept, std::slice::from_ref(&entry)).await?;
+        let removed = dir.join("2.db");
+        write_legacy_sstable(&removed, std::slice::from_ref(&entry)).await?;
+        tokio::fs::remove_file(&removed).await?;
+
+        remove_orphaned_index_files(dir, &LocalFs).await?;
+        assert!(get_index_path(&kept)?.exists());
+        assert!(!get_index_path(&removed)?.exists());
+
+        // an SSTable with a footer carries its own index
+        let path = dir.join("3.db");
+        SSTableWriter::new(&path)
+            .await?
+            .set(&entry)
+            .await?
+            .flush()
+            .await?;
+        assert!(has_index(&path, &LocalFs).await && has_index(&kept, &LocalFs).await);
+        tokio::fs::remove_file(get_index_path(&kept)?).await?;
+        assert!(!has_index(&kept, &LocalFs).await);
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
     fn assert_entry(entry_1: &Entry, entry_2: &Entry) {
         assert_eq!(entry_1.key, entry_2.key);
         assert_eq!(entry_1.value, entry_2.value);
@@ -1,12 +1,76 @@
 use anyhow::{Context, Result};
-use std::{collections::BTreeMap, path::PathBuf};
-use tokio::{
-    fs::OpenOptions,
-    io::{AsyncReadExt, AsyncWriteExt},
+use std::{
+    collections::{btree_map, BTreeMap},
+    ops::{Bound, Range},
+    path::{Path, PathBuf},
+};
+use tokio::io;
+#[cfg(test)]
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use crate::{
+    prelude::*,
+    storage::{self, Storage},
+};
+
+use super::{
+    block::{pack_offset, read_block},
+    footer::{
+        corruption, entry_encoding, is_block_format, SSTableFooter, SSTABLE_MAGIC,
+        SSTABLE_VERSION_CHECKSUM, SSTABLE_VERSION_FOOTER,
+    },
+    get_index_path, index_matches_footer,
 };
 
 type SSTableIndexType = BTreeMap<Vec<u8>, u64>;
 
+/// Stands in for the index on bounds which cannot hold any key
+static NO_INDEXES: SSTableIndexType = BTreeMap::new();
+
+/// Marks a `.idx` file with a header and a checksum, the ones written before are the bare
+/// bincode payload.
+const IDX_MAGIC: &[u8; 8] = b"SDBIDX\0\x01";
+
+const IDX_VERSION: u16 = 1;
+
+/// The magic and the version (u16) ahead of the payload, which is followed by its CRC32 (u32).
+const IDX_HEADER_LEN: usize = IDX_MAGIC.len() + 2;
+
+/// Entry count (u64) at the start of a sorted index block.
+pub(crate) const SORTED_INDEX_HEADER_LEN: u64 = 8;
+
+/// Each entry of a sorted index block has its position (u64) in the table after the header.
+pub(crate) const SORTED_INDEX_POSITION_LEN: u64 = 8;
+
+/// How an index block is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IndexFormat {
+    /// A bincode serialized `BTreeMap`, as in the `.idx` files and the SSTables written before
+    /// the sorted format.
+    Bincode,
+    /// The entry count (u64), the position of every entry within the block (u64), then the
+    /// entries in key order: key length (u32), key, offset (u64), all little endian. A lookup
+    /// can binary search it without loading it, see [`super::lazy_index::LazyIndex`].
+    Sorted,
+}
+
+impl IndexFormat {
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            IndexFormat::Bincode => 0,
+            IndexFormat::Sorted => 1,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(IndexFormat::Bincode),
+            1 => Some(IndexFormat::Sorted),
+            _ => None,
+        }
+    }
+}
+
 /// Sorted String Table Index
 #[derive(Debug)]
 pub struct SSTableIndex {
@@ -17,30 +81,44 @@ pub struct SSTableIndex {
 pub struct SSTableIndexBuilder(SSTableIndex);
 
 impl SSTableIndexBuilder {
-    /// Load SSTable Index from file
-    /// create new file if not exist
+    /// SSTable Index persisted at `path`, a version 1 `.idx` file
     pub fn new(path: PathBuf) -> Self {
         let indexes = BTreeMap::new();
         let index = SSTableIndex { indexes, path };
         Self(index)
     }
 
-    pub async fn indexes(mut self) -> Result<Self> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&self.0.path)
-            .await
-            .context("open idx file to read")?;
-        let mut buf = Vec::new();
-        let read_size = file
-            .read_to_end(&mut buf)
-            .await
-            .context("read content from idx")?;
-        if read_size > 0 {
-            self.0.indexes = bincode::deserialize(&buf).context("deserialize idx to BTreeMap")?;
+    /// Load the indexes from the `.idx` file of `storage`, there are none when it does not
+    /// exist. Fails with [`Error::CorruptIndex`] when the file is truncated or does not match its
+    /// checksum.
+    pub async fn indexes(self, storage: &dyn Storage) -> Result<Self> {
+        let buf = match storage::read(storage, &self.0.path).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(self),
+            Err(e) => return Err(e).context("read content from idx"),
+        };
+        let payload = match buf.starts_with(IDX_MAGIC) {
+            true => idx_payload(&buf),
+            // written before the header, nothing to check it against
+            false => Some(buf.as_slice()).filter(|payload| !payload.is_empty()),
+        };
+        let path = self.0.path.clone();
+        payload
+            .and_then(|payload| self.block(payload, IndexFormat::Bincode).ok())
+            .ok_or_else(|| Error::CorruptIndex(path).into())
+    }
+
+    /// Load the indexes from an index block, as embedded in a version 2 SSTable
+    pub(crate) fn block(mut self, block: &[u8], format: IndexFormat) -> Result<Self> {
+        if block.is_empty() {
+            return Ok(self);
         }
+        self.0.indexes = match format {
+            IndexFormat::Bincode => {
+                bincode::deserialize(block).context("deserialize idx to BTreeMap")?
+            }
+            IndexFormat::Sorted => decode_sorted(block).context("malformed sorted index block")?,
+        };
 
         Ok(self)
     }
@@ -56,44 +134,270 @@ impl SSTableIndex {
         self.indexes.insert(key.to_vec(), offset);
     }
 
-    pub fn contains_key(&self, key: &[u8]) -> bool {
-        self.indexes.contains_key(key)
-    }
-
     pub fn get(&self, key: &[u8]) -> Option<&u64> {
         self.indexes.get(key)
     }
 
+    #[cfg(test)]
     pub fn remove(&mut self, key: &[u8]) -> Option<u64> {
         self.indexes.remove(key)
     }
 
-    /// Get the SSTable Indexes
-    pub fn indexes(&self) -> &SSTableIndexType {
-        &self.indexes
+    /// The keys in `bounds` and their offsets, in key order. Unlike [`BTreeMap::range`] it does
+    /// not panic on bounds which cannot hold any key.
+    pub fn range(
+        &self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> btree_map::Range<'_, Vec<u8>, u64> {
+        let indexes = match bounds {
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) if start > end
+                || (start == end && matches!(bounds, (Bound::Excluded(_), Bound::Excluded(_)))) =>
+            {
+                &NO_INDEXES
+            }
+            _ => &self.indexes,
+        };
+        indexes.range::<[u8], _>(bounds)
+    }
+
+    /// The keys in key order
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &[u8]> {
+        self.indexes.keys().map(Vec::as_slice)
+    }
+
+    pub fn first_key(&self) -> Option<&[u8]> {
+        self.keys().next()
+    }
+
+    pub fn last_key(&self) -> Option<&[u8]> {
+        self.keys().next_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.indexes.len()
     }
 
-    /// Persist the indexes to file
+    pub fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Encode the indexes into an index block of the [`IndexFormat::Sorted`] format
+    pub fn encode(&self) -> Vec<u8> {
+        let count = self.indexes.len() as u64;
+        let entries_len = self.keys().map(|key| 4 + key.len() + 8).sum::<usize>();
+        let table_len = SORTED_INDEX_HEADER_LEN + count * SORTED_INDEX_POSITION_LEN;
+        let mut block = Vec::with_capacity(table_len as usize + entries_len);
+        block.extend_from_slice(&count.to_le_bytes());
+        let mut position = table_len;
+        for key in self.keys() {
+            block.extend_from_slice(&position.to_le_bytes());
+            position += (4 + key.len() + 8) as u64;
+        }
+        for (key, offset) in self.indexes.iter() {
+            block.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            block.extend_from_slice(key);
+            block.extend_from_slice(&offset.to_le_bytes());
+        }
+        block
+    }
+
+    /// Regenerate the index of the SSTable at `db_path` of `storage` by decoding its data records
+    /// in order, for when the index is corrupt. The index block and footer an append leaves amid
+    /// the records are skipped.
+    ///
+    /// Fails with [`Error::CorruptIndex`] when the records do not add up to the footer, as once
+    /// keys were removed from the index: their records stay in the file and would come back.
+    /// A key or value longer than `max_field_len` is a corruption.
+    pub async fn rebuild_from_data(
+        db_path: &Path,
+        storage: &dyn Storage,
+        max_field_len: usize,
+    ) -> Result<Self> {
+        let file = storage.open_read(db_path).await?;
+        let footer = SSTableFooter::read_from(db_path, file.as_ref()).await?;
+        let mut data = file.read_at(0, file.size().await?).await?;
+        let (version, index_interval) = match &footer {
+            Some(footer) => {
+                data.truncate(footer.index_offset as usize);
+                (footer.version, footer.index_interval)
+            }
+            None => (SSTABLE_VERSION_FOOTER - 1, 1),
+        };
+
+        let mut index = SSTableIndexBuilder::new(get_index_path(db_path)?).build();
+        let mut entry_count = 0_u64;
+        let mut add = |key: Vec<u8>, offset: u64| {
+            if entry_count.is_multiple_of(index_interval.into()) {
+                index.indexes.insert(key, offset);
+            }
+            entry_count += 1;
+        };
+        let read_error = |offset: u64, e: WalReadError| -> anyhow::Error {
+            match e {
+                WalReadError::Io(e) => e.into(),
+                e => corruption(db_path, offset, &e.to_string()).into(),
+            }
+        };
+
+        let dead_ranges = dead_ranges_of(&data);
+        let mut offset = 0;
+        while offset < data.len() {
+            if let Some(dead_range) = dead_ranges.iter().find(|range| range.start == offset) {
+                offset = dead_range.end;
+                continue;
+            }
+            let end = dead_ranges
+                .iter()
+                .map(|range| range.start)
+                .filter(|&start| start > offset)
+                .min()
+                .unwrap_or(data.len());
+            let mut input = &data[offset..end];
+            let remaining = input.len() as u64;
+
+            let encoding = entry_encoding(version);
+            if is_block_format(version) {
+                let (records, block_len) = read_block(&mut input, offset as u64, remaining)
+                    .await
+                    .map_err(|e| read_error(offset as u64, e))?;
+                let mut inner_offset = 0;
+                while inner_offset < records.len() {
+                    let mut input = &records[inner_offset..];
+                    let remaining = input.len() as u64;
+                    let Some(entry) = Entry::try_read_checksummed(
+                        &mut input,
+                        offset as u64,
+                        remaining,
+                        encoding,
+                        max_field_len,
+                    )
+                    .await
+                    .map_err(|e| read_error(offset as u64, e))?
+                    else {
+                        break;
+                    };
+                    let len = entry.encoded_len_with(encoding) + 4;
+                    add(entry.key, pack_offset(offset as u64, inner_offset));
+                    inner_offset += len;
+                }
+                offset += block_len as usize;
+                continue;
+            }
+
+            let entry = match version >= SSTABLE_VERSION_CHECKSUM {
+                true => {
+                    Entry::try_read_checksummed(
+                        &mut input,
+                        offset as u64,
+                        remaining,
+                        encoding,
+                        max_field_len,
+                    )
+                    .await
+                }
+                false => Entry::read_with(encoding, &mut input, remaining, max_field_len).await,
+            };
+            let Some(entry) = entry.map_err(|e| read_error(offset as u64, e))? else {
+                break;
+            };
+            let len = match version >= SSTABLE_VERSION_CHECKSUM {
+                true => entry.encoded_len_with(encoding) + 4,
+                false => entry.encoded_len_with(encoding),
+            };
+            add(entry.key, offset as u64);
+            offset += len;
+        }
+
+        match footer {
+            Some(footer) if !index_matches_footer(&index, &footer) => {
+                Err(Error::CorruptIndex(db_path.to_owned()).into())
+            }
+            _ => Ok(index),
+        }
+    }
+
+    /// Persist the indexes to a version 1 `.idx` file, SSTables embed them since version 2
+    #[cfg(test)]
     pub async fn persist(&mut self) -> Result<()> {
         let mut file = OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(&self.path)
             .await
             .context("open idx file to write")?;
-        let bytes = bincode::serialize(&self.indexes).context("serialize idx to bytes")?;
+        let payload = bincode::serialize(&self.indexes).context("serialize idx to bytes")?;
+        let mut bytes = Vec::with_capacity(IDX_HEADER_LEN + payload.len() + 4);
+        bytes.extend_from_slice(IDX_MAGIC);
+        bytes.extend_from_slice(&IDX_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
         file.write_all(&bytes)
             .await
             .context("write idx bytes to file")?;
+        file.sync_all().await.context("sync idx file")?;
         Ok(())
     }
 }
 
+/// The payload of a `.idx` file with the header, `None` when it is truncated, of another version
+/// or does not match its checksum.
+fn idx_payload(buf: &[u8]) -> Option<&[u8]> {
+    let version = u16::from_le_bytes(buf.get(IDX_MAGIC.len()..IDX_HEADER_LEN)?.try_into().ok()?);
+    let (payload, checksum) = buf.get(IDX_HEADER_LEN..)?.split_last_chunk::<4>()?;
+    (version == IDX_VERSION && crc32fast::hash(payload).to_le_bytes() == *checksum)
+        .then_some(payload)
+}
+
+/// Decode the key and the offset of a sorted index entry, `None` when it is malformed.
+pub(crate) fn decode_sorted_entry(entry: &[u8]) -> Option<(&[u8], u64)> {
+    let (key_len, rest) = entry.split_first_chunk::<4>()?;
+    let key_len = u32::from_le_bytes(*key_len) as usize;
+    let (key, rest) = rest.split_at_checked(key_len)?;
+    let offset = u64::from_le_bytes(*rest.first_chunk::<8>()?);
+    Some((key, offset))
+}
+
+fn decode_sorted(block: &[u8]) -> Option<SSTableIndexType> {
+    let (count, _) = block.split_first_chunk::<8>()?;
+    let count = u64::from_le_bytes(*count);
+    let table_len = count
+        .checked_mul(SORTED_INDEX_POSITION_LEN)?
+        .checked_add(SORTED_INDEX_HEADER_LEN)?;
+    let mut entries = block.get(usize::try_from(table_len).ok()?..)?;
+    let mut indexes = BTreeMap::new();
+    for _ in 0..count {
+        let (key, offset) = decode_sorted_entry(entries)?;
+        entries = &entries[4 + key.len() + 8..];
+        indexes.insert(key.to_vec(), offset);
+    }
+    entries.is_empty().then_some(indexes)
+}
+
+/// The index blocks and footers of `data` left behind by appends, each from the index offset
+/// to the end of the footer.
+fn dead_ranges_of(data: &[u8]) -> Vec<Range<usize>> {
+    data.windows(SSTABLE_MAGIC.len())
+        .enumerate()
+        .filter(|(_, window)| window == SSTABLE_MAGIC)
+        .filter_map(|(position, _)| {
+            let end = position + SSTABLE_MAGIC.len();
+            let footer = SSTableFooter::from_trailing_bytes(&data[..end])?;
+            Some(footer.index_offset as usize..end)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use tempdir::TempDir;
 
     use super::*;
+    use crate::storage::LocalFs;
+    use tokio::fs;
 
     #[tokio::test]
     async fn it_works() -> Result<()> {
@@ -102,7 +406,7 @@ mod tests {
 
         // create SSTableIndex
         let mut idx = SSTableIndexBuilder::new(path.clone())
-            .indexes()
+            .indexes(&LocalFs)
             .await?
             .build();
         assert_eq!(idx.indexes.len(), 0);
@@ -115,7 +419,7 @@ mod tests {
 
         // load from file
         let mut idx_2 = SSTableIndexBuilder::new(path.clone())
-            .indexes()
+            .indexes(&LocalFs)
             .await?
             .build();
         assert_eq!(idx_2.indexes.len(), 1);
@@ -129,10 +433,128 @@ mod tests {
 
         // persist to file
         idx_2.persist().await?;
-        let idx_3 = SSTableIndexBuilder::new(path).indexes().await?.build();
+        let idx_3 = SSTableIndexBuilder::new(path)
+            .indexes(&LocalFs)
+            .await?
+            .build();
         assert_eq!(idx_3.indexes.len(), 2);
 
         temp_dir.close().unwrap();
         Ok(())
     }
+
+    #[test]
+    fn it_answers_range_queries() {
+        let unbounded = (Bound::Unbounded, Bound::Unbounded);
+        let mut idx = SSTableIndexBuilder::new(PathBuf::from("test.idx")).build();
+        assert!(idx.is_empty());
+        assert_eq!((idx.first_key(), idx.last_key()), (None, None));
+        assert_eq!(idx.range(unbounded).count(), 0);
+
+        idx.insert(b"b", 1);
+        assert_eq!(idx.len(), 1);
+        assert_eq!(
+            (idx.first_key(), idx.last_key()),
+            (Some(&b"b"[..]), Some(&b"b"[..]))
+        );
+        assert_eq!(idx.range(unbounded).count(), 1);
+        let key: &[u8] = b"b";
+        assert_eq!(
+            idx.range((Bound::Included(key), Bound::Included(key)))
+                .count(),
+            1
+        );
+        assert_eq!(
+            idx.range((Bound::Excluded(key), Bound::Excluded(key)))
+                .count(),
+            0
+        );
+
+        idx.insert(b"d", 2);
+        idx.insert(b"f", 3);
+        assert_eq!(idx.keys().collect::<Vec<_>>(), [b"b", b"d", b"f"]);
+        assert_eq!(
+            (idx.first_key(), idx.last_key()),
+            (Some(&b"b"[..]), Some(&b"f"[..]))
+        );
+        let (a, c, e, z): (&[u8], &[u8], &[u8], &[u8]) = (b"a", b"c", b"e", b"z");
+        let offsets = |bounds| {
+            idx.range(bounds)
+                .map(|(_, &offset)| offset)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(offsets((Bound::Included(c), Bound::Excluded(z))), [2, 3]);
+        assert_eq!(offsets((Bound::Unbounded, Bound::Excluded(a))), [0_u64; 0]);
+        assert_eq!(offsets((Bound::Excluded(z), Bound::Unbounded)), [0_u64; 0]);
+        // inverted bounds hold nothing
+        assert_eq!(
+            offsets((Bound::Included(e), Bound::Included(c))),
+            [0_u64; 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_detects_a_corrupt_idx_file() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_index_checksum")?;
+        let path = temp_dir.path().join("sstable_index.idx");
+
+        let mut idx = SSTableIndexBuilder::new(path.clone()).build();
+        idx.insert(b"hello", 1);
+        idx.persist().await?;
+        let bytes = fs::read(&path).await?;
+        assert!(bytes.starts_with(IDX_MAGIC));
+
+        let mut damaged = bytes.clone();
+        damaged[IDX_HEADER_LEN + 1] ^= 1;
+        for content in [&damaged[..], &bytes[..bytes.len() - 1], &bytes[..4], &[]] {
+            fs::write(&path, content).await?;
+            let err = SSTableIndexBuilder::new(path.clone())
+                .indexes(&LocalFs)
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::CorruptIndex(p)) if *p == path
+            ));
+        }
+
+        // an idx file written before the header still loads
+        fs::write(&path, bincode::serialize(&idx.indexes)?).await?;
+        let idx = SSTableIndexBuilder::new(path)
+            .indexes(&LocalFs)
+            .await?
+            .build();
+        assert_eq!(idx.get(b"hello"), Some(&1));
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_drops_the_removed_keys_from_the_file() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_index_shrink")?;
+        let path = temp_dir.path().join("sstable_index.idx");
+
+        let mut idx = SSTableIndexBuilder::new(path.clone()).build();
+        for i in 0..100u64 {
+            idx.insert(format!("key{}", i).as_bytes(), i);
+        }
+        idx.persist().await?;
+        for i in 1..100u64 {
+            idx.remove(format!("key{}", i).as_bytes());
+        }
+        idx.persist().await?;
+
+        let idx = SSTableIndexBuilder::new(path)
+            .indexes(&LocalFs)
+            .await?
+            .build();
+        assert_eq!(idx.indexes.len(), 1);
+        assert_eq!(idx.get(b"key0"), Some(&0));
+        assert_eq!(idx.get(b"key1"), None);
+
+        temp_dir.close()?;
+        Ok(())
+    }
 }
@@ -1,46 +1,345 @@
 use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
-use crate::prelude::*;
-use crate::utils;
+use crate::{
+    prelude::*,
+    storage::{LocalFs, Storage},
+    utils::allocation_order,
+};
 
-use super::sstable_reader::SSTableReader;
+use super::{
+    has_index, list_level_files,
+    manifest::Manifest,
+    sstable_reader::{IndexMode, SSTableReader, SSTableReaderOptions},
+    LEVEL_COUNT,
+};
 
+/// The SSTable files of a directory by level, see [`level_dir`](super::level_dir).
+struct PathCollection {
+    /// Newest first
+    level0: Vec<PathBuf>,
+    level1: Vec<PathBuf>,
+}
+
+/// The key ranges of the level 1 SSTables of a [`PathCollection`], which do not overlap.
+struct KeyRanges {
+    /// Min key, max key and path, sorted by min key
+    ranges: Vec<(Vec<u8>, Vec<u8>, PathBuf)>,
+    /// The files whose range could not be read, they may hold any key
+    unranged: Vec<PathBuf>,
+}
+
+impl KeyRanges {
+    /// The level 1 files which may hold `key`, the one whose range contains it and the unranged
+    fn files_for(&self, key: &[u8]) -> impl Iterator<Item = &PathBuf> {
+        let position = self
+            .ranges
+            .partition_point(|(min_key, _, _)| min_key.as_slice() <= key);
+        let containing = position
+            .checked_sub(1)
+            .map(|position| &self.ranges[position])
+            .filter(|(_, max_key, _)| max_key.as_slice() >= key)
+            .map(|(_, _, path)| path);
+        containing.into_iter().chain(self.unranged.iter())
+    }
+}
+
+/// Looks keys up across the SSTable files of a directory: the level 0 files newest first, then
+/// the one level 1 file whose key range holds the key. Only the files in its manifest are read,
+/// when it has one.
+///
+/// The file list and the opened readers are kept between lookups, whoever writes, rewrites or
+/// removes an SSTable file has to [`SSTableQuerier::invalidate`] it afterwards.
 pub struct SSTableQuerier {
-    path_collection: Vec<PathBuf>,
+    dir: PathBuf,
+    /// `None` once invalidated, the directory is listed again on the next lookup
+    path_collection: RwLock<Option<Arc<PathCollection>>>,
+    /// Bumped by every invalidation, a listing which raced one is not kept
+    generation: AtomicU64,
+    /// The key ranges of the level 1 files, along with the collection they were read for
+    key_ranges: Mutex<Option<(Arc<PathCollection>, Arc<KeyRanges>)>>,
+    readers: Mutex<HashMap<PathBuf, Arc<SSTableReader>>>,
+    reader_options: SSTableReaderOptions,
+    storage: Arc<dyn Storage>,
+    /// SSTable files opened so far, the others were pruned by their key range or bloom filter
+    files_opened: AtomicUsize,
 }
 
 impl SSTableQuerier {
-    pub fn new(dir: &Path) -> Result<Self> {
-        let mut path_collection = utils::get_files_with_ext(dir, "db")?;
-        path_collection.sort_by(|a, b| b.cmp(a));
-        Ok(Self { path_collection })
+    pub async fn new(dir: &Path) -> Result<Self> {
+        Self::with_storage(dir, Arc::new(LocalFs)).await
+    }
+
+    /// The querier of the SSTable files of `dir` in `storage`.
+    pub async fn with_storage(dir: &Path, storage: Arc<dyn Storage>) -> Result<Self> {
+        let querier = Self {
+            dir: dir.to_path_buf(),
+            path_collection: RwLock::new(None),
+            generation: AtomicU64::new(0),
+            key_ranges: Mutex::new(None),
+            readers: Mutex::new(HashMap::new()),
+            reader_options: SSTableReaderOptions::default(),
+            storage,
+            files_opened: AtomicUsize::new(0),
+        };
+        querier.path_collection().await?;
+        Ok(querier)
+    }
+
+    /// How the readers get at the indexes, see [`IndexMode`]
+    pub fn index_mode(mut self, index_mode: IndexMode) -> Self {
+        self.reader_options.index_mode = index_mode;
+        self
+    }
+
+    /// Rebuild a corrupt index from the data records of its SSTable instead of failing the
+    /// lookups, off by default
+    pub fn rebuild_corrupt_index(mut self, rebuild: bool) -> Self {
+        self.reader_options.rebuild_corrupt_index = rebuild;
+        self
+    }
+
+    /// The longest key or value the readers read back, see
+    /// [`SSTableReaderOptions::max_field_len`]
+    pub fn max_field_len(mut self, max_field_len: usize) -> Self {
+        self.reader_options.max_field_len = max_field_len;
+        self
+    }
+
+    /// Where the SSTable files are read from, [`LocalFs`] by default, see
+    /// [`SSTableQuerier::with_storage`]
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = storage;
+        // listed from the former storage
+        *self.path_collection.get_mut().unwrap() = None;
+        self
+    }
+
+    /// Number of the live level 0 SSTables, the ones a compaction has yet to merge
+    pub(crate) async fn level0_len(&self) -> Result<usize> {
+        Ok(self.path_collection().await?.level0.len())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn files_opened(&self) -> usize {
+        self.files_opened.load(Ordering::Relaxed)
+    }
+
+    /// Forget the reader of `path` and list the directory again on the next lookup, call it
+    /// once the file has been created, changed or removed.
+    pub fn invalidate(&self, path: &Path) {
+        self.readers.lock().unwrap().remove(path);
+        let mut cached = self.path_collection.write().unwrap();
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        *cached = None;
+    }
+
+    async fn path_collection(&self) -> Result<Arc<PathCollection>> {
+        loop {
+            if let Some(path_collection) = self.path_collection.read().unwrap().as_ref() {
+                return Ok(Arc::clone(path_collection));
+            }
+
+            let generation = self.generation.load(Ordering::Relaxed);
+            let path_collection = Arc::new(self.list_paths().await?);
+            // the generation only moves under the write lock, so no invalidation slips in
+            // between the check and the store
+            let mut cached = self.path_collection.write().unwrap();
+            if self.generation.load(Ordering::Relaxed) == generation {
+                return Ok(Arc::clone(cached.insert(path_collection)));
+            }
+            // invalidated while listing, the new file may be missing from the listing
+        }
+    }
+
+    async fn list_paths(&self) -> Result<PathCollection> {
+        let storage = self.storage.as_ref();
+        let manifest = Manifest::load(&self.dir, storage).await?;
+        let mut levels: [Vec<PathBuf>; LEVEL_COUNT] = Default::default();
+        for (level, files) in list_level_files(&self.dir, "db", storage)
+            .await?
+            .into_iter()
+            .enumerate()
+        {
+            for path in files {
+                if manifest.as_ref().is_some_and(|m| !m.contains(&path)) {
+                    continue;
+                }
+                if !has_index(&path, storage).await {
+                    tracing::warn!("Skipping the SSTable {:?}, its index is missing", path);
+                    continue;
+                }
+                levels[level].push(path);
+            }
+        }
+        let [mut level0, level1] = levels;
+        // named after the time they were written, whichever directory they are in
+        level0.sort_by(|a, b| allocation_order(b).cmp(&allocation_order(a)));
+        Ok(PathCollection { level0, level1 })
+    }
+
+    /// The key ranges of the level 1 files of `path_collection`, read from their footers the
+    /// first time.
+    async fn key_ranges(&self, path_collection: &Arc<PathCollection>) -> Arc<KeyRanges> {
+        if let Some((cached_for, key_ranges)) = self.key_ranges.lock().unwrap().as_ref() {
+            if Arc::ptr_eq(cached_for, path_collection) {
+                return Arc::clone(key_ranges);
+            }
+        }
+
+        let mut key_ranges = KeyRanges {
+            ranges: Vec::new(),
+            unranged: Vec::new(),
+        };
+        for path in path_collection.level1.iter() {
+            match SSTableReader::footer_key_range(path, self.storage.as_ref()).await {
+                Some((min_key, max_key)) => {
+                    key_ranges.ranges.push((min_key, max_key, path.clone()))
+                }
+                None => key_ranges.unranged.push(path.clone()),
+            }
+        }
+        key_ranges.ranges.sort();
+        let key_ranges = Arc::new(key_ranges);
+        *self.key_ranges.lock().unwrap() =
+            Some((Arc::clone(path_collection), Arc::clone(&key_ranges)));
+        key_ranges
+    }
+
+    /// Check the key range of the cached reader of `path`, or else the one in its footer.
+    async fn may_overlap(&self, path: &PathBuf, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> bool {
+        let cached = self.readers.lock().unwrap().get(path).cloned();
+        match cached {
+            Some(reader) => reader.overlaps(bounds),
+            None => SSTableReader::may_overlap(path, bounds, self.storage.as_ref()).await,
+        }
+    }
+
+    /// The cached reader of `path`, opening the file the first time.
+    async fn open(&self, path: &PathBuf) -> Result<Arc<SSTableReader>> {
+        if let Some(reader) = self.readers.lock().unwrap().get(path) {
+            return Ok(Arc::clone(reader));
+        }
+
+        self.files_opened.fetch_add(1, Ordering::Relaxed);
+        let reader = Arc::new(
+            SSTableReader::with_storage(path, self.reader_options, self.storage.as_ref()).await?,
+        );
+        self.readers
+            .lock()
+            .unwrap()
+            .insert(path.clone(), Arc::clone(&reader));
+        Ok(reader)
     }
 
+    /// Check the max timestamp of the cached reader of `path`, or else the one in its footer.
+    async fn may_be_newer(&self, path: &PathBuf, timestamp: u128) -> bool {
+        let cached = self.readers.lock().unwrap().get(path).cloned();
+        match cached {
+            Some(reader) => reader
+                .max_timestamp()
+                .is_none_or(|max_timestamp| max_timestamp > timestamp),
+            None => SSTableReader::may_be_newer(path, timestamp, self.storage.as_ref()).await,
+        }
+    }
+
+    /// The newest version of `key` across all SSTable files, a tombstone included
     pub async fn query(&self, key: &[u8]) -> Option<Entry> {
-        for p in self.path_collection.iter() {
-            match SSTableReader::new(p).await {
-                Ok(mut reader) => {
+        self.query_newer_than(key, None).await
+    }
+
+    /// The newest version of `key` across all SSTable files when it is newer than `timestamp`.
+    ///
+    /// File names do not order the versions (compaction writes old entries to a new file), so
+    /// every file which may hold the key is looked at, except those whose max timestamp shows
+    /// they cannot beat the version found so far.
+    pub async fn query_newer_than(&self, key: &[u8], timestamp: Option<u128>) -> Option<Entry> {
+        let path_collection = match self.path_collection().await {
+            Ok(path_collection) => path_collection,
+            Err(e) => {
+                tracing::error!("{e:?}");
+                return None;
+            }
+        };
+        let key_ranges = self.key_ranges(&path_collection).await;
+        let mut newest: Option<Entry> = None;
+        for p in path_collection
+            .level0
+            .iter()
+            .chain(key_ranges.files_for(key))
+        {
+            let newest_timestamp = newest.as_ref().map(|entry| entry.timestamp).or(timestamp);
+            if let Some(newest_timestamp) = newest_timestamp {
+                if !self.may_be_newer(p, newest_timestamp).await {
+                    continue;
+                }
+            }
+            if !self
+                .may_overlap(p, (Bound::Included(key), Bound::Included(key)))
+                .await
+                || !SSTableReader::may_contain(p, key, self.storage.as_ref()).await
+            {
+                continue;
+            }
+            match self.open(p).await {
+                Ok(reader) => {
                     let entry_opt = reader.get(key).await;
-                    if entry_opt.is_some() {
-                        return entry_opt;
+                    if let Some(entry) = entry_opt
+                        .filter(|entry| newest_timestamp.is_none_or(|t| entry.timestamp > t))
+                    {
+                        newest = Some(entry);
                     }
                 }
-                Err(e) => {
-                    tracing::error!("{e:?}");
-                    return None;
+                // the other files may still hold the key
+                Err(e) => tracing::error!("{e:?}"),
+            }
+        }
+
+        newest
+    }
+
+    /// Collect the latest version of every key in `bounds` across all SSTable files.
+    /// Tombstones are kept so the caller can shadow older data with them.
+    pub async fn scan(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Result<Vec<Entry>> {
+        self.scan_limit(bounds, usize::MAX).await
+    }
+
+    /// Like [`SSTableQuerier::scan`], the first `limit` keys only. Reads at most `limit`
+    /// records of every file, which hold the newest version of those keys.
+    pub async fn scan_limit(
+        &self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+        limit: usize,
+    ) -> Result<Vec<Entry>> {
+        let mut merged: BTreeMap<Vec<u8>, Entry> = BTreeMap::new();
+        let path_collection = self.path_collection().await?;
+        for p in path_collection.level0.iter().chain(&path_collection.level1) {
+            if !self.may_overlap(p, bounds).await {
+                continue;
+            }
+            let reader = self.open(p).await?;
+            for entry in reader.range_limit(bounds, limit).await {
+                match merged.get(&entry.key) {
+                    Some(existing) if existing.timestamp >= entry.timestamp => {}
+                    _ => {
+                        merged.insert(entry.key.clone(), entry);
+                    }
                 }
             }
         }
 
-        None
+        Ok(merged.into_values().take(limit).collect())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::sstable::sstable_writer::SSTableWriter;
+    use crate::sstable::{sstable_writer::SSTableWriter, tests::write_legacy_sstable};
 
     use super::*;
     use anyhow::Result;
@@ -62,11 +361,279 @@ mod tests {
         sst_writer_2.set(&entry_2).await?.flush().await?;
 
         // test SSTableQuerier
-        let querier = SSTableQuerier::new(&dir.to_path_buf())?;
+        let querier = SSTableQuerier::new(dir).await?;
+        assert!(querier.query(b"test1").await.is_some());
+        assert!(querier.query(b"test2").await.is_some());
+        assert!(querier.query(b"test3").await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_scans_the_latest_version_across_files() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_querier_scan")?;
+        let dir = temp_dir.path();
+
+        // seed
+        let old_entry = Entry::new(b"b".to_vec(), Some(b"old".to_vec()), 1);
+        let new_entry = Entry::new(b"b".to_vec(), Some(b"new".to_vec()), 2);
+        let other_entry = Entry::new(b"d".to_vec(), None, 3);
+        SSTableWriter::new(&dir.join("1.db"))
+            .await?
+            .set(&old_entry)
+            .await?
+            .flush()
+            .await?;
+        SSTableWriter::new(&dir.join("2.db"))
+            .await?
+            .set(&new_entry)
+            .await?
+            .set(&other_entry)
+            .await?
+            .flush()
+            .await?;
+
+        // test SSTableQuerier#scan
+        let querier = SSTableQuerier::new(dir).await?;
+        let entries = querier
+            .scan((Bound::Included(b"a"), Bound::Excluded(b"z")))
+            .await?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].value.as_deref(), Some(&b"new"[..]));
+        assert!(entries[1].is_deleted());
+
+        let entries = querier
+            .scan((Bound::Excluded(b"b"), Bound::Unbounded))
+            .await?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, b"d");
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_queries_the_newest_version_across_files() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_querier_newest")?;
+        let dir = temp_dir.path();
+
+        // a compaction can put an old version in a newer file
+        for (file, timestamp) in [("3.db", 10), ("2.db", 5), ("1.db", 20)] {
+            let value = format!("at {}", timestamp).into_bytes();
+            SSTableWriter::new(&dir.join(file))
+                .await?
+                .set(&Entry::new(b"a".to_vec(), Some(value), timestamp))
+                .await?
+                .flush()
+                .await?;
+        }
+
+        let querier = SSTableQuerier::new(dir).await?;
+        let entry = querier.query(b"a").await.unwrap();
+        assert_eq!(
+            (entry.timestamp, entry.value.unwrap()),
+            (20, bytes::Bytes::from_static(b"at 20"))
+        );
+        // 2.db cannot hold anything newer than the version found in 3.db
+        assert_eq!(querier.files_opened(), 2);
+
+        assert_eq!(
+            querier
+                .query_newer_than(b"a", Some(15))
+                .await
+                .unwrap()
+                .timestamp,
+            20
+        );
+        assert!(querier.query_newer_than(b"a", Some(20)).await.is_none());
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_skips_the_files_without_an_index() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_querier_missing_index")?;
+        let dir = temp_dir.path();
+
+        let entry_1 = Entry::new(b"a".to_vec(), Some(b"v1".to_vec()), 1);
+        let entry_2 = Entry::new(b"b".to_vec(), Some(b"v1".to_vec()), 2);
+        write_legacy_sstable(&dir.join("1.db"), std::slice::from_ref(&entry_1)).await?;
+        write_legacy_sstable(&dir.join("2.db"), std::slice::from_ref(&entry_2)).await?;
+        tokio::fs::remove_file(dir.join("2.db.idx")).await?;
+
+        let querier = SSTableQuerier::new(dir).await?;
+        assert_eq!(
+            querier.query(b"a").await.unwrap().value.unwrap(),
+            &b"v1"[..]
+        );
+        assert!(querier.query(b"b").await.is_none());
+        assert_eq!(querier.files_opened(), 1);
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_queries_files_of_both_versions() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_querier_versions")?;
+        let dir = temp_dir.path();
+
+        // seed
+        let old_entry = Entry::new(b"a".to_vec(), Some(b"old".to_vec()), 1);
+        let other_entry = Entry::new(b"b".to_vec(), Some(b"v1".to_vec()), 2);
+        let new_entry = Entry::new(b"a".to_vec(), Some(b"new".to_vec()), 3);
+        write_legacy_sstable(&dir.join("1.db"), &[old_entry, other_entry]).await?;
+        SSTableWriter::new(&dir.join("2.db"))
+            .await?
+            .set(&new_entry)
+            .await?
+            .flush()
+            .await?;
+
+        let querier = SSTableQuerier::new(dir).await?;
+        assert_eq!(
+            querier.query(b"a").await.unwrap().value.unwrap(),
+            &b"new"[..]
+        );
+        assert_eq!(
+            querier.query(b"b").await.unwrap().value.unwrap(),
+            &b"v1"[..]
+        );
+        let entries = querier.scan((Bound::Unbounded, Bound::Unbounded)).await?;
+        assert_eq!(entries.len(), 2);
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_prunes_files_by_key_range() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_querier_key_range")?;
+        let dir = temp_dir.path();
+
+        // seed
+        for (file, keys) in [("1.db", [b"a", b"c"]), ("2.db", [b"x", b"z"])] {
+            let mut writer = SSTableWriter::new(&dir.join(file)).await?;
+            for key in keys {
+                writer
+                    .set(&Entry::new(key.to_vec(), Some(b"v".to_vec()), 1))
+                    .await?;
+            }
+            writer.flush().await?;
+        }
+        let reader = SSTableReader::new(&dir.join("1.db")).await?;
+        assert_eq!(reader.key_range(), Some(&b"a"[..]..=&b"c"[..]));
+
+        // between the two ranges
+        let querier = SSTableQuerier::new(dir).await?;
+        assert!(querier.query(b"m").await.is_none());
+        let entries = querier
+            .scan((Bound::Excluded(b"c"), Bound::Excluded(b"x")))
+            .await?;
+        assert!(entries.is_empty());
+        assert_eq!(querier.files_opened(), 0);
+
+        // within one of them
+        assert!(querier.query(b"c").await.is_some());
+        assert_eq!(querier.files_opened(), 1);
+        let entries = querier
+            .scan((Bound::Included(b"y"), Bound::Unbounded))
+            .await?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(querier.files_opened(), 2);
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_caches_the_readers_until_invalidated() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_querier_cache")?;
+        let dir = temp_dir.path();
+        let db_path_1 = dir.join("1.db");
+        let db_path_2 = dir.join("2.db");
+
+        // seed
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        SSTableWriter::new(&db_path_1)
+            .await?
+            .set(&entry_1)
+            .await?
+            .flush()
+            .await?;
+
+        let querier = SSTableQuerier::new(dir).await?;
+        assert!(querier.query(b"test1").await.is_some());
+        assert!(querier.query(b"test1").await.is_some());
+        assert_eq!(
+            querier
+                .scan((Bound::Unbounded, Bound::Unbounded))
+                .await?
+                .len(),
+            1
+        );
+        assert_eq!(querier.files_opened(), 1);
+
+        // a new file is only seen once invalidated
+        let entry_2 = Entry::new(b"test2".to_vec(), Some(b"world".to_vec()), 2);
+        SSTableWriter::new(&db_path_2)
+            .await?
+            .set(&entry_2)
+            .await?
+            .flush()
+            .await?;
+        assert!(querier.query(b"test2").await.is_none());
+        querier.invalidate(&db_path_2);
+        assert!(querier.query(b"test2").await.is_some());
+        assert_eq!(querier.files_opened(), 2);
+
+        // as is a change to a cached one
+        let mut writer = SSTableWriter::new(&db_path_1).await?;
+        writer.remove(b"test1");
+        writer.flush().await?;
+        querier.invalidate(&db_path_1);
+        assert!(querier.query(b"test1").await.is_none());
+
+        temp_dir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_skips_files_with_the_bloom_filter() -> Result<()> {
+        let temp_dir = TempDir::new("sstable_querier_bloom_filter")?;
+        let dir = temp_dir.path();
+        let db_path_1 = dir.join("1.db");
+        let db_path_2 = dir.join("2.db");
+
+        // seed
+        let entry_1 = Entry::new(b"test1".to_vec(), Some(b"hello".to_vec()), 1);
+        let entry_2 = Entry::new(b"test2".to_vec(), Some(b"world".to_vec()), 2);
+        SSTableWriter::new(&db_path_1)
+            .await?
+            .set(&entry_1)
+            .await?
+            .flush()
+            .await?;
+        SSTableWriter::new(&db_path_2)
+            .await?
+            .set(&entry_2)
+            .await?
+            .flush()
+            .await?;
+        assert!(SSTableReader::may_contain(&db_path_1, b"test1", &LocalFs).await);
+        assert!(!SSTableReader::may_contain(&db_path_1, b"test2", &LocalFs).await);
+
+        // an SSTable without a filter may contain anything
+        tokio::fs::remove_file(dir.join("1.db.bf")).await?;
+        assert!(SSTableReader::may_contain(&db_path_1, b"test2", &LocalFs).await);
+
+        let querier = SSTableQuerier::new(dir).await?;
         assert!(querier.query(b"test1").await.is_some());
         assert!(querier.query(b"test2").await.is_some());
         assert!(querier.query(b"test3").await.is_none());
 
+        temp_dir.close()?;
         Ok(())
     }
 }
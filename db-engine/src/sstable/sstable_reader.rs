@@ -1,16 +1,37 @@
 use anyhow::Result;
 use async_trait::async_trait;
-use std::path::PathBuf;
-use tokio::{
-    fs::{File, OpenOptions},
-    io::{self, AsyncSeekExt, BufReader},
+use bytes::Bytes;
+use std::{
+    cmp::Ordering,
+    collections::btree_map,
+    future::Future,
+    ops::{Bound, RangeInclusive},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
 };
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio_stream::{Stream, StreamExt};
 
-use crate::prelude::*;
+use crate::{
+    dump::{corruption_line, entry_line, DumpSummary},
+    entries::DEFAULT_MAX_FIELD_LEN,
+    prelude::*,
+    storage::{LocalFs, ReadableFile, Storage},
+};
 
 use super::{
-    get_index_path,
-    sstable_index::{SSTableIndex, SSTableIndexBuilder},
+    block::{pack_offset, read_block, unpack_offset, BLOCK_SIZE},
+    bloom_filter::BloomFilter,
+    footer::{
+        corruption, entry_encoding, is_block_format, SSTableFooter, SSTABLE_VERSION_CHECKSUM,
+        SSTABLE_VERSION_FOOTER,
+    },
+    get_bloom_filter_path, key_range_overlaps,
+    lazy_index::LazyIndex,
+    load_index,
+    sstable_index::{IndexFormat, SSTableIndex},
 };
 
 /// This function will be called for each Entry when calling SSTableReader#scan
@@ -19,48 +40,793 @@ pub trait SSTableReaderScanHandler {
     async fn handle(&mut self, entry: Entry) -> Result<()>;
 }
 
-/// Sorted String Table
+/// Bytes read at once for a record, a longer one takes another read.
+const RECORD_READ_AHEAD: u64 = 512;
+
+/// How an [`SSTableReader`] gets at the index of its SSTable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexMode {
+    /// Load the whole index into memory on open
+    #[default]
+    Eager,
+    /// Leave the index in the file and binary search it on every lookup, O(log n) reads. An
+    /// SSTable written before the sorted index format is still loaded eagerly.
+    Lazy,
+}
+
+/// How an [`SSTableReader`] opens its SSTable.
+#[derive(Debug, Clone, Copy)]
+pub struct SSTableReaderOptions {
+    pub index_mode: IndexMode,
+    /// Rebuild a corrupt index from the data records instead of failing
+    pub rebuild_corrupt_index: bool,
+    /// The longest key or value read back, a longer length prefix is a corrupted record. See
+    /// [`DatabaseBuilder::max_field_len`](crate::DatabaseBuilder::max_field_len).
+    pub max_field_len: usize,
+}
+
+impl Default for SSTableReaderOptions {
+    fn default() -> Self {
+        Self {
+            index_mode: IndexMode::default(),
+            rebuild_corrupt_index: false,
+            max_field_len: DEFAULT_MAX_FIELD_LEN,
+        }
+    }
+}
+
+/// Sorted String Table, read with positional reads so one reader can serve concurrent lookups
 pub struct SSTableReader {
-    index: SSTableIndex,
-    reader: BufReader<File>,
+    path: PathBuf,
+    version: u16,
+    file_len: u64,
+    /// End of the data records, where the index block starts
+    data_end: u64,
+    /// Only every `index_interval`th key is in the index, see [`SSTableReader::try_get`]
+    index_interval: u32,
+    key_range: Option<(Vec<u8>, Vec<u8>)>,
+    /// `None` when the footer does not know it
+    max_timestamp: Option<u128>,
+    index: ReaderIndex,
+    file: Arc<dyn ReadableFile>,
+    max_field_len: usize,
+    /// The last block read, block format only
+    cached_block: Mutex<Option<Arc<CachedBlock>>>,
+}
+
+enum ReaderIndex {
+    Loaded(SSTableIndex),
+    Lazy(LazyIndex),
+}
+
+struct CachedBlock {
+    offset: u64,
+    /// Length of the block in the file
+    len: u64,
+    records: Bytes,
 }
 
 impl SSTableReader {
     pub async fn new(path: &PathBuf) -> Result<Self> {
-        let index_path = get_index_path(path)?;
-        let index = SSTableIndexBuilder::new(index_path)
-            .indexes()
-            .await?
-            .build();
+        Self::with_options(path, SSTableReaderOptions::default()).await
+    }
+
+    pub async fn with_options(path: &PathBuf, options: SSTableReaderOptions) -> Result<Self> {
+        Self::with_storage(path, options, &LocalFs).await
+    }
+
+    /// Like [`SSTableReader::with_options`], for an SSTable of `storage`.
+    pub async fn with_storage(
+        path: &PathBuf,
+        options: SSTableReaderOptions,
+        storage: &dyn Storage,
+    ) -> Result<Self> {
+        let file = storage.open_read(path).await?;
+        let file_len = file.size().await?;
+        let (index, footer) = match open_index(path, &file, options.index_mode, storage).await {
+            Err(e)
+                if options.rebuild_corrupt_index
+                    && matches!(e.downcast_ref::<Error>(), Some(Error::CorruptIndex(_))) =>
+            {
+                tracing::warn!("Rebuilding the index of {:?} from its data: {}", path, e);
+                let index =
+                    SSTableIndex::rebuild_from_data(path, storage, options.max_field_len).await?;
+                let footer = SSTableFooter::read_from(path, file.as_ref()).await?;
+                (ReaderIndex::Loaded(index), footer)
+            }
+            result => result?,
+        };
+
+        let max_timestamp = footer.as_ref().and_then(|footer| footer.max_timestamp);
+        let (version, data_end, index_interval, key_range) = match footer {
+            Some(footer) => {
+                let key_range =
+                    (footer.entry_count > 0).then_some((footer.min_key, footer.max_key));
+                (
+                    footer.version,
+                    footer.index_offset,
+                    footer.index_interval,
+                    key_range,
+                )
+            }
+            None => {
+                let key_range = match &index {
+                    ReaderIndex::Loaded(index) => index
+                        .first_key()
+                        .zip(index.last_key())
+                        .map(|(min, max)| (min.to_vec(), max.to_vec())),
+                    ReaderIndex::Lazy(_) => None,
+                };
+                (SSTABLE_VERSION_FOOTER - 1, file_len, 1, key_range)
+            }
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            version,
+            file_len,
+            data_end,
+            index_interval,
+            key_range,
+            max_timestamp,
+            index,
+            file,
+            max_field_len: options.max_field_len,
+            cached_block: Mutex::new(None),
+        })
+    }
+
+    /// Check the bloom filter of the SSTable at `path` of `storage` without loading its index.
+    /// `false` means the key is definitely not there, an SSTable without a filter may contain
+    /// any key.
+    pub async fn may_contain(path: &Path, key: &[u8], storage: &dyn Storage) -> bool {
+        let Ok(bloom_filter_path) = get_bloom_filter_path(path) else {
+            return true;
+        };
+        BloomFilter::load(&bloom_filter_path, storage)
+            .await
+            .is_none_or(|bloom_filter| bloom_filter.may_contain(key))
+    }
+
+    /// Check the key range in the footer of the SSTable at `path` of `storage` without loading
+    /// its index. `false` means no key in `bounds` is there, the range of a version 1 SSTable is
+    /// unknown.
+    pub async fn may_overlap(
+        path: &Path,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+        storage: &dyn Storage,
+    ) -> bool {
+        let footer = read_footer(path, storage).await;
+        match footer {
+            Ok(Some(footer)) if footer.entry_count == 0 => false,
+            Ok(Some(footer)) => key_range_overlaps(&footer.min_key, &footer.max_key, bounds),
+            Ok(None) => true,
+            Err(e) => {
+                tracing::warn!("Failed to read the footer of {:?}: {:?}", path, e);
+                true
+            }
+        }
+    }
+
+    /// The smallest and the largest key in the footer of the SSTable at `path` of `storage`,
+    /// without loading its index. `None` when it is empty or its footer does not tell.
+    pub async fn footer_key_range(
+        path: &Path,
+        storage: &dyn Storage,
+    ) -> Option<(Vec<u8>, Vec<u8>)> {
+        let footer = read_footer(path, storage).await;
+        match footer {
+            Ok(Some(footer)) if footer.entry_count > 0 => Some((footer.min_key, footer.max_key)),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::warn!("Failed to read the footer of {:?}: {:?}", path, e);
+                None
+            }
+        }
+    }
 
-        let file = OpenOptions::new().write(true).read(true).open(path).await?;
-        let reader = BufReader::new(file);
+    /// Check the max timestamp in the footer of the SSTable at `path` of `storage` without
+    /// loading its index. `false` means no entry there is newer than `timestamp`, an SSTable
+    /// whose footer does not know its max timestamp may hold any.
+    pub async fn may_be_newer(path: &Path, timestamp: u128, storage: &dyn Storage) -> bool {
+        match Self::footer_max_timestamp(path, storage).await {
+            Ok(max_timestamp) => {
+                max_timestamp.is_none_or(|max_timestamp| max_timestamp > timestamp)
+            }
+            Err(e) => {
+                tracing::warn!("Failed to read the footer of {:?}: {:?}", path, e);
+                true
+            }
+        }
+    }
+
+    /// The max timestamp in the footer of the SSTable at `path` of `storage` without loading its
+    /// index, `None` when the footer does not know it.
+    pub(crate) async fn footer_max_timestamp(
+        path: &Path,
+        storage: &dyn Storage,
+    ) -> Result<Option<u128>> {
+        let footer = read_footer(path, storage).await?;
+        Ok(footer.and_then(|footer| footer.max_timestamp))
+    }
+
+    /// The greatest timestamp of the entries, `None` when unknown
+    pub fn max_timestamp(&self) -> Option<u128> {
+        self.max_timestamp
+    }
+
+    /// The smallest and the largest key of the SSTable, `None` when it is empty
+    pub fn key_range(&self) -> Option<RangeInclusive<&[u8]>> {
+        let (min, max) = self.key_range.as_ref()?;
+        Some(min.as_slice()..=max.as_slice())
+    }
+
+    /// `false` when no key of the SSTable falls in `bounds`, [`SSTableReader::may_overlap`]
+    /// for an opened SSTable
+    pub fn overlaps(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> bool {
+        self.key_range()
+            .is_some_and(|keys| key_range_overlaps(keys.start(), keys.end(), bounds))
+    }
 
-        Ok(Self { index, reader })
+    /// Only every `index_interval`th key is in the index, 1 for a dense index
+    #[cfg(test)]
+    pub fn index_interval(&self) -> u32 {
+        self.index_interval
     }
 
-    /// Get Entry from SSTable file
-    pub async fn get(&mut self, key: &[u8]) -> Option<Entry> {
-        if let Some(&offset) = self.index.get(key) {
-            return self.read(offset).await;
+    #[cfg(test)]
+    pub(crate) fn index_mode(&self) -> IndexMode {
+        match self.index {
+            ReaderIndex::Loaded(_) => IndexMode::Eager,
+            ReaderIndex::Lazy(_) => IndexMode::Lazy,
         }
+    }
 
-        None
+    /// Get Entry from SSTable file, a corrupted entry is logged and treated as missing
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(path = ?self.path, key_len = key.len())))]
+    pub async fn get(&self, key: &[u8]) -> Option<Entry> {
+        self.try_get(key).await.unwrap_or_else(|e| {
+            tracing::error!("{e}");
+            None
+        })
     }
 
-    /// Read Entry from SSTable file by offset
-    pub async fn read(&mut self, offset: u64) -> Option<Entry> {
-        self.reader.seek(io::SeekFrom::Start(offset)).await.ok()?;
-        Entry::read_from(&mut self.reader).await
+    /// Get Entry from SSTable file, failing with [`Error::Corruption`] when the index points
+    /// at an entry of another key. With a sparse index the records are walked from the greatest
+    /// indexed key not after `key` until it is found or passed.
+    pub async fn try_get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if self.index_interval > 1 {
+            let Some(mut offset) = self.start_offset(Bound::Included(key)).await? else {
+                return Ok(None);
+            };
+            while let Some((entry, next_offset)) = self.try_read_next(offset).await? {
+                match entry.key.as_slice().cmp(key) {
+                    Ordering::Less => offset = next_offset,
+                    Ordering::Equal => return Ok(Some(entry)),
+                    Ordering::Greater => break,
+                }
+            }
+            return Ok(None);
+        }
+
+        let offset = match &self.index {
+            ReaderIndex::Loaded(index) => index.get(key).copied(),
+            ReaderIndex::Lazy(index) => index.get(key).await?,
+        };
+        let Some(offset) = offset else {
+            return Ok(None);
+        };
+        let Some(entry) = self.try_read(offset).await? else {
+            return Ok(None);
+        };
+        if entry.key != key {
+            return Err(Error::Corruption {
+                path: self.path.clone(),
+                offset,
+                reason: format!(
+                    "the index entry of key {:?} points at key {:?}",
+                    String::from_utf8_lossy(key),
+                    String::from_utf8_lossy(&entry.key)
+                ),
+            }
+            .into());
+        }
+
+        Ok(Some(entry))
+    }
+
+    /// Read Entry from SSTable file by offset, failing with [`Error::Corruption`] when the
+    /// record is malformed or does not match its checksum
+    pub async fn try_read(&self, offset: u64) -> Result<Option<Entry>> {
+        if !is_block_format(self.version) {
+            return self
+                .read_record(offset)
+                .await
+                .map_err(|e| self.read_error(offset, e));
+        }
+
+        let (block_offset, inner_offset) = unpack_offset(offset);
+        self.read_from_block(block_offset, inner_offset)
+            .await
+            .map(|(entry, _)| entry)
+            .map_err(|e| self.read_error(block_offset, e))
     }
 
-    /// Scan Entries from SSTable file
-    pub async fn scan(&mut self, mut handler: impl SSTableReaderScanHandler) -> Result<()> {
-        for (_, offset) in self.index.indexes().clone() {
-            if let Some(entry) = self.read(offset).await {
-                handler.handle(entry).await?
+    fn read_error(&self, offset: u64, e: WalReadError) -> anyhow::Error {
+        match e {
+            WalReadError::Io(e) => e.into(),
+            e => corruption(&self.path, offset, &e.to_string()).into(),
+        }
+    }
+
+    /// Read the Entry at `offset` together with the offset of the record after it, `None` at
+    /// the end of the data records.
+    async fn try_read_next(&self, offset: u64) -> Result<Option<(Entry, u64)>> {
+        let encoding = entry_encoding(self.version);
+        if !is_block_format(self.version) {
+            if offset >= self.data_end {
+                return Ok(None);
+            }
+            let Some(entry) = self.try_read(offset).await? else {
+                return Ok(None);
+            };
+            let checksum_len = match self.version >= SSTABLE_VERSION_CHECKSUM {
+                true => 4,
+                false => 0,
+            };
+            let next_offset = offset + (entry.encoded_len_with(encoding) + checksum_len) as u64;
+            return Ok(Some((entry, next_offset)));
+        }
+
+        let (block_offset, inner_offset) = unpack_offset(offset);
+        if block_offset >= self.data_end {
+            return Ok(None);
+        }
+        let (entry, block) = self
+            .read_from_block(block_offset, inner_offset)
+            .await
+            .map_err(|e| self.read_error(block_offset, e))?;
+        let Some(entry) = entry else {
+            return Ok(None);
+        };
+        let inner_offset = inner_offset + entry.encoded_len_with(encoding) + 4;
+        let next_offset = match inner_offset < block.records.len() {
+            true => pack_offset(block_offset, inner_offset),
+            false => pack_offset(block_offset + block.len, 0),
+        };
+        Ok(Some((entry, next_offset)))
+    }
+
+    /// Offset of the greatest indexed key not after `start`, or of the first one
+    async fn start_offset(&self, start: Bound<&[u8]>) -> Result<Option<u64>> {
+        let index = match &self.index {
+            ReaderIndex::Loaded(index) => index,
+            ReaderIndex::Lazy(index) => return index.start_offset(start).await,
+        };
+        let preceding = match start {
+            Bound::Included(key) | Bound::Excluded(key) => index
+                .range((Bound::Unbounded, Bound::Included(key)))
+                .next_back(),
+            Bound::Unbounded => None,
+        };
+        Ok(preceding
+            .or_else(|| index.range((Bound::Unbounded, Bound::Unbounded)).next())
+            .map(|(_, &offset)| offset))
+    }
+
+    async fn read_record(&self, offset: u64) -> Result<Option<Entry>, WalReadError> {
+        let remaining = self.file_len.saturating_sub(offset);
+        let mut len = RECORD_READ_AHEAD.min(remaining);
+        loop {
+            let bytes = Bytes::from(self.read_at(offset, len).await?);
+            let encoding = entry_encoding(self.version);
+            let result = match self.version >= SSTABLE_VERSION_CHECKSUM {
+                true => {
+                    Entry::try_read_checksummed_from(&bytes, offset, encoding, self.max_field_len)
+                        .await
+                }
+                false => {
+                    Entry::read_with(encoding, &mut bytes.as_ref(), len, self.max_field_len).await
+                }
+            };
+            match result {
+                // the record goes on past what was read
+                Err(WalReadError::UnexpectedEof { missing }) if len < remaining => {
+                    len = (len + missing as u64).max(len * 2).min(remaining);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Decode the record at `inner_offset` of the block at `block_offset`, together with the
+    /// block. The last block is kept around, so walking the records in order decompresses every
+    /// block once.
+    async fn read_from_block(
+        &self,
+        block_offset: u64,
+        inner_offset: usize,
+    ) -> Result<(Option<Entry>, Arc<CachedBlock>), WalReadError> {
+        let cached_block = self.cached_block.lock().unwrap().clone();
+        let block = match cached_block.filter(|block| block.offset == block_offset) {
+            Some(block) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(path = ?self.path, block_offset, "block cache hit");
+                block
+            }
+            None => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(path = ?self.path, block_offset, "block cache miss");
+                let block = Arc::new(self.read_block(block_offset).await?);
+                *self.cached_block.lock().unwrap() = Some(Arc::clone(&block));
+                block
+            }
+        };
+
+        let records = block.records.slice(inner_offset.min(block.records.len())..);
+        let encoding = entry_encoding(self.version);
+        let entry =
+            Entry::try_read_checksummed_from(&records, block_offset, encoding, self.max_field_len)
+                .await?;
+        Ok((entry, block))
+    }
+
+    async fn read_block(&self, offset: u64) -> Result<CachedBlock, WalReadError> {
+        let remaining = self.file_len.saturating_sub(offset);
+        let mut len = (BLOCK_SIZE as u64).min(remaining);
+        loop {
+            let bytes = self.read_at(offset, len).await?;
+            match read_block(&mut bytes.as_slice(), offset, len).await {
+                // a block compressing badly is larger than BLOCK_SIZE
+                Err(WalReadError::UnexpectedEof { missing }) if len < remaining => {
+                    len = (len + missing as u64).max(len * 2).min(remaining);
+                }
+                result => {
+                    let (records, len) = result?;
+                    return Ok(CachedBlock {
+                        offset,
+                        len,
+                        records: records.into(),
+                    });
+                }
+            }
+        }
+    }
+
+    async fn read_at(&self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        self.file.read_at(offset, len).await
+    }
+
+    /// Walk the Entries of the SSTable file in key order, reading them as the stream is
+    /// polled. A corrupted entry comes out as an error, the walk goes on after it unless the
+    /// index is sparse.
+    pub fn iter(&self) -> SSTableIterator<'_> {
+        self.iter_range((Bound::Unbounded, Bound::Unbounded))
+    }
+
+    /// [`SSTableReader::iter`] over the Entries whose key falls in `bounds`, starting at the
+    /// first of them
+    pub fn iter_range<'a>(
+        &'a self,
+        bounds: (Bound<&'a [u8]>, Bound<&'a [u8]>),
+    ) -> SSTableIterator<'a> {
+        let cursor = match self.overlaps(bounds) {
+            true => Cursor::Start,
+            false => Cursor::Done,
+        };
+        SSTableIterator {
+            reader: self,
+            bounds,
+            cursor,
+            pending: None,
+        }
+    }
+
+    /// Read the Entries whose key falls in `bounds` from SSTable file, in key order
+    #[cfg(test)]
+    pub async fn range(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Vec<Entry> {
+        self.range_limit(bounds, usize::MAX).await
+    }
+
+    /// The first `limit` Entries whose key falls in `bounds`, in key order
+    pub async fn range_limit(
+        &self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+        limit: usize,
+    ) -> Vec<Entry> {
+        self.iter_range(bounds)
+            .filter_map(|entry| entry.map_err(|e| tracing::error!("{e}")).ok())
+            .take(limit)
+            .collect()
+            .await
+    }
+
+    /// Scan Entries from SSTable file, corrupted entries are reported and skipped so the rest
+    /// of the file can still be salvaged
+    pub async fn scan(&self, mut handler: impl SSTableReaderScanHandler) -> Result<()> {
+        let mut entries = self.iter();
+        while let Some(entry) = entries.next().await {
+            match entry {
+                Ok(entry) => handler.handle(entry).await?,
+                Err(e) => tracing::error!("{e}"),
             }
         }
         Ok(())
     }
+
+    /// Write every record of the SSTable to `out` as text: its offset, key, timestamp and the
+    /// length of its value, or that it is a tombstone, with the value in hex when `raw` is set.
+    /// The records are found through the index, so a corrupted one is reported with its
+    /// offset and the dump goes on with the next indexed one. The offset of a record in a
+    /// block is the one of the block plus the one within it.
+    pub async fn dump(&self, mut out: impl AsyncWrite + Unpin, raw: bool) -> Result<DumpSummary> {
+        let mut summary = DumpSummary::default();
+        let header = format!(
+            "SSTable {:?}: version {}, {} bytes, data records up to {}, index interval {}\n",
+            self.path, self.version, self.file_len, self.data_end, self.index_interval
+        );
+        out.write_all(header.as_bytes()).await?;
+
+        let index = match self.index_entries().await {
+            Ok(index) => index,
+            Err(e) => {
+                summary.corruptions += 1;
+                out.write_all(corruption_line("index", &format!("{:#}", e)).as_bytes())
+                    .await?;
+                return Ok(summary);
+            }
+        };
+        for (position, (key, start)) in index.iter().enumerate() {
+            // a sparse index leaves the records up to the next indexed one to walk
+            let end = index
+                .get(position + 1)
+                .map_or(u64::MAX, |(_, offset)| *offset);
+            let mut offset = *start;
+            loop {
+                let line = match self.try_read_next(offset).await {
+                    Ok(None) => break,
+                    Ok(Some((entry, _))) if offset == *start && entry.key != *key => {
+                        summary.corruptions += 1;
+                        let reason = format!(
+                            "the index entry of key \"{}\" points at key \"{}\"",
+                            key.escape_ascii(),
+                            entry.key.escape_ascii()
+                        );
+                        out.write_all(
+                            corruption_line(&self.dump_offset(offset), &reason).as_bytes(),
+                        )
+                        .await?;
+                        break;
+                    }
+                    Ok(Some((entry, next_offset))) => {
+                        summary.records += 1;
+                        let line = entry_line(&self.dump_offset(offset), &entry, raw);
+                        offset = next_offset;
+                        line
+                    }
+                    Err(e) => {
+                        summary.corruptions += 1;
+                        let line = corruption_line(&self.dump_offset(offset), &format!("{:#}", e));
+                        out.write_all(line.as_bytes()).await?;
+                        break;
+                    }
+                };
+                out.write_all(line.as_bytes()).await?;
+                if offset >= end {
+                    break;
+                }
+            }
+        }
+        out.write_all(
+            format!(
+                "{} records, {} corrupted\n",
+                summary.records, summary.corruptions
+            )
+            .as_bytes(),
+        )
+        .await?;
+        out.flush().await?;
+        Ok(summary)
+    }
+
+    /// The keys of the index and the offsets of their records, in key order
+    async fn index_entries(&self) -> Result<Vec<(Vec<u8>, u64)>> {
+        match &self.index {
+            ReaderIndex::Loaded(index) => Ok(index
+                .range((Bound::Unbounded, Bound::Unbounded))
+                .map(|(key, offset)| (key.clone(), *offset))
+                .collect()),
+            ReaderIndex::Lazy(index) => {
+                let mut entries = Vec::new();
+                for position in 0..index.len() {
+                    entries.push(index.entry(position).await?);
+                }
+                Ok(entries)
+            }
+        }
+    }
+
+    /// `offset` as written by [`SSTableReader::dump`]
+    fn dump_offset(&self, offset: u64) -> String {
+        match is_block_format(self.version) {
+            true => {
+                let (block_offset, inner_offset) = unpack_offset(offset);
+                format!("{}+{}", block_offset, inner_offset)
+            }
+            false => offset.to_string(),
+        }
+    }
+
+    /// Where the walk over the Entries in `bounds` starts
+    async fn seek<'a>(&'a self, bounds: (Bound<&'a [u8]>, Bound<&'a [u8]>)) -> Result<Cursor<'a>> {
+        if self.index_interval > 1 {
+            return Ok(match self.start_offset(bounds.0).await? {
+                Some(offset) => Cursor::Records(offset),
+                None => Cursor::Done,
+            });
+        }
+        match &self.index {
+            ReaderIndex::Loaded(index) => Ok(Cursor::Index(index.range(bounds))),
+            ReaderIndex::Lazy(index) => Ok(Cursor::LazyIndex(index.position(bounds.0).await?)),
+        }
+    }
+
+    /// Move `cursor` on to the next Entry in `bounds`, `None` once the walk is done
+    async fn next_entry<'a>(
+        &'a self,
+        bounds: (Bound<&'a [u8]>, Bound<&'a [u8]>),
+        mut cursor: Cursor<'a>,
+    ) -> (Cursor<'a>, Option<Result<Entry>>) {
+        loop {
+            cursor = match cursor {
+                Cursor::Start => match self.seek(bounds).await {
+                    Ok(cursor) => cursor,
+                    Err(e) => return (Cursor::Done, Some(Err(e))),
+                },
+                Cursor::Index(mut keys) => {
+                    let Some((_, &offset)) = keys.next() else {
+                        return (Cursor::Done, None);
+                    };
+                    match self.try_read(offset).await {
+                        // the index points past the end of the file
+                        Ok(None) => Cursor::Index(keys),
+                        read => return (Cursor::Index(keys), read.transpose()),
+                    }
+                }
+                Cursor::LazyIndex(position) => {
+                    let ReaderIndex::Lazy(index) = &self.index else {
+                        unreachable!("only a lazy index is walked by position")
+                    };
+                    if position >= index.len() {
+                        return (Cursor::Done, None);
+                    }
+                    let next = Cursor::LazyIndex(position + 1);
+                    let offset = match index.entry(position).await {
+                        Ok((key, _)) if past_end(&key, bounds.1) => return (Cursor::Done, None),
+                        Ok((_, offset)) => offset,
+                        Err(e) => return (next, Some(Err(e))),
+                    };
+                    match self.try_read(offset).await {
+                        Ok(None) => next,
+                        read => return (next, read.transpose()),
+                    }
+                }
+                Cursor::Records(offset) => match self.try_read_next(offset).await {
+                    Ok(Some((entry, _))) if past_end(&entry.key, bounds.1) => {
+                        return (Cursor::Done, None)
+                    }
+                    Ok(Some((entry, next_offset))) => {
+                        let next = Cursor::Records(next_offset);
+                        if !before_start(&entry.key, bounds.0) {
+                            return (next, Some(Ok(entry)));
+                        }
+                        next
+                    }
+                    Ok(None) => return (Cursor::Done, None),
+                    // nothing after a corrupted record can be found without the index
+                    Err(e) => return (Cursor::Done, Some(Err(e))),
+                },
+                Cursor::Done => return (Cursor::Done, None),
+            }
+        }
+    }
+}
+
+type PendingRead<'a> =
+    Pin<Box<dyn Future<Output = (Cursor<'a>, Option<Result<Entry>>)> + Send + 'a>>;
+
+/// The Entries of an SSTable in key order, see [`SSTableReader::iter`]
+pub struct SSTableIterator<'a> {
+    reader: &'a SSTableReader,
+    bounds: (Bound<&'a [u8]>, Bound<&'a [u8]>),
+    /// Taken by the pending read, which hands it back moved past the Entry it read
+    cursor: Cursor<'a>,
+    pending: Option<PendingRead<'a>>,
+}
+
+enum Cursor<'a> {
+    /// Nothing read yet, the start of the bounds is looked up on the first poll
+    Start,
+    /// Dense loaded index, the keys left in the bounds
+    Index(btree_map::Range<'a, Vec<u8>, u64>),
+    /// Dense lazy index, the position of the next key
+    LazyIndex(u64),
+    /// Sparse index, the next record to read
+    Records(u64),
+    Done,
+}
+
+impl Stream for SSTableIterator<'_> {
+    type Item = Result<Entry>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let pending = match this.pending.as_mut() {
+            Some(pending) => pending,
+            None => match std::mem::replace(&mut this.cursor, Cursor::Done) {
+                Cursor::Done => return Poll::Ready(None),
+                cursor => this
+                    .pending
+                    .insert(Box::pin(this.reader.next_entry(this.bounds, cursor))),
+            },
+        };
+
+        let (cursor, entry) = match pending.as_mut().poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(read) => read,
+        };
+        this.pending = None;
+        this.cursor = cursor;
+        Poll::Ready(entry)
+    }
+}
+
+/// The index of the SSTable `file` together with its footer, left in the file in
+/// [`IndexMode::Lazy`] when it is in the sorted format
+/// The footer of the SSTable at `path` of `storage`, see [`SSTableFooter::read_from`]
+async fn read_footer(path: &Path, storage: &dyn Storage) -> Result<Option<SSTableFooter>> {
+    let file = storage.open_read(path).await?;
+    SSTableFooter::read_from(path, file.as_ref()).await
+}
+
+async fn open_index(
+    path: &Path,
+    file: &Arc<dyn ReadableFile>,
+    index_mode: IndexMode,
+    storage: &dyn Storage,
+) -> Result<(ReaderIndex, Option<SSTableFooter>)> {
+    let footer = match index_mode {
+        IndexMode::Eager => None,
+        IndexMode::Lazy => SSTableFooter::read_from(path, file.as_ref())
+            .await?
+            .filter(|footer| footer.index_format == IndexFormat::Sorted),
+    };
+    match footer {
+        Some(footer) => {
+            let index = LazyIndex::open(path, Arc::clone(file), &footer).await?;
+            Ok((ReaderIndex::Lazy(index), Some(footer)))
+        }
+        None => {
+            let (index, footer) = load_index(path, file.as_ref(), storage).await?;
+            Ok((ReaderIndex::Loaded(index), footer))
+        }
+    }
+}
+
+/// Whether `key` comes before the `start` bound
+fn before_start(key: &[u8], start: Bound<&[u8]>) -> bool {
+    match start {
+        Bound::Included(start) => key < start,
+        Bound::Excluded(start) => key <= start,
+        Bound::Unbounded => false,
+    }
+}
+
+/// Whether `key` comes after the `end` bound
+fn past_end(key: &[u8], end: Bound<&[u8]>) -> bool {
+    match end {
+        Bound::Included(end) => key > end,
+        Bound::Excluded(end) => key >= end,
+        Bound::Unbounded => false,
+    }
 }
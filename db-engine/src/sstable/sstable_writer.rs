@@ -1,69 +1,405 @@
-use anyhow::Result;
-use std::path::PathBuf;
-use tokio::{
-    fs::{File, OpenOptions},
-    io::{self, AsyncSeekExt, AsyncWriteExt, BufWriter},
+use anyhow::{bail, Context, Result};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
 };
+use tokio::io::{self, AsyncWriteExt, BufWriter};
 
-use crate::prelude::*;
+use crate::{
+    compression::Codec,
+    prelude::*,
+    storage::{self, AppendMode, Storage, WritableFile},
+    utils::allocate_tmp_file,
+};
 
 use super::{
-    get_index_path,
-    sstable_index::{SSTableIndex, SSTableIndexBuilder},
+    block::{encode_block, pack_offset, BLOCK_SIZE},
+    bloom_filter::{hash_key, BloomFilter},
+    footer::{
+        entry_encoding, is_block_format, SSTableFooter, SSTABLE_VERSION_CHECKSUM,
+        SSTABLE_VERSION_FOOTER, SSTABLE_VERSION_VARINT, SSTABLE_VERSION_VARINT_BLOCKS,
+    },
+    get_bloom_filter_path, get_index_path, load_index,
+    sstable_index::{IndexFormat, SSTableIndex, SSTableIndexBuilder},
+    with_tmp_suffix,
 };
 
-/// Sorted String Table
+/// How new SSTable files are written.
+#[derive(Debug, Clone, Copy)]
+pub struct SSTableOptions {
+    /// Compress the records in blocks, see [`SSTableWriter::set_compression`].
+    pub compression: Codec,
+    /// Index every this many keys, see [`SSTableWriter::set_index_interval`].
+    pub index_interval: u32,
+    /// Accept a key equal to the previous one, see [`SSTableWriter::set_allow_duplicates`].
+    pub allow_duplicates: bool,
+}
+
+impl Default for SSTableOptions {
+    fn default() -> Self {
+        Self {
+            compression: Codec::None,
+            index_interval: 1,
+            allow_duplicates: false,
+        }
+    }
+}
+
+/// Sorted String Table, written as the data records, the index block and the footer.
+///
+/// Everything goes to a `.tmp` file next to the SSTable, which [`SSTableWriter::flush`] renames
+/// into place, so a crash never leaves a partial SSTable behind. Appending to an existing
+/// SSTable starts from a copy of it.
 pub struct SSTableWriter {
+    /// Format version, an existing file keeps the record format it was written with
+    version: u16,
     index: SSTableIndex,
-    writer: BufWriter<File>,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    /// Opened on the first write
+    writer: Option<BufWriter<Box<dyn WritableFile>>>,
+    storage: Arc<dyn Storage>,
+    /// Length of the file once everything written so far is flushed
     offset: u64,
+    /// Codec of the blocks and the records of the block being gathered, block format only
+    codec: Codec,
+    block: Vec<u8>,
+    sparse_index: Option<SparseIndex>,
+    bloom_filter_path: PathBuf,
+    legacy_index_path: PathBuf,
+    /// The last key set, the next one has to come after it
+    last_key: Option<Vec<u8>>,
+    /// `None` when appending to an SSTable whose footer does not know it
+    max_timestamp: Option<u128>,
+    allow_duplicates: bool,
+    entries_written: u64,
+    bytes_written: u64,
+}
+
+/// The keys written to an SSTable whose index only holds every `interval`th of them.
+struct SparseIndex {
+    interval: u32,
+    entry_count: u64,
+    /// For the bloom filter, which covers every key
+    key_hashes: Vec<u64>,
+}
+
+impl SparseIndex {
+    /// Account for the next key, `true` when it goes into the index
+    fn add(&mut self, key: &[u8]) -> bool {
+        let indexed = self.entry_count.is_multiple_of(self.interval.into());
+        self.entry_count += 1;
+        self.key_hashes.push(hash_key(key));
+        indexed
+    }
 }
 
 impl SSTableWriter {
+    #[cfg(test)]
     pub async fn new(path: &PathBuf) -> Result<Self> {
-        let index_path = get_index_path(path)?;
-        let index = SSTableIndexBuilder::new(index_path)
-            .indexes()
-            .await?
-            .build();
-
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(path)
-            .await?;
-        let offset = file.metadata().await?.len();
-        let writer = BufWriter::new(file);
+        Self::with_storage(path, Arc::new(storage::LocalFs)).await
+    }
+
+    /// Like [`SSTableWriter::new`], for an SSTable of `storage`.
+    pub async fn with_storage(path: &PathBuf, storage: Arc<dyn Storage>) -> Result<Self> {
+        let legacy_index_path = get_index_path(path)?;
+        let bloom_filter_path = get_bloom_filter_path(path)?;
+
+        let (index, footer, offset) = match storage.open_read(path).await {
+            Ok(file) => {
+                let (index, footer) = load_index(path, file.as_ref(), storage.as_ref()).await?;
+                (index, footer, file.size().await?)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let index = SSTableIndexBuilder::new(legacy_index_path.clone()).build();
+                (index, None, 0)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if footer.as_ref().is_some_and(|f| f.index_interval > 1) {
+            bail!("cannot append to {:?}, its index is sparse", path);
+        }
+        let (version, max_timestamp) = match (offset, footer) {
+            (0, _) => (SSTABLE_VERSION_VARINT, Some(0)),
+            (_, Some(footer)) => (footer.version, footer.max_timestamp),
+            (_, None) => (SSTABLE_VERSION_FOOTER, None),
+        };
 
         Ok(Self {
+            version,
             index,
-            writer,
+            path: path.clone(),
+            tmp_path: with_tmp_suffix(path),
+            writer: None,
+            storage,
             offset,
+            codec: Codec::None,
+            block: Vec::new(),
+            sparse_index: None,
+            bloom_filter_path,
+            legacy_index_path,
+            last_key: None,
+            max_timestamp,
+            allow_duplicates: false,
+            entries_written: 0,
+            bytes_written: 0,
         })
     }
 
-    pub fn contains_key(&self, key: &[u8]) -> bool {
-        self.index.contains_key(key)
+    /// Apply the compression, the index interval and the key order of `options`.
+    pub fn set_options(&mut self, options: SSTableOptions) {
+        self.set_compression(options.compression);
+        self.set_index_interval(options.index_interval);
+        self.set_allow_duplicates(options.allow_duplicates);
+    }
+
+    /// Compress the records in blocks of about 4 KB with `codec`. Only a new file switches to
+    /// the block format, so call it before the first [`SSTableWriter::set`], an existing file
+    /// keeps its format.
+    pub fn set_compression(&mut self, codec: Codec) {
+        if self.offset == 0 && codec != Codec::None {
+            self.version = SSTABLE_VERSION_VARINT_BLOCKS;
+        }
+        self.codec = codec;
+    }
+
+    /// Only index every `interval`th key of a new file, the entries then have to be set in
+    /// ascending key order. Call it before the first [`SSTableWriter::set`], an existing file
+    /// keeps its dense index.
+    pub fn set_index_interval(&mut self, interval: u32) {
+        if self.offset > 0 || !self.index.is_empty() {
+            return;
+        }
+        self.sparse_index = (interval > 1).then(|| SparseIndex {
+            interval,
+            entry_count: 0,
+            key_hashes: Vec::new(),
+        });
+    }
+
+    /// Accept a key equal to the previous one, the later record then wins. A sparse index
+    /// still needs every key to be greater than the previous one.
+    pub fn set_allow_duplicates(&mut self, allow_duplicates: bool) {
+        self.allow_duplicates = allow_duplicates;
+    }
+
+    /// Entries set so far
+    pub fn entries_written(&self) -> u64 {
+        self.entries_written
     }
 
-    /// Set Entry to SSTable
-    pub async fn set(&mut self, entry: &Entry) -> io::Result<&mut Self> {
-        entry.write_to(&mut self.writer).await?;
-        self.index.insert(entry.key.as_slice(), self.offset);
-        self.offset += self.writer.stream_position().await?;
+    /// A writer to a new SSTable of `dir` in `storage` named after the `timestamp`, with a
+    /// suffix when that name is taken, see [`allocate_tmp_file`].
+    pub(crate) async fn create(
+        dir: &Path,
+        timestamp: u128,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Self> {
+        let (file, path) = allocate_tmp_file(dir, timestamp, "db", storage.as_ref()).await?;
+        let mut writer = Self::with_storage(&path, storage).await?;
+        writer.writer = Some(BufWriter::new(file));
+        Ok(writer)
+    }
+
+    /// Where the SSTable is renamed to once flushed
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Bytes written to the file so far, the records of a block count once it is compressed
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Write a new file in the format of an older `version`
+    #[cfg(test)]
+    pub(crate) fn set_version(&mut self, version: u16) {
+        assert_eq!(
+            self.offset, 0,
+            "only a new file can be written in another version"
+        );
+        self.version = version;
+    }
+
+    /// Remove the key from the index, its record stays in the file but can no longer be read
+    #[cfg(test)]
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        self.index.remove(key).is_some()
+    }
+
+    /// The `.tmp` file, created on the first write. New records go after the previous index
+    /// block and footer of a copied SSTable, which become dead space.
+    async fn writer(&mut self) -> io::Result<&mut BufWriter<Box<dyn WritableFile>>> {
+        if self.writer.is_none() {
+            let mut file = self
+                .storage
+                .open_append(&self.tmp_path, AppendMode::Truncate)
+                .await?;
+            if self.offset > 0 {
+                let copy = storage::read(self.storage.as_ref(), &self.path).await?;
+                file.write_all(&copy).await?;
+            }
+            self.writer = Some(BufWriter::new(file));
+        }
+        Ok(self.writer.as_mut().unwrap())
+    }
+
+    /// Set Entry to SSTable, the keys have to be set in ascending order. Fails with
+    /// [`Error::OutOfOrderKey`] when the key does not come after the previous one, a range scan
+    /// and a sparse index rely on it.
+    pub async fn set(&mut self, entry: &Entry) -> Result<&mut Self> {
+        if let Some(prev) = self.last_key.as_deref() {
+            let in_order = match self.allow_duplicates && self.sparse_index.is_none() {
+                true => prev <= entry.key.as_slice(),
+                false => prev < entry.key.as_slice(),
+            };
+            if !in_order {
+                return Err(Error::OutOfOrderKey {
+                    prev: prev.to_vec(),
+                    next: entry.key.clone(),
+                }
+                .into());
+            }
+        }
+        self.last_key = Some(entry.key.clone());
+        self.max_timestamp = self.max_timestamp.map(|max| max.max(entry.timestamp));
+        self.entries_written += 1;
+
+        let indexed = match self.sparse_index.as_mut() {
+            Some(sparse_index) => sparse_index.add(&entry.key),
+            None => true,
+        };
+
+        let encoding = entry_encoding(self.version);
+        if is_block_format(self.version) {
+            if self.block.len() >= BLOCK_SIZE {
+                self.write_block().await?;
+            }
+            if indexed {
+                let offset = pack_offset(self.offset, self.block.len());
+                self.index.insert(entry.key.as_slice(), offset);
+            }
+            entry
+                .write_checksummed_with(encoding, &mut self.block)
+                .await?;
+            return Ok(self);
+        }
+
+        let mut len = entry.encoded_len_with(encoding);
+        let checksummed = self.version >= SSTABLE_VERSION_CHECKSUM;
+        let writer = self.writer().await?;
+        if checksummed {
+            entry.write_checksummed_with(encoding, writer).await?;
+            len += 4;
+        } else {
+            entry.write_with(encoding, writer).await?;
+        }
+        if indexed {
+            self.index.insert(entry.key.as_slice(), self.offset);
+        }
+        self.offset += len as u64;
+        self.bytes_written += len as u64;
         Ok(self)
     }
 
-    /// Flush SSTable to the file
+    /// Compress the gathered records into a block and write it out
+    async fn write_block(&mut self) -> io::Result<()> {
+        let block = encode_block(self.codec, &self.block);
+        self.writer().await?.write_all(&block).await?;
+        self.offset += block.len() as u64;
+        self.bytes_written += block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Flush SSTable to the file, followed by its index block and footer, and write the bloom
+    /// filter over all of its keys.
+    ///
+    /// Both are synced and then renamed into place, the bloom filter first: a crash in between
+    /// leaves the previous SSTable (or none) next to a filter of the new one, which knows about
+    /// all of its keys.
     pub async fn flush(&mut self) -> Result<&mut Self> {
-        let persist_index = self.index.persist();
-        let flush_db = self.writer.flush();
+        if !self.block.is_empty() {
+            self.write_block().await?;
+        }
 
-        let (persist_result, flush_result) = tokio::join!(persist_index, flush_db);
-        persist_result?;
+        let min_key = self.index.first_key().unwrap_or_default().to_vec();
+        let (bloom_filter, entry_count, max_key, index_interval) = match &self.sparse_index {
+            Some(sparse_index) => {
+                let mut bloom_filter = BloomFilter::with_capacity(sparse_index.key_hashes.len());
+                for &hash in sparse_index.key_hashes.iter() {
+                    bloom_filter.insert_hash(hash);
+                }
+                let max_key = self.last_key.clone().unwrap_or_default();
+                (
+                    bloom_filter,
+                    sparse_index.entry_count,
+                    max_key,
+                    sparse_index.interval,
+                )
+            }
+            None => {
+                let mut bloom_filter = BloomFilter::with_capacity(self.index.len());
+                for key in self.index.keys() {
+                    bloom_filter.insert(key);
+                }
+                let max_key = self.index.last_key().unwrap_or_default().to_vec();
+                (bloom_filter, self.index.len() as u64, max_key, 1)
+            }
+        };
+
+        let index_block = self.index.encode();
+        let footer = SSTableFooter {
+            version: self.version,
+            index_offset: self.offset,
+            index_len: index_block.len() as u64,
+            entry_count,
+            min_key,
+            max_key,
+            index_interval,
+            index_format: IndexFormat::Sorted,
+            max_timestamp: self.max_timestamp,
+        }
+        .encode();
+        let offset = self.offset + (index_block.len() + footer.len()) as u64;
+        let writer = self.writer().await?;
+        writer.write_all(&index_block).await?;
+        writer.write_all(&footer).await?;
+        self.bytes_written += offset - self.offset;
+        self.offset = offset;
+
+        let bloom_filter_tmp_path = with_tmp_suffix(&self.bloom_filter_path);
+        let persist_bloom_filter =
+            bloom_filter.persist(&bloom_filter_tmp_path, self.storage.as_ref());
+        let mut writer = self.writer.take().unwrap();
+        let flush_db = async {
+            writer.flush().await?;
+            writer.get_mut().sync_data().await
+        };
+
+        let (bloom_filter_result, flush_result) = tokio::join!(persist_bloom_filter, flush_db);
+        bloom_filter_result?;
         flush_result?;
 
+        self.storage
+            .rename(&bloom_filter_tmp_path, &self.bloom_filter_path)
+            .await
+            .context("publish the bloom filter")?;
+        self.storage
+            .rename(&self.tmp_path, &self.path)
+            .await
+            .context("publish the sstable")?;
+        let dir = self.path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        self.storage.sync(dir.unwrap_or(Path::new("."))).await?;
+
+        // the index of a version 1 SSTable now lives in the file itself
+        match self.storage.remove(&self.legacy_index_path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).context("remove the legacy idx file")?
+            }
+            _ => {}
+        }
+
         Ok(self)
     }
 }
@@ -0,0 +1,54 @@
+/// Point-in-time statistics of a [`Database`](crate::Database).
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DatabaseStats {
+    /// Number of entries (including tombstones) in the active MemTable
+    pub mem_table_len: usize,
+    /// Number of entries holding a value in the active MemTable
+    pub mem_table_live_count: usize,
+    /// Number of tombstones in the active MemTable
+    pub mem_table_tombstone_count: usize,
+    /// Logical size of the active MemTable (key + value + timestamp + tombstone bytes)
+    pub mem_table_size: usize,
+    /// Approximate heap footprint of the active MemTable
+    pub mem_table_memory_usage: usize,
+    /// Approximate heap footprint of the MemTable being flushed in the background
+    pub immutable_mem_table_memory_usage: usize,
+    /// Bytes taken by the files of the database when last measured, on open and after a flush
+    /// or a compaction, plus the WAL bytes appended since
+    pub disk_usage: u64,
+    /// See [`DatabaseBuilder::max_disk_usage`](crate::DatabaseBuilder::max_disk_usage)
+    pub max_disk_usage: Option<u64>,
+    /// Whether the writes are held back when last measured: before a write, and after a flush
+    /// or a compaction
+    pub write_stall: WriteStall,
+    /// Whether the last background flush of a MemTable failed. It is retried while the writes
+    /// go on, their records are in the WAL, until one goes through.
+    pub flush_failing: bool,
+    /// Bytes of the values written since the database was opened, as given to the writes
+    pub logical_value_bytes: u64,
+    /// Bytes the same values are stored with, smaller than
+    /// [`logical_value_bytes`](Self::logical_value_bytes) once compressed, see
+    /// [`DatabaseBuilder::value_compression`](crate::DatabaseBuilder::value_compression)
+    pub stored_value_bytes: u64,
+    /// Reads answered by the read cache since the database was opened, see
+    /// [`DatabaseBuilder::read_cache_capacity`](crate::DatabaseBuilder::read_cache_capacity)
+    pub read_cache_hits: u64,
+    /// Reads of the SSTables the read cache could not answer since the database was opened
+    pub read_cache_misses: u64,
+}
+
+/// How the writes are held back while the flushes and compactions fall behind, see
+/// [`DatabaseBuilder::slowdown_sstable_count`](crate::DatabaseBuilder::slowdown_sstable_count).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum WriteStall {
+    /// The writes go through right away
+    #[default]
+    None,
+    /// Every write is delayed
+    Slowdown,
+    /// The writes fail with [`Error::WriteStalled`](crate::Error::WriteStalled)
+    Stopped,
+}
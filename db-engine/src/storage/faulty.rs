@@ -0,0 +1,201 @@
+use async_trait::async_trait;
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+use tokio::io::{self, AsyncWrite};
+
+use super::{AppendMode, LocalFs, Metadata, ReadableFile, Storage, WritableFile};
+
+/// Counts the writes and whether the fault hit, shared with the opened files
+#[derive(Debug)]
+struct Faults {
+    writes: AtomicU64,
+    /// The write which fails, `u64::MAX` for none
+    failing_write: AtomicU64,
+    /// The last write to go through, `u64::MAX` for none
+    last_write: AtomicU64,
+    /// The extension of the files whose removal fails, see [`FaultyFs::fail_remove`]
+    failing_remove: Mutex<Option<&'static str>>,
+    crashed: AtomicBool,
+}
+
+impl Faults {
+    /// Fails once the failing write is reached, and everything after it
+    fn check(&self, write: bool) -> io::Result<()> {
+        let fails = match write {
+            true => {
+                self.writes.load(Ordering::SeqCst) + 1 >= self.failing_write.load(Ordering::SeqCst)
+            }
+            false => false,
+        };
+        if fails {
+            self.crashed.store(true, Ordering::SeqCst);
+        }
+        match self.crashed.load(Ordering::SeqCst) {
+            true => Err(io::Error::other("injected fault")),
+            false => Ok(()),
+        }
+    }
+
+    /// Count a write which went through, the last one crashes right after it
+    fn written(&self) {
+        let writes = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        if writes >= self.last_write.load(Ordering::SeqCst) {
+            self.crashed.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// [`LocalFs`] failing the `n`th write, as if the process crashed right there: every write,
+/// sync, rename and removal after it fails as well, the reads still go through. See
+/// [`FaultyFs::fail_write`], [`FaultyFs::crash_after_write`] and [`FaultyFs::fail_remove`].
+/// Built with the `testutil` feature, for the crash tests.
+#[derive(Debug, Clone)]
+pub struct FaultyFs {
+    faults: Arc<Faults>,
+}
+
+impl FaultyFs {
+    pub fn new() -> Self {
+        Self {
+            faults: Arc::new(Faults {
+                writes: AtomicU64::new(0),
+                failing_write: AtomicU64::new(u64::MAX),
+                last_write: AtomicU64::new(u64::MAX),
+                failing_remove: Mutex::new(None),
+                crashed: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Fail the `n`th write from now on, 1 for the next one.
+    pub fn fail_write(&self, n: u64) {
+        let writes = self.faults.writes.load(Ordering::SeqCst);
+        self.faults
+            .failing_write
+            .store(writes + n, Ordering::SeqCst);
+    }
+
+    /// Crash right after the `n`th write from now on, which still goes through but is not
+    /// synced, 1 for the next one.
+    pub fn crash_after_write(&self, n: u64) {
+        let writes = self.faults.writes.load(Ordering::SeqCst);
+        self.faults.last_write.store(writes + n, Ordering::SeqCst);
+    }
+
+    /// Crash on the first removal of a file with `extension`, e.g. `wal` for the WAL files a
+    /// flush made obsolete.
+    pub fn fail_remove(&self, extension: &'static str) {
+        *self.faults.failing_remove.lock().unwrap() = Some(extension);
+    }
+
+    /// Whether the fault hit
+    pub fn crashed(&self) -> bool {
+        self.faults.crashed.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for FaultyFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Storage for FaultyFs {
+    async fn open_append(
+        &self,
+        path: &Path,
+        mode: AppendMode,
+    ) -> io::Result<Box<dyn WritableFile>> {
+        self.faults.check(false)?;
+        let inner = LocalFs.open_append(path, mode).await?;
+        Ok(Box::new(FaultyFile {
+            inner,
+            faults: Arc::clone(&self.faults),
+        }))
+    }
+
+    async fn open_read(&self, path: &Path) -> io::Result<Arc<dyn ReadableFile>> {
+        LocalFs.open_read(path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.faults.check(false)?;
+        LocalFs.rename(from, to).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let failing_remove = *self.faults.failing_remove.lock().unwrap();
+        if failing_remove.is_some_and(|ext| path.extension().is_some_and(|e| e == ext)) {
+            self.faults.crashed.store(true, Ordering::SeqCst);
+        }
+        self.faults.check(false)?;
+        LocalFs.remove(path).await
+    }
+
+    async fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.faults.check(false)?;
+        LocalFs.create_dir_all(dir).await
+    }
+
+    async fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        LocalFs.metadata(path).await
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        LocalFs.list(dir).await
+    }
+
+    async fn sync(&self, dir: &Path) -> io::Result<()> {
+        self.faults.check(false)?;
+        LocalFs.sync(dir).await
+    }
+}
+
+struct FaultyFile {
+    inner: Box<dyn WritableFile>,
+    faults: Arc<Faults>,
+}
+
+impl AsyncWrite for FaultyFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.faults.check(true)?;
+        let written = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if matches!(written, Poll::Ready(Ok(_))) {
+            self.faults.written();
+        }
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.faults.check(false)?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl WritableFile for FaultyFile {
+    async fn size(&self) -> io::Result<u64> {
+        self.inner.size().await
+    }
+
+    async fn sync_data(&mut self) -> io::Result<()> {
+        self.faults.check(false)?;
+        self.inner.sync_data().await
+    }
+}
@@ -0,0 +1,125 @@
+use async_trait::async_trait;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs::{create_dir_all, metadata, read_dir, remove_file, rename, File, OpenOptions},
+    io,
+};
+
+use super::{AppendMode, Metadata, ReadableFile, Storage, WritableFile};
+
+/// The files on the local filesystem, the default [`Storage`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalFs;
+
+#[async_trait]
+impl Storage for LocalFs {
+    async fn open_append(
+        &self,
+        path: &Path,
+        mode: AppendMode,
+    ) -> io::Result<Box<dyn WritableFile>> {
+        let mut options = OpenOptions::new();
+        match mode {
+            AppendMode::OpenOrCreate => options.append(true).create(true),
+            AppendMode::CreateNew => options.append(true).create_new(true),
+            // appending cannot truncate, a new file is written from its start all the same
+            AppendMode::Truncate => options.write(true).create(true).truncate(true),
+        };
+        Ok(Box::new(options.open(path).await?))
+    }
+
+    async fn open_read(&self, path: &Path) -> io::Result<Arc<dyn ReadableFile>> {
+        let file = File::open(path).await?.into_std().await;
+        Ok(Arc::new(LocalFile(Arc::new(file))))
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        rename(from, to).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        remove_file(path).await
+    }
+
+    async fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        create_dir_all(dir).await
+    }
+
+    async fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = metadata(path).await?;
+        Ok(Metadata {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            is_dir: metadata.is_dir(),
+        })
+    }
+
+    async fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut entries = read_dir(dir).await?;
+        let mut paths = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            paths.push(entry.path());
+        }
+        Ok(paths)
+    }
+
+    async fn sync(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir).await?.sync_all().await
+    }
+}
+
+#[async_trait]
+impl WritableFile for File {
+    async fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata().await?.len())
+    }
+
+    async fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self).await
+    }
+}
+
+/// A local file read with positional reads, without moving a shared cursor, so concurrent
+/// lookups can share it
+struct LocalFile(Arc<std::fs::File>);
+
+#[async_trait]
+impl ReadableFile for LocalFile {
+    async fn size(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    async fn read_at(&self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let file = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0; len as usize];
+            read_exact_at(&file, &mut buf, offset)?;
+            Ok(buf)
+        })
+        .await?
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &std::fs::File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use async_trait::async_trait;
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+#[cfg(feature = "testutil")]
+mod faulty;
+mod local;
+
+#[cfg(feature = "testutil")]
+pub use self::faulty::FaultyFs;
+pub use self::local::LocalFs;
+
+/// How [`Storage::open_append`] treats a file which already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendMode {
+    /// Append to it, a missing file is created
+    OpenOrCreate,
+    /// Fail with [`io::ErrorKind::AlreadyExists`], so two writers never share a file
+    CreateNew,
+    /// Start it over empty, a missing file is created
+    Truncate,
+}
+
+/// What [`Storage::metadata`] tells of a file or directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Size of the file in bytes
+    pub len: u64,
+    pub modified: SystemTime,
+    pub is_dir: bool,
+}
+
+/// Where the files of a database go: its WAL files, SSTables, their indexes and bloom filters,
+/// its manifest and its compaction lock. [`LocalFs`] keeps them on the local filesystem,
+/// another implementation may keep them in an object store or inject faults, see
+/// [`DatabaseBuilder::with_storage`](crate::DatabaseBuilder::with_storage).
+///
+/// Only the lock held by the open database of a directory stays on the local filesystem, the
+/// OS releases it when the process dies.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Open the file at `path` to write to its end, see [`AppendMode`].
+    async fn open_append(&self, path: &Path, mode: AppendMode)
+        -> io::Result<Box<dyn WritableFile>>;
+
+    /// Open the file at `path` for positional reads, which may be shared by concurrent
+    /// readers.
+    async fn open_read(&self, path: &Path) -> io::Result<Arc<dyn ReadableFile>>;
+
+    /// Move the file `from` to `to`, replacing the file there at once.
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    async fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Create the directory `dir` and its missing parents, nothing to do when it exists.
+    async fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// The size and modification time of the file at `path`,
+    /// [`io::ErrorKind::NotFound`] when there is none.
+    async fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// The paths of the entries of the directory `dir`, in no particular order.
+    async fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Make the entries of the directory `dir` durable, so a file created or renamed into it
+    /// survives a crash.
+    async fn sync(&self, dir: &Path) -> io::Result<()>;
+}
+
+/// A file of a [`Storage`] opened to append to.
+#[async_trait]
+pub trait WritableFile: AsyncWrite + Send + Sync + Unpin {
+    /// Size of the file in bytes, without the ones still buffered in a writer
+    async fn size(&self) -> io::Result<u64>;
+
+    /// Make the bytes written so far durable, along with the length of the file.
+    async fn sync_data(&mut self) -> io::Result<()>;
+}
+
+/// A file of a [`Storage`] opened for reading.
+#[async_trait]
+pub trait ReadableFile: Send + Sync {
+    async fn size(&self) -> io::Result<u64>;
+
+    /// Read `len` bytes at `offset`, [`io::ErrorKind::UnexpectedEof`] when the file ends before.
+    async fn read_at(&self, offset: u64, len: u64) -> io::Result<Vec<u8>>;
+}
+
+/// Length of the file at `path` of `storage`.
+pub(crate) async fn file_len(storage: &dyn Storage, path: &Path) -> io::Result<u64> {
+    storage.open_read(path).await?.size().await
+}
+
+/// The whole content of the file at `path` of `storage`.
+pub(crate) async fn read(storage: &dyn Storage, path: &Path) -> io::Result<Vec<u8>> {
+    let file = storage.open_read(path).await?;
+    file.read_at(0, file.size().await?).await
+}
+
+/// Whether there is a file or directory at `path` of `storage`.
+pub(crate) async fn exists(storage: &dyn Storage, path: &Path) -> io::Result<bool> {
+    match storage.metadata(path).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replace the file at `path` of `storage` with `bytes`, synced.
+pub(crate) async fn write(storage: &dyn Storage, path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut file = storage.open_append(path, AppendMode::Truncate).await?;
+    file.write_all(bytes).await?;
+    file.flush().await?;
+    file.sync_data().await
+}
+
+/// Bytes read from the file at once by a [`FileReader`]
+const READ_CHUNK_LEN: u64 = 64 * 1024;
+
+type ReadChunkFuture = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+
+/// Reads a [`ReadableFile`] from `offset` to its end as a stream of bytes, a chunk at a time.
+pub(crate) struct FileReader {
+    file: Arc<dyn ReadableFile>,
+    offset: u64,
+    file_len: u64,
+    chunk: Vec<u8>,
+    /// Position of the next byte to hand out in `chunk`
+    position: usize,
+    pending: Option<ReadChunkFuture>,
+}
+
+impl FileReader {
+    pub(crate) fn new(file: Arc<dyn ReadableFile>, offset: u64, file_len: u64) -> Self {
+        Self {
+            file,
+            offset,
+            file_len,
+            chunk: Vec::new(),
+            position: 0,
+            pending: None,
+        }
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position == self.chunk.len() {
+            if self.offset >= self.file_len {
+                return Poll::Ready(Ok(()));
+            }
+            if self.pending.is_none() {
+                let (file, offset) = (Arc::clone(&self.file), self.offset);
+                let len = READ_CHUNK_LEN.min(self.file_len - offset);
+                self.pending = Some(Box::pin(async move { file.read_at(offset, len).await }));
+            }
+            let chunk = match self.pending.as_mut().unwrap().as_mut().poll(cx) {
+                Poll::Ready(chunk) => chunk,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.pending = None;
+            let chunk = chunk?;
+            self.offset += chunk.len() as u64;
+            self.chunk = chunk;
+            self.position = 0;
+        }
+        let len = buf.remaining().min(self.chunk.len() - self.position);
+        let position = self.position;
+        buf.put_slice(&self.chunk[position..position + len]);
+        self.position += len;
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,137 @@
+//! Deterministic data for the benchmarks and the tests: the same keys, values and orders on
+//! every run, so two runs do the same work. Built for the tests and with the `testutil`
+//! feature.
+use anyhow::{Context, Result};
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{entries::Entry, sstable::SSTableWriter, Database, DatabaseBuilder, LocalFs};
+
+/// Key number `i`, zero padded so the keys sort like their numbers
+pub fn key(i: u64) -> Vec<u8> {
+    format!("key{:012}", i).into_bytes()
+}
+
+/// A value of `len` lowercase letters for key number `i`, pseudo-random so it does not
+/// compress to nothing
+pub fn value(i: u64, len: usize) -> Vec<u8> {
+    let mut rng = Rng::new(i);
+    (0..len)
+        .map(|_| b'a' + (rng.next_u64() % 26) as u8)
+        .collect()
+}
+
+/// The numbers `0..n` in a random order, the same one for the same `seed`
+pub fn shuffled(n: u64, seed: u64) -> Vec<u64> {
+    let mut rng = Rng::new(seed);
+    let mut numbers = (0..n).collect::<Vec<_>>();
+    for i in (1..numbers.len()).rev() {
+        let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+        numbers.swap(i, j);
+    }
+    numbers
+}
+
+/// The entries of the `keys` in order, with values of `value_len` bytes written at
+/// `timestamp`
+pub fn entries(keys: Range<u64>, value_len: usize, timestamp: u128) -> impl Iterator<Item = Entry> {
+    keys.map(move |i| Entry::new(key(i), Some(value(i, value_len)), timestamp))
+}
+
+/// Write the `entries`, in key order, to a new SSTable at `path`
+pub async fn write_sstable(path: &Path, entries: impl IntoIterator<Item = Entry>) -> Result<()> {
+    let mut writer = SSTableWriter::with_storage(&path.to_path_buf(), Arc::new(LocalFs)).await?;
+    for entry in entries {
+        writer.set(&entry).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// A database in `dir` holding the keys `0..n` with values of `value_len` bytes, all in its
+/// MemTable or, with `flush`, all in one SSTable. Creates `dir` if needed.
+pub async fn populated_db(dir: &Path, n: u64, value_len: usize, flush: bool) -> Result<Database> {
+    tokio::fs::create_dir_all(dir).await?;
+    let mut db = DatabaseBuilder::new(dir.to_path_buf())
+        .max_mem_table_size(usize::MAX)
+        .build()
+        .await?;
+    for i in 0..n {
+        db.set(&key(i), &value(i, value_len)).await?;
+    }
+    if flush {
+        db.flush().await.context("flush the populated database")?;
+    }
+    Ok(db)
+}
+
+/// Leave `n` records with values of `value_len` bytes in the WAL of a database in `dir`, none
+/// flushed, for the next open to replay. Returns the WAL file.
+pub async fn write_wal(dir: &Path, n: u64, value_len: usize) -> Result<PathBuf> {
+    let db = populated_db(dir, n, value_len, false).await?;
+    Ok(db.wal_path())
+}
+
+/// A SplitMix64 generator, plenty for test data
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::SSTableReader;
+
+    #[test]
+    fn it_generates_the_same_data_on_every_run() {
+        assert_eq!(key(42), b"key000000000042");
+        assert!(key(9) < key(10));
+        assert_eq!(value(7, 100), value(7, 100));
+        assert_ne!(value(7, 100), value(8, 100));
+        assert!(value(7, 100).iter().all(u8::is_ascii_lowercase));
+
+        let numbers = shuffled(1000, 1);
+        assert_eq!(numbers, shuffled(1000, 1));
+        assert_ne!(numbers, shuffled(1000, 2));
+        let mut sorted = numbers.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn it_writes_the_sstables_and_the_wal_to_replay() -> Result<()> {
+        let tmpdir = TempDir::new("testutil")?;
+        let path = tmpdir.path().join("1.db");
+        write_sstable(&path, entries(0..100, 10, 1)).await?;
+        let reader = SSTableReader::new(&path).await?;
+        let entry = reader.get(&key(99)).await.unwrap();
+        assert_eq!(entry.value.as_deref(), Some(&value(99, 10)[..]));
+
+        let dir = tmpdir.path().join("db");
+        write_wal(&dir, 100, 10).await?;
+        let db = DatabaseBuilder::new(dir).build().await?;
+        assert_eq!(db.recovery_report().records_applied, 100);
+        assert_eq!(db.get(&key(50)).await.unwrap().value, value(50, 10));
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket keeping the I/O of a background job under `bytes_per_sec`, it holds up to one
+/// second worth of bytes so short bursts go through right away.
+pub(crate) struct Throttle {
+    bytes_per_sec: u64,
+    /// Bytes which can go through without waiting, negative once overdrawn
+    available: f64,
+    refilled_at: Instant,
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            available: bytes_per_sec as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` from the bucket, sleeping until it has refilled enough when overdrawn.
+    pub(crate) async fn consume(&mut self, bytes: u64) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled_at).as_secs_f64() * self.bytes_per_sec as f64;
+        self.available = (self.available + refill).min(self.bytes_per_sec as f64);
+        self.refilled_at = now;
+
+        self.available -= bytes as f64;
+        if self.available < 0.0 {
+            let wait = -self.available / self.bytes_per_sec as f64;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_works() {
+        let mut throttle = Throttle::new(1000);
+
+        // a burst of a second worth of bytes goes through
+        let started_at = Instant::now();
+        throttle.consume(1000).await;
+        assert!(started_at.elapsed() < Duration::from_millis(100));
+
+        // the next bytes wait for the bucket to refill
+        throttle.consume(300).await;
+        assert!(started_at.elapsed() >= Duration::from_millis(250));
+    }
+}
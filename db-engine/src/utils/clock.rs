@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result};
+
+use super::micros_now;
+
+/// The last timestamp handed out by a [`HybridClock`] of this process.
+static LAST_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+/// Source of the timestamps of the writes and of the names of the WAL and SSTable files, see
+/// [`DatabaseBuilder::clock`](crate::DatabaseBuilder::clock).
+pub trait Clock: Send + Sync {
+    /// Microseconds since the Unix epoch, strictly greater than every timestamp returned before:
+    /// the newest write of a key wins and two files never share a name.
+    fn now(&self) -> Result<u128>;
+}
+
+/// The system time, moved one microsecond past the last timestamp of the process whenever it
+/// did not advance since, e.g. for two writes within the same microsecond or after the time
+/// was set back. It catches up with the system time once that passes it again.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HybridClock;
+
+impl Clock for HybridClock {
+    fn now(&self) -> Result<u128> {
+        let physical = u64::try_from(micros_now()?).context("timestamp overflows u64")?;
+        let last = LAST_TIMESTAMP
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(physical.max(last + 1))
+            })
+            .expect("the update always succeeds");
+        Ok(u128::from(physical.max(last + 1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, thread};
+
+    use super::*;
+
+    #[test]
+    fn it_never_returns_the_same_timestamp_twice() -> Result<()> {
+        let timestamps = (0..10_000)
+            .map(|_| HybridClock.now())
+            .collect::<Result<Vec<_>>>()?;
+        assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+        // close to the system time
+        assert!(micros_now()?.abs_diff(timestamps[0]) < 60_000_000);
+
+        let threads = (0..4)
+            .map(|_| thread::spawn(|| (0..1_000).map(|_| HybridClock.now().unwrap()).collect()))
+            .collect::<Vec<thread::JoinHandle<Vec<u128>>>>();
+        let mut unique = HashSet::new();
+        for thread in threads {
+            for timestamp in thread.join().unwrap() {
+                assert!(unique.insert(timestamp));
+            }
+        }
+        Ok(())
+    }
+}
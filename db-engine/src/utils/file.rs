@@ -1,37 +1,218 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use std::{
+    ffi::OsStr,
     fs::read_dir,
+    iter,
     os::unix::prelude::MetadataExt,
     path::{Path, PathBuf},
 };
+use tokio::io;
 
-/// Gets the set of files with an extension for a given directory.
-pub fn get_files_with_ext(dir: &Path, ext: &str) -> Result<Vec<PathBuf>> {
-    let files = read_dir(dir)?
+use crate::{
+    cdc::ARCHIVE_DIR_NAME,
+    compaction::SizeFilter,
+    sstable::{level_dir, LEVEL_COUNT},
+    storage::{AppendMode, LocalFs, Storage, WritableFile},
+};
+
+/// Gets the set of files with an extension for a given directory of `storage`, without
+/// blocking the runtime.
+pub async fn list_files_with_ext(
+    dir: &Path,
+    ext: &str,
+    storage: &dyn Storage,
+) -> Result<Vec<PathBuf>> {
+    let mut files = storage.list(dir).await?;
+    files.retain(|path| path.extension().is_some_and(|e| e == ext));
+    Ok(files)
+}
+
+/// Gets the set of files with an extension for a given directory, blocking.
+#[cfg(test)]
+pub(crate) fn get_files_with_ext(dir: &Path, ext: &str) -> Result<Vec<PathBuf>> {
+    let files = std::fs::read_dir(dir)?
         .filter_map(|file| file.ok())
         .map(|file| file.path())
-        .filter(|path| path.extension().map_or(false, |e| e == ext))
+        .filter(|path| path.extension().is_some_and(|e| e == ext))
         .collect::<Vec<_>>();
     Ok(files)
 }
 
-/// Get the set of files with and extension and size for a given directory.
+/// Gets the set of files with an extension for a given directory whose size matches
+/// `size_filter`, without blocking the runtime.
+pub async fn get_files_with_ext_filtered(
+    dir: &Path,
+    ext: &str,
+    size_filter: SizeFilter,
+) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for file in list_files_with_ext(dir, ext, &LocalFs).await? {
+        // removed since the listing, e.g. by a compaction
+        if let Ok(metadata) = LocalFs.metadata(&file).await {
+            if size_filter.matches(metadata.len) {
+                files.push(file);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Get the set of files with an extension for a given directory strictly smaller than `size`.
+#[deprecated(note = "use `get_files_with_ext_filtered` with `SizeFilter::SmallerThan`")]
 pub fn get_files_with_ext_and_size(dir: &Path, ext: &str, size: u64) -> Result<Vec<PathBuf>> {
+    let size_filter = SizeFilter::SmallerThan(size);
     let files = read_dir(dir)?
         .filter_map(|entry| entry.ok())
         .map(|entry| entry.path())
-        .filter(|file| file.extension().map_or(false, |e| e == ext))
-        .filter(|file| file.metadata().map_or(false, |m| m.size() < size))
+        .filter(|file| file.extension().is_some_and(|e| e == ext))
+        .filter(|file| file.metadata().is_ok_and(|m| size_filter.matches(m.size())))
         .collect::<Vec<_>>();
 
     Ok(files)
 }
 
+/// Bytes taken by the SSTable, index, bloom filter and WAL files of the database of `dir` in
+/// `storage`: the ones of `dir`, of its levels and of its CDC archive. The other
+/// subdirectories are left out, e.g. the namespaces a server keeps in its data directory.
+pub async fn dir_size(dir: &Path, storage: &dyn Storage) -> Result<u64> {
+    let mut size = 0;
+    let subdirs = (0..LEVEL_COUNT)
+        .map(|level| level_dir(dir, level))
+        .chain([dir.join(ARCHIVE_DIR_NAME)]);
+    for (i, dir) in iter::once(dir.to_path_buf()).chain(subdirs).enumerate() {
+        let paths = match storage.list(&dir).await {
+            Ok(paths) => paths,
+            // no file in this level or archive yet
+            Err(e) if i > 0 && e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for path in paths {
+            let metadata = match storage.metadata(&path).await {
+                Ok(metadata) => metadata,
+                // removed since the listing, e.g. by a compaction
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if !metadata.is_dir
+                && path
+                    .extension()
+                    .is_some_and(|ext| ["db", "idx", "bf", "wal"].iter().any(|e| ext == *e))
+            {
+                size += metadata.len;
+            }
+        }
+    }
+    Ok(size)
+}
+
+/// Create the file `<name>.<ext>` of `dir` in `storage`, or `<name>-1.<ext>`,
+/// `<name>-2.<ext>`... when the name is taken, so two writers never share a file. Returns the
+/// file, opened to append, and its path.
+pub async fn allocate_file(
+    dir: &Path,
+    name: u128,
+    ext: &str,
+    storage: &dyn Storage,
+) -> io::Result<(Box<dyn WritableFile>, PathBuf)> {
+    allocate(dir, name, ext, false, storage).await
+}
+
+/// Like [`allocate_file`], for a writer which renames its file into place once complete: the
+/// file is created at the path with `.tmp` appended and a name is skipped as well while the
+/// complete file exists. Returns the temporary file and the path to rename it to.
+pub async fn allocate_tmp_file(
+    dir: &Path,
+    name: u128,
+    ext: &str,
+    storage: &dyn Storage,
+) -> io::Result<(Box<dyn WritableFile>, PathBuf)> {
+    allocate(dir, name, ext, true, storage).await
+}
+
+async fn allocate(
+    dir: &Path,
+    name: u128,
+    ext: &str,
+    tmp: bool,
+    storage: &dyn Storage,
+) -> io::Result<(Box<dyn WritableFile>, PathBuf)> {
+    let existing = match tmp {
+        true => storage.list(dir).await?,
+        false => Vec::new(),
+    };
+    for suffix in 0_u64.. {
+        let path = match suffix {
+            0 => dir.join(format!("{}.{}", name, ext)),
+            _ => dir.join(format!("{}-{}.{}", name, suffix, ext)),
+        };
+        let created_path = match tmp {
+            true if existing.contains(&path) => continue,
+            true => path.with_extension(format!("{}.tmp", ext)),
+            false => path.clone(),
+        };
+        match storage
+            .open_append(&created_path, AppendMode::CreateNew)
+            .await
+        {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("every suffix is taken")
+}
+
+/// Sorts the files named by [`allocate_file`] in the order they were created: by name, then
+/// by suffix. The other files come first, by file name.
+pub fn allocation_order(path: &Path) -> (Option<(u128, u64)>, Option<&OsStr>) {
+    let file_name = path.file_name();
+    let order = file_name
+        .and_then(|file_name| file_name.to_str()?.split('.').next())
+        .and_then(|stem| {
+            let (name, suffix) = stem.split_once('-').unwrap_or((stem, "0"));
+            Some((name.parse().ok()?, suffix.parse().ok()?))
+        });
+    (order, file_name)
+}
+
+/// Name of the file the open database of a directory locks, see [`lock_dir`]
+pub const DIR_LOCK_FILE_NAME: &str = "LOCK";
+
+/// Held by the open [`Database`](crate::Database) of a directory, the lock goes with the
+/// file when it is dropped. The OS releases it when the process dies, so a crash never leaves
+/// a stale one behind.
+#[derive(Debug)]
+pub struct DirLock {
+    _file: std::fs::File,
+}
+
+/// Lock `dir` against another [`Database`](crate::Database) in this process or any other,
+/// [`Error::DirectoryLocked`](crate::Error::DirectoryLocked) when one already holds it.
+pub fn lock_dir(dir: &Path) -> Result<DirLock> {
+    let path = dir.join(DIR_LOCK_FILE_NAME);
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("open directory lock {:?}", path))?;
+    match file.try_lock() {
+        Ok(()) => Ok(DirLock { _file: file }),
+        Err(std::fs::TryLockError::WouldBlock) => {
+            Err(crate::Error::DirectoryLocked(dir.to_owned()).into())
+        }
+        Err(std::fs::TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("lock directory {:?}", dir))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use tempdir::TempDir;
 
     use super::*;
+    use crate::storage::LocalFs;
     use std::{fs::File, io::Write};
 
     #[test]
@@ -95,6 +276,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn test_get_files_with_ext_and_size() {
         let dir = TempDir::new("utils").unwrap();
         let file_path1 = dir.path().join("test1.txt");
@@ -116,4 +298,138 @@ mod tests {
         assert_eq!(result.len(), 1);
         assert!(result.contains(&file_path1));
     }
+
+    #[tokio::test]
+    async fn test_get_files_with_ext_filtered_at_the_limits() -> Result<()> {
+        let dir = TempDir::new("utils")?;
+        let dir_path = dir.path();
+        for (name, size) in [("99.db", 99), ("100.db", 100), ("101.db", 101), ("0.db", 0)] {
+            std::fs::write(dir_path.join(name), vec![0; size])?;
+        }
+        std::fs::write(dir_path.join("100.wal"), [0; 100])?;
+        let filtered = |size_filter| async move {
+            let mut names = get_files_with_ext_filtered(dir_path, "db", size_filter)
+                .await?
+                .iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            names.sort();
+            anyhow::Ok(names)
+        };
+
+        // a file exactly at the limit is `AtLeast`, not `SmallerThan`
+        assert_eq!(
+            filtered(SizeFilter::SmallerThan(100)).await?,
+            ["0.db", "99.db"]
+        );
+        assert_eq!(
+            filtered(SizeFilter::AtLeast(100)).await?,
+            ["100.db", "101.db"]
+        );
+        assert_eq!(filtered(SizeFilter::Between(100, 101)).await?, ["100.db"]);
+        assert!(filtered(SizeFilter::Between(100, 100)).await?.is_empty());
+        assert!(filtered(SizeFilter::SmallerThan(0)).await?.is_empty());
+        assert_eq!(filtered(SizeFilter::AtLeast(0)).await?.len(), 4);
+        assert_eq!(filtered(SizeFilter::Any).await?.len(), 4);
+
+        #[allow(deprecated)]
+        let mut smaller = get_files_with_ext_and_size(dir_path, "db", 100)?;
+        smaller.sort();
+        assert_eq!(smaller, [dir_path.join("0.db"), dir_path.join("99.db")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dir_size() -> Result<()> {
+        let dir = TempDir::new("utils")?;
+        let dir_path = dir.path();
+        assert_eq!(dir_size(dir_path, &LocalFs).await?, 0);
+
+        std::fs::write(dir_path.join("1.wal"), [0; 10])?;
+        std::fs::write(dir_path.join("1.db.tmp"), [0; 100])?;
+        std::fs::create_dir(dir_path.join("L0"))?;
+        std::fs::write(dir_path.join("L0").join("2.db"), [0; 20])?;
+        std::fs::write(dir_path.join("L0").join("2.db.idx"), [0; 3])?;
+        std::fs::write(dir_path.join("L0").join("2.db.bf"), [0; 4])?;
+        std::fs::write(dir_path.join("L0").join("MANIFEST"), [0; 1000])?;
+        assert_eq!(dir_size(dir_path, &LocalFs).await?, 37);
+
+        // the archive counts, another database in a subdirectory does not
+        std::fs::create_dir(dir_path.join("cdc"))?;
+        std::fs::write(dir_path.join("cdc").join("3-1.wal"), [0; 5])?;
+        std::fs::create_dir_all(dir_path.join("namespaces").join("tenant"))?;
+        std::fs::write(
+            dir_path.join("namespaces").join("tenant").join("4.db"),
+            [0; 50],
+        )?;
+        assert_eq!(dir_size(dir_path, &LocalFs).await?, 42);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_allocate_file_on_collision() -> Result<()> {
+        let dir = TempDir::new("utils")?;
+        let dir_path = dir.path();
+
+        let (_, first) = allocate_file(dir_path, 1000, "wal", &LocalFs).await?;
+        let (_, second) = allocate_file(dir_path, 1000, "wal", &LocalFs).await?;
+        let (_, third) = allocate_file(dir_path, 1000, "wal", &LocalFs).await?;
+        assert_eq!(first, dir_path.join("1000.wal"));
+        assert_eq!(second, dir_path.join("1000-1.wal"));
+        assert_eq!(third, dir_path.join("1000-2.wal"));
+
+        // a complete file takes its name as well as an unfinished one
+        File::create(dir_path.join("2000.db"))?;
+        File::create(dir_path.join("2000-1.db.tmp"))?;
+        let (_, path) = allocate_tmp_file(dir_path, 2000, "db", &LocalFs).await?;
+        assert_eq!(path, dir_path.join("2000-2.db"));
+        assert!(dir_path.join("2000-2.db.tmp").exists());
+        assert!(!path.exists());
+
+        let mut paths = vec![
+            third.clone(),
+            dir_path.join("999.wal"),
+            first.clone(),
+            dir_path.join("other.wal"),
+            second.clone(),
+        ];
+        paths.sort_by(|a, b| allocation_order(a).cmp(&allocation_order(b)));
+        assert_eq!(
+            paths,
+            [
+                dir_path.join("other.wal"),
+                dir_path.join("999.wal"),
+                first,
+                second,
+                third
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_files_with_ext() -> Result<()> {
+        let dir = TempDir::new("utils")?;
+        File::create(dir.path().join("file1.txt"))?;
+        File::create(dir.path().join("image.png"))?;
+        std::fs::create_dir(dir.path().join("subdir.txt"))?;
+
+        let mut files = list_files_with_ext(dir.path(), "txt", &LocalFs).await?;
+        files.sort();
+        assert_eq!(
+            files,
+            vec![dir.path().join("file1.txt"), dir.path().join("subdir.txt")]
+        );
+        assert_eq!(files, {
+            let mut files = get_files_with_ext(dir.path(), "txt")?;
+            files.sort();
+            files
+        });
+        assert!(
+            list_files_with_ext(Path::new("/path/that/does/not/exist"), "txt", &LocalFs)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
 }
@@ -1,5 +1,7 @@
+mod clock;
 mod file;
 mod microseconds;
 
+pub use self::clock::*;
 pub use self::file::*;
 pub use self::microseconds::*;
@@ -0,0 +1,136 @@
+use std::path::PathBuf;
+
+use tokio_stream::StreamExt;
+
+use crate::{
+    sstable::{SSTableReader, SSTableReaderOptions},
+    storage::Storage,
+};
+
+/// Outcome of [`Database::verify`](crate::Database::verify).
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VerifyReport {
+    /// SSTable files read
+    pub files: usize,
+    /// Entries read back intact, tombstones included
+    pub entries: u64,
+    pub corruptions: Vec<Corruption>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corruptions.is_empty()
+    }
+}
+
+/// A file of a [`VerifyReport`] which does not read back as written
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Corruption {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Read every entry of `files` through their index, checking the checksums and the order of
+/// the keys. A corrupted record is reported and the walk goes on with the next one.
+pub(crate) async fn verify_sstables(
+    files: impl IntoIterator<Item = PathBuf>,
+    storage: &dyn Storage,
+    max_field_len: usize,
+) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    for path in files {
+        report.files += 1;
+        let options = SSTableReaderOptions {
+            max_field_len,
+            ..Default::default()
+        };
+        let reader = match SSTableReader::with_storage(&path, options, storage).await {
+            Ok(reader) => reader,
+            Err(e) => {
+                report.corruptions.push(Corruption {
+                    path,
+                    reason: format!("{:#}", e),
+                });
+                continue;
+            }
+        };
+        let mut entries = reader.iter();
+        let mut prev_key: Option<Vec<u8>> = None;
+        while let Some(entry) = entries.next().await {
+            let reason = match entry {
+                Ok(entry) if prev_key.as_ref().is_some_and(|prev| *prev >= entry.key) => {
+                    let prev = prev_key.replace(entry.key.clone()).unwrap_or_default();
+                    crate::Error::OutOfOrderKey {
+                        prev,
+                        next: entry.key,
+                    }
+                    .to_string()
+                }
+                Ok(entry) => {
+                    report.entries += 1;
+                    prev_key = Some(entry.key);
+                    continue;
+                }
+                Err(e) => format!("{:#}", e),
+            };
+            report.corruptions.push(Corruption {
+                path: path.clone(),
+                reason,
+            });
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tempdir::TempDir;
+
+    use crate::DatabaseBuilder;
+
+    #[tokio::test]
+    async fn it_reports_the_corrupted_records() -> Result<()> {
+        let tmpdir = TempDir::new("verify")?;
+        let mut db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .build()
+            .await?;
+        for i in 0..100 {
+            db.set(
+                format!("key{:03}", i).as_bytes(),
+                format!("value-{:03}", i).as_bytes(),
+            )
+            .await?;
+        }
+        db.delete(b"key050").await?;
+        db.flush().await?;
+
+        let report = db.verify().await?;
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!((report.files, report.entries), (1, 100));
+
+        // flip a byte of one value, its checksum no longer matches
+        let (path, _) = db.sstable_files().await?.remove(0);
+        let mut bytes = std::fs::read(&path)?;
+        let at = bytes
+            .windows(9)
+            .position(|window| window == b"value-042")
+            .unwrap();
+        bytes[at + 8] ^= 0xff;
+        std::fs::write(&path, bytes)?;
+        drop(db);
+
+        let db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .build()
+            .await?;
+        let report = db.verify().await?;
+        assert_eq!(report.entries, 99);
+        assert_eq!(report.corruptions.len(), 1);
+        assert_eq!(report.corruptions[0].path, path);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
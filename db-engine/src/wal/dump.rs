@@ -0,0 +1,98 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::{read_record, Record, WALIterator, WalRecord, WriteAheadLog};
+use crate::{
+    dump::{corruption_line, entry_line, DumpSummary},
+    entries::DEFAULT_MAX_FIELD_LEN,
+    prelude::WalReadError,
+};
+
+impl WriteAheadLog {
+    /// Write every record of the WAL file at `path` to `out` as text, like
+    /// [`SSTableReader::dump`](crate::SSTableReader::dump) along with the batch markers. A
+    /// corrupted record is reported with its offset, then the dump goes on with the next
+    /// record which decodes, found byte by byte like [`WriteAheadLog::repair`] does.
+    pub async fn dump(
+        path: &Path,
+        mut out: impl AsyncWrite + Unpin,
+        raw: bool,
+    ) -> Result<DumpSummary> {
+        let wal_iter = WALIterator::new(path.to_owned()).await?;
+        let (header_len, version) = (wal_iter.offset() as usize, wal_iter.version());
+        drop(wal_iter);
+        let bytes = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("read wal file {:?}", path))?;
+        let header = format!(
+            "WAL {:?}: version {}, {} bytes\n",
+            path,
+            version,
+            bytes.len()
+        );
+        out.write_all(header.as_bytes()).await?;
+
+        let mut summary = DumpSummary::default();
+        let mut pos = header_len;
+        while pos < bytes.len() {
+            let line = match read_at(&bytes, pos, version).await {
+                Ok(Some((record, len))) => {
+                    summary.records += 1;
+                    let offset = pos.to_string();
+                    pos += len;
+                    match record {
+                        WalRecord::Entry(entry) => entry_line(&offset, &entry, raw),
+                        WalRecord::BatchBegin { count } => {
+                            format!("{:>12}  batch of {} entries\n", offset, count)
+                        }
+                        WalRecord::BatchCommit { count } => {
+                            format!("{:>12}  commit of the batch of {} entries\n", offset, count)
+                        }
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    summary.corruptions += 1;
+                    let mut line = corruption_line(&pos.to_string(), &e);
+                    let start = pos;
+                    pos += 1;
+                    while pos < bytes.len()
+                        && !matches!(read_at(&bytes, pos, version).await, Ok(Some(_)))
+                    {
+                        pos += 1;
+                    }
+                    line.push_str(&format!("{:>12}  skipped {} bytes\n", start, pos - start));
+                    line
+                }
+            };
+            out.write_all(line.as_bytes()).await?;
+        }
+        out.write_all(
+            format!(
+                "{} records, {} corrupted\n",
+                summary.records, summary.corruptions
+            )
+            .as_bytes(),
+        )
+        .await?;
+        out.flush().await?;
+        Ok(summary)
+    }
+}
+
+/// The record at `pos` of the WAL file `bytes`, with keys and values up to the
+/// [`DEFAULT_MAX_FIELD_LEN`]
+async fn read_at(bytes: &[u8], pos: usize, version: u16) -> Result<Option<Record>, WalReadError> {
+    let mut input = &bytes[pos..];
+    let remaining = input.len() as u64;
+    read_record(
+        &mut input,
+        pos as u64,
+        version,
+        remaining,
+        DEFAULT_MAX_FIELD_LEN,
+    )
+    .await
+}
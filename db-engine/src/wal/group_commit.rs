@@ -0,0 +1,259 @@
+use std::time::Duration;
+use tokio::{
+    io::{self, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+    time::{timeout_at, Instant},
+};
+
+use crate::storage::WritableFile;
+
+/// How long the flusher waits for more records before syncing a batch.
+pub const DEFAULT_MAX_BATCH_DELAY: Duration = Duration::from_millis(1);
+
+/// How many bytes the flusher collects at most before syncing a batch.
+pub const DEFAULT_MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// A serialized record waiting to become durable. An empty record only waits for
+/// everything queued before it, which is how a flush drains the queue.
+struct CommitRequest {
+    bytes: Vec<u8>,
+    ack: oneshot::Sender<io::Result<()>>,
+}
+
+/// Group commit for the WAL.
+///
+/// Writers enqueue serialized records and a single flusher task appends them to the file
+/// and fsyncs once per batch, so concurrent writers share the cost of a sync. Once a batch
+/// fails the file may end with part of it: every later commit fails as well.
+#[derive(Clone)]
+pub struct GroupCommitter {
+    sender: mpsc::UnboundedSender<CommitRequest>,
+}
+
+impl GroupCommitter {
+    /// Spawn the flusher task owning `file`.
+    pub fn spawn(
+        file: Box<dyn WritableFile>,
+        max_batch_delay: Duration,
+        max_batch_bytes: usize,
+    ) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_flusher(
+            file,
+            receiver,
+            max_batch_delay,
+            max_batch_bytes,
+        ));
+        Self { sender }
+    }
+
+    /// Append the record and wait until it is synced to disk.
+    pub async fn commit(&self, bytes: Vec<u8>) -> io::Result<()> {
+        let (ack, ack_receiver) = oneshot::channel();
+        self.sender
+            .send(CommitRequest { bytes, ack })
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "wal flusher is gone"))?;
+        ack_receiver
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "wal flusher is gone"))?
+    }
+
+    /// Wait until every record queued so far is synced to disk.
+    pub async fn flush(&self) -> io::Result<()> {
+        self.commit(Vec::new()).await
+    }
+}
+
+async fn run_flusher(
+    mut file: Box<dyn WritableFile>,
+    mut receiver: mpsc::UnboundedReceiver<CommitRequest>,
+    max_batch_delay: Duration,
+    max_batch_bytes: usize,
+) {
+    // the loop ends once every GroupCommitter is dropped and the queue is drained
+    let mut failure: Option<io::Error> = None;
+    while let Some(first) = receiver.recv().await {
+        if let Some(e) = failure.as_ref() {
+            let _ = first.ack.send(Err(io::Error::new(
+                e.kind(),
+                format!("an earlier wal commit failed: {}", e),
+            )));
+            continue;
+        }
+        let mut batch_bytes = first.bytes.len();
+        let mut batch = vec![first];
+        let deadline = Instant::now() + max_batch_delay;
+        while batch_bytes < max_batch_bytes {
+            match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(request)) => {
+                    batch_bytes += request.bytes.len();
+                    batch.push(request);
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        if let Err(e) = write_batch(&mut file, &batch).await {
+            tracing::error!("Failed to commit {} wal records: {}", batch.len(), e);
+            failure = Some(e);
+        }
+        for request in batch {
+            let ack = match failure.as_ref() {
+                None => Ok(()),
+                Some(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            };
+            // the writer may have given up waiting, that's fine
+            let _ = request.ack.send(ack);
+        }
+    }
+}
+
+async fn write_batch(file: &mut Box<dyn WritableFile>, batch: &[CommitRequest]) -> io::Result<()> {
+    if batch.iter().all(|request| request.bytes.is_empty()) {
+        return Ok(());
+    }
+
+    let bytes = batch
+        .iter()
+        .flat_map(|request| request.bytes.iter().copied())
+        .collect::<Vec<_>>();
+    file.write_all(&bytes).await?;
+    file.sync_data().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+    };
+
+    use async_trait::async_trait;
+    use tempdir::TempDir;
+    use tokio::{
+        fs::{read, File, OpenOptions},
+        io::AsyncWrite,
+    };
+
+    use super::*;
+
+    /// A file whose first write fails, the next ones go through
+    struct FailingOnce {
+        file: File,
+        failed: bool,
+    }
+
+    impl AsyncWrite for FailingOnce {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if !self.failed {
+                self.failed = true;
+                return Poll::Ready(Err(io::Error::other("injected fault")));
+            }
+            Pin::new(&mut self.file).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.file).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.file).poll_shutdown(cx)
+        }
+    }
+
+    #[async_trait]
+    impl WritableFile for FailingOnce {
+        async fn size(&self) -> io::Result<u64> {
+            Ok(self.file.metadata().await?.len())
+        }
+
+        async fn sync_data(&mut self) -> io::Result<()> {
+            self.file.sync_data().await
+        }
+    }
+
+    #[tokio::test]
+    async fn it_commits_records_from_concurrent_writers() {
+        let temp_dir = TempDir::new("group_commit").unwrap();
+        let path = temp_dir.path().join("test.wal");
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .await
+            .unwrap();
+
+        let committer = Arc::new(GroupCommitter::spawn(
+            Box::new(file),
+            DEFAULT_MAX_BATCH_DELAY,
+            DEFAULT_MAX_BATCH_BYTES,
+        ));
+        let writers = (0..64u8)
+            .map(|i| {
+                let committer = Arc::clone(&committer);
+                tokio::spawn(async move { committer.commit(vec![i; 4]).await })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.await.unwrap().unwrap();
+        }
+        committer.flush().await.unwrap();
+
+        let bytes = read(&path).await.unwrap();
+        assert_eq!(bytes.len(), 64 * 4);
+        for chunk in bytes.chunks(4) {
+            // every record is written in one piece
+            assert!(chunk.iter().all(|b| *b == chunk[0]));
+        }
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_fails_every_commit_after_a_failed_one() {
+        let temp_dir = TempDir::new("group_commit_failed").unwrap();
+        let path = temp_dir.path().join("test.wal");
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .await
+            .unwrap();
+        let file = FailingOnce {
+            file,
+            failed: false,
+        };
+
+        let committer = GroupCommitter::spawn(Box::new(file), DEFAULT_MAX_BATCH_DELAY, 0);
+        assert!(committer.commit(vec![1; 4]).await.is_err());
+        // the file would take it, but not after a record which may be torn
+        assert!(committer.commit(vec![2; 4]).await.is_err());
+        assert!(committer.flush().await.is_err());
+        assert_eq!(read(&path).await.unwrap().len(), 0);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_flushes_an_empty_queue() {
+        let temp_dir = TempDir::new("group_commit_empty").unwrap();
+        let path = temp_dir.path().join("test.wal");
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .await
+            .unwrap();
+
+        let committer = GroupCommitter::spawn(Box::new(file), DEFAULT_MAX_BATCH_DELAY, 0);
+        committer.flush().await.unwrap();
+        assert_eq!(read(&path).await.unwrap().len(), 0);
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,1883 @@
+use anyhow::Result;
+use std::{
+    borrow::Cow,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{self, AsyncRead, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+
+mod dump;
+mod group_commit;
+mod progress;
+mod repair;
+
+pub use self::group_commit::*;
+pub use self::progress::*;
+pub use self::repair::*;
+
+use self::{progress::ProgressReporter, repair::with_suffix};
+
+use crate::{
+    cleanup::{self, CleanupReport},
+    compression::Codec,
+    entries::{read_field, verify_checksum, EntryEncoding, DEFAULT_MAX_FIELD_LEN},
+    mem_table::MemTable,
+    prelude::*,
+    storage::{self, AppendMode, FileReader, LocalFs, Storage, WritableFile},
+    utils::{self, Clock},
+};
+
+/// Magic number at the start of every WAL file.
+pub(crate) const WAL_MAGIC: [u8; 8] = *b"SDBWAL\0\x01";
+/// Version of the WAL record format, records carry a record type since version 3.
+const WAL_VERSION: u16 = 4;
+/// The first version, also the layout of the headerless files.
+const WAL_VERSION_UNTAGGED: u16 = 1;
+/// Records carry a codec tag since version 2.
+const WAL_VERSION_CODEC: u16 = 2;
+/// Entries have varint lengths and timestamp since version 4, see [`Entry::write_to_v2`].
+const WAL_VERSION_VARINT: u16 = 4;
+
+/// Type of a WAL record, written first in every record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordType {
+    Put = 1,
+    Delete = 2,
+    BatchBegin = 3,
+    BatchCommit = 4,
+}
+
+impl RecordType {
+    fn from_u8(record_type: u8) -> Option<Self> {
+        match record_type {
+            1 => Some(RecordType::Put),
+            2 => Some(RecordType::Delete),
+            3 => Some(RecordType::BatchBegin),
+            4 => Some(RecordType::BatchCommit),
+            _ => None,
+        }
+    }
+}
+
+/// A record read back from the WAL.
+#[derive(Debug)]
+pub enum WalRecord {
+    /// A set or a delete (tombstone) of a key.
+    Entry(Entry),
+    /// The next `count` entries are applied together or not at all.
+    BatchBegin { count: u32 },
+    /// Closes the batch of `count` entries.
+    BatchCommit { count: u32 },
+}
+/// Magic number followed by the little endian format version.
+const WAL_HEADER_SIZE: usize = WAL_MAGIC.len() + 2;
+
+/// When the WAL records are synced to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// The records are handed to the OS on every flush, a crash of the machine may lose them.
+    #[default]
+    Never,
+    /// Every record is fsynced before the write is acknowledged, concurrent writers share
+    /// the syncs through group commit.
+    Always,
+}
+
+/// Where the WAL records go.
+enum WalSink {
+    Buffered(BufWriter<Box<dyn WritableFile>>),
+    GroupCommit(GroupCommitter),
+}
+
+/// Write Ahead Log
+pub struct WriteAheadLog {
+    path: PathBuf,
+    sink: WalSink,
+    codec: Codec,
+    storage: Arc<dyn Storage>,
+    /// Bytes of the records appended through this handle, see
+    /// [`WriteAheadLog::bytes_appended`]
+    bytes_appended: u64,
+}
+
+impl WriteAheadLog {
+    /// Creates a new WAL in a given directory.
+    #[cfg(test)]
+    pub async fn new(dir: &Path) -> Result<Self> {
+        Self::new_with_clock(dir, &utils::HybridClock, Arc::new(LocalFs)).await
+    }
+
+    /// Creates a new WAL in a given directory of `storage`, named after a timestamp of
+    /// `clock`, see [`utils::allocate_file`].
+    pub(crate) async fn new_with_clock(
+        dir: &Path,
+        clock: &dyn Clock,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Self> {
+        let (file, path) = utils::allocate_file(dir, clock.now()?, "wal", storage.as_ref()).await?;
+        Self::from_file(&path, file, storage).await
+    }
+
+    /// Creates a WAL from an existing file path, a new file starts with the WAL header.
+    pub async fn from_path(path: &Path) -> Result<Self> {
+        Self::from_path_with_storage(path, Arc::new(LocalFs)).await
+    }
+
+    /// Like [`WriteAheadLog::from_path`], for a file of `storage`.
+    pub async fn from_path_with_storage(path: &Path, storage: Arc<dyn Storage>) -> Result<Self> {
+        let file = storage.open_append(path, AppendMode::OpenOrCreate).await?;
+        Self::from_file(path, file, storage).await
+    }
+
+    /// A WAL appending to `file`, opened at `path` of `storage`.
+    async fn from_file(
+        path: &Path,
+        mut file: Box<dyn WritableFile>,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Self> {
+        if file.size().await? == 0 {
+            file.write_all(&wal_header()).await?;
+            file.flush().await?;
+        }
+        let writer = BufWriter::new(file);
+        Ok(Self {
+            sink: WalSink::Buffered(writer),
+            path: path.to_owned(),
+            codec: Codec::None,
+            storage,
+            bytes_appended: 0,
+        })
+    }
+
+    /// Switch the WAL to the given sync policy.
+    pub async fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> io::Result<Self> {
+        self.sink = match (self.sink, sync_policy) {
+            (WalSink::Buffered(mut writer), SyncPolicy::Always) => {
+                writer.flush().await?;
+                WalSink::GroupCommit(GroupCommitter::spawn(
+                    writer.into_inner(),
+                    DEFAULT_MAX_BATCH_DELAY,
+                    DEFAULT_MAX_BATCH_BYTES,
+                ))
+            }
+            (WalSink::GroupCommit(committer), SyncPolicy::Never) => {
+                committer.flush().await?;
+                let file = self
+                    .storage
+                    .open_append(&self.path, AppendMode::OpenOrCreate)
+                    .await?;
+                WalSink::Buffered(BufWriter::new(file))
+            }
+            (sink, _) => sink,
+        };
+        Ok(self)
+    }
+
+    /// Compress the values of the records appended from now on.
+    pub fn set_compression(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// Restore our MemTable from the WAL files in a directory by replaying all of the
+    /// operations, the records are not copied anywhere.
+    ///
+    /// Returns the WAL to keep appending to (the newest existing file, or a new one), the
+    /// MemTable and the older WAL files which still back the MemTable. Those have to stay on
+    /// disk until the MemTable is flushed to SSTable.
+    ///
+    /// The replay is reported to `progress`, and stops with [`Error::RestoreCancelled`] before
+    /// the next file once `cancellation` is cancelled.
+    #[cfg(test)]
+    pub async fn restore_from_dir(
+        dir: &Path,
+        recovery_mode: RecoveryMode,
+        progress: Option<mpsc::Sender<RestoreProgress>>,
+        cancellation: CancellationToken,
+    ) -> Result<(WriteAheadLog, MemTable, Vec<PathBuf>)> {
+        let (wal, mem_table, wal_files, _) = Self::restore_from_dir_with_clock(
+            dir,
+            recovery_mode,
+            progress,
+            cancellation,
+            &utils::HybridClock,
+            Arc::new(LocalFs),
+            DEFAULT_MAX_FIELD_LEN,
+        )
+        .await?;
+        Ok((wal, mem_table, wal_files))
+    }
+
+    /// Like [`WriteAheadLog::restore_from_dir`] for the files of `storage`, a new WAL is named
+    /// after a timestamp of `clock`. A key or value longer than `max_field_len` is a corrupted
+    /// record. Also returns the summary of the replay.
+    pub(crate) async fn restore_from_dir_with_clock(
+        dir: &Path,
+        recovery_mode: RecoveryMode,
+        progress: Option<mpsc::Sender<RestoreProgress>>,
+        cancellation: CancellationToken,
+        clock: &dyn Clock,
+        storage: Arc<dyn Storage>,
+        max_field_len: usize,
+    ) -> Result<(WriteAheadLog, MemTable, Vec<PathBuf>, RecoveryReport)> {
+        let started_at = Instant::now();
+        let mut wal_files = storage
+            .list(dir)
+            .await?
+            .into_iter()
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wal"))
+            .collect::<Vec<_>>();
+        wal_files.sort_by(|a, b| utils::allocation_order(a).cmp(&utils::allocation_order(b)));
+
+        let mut new_memtable = MemTable::new();
+        let mut newest_version = WAL_VERSION;
+        let mut reporter = ProgressReporter::new(progress, wal_files.len());
+        let mut report = RecoveryReport::default();
+        for file in wal_files.iter() {
+            if cancellation.is_cancelled() {
+                return Err(Error::RestoreCancelled.into());
+            }
+
+            let mut replay = replay_wal_file(
+                file,
+                &mut new_memtable,
+                &mut reporter,
+                storage.as_ref(),
+                max_field_len,
+                None,
+            )
+            .await?;
+            if replay.error.is_some() && recovery_mode == RecoveryMode::Repair {
+                let report =
+                    Self::repair_with_storage(file, storage.as_ref(), max_field_len).await?;
+                tracing::warn!("Repaired wal file {:?}: {:?}", file, report);
+                let corrupted_path = with_suffix(file, "corrupted");
+                storage.rename(file, &corrupted_path).await?;
+                storage.rename(&report.repaired_path, file).await?;
+                // the repaired file starts with the records applied already
+                replay = replay_wal_file(
+                    file,
+                    &mut new_memtable,
+                    &mut reporter,
+                    storage.as_ref(),
+                    max_field_len,
+                    Some(&replay),
+                )
+                .await?;
+            }
+            match &replay.error {
+                None => {}
+                Some(e @ WalReadError::UnexpectedEof { .. }) => {
+                    tracing::warn!("Truncated last record in wal file {:?}: {}", file, e)
+                }
+                Some(e) => tracing::error!("Corrupted record in wal file {:?}: {}", file, e),
+            }
+            newest_version = replay.version;
+
+            // drop the torn or corrupted tail, so nothing gets appended after the junk
+            let file_len = storage::file_len(storage.as_ref(), file).await?;
+            if replay.valid_len < file_len {
+                truncate(storage.as_ref(), file, replay.valid_len).await?;
+            }
+            tracing::info!(
+                "Recovered {} records from wal file {:?}, truncated {} bytes",
+                replay.records,
+                file,
+                file_len - replay.valid_len
+            );
+            reporter.next_file();
+            report.wal_files_replayed += 1;
+            report.records_applied += replay.records;
+            report.tombstones += replay.tombstones;
+            report.bytes_read += file_len;
+            report.truncated_tail_bytes += file_len - replay.valid_len;
+        }
+        report.duration = started_at.elapsed();
+
+        // records of an older format cannot be appended to, start a new file next to it
+        let wal = match wal_files.last() {
+            Some(_) if newest_version == WAL_VERSION => {
+                let newest = wal_files.pop().unwrap();
+                WriteAheadLog::from_path_with_storage(&newest, storage).await?
+            }
+            _ => WriteAheadLog::new_with_clock(dir, clock, storage).await?,
+        };
+
+        Ok((wal, new_memtable, wal_files, report))
+    }
+
+    /// Remove the WAL files the database in `dir` archived for its CDC streams which were last
+    /// written more than `age` ago, never less than [`MIN_CLEANUP_AGE`](crate::MIN_CLEANUP_AGE).
+    /// A stream further behind than that misses the changes of the removed files.
+    pub async fn prune_archive(dir: &Path, age: Duration) -> Result<CleanupReport> {
+        cleanup::prune_archive(dir, age, &LocalFs).await
+    }
+
+    /// Sets a Key-Value pair and the operation is appended to the WAL.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(path = ?self.path, key_len = key.len(), value_len = value.len())))]
+    pub async fn set(&mut self, key: &[u8], value: &[u8], timestamp: u128) -> io::Result<()> {
+        let entry = Entry::new(key.to_vec(), Some(value.to_vec()), timestamp);
+        self.append(&entry).await
+    }
+
+    /// Sets a Key-Value pair tagged with the `content_type` of its value and the operation is
+    /// appended to the WAL.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(path = ?self.path, key_len = key.len(), value_len = value.len())))]
+    pub async fn set_typed(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        content_type: &str,
+        timestamp: u128,
+    ) -> io::Result<()> {
+        let entry = Entry::new(key.to_vec(), Some(value.to_vec()), timestamp)
+            .with_content_type(content_type);
+        self.append(&entry).await
+    }
+
+    /// Sets a Key-Value pair which expires at `expires_at` and the operation is appended to
+    /// the WAL.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(path = ?self.path, key_len = key.len(), value_len = value.len())))]
+    pub async fn set_expiring(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u128,
+        timestamp: u128,
+    ) -> io::Result<()> {
+        let entry =
+            Entry::new(key.to_vec(), Some(value.to_vec()), timestamp).with_expires_at(expires_at);
+        self.append(&entry).await
+    }
+
+    /// Appends the Entry as is, with its timestamp and metadata, e.g. one replicated from a
+    /// primary.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(path = ?self.path, key_len = entry.key.len())))]
+    pub async fn put(&mut self, entry: &Entry) -> io::Result<()> {
+        self.append(entry).await
+    }
+
+    /// Deletes a Key-Value pair and the operation is appended to the WAL.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(path = ?self.path, key_len = key.len())))]
+    pub async fn delete(&mut self, key: &[u8], timestamp: u128) -> io::Result<()> {
+        let entry = Entry::new(key.to_vec(), None, timestamp);
+        self.append(&entry).await
+    }
+
+    /// Appends the Entry followed by its CRC32 checksum.
+    ///
+    /// With [`SyncPolicy::Always`] this returns once the record is synced to disk.
+    async fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        let bytes = encode_records(std::slice::from_ref(entry), self.codec).await?;
+        self.bytes_appended += bytes.len() as u64;
+        match &mut self.sink {
+            WalSink::Buffered(writer) => writer.write_all(&bytes).await,
+            WalSink::GroupCommit(committer) => committer.commit(bytes).await,
+        }
+    }
+
+    /// Appends all the Entries as one batch with a single write and flushes the WAL once. The
+    /// batch is restored all together or not at all.
+    ///
+    /// With [`SyncPolicy::Always`] this returns once the whole batch is synced to disk.
+    pub async fn append_batch(&mut self, entries: &[Entry]) -> io::Result<()> {
+        let bytes = encode_batch(entries, self.codec).await?;
+        self.bytes_appended += bytes.len() as u64;
+        match &mut self.sink {
+            WalSink::Buffered(writer) => {
+                writer.write_all(&bytes).await?;
+                writer.flush().await
+            }
+            WalSink::GroupCommit(committer) => committer.commit(bytes).await,
+        }
+    }
+
+    /// Flushes the WAL to disk.
+    ///
+    /// With [`SyncPolicy::Always`] this drains the group commit queue.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(path = ?self.path)))]
+    pub async fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            WalSink::Buffered(writer) => writer.flush().await,
+            WalSink::GroupCommit(committer) => committer.flush().await,
+        }
+    }
+
+    /// Flushes the records appended so far to the file, unless they are already: with
+    /// [`SyncPolicy::Always`] every append returns once synced, and waiting for the group
+    /// commit once more would only add a batch delay to the write.
+    pub async fn flush_appended(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            WalSink::Buffered(writer) => writer.flush().await,
+            WalSink::GroupCommit(_) => Ok(()),
+        }
+    }
+
+    /// Flushes the WAL and syncs it to disk, whatever the [`SyncPolicy`].
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(path = ?self.path)))]
+    pub async fn sync(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            WalSink::Buffered(writer) => {
+                writer.flush().await?;
+                writer.get_mut().sync_data().await
+            }
+            // every commit is synced
+            WalSink::GroupCommit(committer) => committer.flush().await,
+        }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+
+    /// Bytes of the records appended through this handle so far, the ones still buffered
+    /// included.
+    pub fn bytes_appended(&self) -> u64 {
+        self.bytes_appended
+    }
+}
+
+/// A decoded record together with its length in the file.
+type Record = (WalRecord, usize);
+
+/// Outcome of replaying one WAL file into a MemTable.
+struct Replay {
+    version: u16,
+    records: usize,
+    /// Records among them which delete their key.
+    tombstones: usize,
+    /// Length of the header and the valid records.
+    valid_len: u64,
+    /// The bad record which ended the replay early.
+    error: Option<WalReadError>,
+}
+
+/// A batch whose commit record has not been read yet.
+struct PendingBatch {
+    /// Offset of the batch begin record.
+    offset: u64,
+    count: u32,
+    entries: Vec<Entry>,
+}
+
+/// Apply the records of the WAL file to the MemTable up to the first bad one, I/O errors
+/// abort the replay. The entries of a batch are applied once its commit record is read, an
+/// uncommitted batch at the end is discarded.
+///
+/// The records `applied` by an earlier replay of the file, the ones before its `valid_len`,
+/// are read again but neither applied nor counted twice.
+async fn replay_wal_file(
+    file: &Path,
+    mem_table: &mut MemTable,
+    reporter: &mut ProgressReporter,
+    storage: &dyn Storage,
+    max_field_len: usize,
+    applied: Option<&Replay>,
+) -> Result<Replay> {
+    let mut wal_iter = WALIterator::with_storage(file.to_owned(), storage)
+        .await?
+        .with_max_field_len(max_field_len);
+    reporter.start_file(file, wal_iter.file_len);
+    let applied_len = applied.map_or(0, |applied| applied.valid_len);
+    let mut records = applied.map_or(0, |applied| applied.records);
+    let mut tombstones = applied.map_or(0, |applied| applied.tombstones);
+    let mut error = None;
+    let mut batch: Option<PendingBatch> = None;
+    let mut record_offset = wal_iter.offset();
+    while let Some(record) = wal_iter.next().await {
+        let record = match record {
+            Ok(record) => record,
+            Err(e @ WalReadError::Io(_)) => {
+                return Err(anyhow::Error::new(e).context(format!("read wal file {:?}", file)));
+            }
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+        match (record, batch.as_mut()) {
+            (WalRecord::Entry(entry), Some(batch)) => batch.entries.push(entry),
+            (WalRecord::Entry(_), None) if record_offset < applied_len => {}
+            (WalRecord::Entry(entry), None) => {
+                tombstones += usize::from(entry.is_deleted());
+                apply_entry(mem_table, entry);
+                records += 1;
+            }
+            (WalRecord::BatchBegin { count }, None) => {
+                batch = Some(PendingBatch {
+                    offset: record_offset,
+                    count,
+                    entries: Vec::with_capacity(count as usize),
+                });
+            }
+            (WalRecord::BatchCommit { count }, Some(pending))
+                if pending.count == count && pending.entries.len() == count as usize =>
+            {
+                let pending = batch.take().unwrap();
+                if pending.offset >= applied_len {
+                    records += pending.entries.len();
+                    for entry in pending.entries {
+                        tombstones += usize::from(entry.is_deleted());
+                        apply_entry(mem_table, entry);
+                    }
+                }
+            }
+            _ => {
+                error = Some(WalReadError::InvalidBatch {
+                    offset: record_offset,
+                });
+                break;
+            }
+        }
+        record_offset = wal_iter.offset();
+        reporter.report(record_offset, records);
+    }
+    reporter.finish_file(record_offset, records).await;
+
+    // nothing after the last complete record or batch is kept
+    let valid_len = match batch {
+        Some(batch) => {
+            tracing::warn!(
+                "Discarded an uncommitted batch of {} records in wal file {:?}",
+                batch.entries.len(),
+                file
+            );
+            batch.offset
+        }
+        None => record_offset,
+    };
+
+    Ok(Replay {
+        version: wal_iter.version(),
+        records,
+        tombstones,
+        valid_len,
+        error,
+    })
+}
+
+/// Cut the file at `path` of `storage` to its first `len` bytes. The kept bytes are written to
+/// a `.tmp` file renamed over it, which works the same for a storage unable to truncate.
+async fn truncate(storage: &dyn Storage, path: &Path, len: u64) -> io::Result<()> {
+    let kept = storage.open_read(path).await?.read_at(0, len).await?;
+    let tmp_path = with_suffix(path, "tmp");
+    storage::write(storage, &tmp_path, &kept).await?;
+    storage.rename(&tmp_path, path).await
+}
+
+/// Replay the entry with its content type and expiry, a tombstone never has either.
+fn apply_entry(mem_table: &mut MemTable, entry: Entry) {
+    mem_table.put(entry);
+}
+
+type ReadRecordFuture = Pin<
+    Box<dyn Future<Output = (BufReader<FileReader>, Option<Result<Record, WalReadError>>)> + Send>,
+>;
+
+/// WAL Iterator will iterate over the items in the WAL file.
+///
+/// The iteration ends at the end of the file, or after yielding the first error: a record
+/// which is incomplete, fails its checksum or cannot be read.
+pub struct WALIterator {
+    reader: Option<BufReader<FileReader>>,
+    // the in-flight read owns the reader, so a record spanning several polls stays intact
+    pending: Option<ReadRecordFuture>,
+    offset: u64,
+    file_len: u64,
+    version: u16,
+    max_field_len: usize,
+    done: bool,
+}
+
+impl WALIterator {
+    /// Opens the WAL file and validates its header.
+    ///
+    /// Fails with [`Error::UnsupportedWalVersion`] for a format this build cannot read.
+    pub async fn new(path: PathBuf) -> Result<Self> {
+        Self::with_storage(path, &LocalFs).await
+    }
+
+    /// Like [`WALIterator::new`], for a file of `storage`.
+    pub async fn with_storage(path: PathBuf, storage: &dyn Storage) -> Result<Self> {
+        let file = storage.open_read(&path).await?;
+        let file_len = file.size().await?;
+        let mut reader = BufReader::new(FileReader::new(Arc::clone(&file), 0, file_len));
+
+        let mut header = [0; WAL_HEADER_SIZE];
+        let read = read_field(&mut reader, &mut header, true).await?;
+        let (offset, version, done) = if read == WAL_HEADER_SIZE
+            && header[..WAL_MAGIC.len()] == WAL_MAGIC
+        {
+            let found = u16::from_le_bytes([header[WAL_MAGIC.len()], header[WAL_MAGIC.len() + 1]]);
+            if !(WAL_VERSION_UNTAGGED..=WAL_VERSION).contains(&found) {
+                return Err(Error::UnsupportedWalVersion {
+                    found,
+                    supported: WAL_VERSION,
+                }
+                .into());
+            }
+            (WAL_HEADER_SIZE as u64, found, false)
+        } else if wal_header().starts_with(&header[..read]) {
+            // a torn header of a brand new file, there are no records to read
+            (0, WAL_VERSION, true)
+        } else {
+            tracing::warn!(
+                "WAL file {:?} has no header, the headerless format is deprecated and will not be replayed by the next release",
+                path
+            );
+            reader = BufReader::new(FileReader::new(file, 0, file_len));
+            (0, WAL_VERSION_UNTAGGED, false)
+        };
+
+        Ok(Self {
+            reader: Some(reader),
+            pending: None,
+            offset,
+            file_len,
+            version,
+            max_field_len: DEFAULT_MAX_FIELD_LEN,
+            done,
+        })
+    }
+
+    /// Read keys and values up to `max_field_len` long, a longer one is
+    /// [`WalReadError::FieldTooLong`]. [`DEFAULT_MAX_FIELD_LEN`] by default.
+    pub fn with_max_field_len(mut self, max_field_len: usize) -> Self {
+        self.max_field_len = max_field_len;
+        self
+    }
+
+    /// Format version of the WAL file.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Bytes of the valid records read so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+/// Serialize the Entries as WAL records: the record type, the codec tag, the Entry with its
+/// value compressed (kept raw when the codec does not shrink it) and the CRC32 checksum of
+/// all of them.
+async fn encode_records(entries: &[Entry], codec: Codec) -> io::Result<Vec<u8>> {
+    let len = entries.iter().map(|entry| entry.encoded_len_v2() + 6).sum();
+    let mut bytes = Vec::with_capacity(len);
+    encode_entries(&mut bytes, entries, codec).await?;
+    Ok(bytes)
+}
+
+/// Serialize the Entries like [`encode_records`] between a batch begin and commit record, so
+/// they are restored all together or not at all.
+async fn encode_batch(entries: &[Entry], codec: Codec) -> io::Result<Vec<u8>> {
+    let count = u32::try_from(entries.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many entries in batch"))?;
+    let len = entries
+        .iter()
+        .map(|entry| entry.encoded_len_v2() + 6)
+        .sum::<usize>()
+        + 18;
+    let mut bytes = Vec::with_capacity(len);
+    encode_batch_marker(&mut bytes, RecordType::BatchBegin, count);
+    encode_entries(&mut bytes, entries, codec).await?;
+    encode_batch_marker(&mut bytes, RecordType::BatchCommit, count);
+    Ok(bytes)
+}
+
+async fn encode_entries(bytes: &mut Vec<u8>, entries: &[Entry], codec: Codec) -> io::Result<()> {
+    for entry in entries {
+        let record_type = if entry.is_deleted() {
+            RecordType::Delete
+        } else {
+            RecordType::Put
+        };
+        // a value the database compressed already is not compressed again
+        let compressed = entry
+            .value
+            .as_deref()
+            .filter(|_| entry.value_codec == Codec::None)
+            .and_then(|value| codec.compress(value));
+        let (tag, stored) = match compressed {
+            Some(value) => (
+                codec.tag(),
+                Cow::Owned(Entry {
+                    value: Some(value.into()),
+                    ..entry.clone()
+                }),
+            ),
+            None => (Codec::None.tag(), Cow::Borrowed(entry)),
+        };
+        let prefix = [record_type as u8, tag];
+        bytes.extend_from_slice(&prefix);
+        stored.write_to_v2(bytes).await?;
+        bytes.extend_from_slice(&record_checksum(&prefix, &stored).to_le_bytes());
+    }
+    Ok(())
+}
+
+/// A batch marker: the record type, the number of entries in the batch and the CRC32
+/// checksum of both.
+fn encode_batch_marker(bytes: &mut Vec<u8>, record_type: RecordType, count: u32) {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[record_type as u8]);
+    hasher.update(&count.to_le_bytes());
+    bytes.push(record_type as u8);
+    bytes.extend_from_slice(&count.to_le_bytes());
+    bytes.extend_from_slice(&hasher.finalize().to_le_bytes());
+}
+
+/// CRC32 over the bytes in front of the Entry (record type and codec tag) and the Entry as
+/// stored.
+fn record_checksum(prefix: &[u8], stored: &Entry) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(prefix);
+    stored.hash_into(&mut hasher);
+    hasher.finalize()
+}
+
+/// The header written at the start of a new WAL file.
+fn wal_header() -> [u8; WAL_HEADER_SIZE] {
+    let mut header = [0; WAL_HEADER_SIZE];
+    header[..WAL_MAGIC.len()].copy_from_slice(&WAL_MAGIC);
+    header[WAL_MAGIC.len()..].copy_from_slice(&WAL_VERSION.to_le_bytes());
+    header
+}
+
+/// Read one record, verify its trailing checksum and decompress the value.
+///
+/// `remaining` is the number of bytes left in the input from `offset`.
+async fn read_record<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    offset: u64,
+    version: u16,
+    remaining: u64,
+    max_field_len: usize,
+) -> Result<Option<Record>, WalReadError> {
+    // record type and codec tag, depending on the version
+    let mut prefix = Vec::with_capacity(2);
+    let mut byte_buffers = [0; 1];
+    let mut record_type = None;
+    if version >= WAL_VERSION_CODEC {
+        if read_field(reader, &mut byte_buffers, true).await? == 0 {
+            return Ok(None);
+        }
+        prefix.push(byte_buffers[0]);
+    }
+    if version > WAL_VERSION_CODEC {
+        let invalid = WalReadError::InvalidRecordType {
+            record_type: prefix[0],
+            offset,
+        };
+        match RecordType::from_u8(prefix[0]).ok_or(invalid)? {
+            marker @ (RecordType::BatchBegin | RecordType::BatchCommit) => {
+                return read_batch_marker(reader, offset, marker).await.map(Some);
+            }
+            entry_type => record_type = Some(entry_type),
+        }
+        read_field(reader, &mut byte_buffers, false).await?;
+        prefix.push(byte_buffers[0]);
+    }
+
+    let encoding = match version >= WAL_VERSION_VARINT {
+        true => EntryEncoding::Varint,
+        false => EntryEncoding::Fixed,
+    };
+    let Some(mut entry) = Entry::read_with(encoding, reader, remaining, max_field_len).await?
+    else {
+        return match prefix.is_empty() {
+            true => Ok(None),
+            // the record was cut right after its type or tag, the shortest entry is an empty
+            // tombstone followed by its checksum
+            false => Err(WalReadError::UnexpectedEof {
+                missing: Entry::new(Vec::new(), None, 0).encoded_len_with(encoding) + 4,
+            }),
+        };
+    };
+    let expected = match prefix.is_empty() {
+        true => entry.checksum(),
+        false => record_checksum(&prefix, &entry),
+    };
+    verify_checksum(reader, expected, offset).await?;
+    let len = prefix.len() + entry.encoded_len_with(encoding) + 4;
+
+    // a put carries a value, a delete does not
+    if let Some(record_type) = record_type {
+        if (record_type == RecordType::Delete) != entry.is_deleted() {
+            return Err(WalReadError::InvalidRecordType {
+                record_type: record_type as u8,
+                offset,
+            });
+        }
+    }
+    if let (Some(&tag), Some(value)) = (prefix.last(), entry.value.as_mut()) {
+        let codec = Codec::from_tag(tag).ok_or(WalReadError::UnknownCodec { tag, offset })?;
+        *value = codec
+            .decompress(value)
+            .map_err(|source| WalReadError::Decompress { offset, source })?
+            .into();
+    }
+
+    Ok(Some((WalRecord::Entry(entry), len)))
+}
+
+/// Read the rest of a batch marker after its record type.
+async fn read_batch_marker<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    offset: u64,
+    record_type: RecordType,
+) -> Result<Record, WalReadError> {
+    let mut count_buffers = [0; 4];
+    read_field(reader, &mut count_buffers, false).await?;
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[record_type as u8]);
+    hasher.update(&count_buffers);
+    verify_checksum(reader, hasher.finalize(), offset).await?;
+
+    let count = u32::from_le_bytes(count_buffers);
+    let record = match record_type {
+        RecordType::BatchBegin => WalRecord::BatchBegin { count },
+        _ => WalRecord::BatchCommit { count },
+    };
+    Ok((record, 9))
+}
+
+impl Stream for WALIterator {
+    type Item = Result<WalRecord, WalReadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        let pending = match this.pending.as_mut() {
+            Some(pending) => pending,
+            None => {
+                let Some(mut reader) = this.reader.take() else {
+                    return Poll::Ready(None);
+                };
+                let (offset, version) = (this.offset, this.version);
+                let (remaining, max_field_len) =
+                    (this.file_len.saturating_sub(offset), this.max_field_len);
+                this.pending.insert(Box::pin(async move {
+                    let record =
+                        read_record(&mut reader, offset, version, remaining, max_field_len)
+                            .await
+                            .transpose();
+                    (reader, record)
+                }))
+            }
+        };
+
+        let (reader, record) = match pending.as_mut().poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(output) => output,
+        };
+        this.pending = None;
+        this.reader = Some(reader);
+        match &record {
+            Some(Ok((_, len))) => this.offset += *len as u64,
+            _ => this.done = true,
+        }
+        Poll::Ready(record.map(|record| record.map(|(record, _)| record)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use tokio::{
+        fs::{metadata, File, OpenOptions},
+        io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    };
+
+    use crate::compression::Codec;
+    use crate::dump::{DumpSummary, FileKind};
+    use crate::entries::DEFAULT_MAX_FIELD_LEN;
+    use crate::prelude::{Entry, Error, WalReadError};
+    use crate::storage::LocalFs;
+    use crate::utils::HybridClock;
+    use crate::wal::{
+        encode_batch_marker, encode_records, record_checksum, wal_header, RecordType, RecoveryMode,
+        SyncPolicy, WALIterator, WalRecord, WriteAheadLog, WAL_HEADER_SIZE, WAL_MAGIC,
+    };
+    use std::{
+        path::Path,
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+    use tokio_stream::StreamExt;
+    use tokio_util::sync::CancellationToken;
+
+    /// Open a WAL file and skip its header.
+    async fn open_records(path: &Path) -> BufReader<File> {
+        let file = OpenOptions::new().read(true).open(path).await.unwrap();
+        let mut reader = BufReader::new(file);
+        let mut header = [0; WAL_HEADER_SIZE];
+        reader.read_exact(&mut header).await.unwrap();
+        assert_eq!(header, wal_header());
+        reader
+    }
+
+    async fn check_batch_marker(reader: &mut BufReader<File>, record_type: RecordType, count: u32) {
+        assert_eq!(reader.read_u8().await.unwrap(), record_type as u8);
+        assert_eq!(reader.read_u32_le().await.unwrap(), count);
+        reader.read_u32_le().await.unwrap();
+    }
+
+    /// The Entry of an entry record.
+    fn entry(record: WalRecord) -> Entry {
+        match record {
+            WalRecord::Entry(entry) => entry,
+            other => panic!("expected an entry record, got {:?}", other),
+        }
+    }
+
+    async fn check_entry(
+        reader: &mut BufReader<File>,
+        key: &[u8],
+        value: Option<&[u8]>,
+        timestamp: u128,
+        deleted: bool,
+    ) {
+        let record_type = reader.read_u8().await.unwrap();
+        let expected_type = if deleted {
+            RecordType::Delete
+        } else {
+            RecordType::Put
+        };
+        assert_eq!(record_type, expected_type as u8);
+        let tag = reader.read_u8().await.unwrap();
+        assert_eq!(tag, Codec::None.tag());
+        let entry = Entry::read_from_v2(reader, u64::MAX, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap()
+            .unwrap();
+        let checksum = reader.read_u32_le().await.unwrap();
+        assert_eq!(checksum, record_checksum(&[record_type, tag], &entry));
+        assert_eq!(entry.key, key);
+        assert_eq!(entry.value.as_deref(), value);
+        assert_eq!(entry.timestamp, timestamp);
+        assert_eq!(entry.is_deleted(), deleted);
+    }
+
+    #[tokio::test]
+    async fn test_write_one() {
+        let temp_dir = TempDir::new("test_write_one").unwrap();
+        let dir = temp_dir.path();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros();
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", timestamp).await.unwrap();
+        wal.flush().await.unwrap();
+
+        let mut reader = open_records(&wal.path).await;
+
+        check_entry(
+            &mut reader,
+            b"Lime",
+            Some(b"Lime Smoothie"),
+            timestamp,
+            false,
+        )
+        .await;
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_many() {
+        let temp_dir = TempDir::new("test_write_many").unwrap();
+        let dir = temp_dir.path();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros();
+
+        let entries: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"Apple", Some(b"Apple Smoothie")),
+            (b"Lime", Some(b"Lime Smoothie")),
+            (b"Orange", Some(b"Orange Smoothie")),
+        ];
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+
+        for e in entries.iter() {
+            wal.set(e.0, e.1.unwrap(), timestamp).await.unwrap();
+        }
+        wal.flush().await.unwrap();
+
+        let mut reader = open_records(&wal.path).await;
+
+        for e in entries.iter() {
+            check_entry(&mut reader, e.0, e.1, timestamp, false).await;
+        }
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_delete() {
+        let temp_dir = TempDir::new("test_write_delete").unwrap();
+        let dir = temp_dir.path();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros();
+
+        let entries: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"Apple", Some(b"Apple Smoothie")),
+            (b"Lime", Some(b"Lime Smoothie")),
+            (b"Orange", Some(b"Orange Smoothie")),
+        ];
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+
+        for e in entries.iter() {
+            wal.set(e.0, e.1.unwrap(), timestamp).await.unwrap();
+        }
+        for e in entries.iter() {
+            wal.delete(e.0, timestamp).await.unwrap();
+        }
+
+        wal.flush().await.unwrap();
+
+        let mut reader = open_records(&wal.path).await;
+
+        for e in entries.iter() {
+            check_entry(&mut reader, e.0, e.1, timestamp, false).await;
+        }
+        for e in entries.iter() {
+            check_entry(&mut reader, e.0, None, timestamp, true).await;
+        }
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_wal_none() {
+        let temp_dir = TempDir::new("test_read_wal_none").unwrap();
+        let dir = temp_dir.path();
+
+        let (new_wal, new_mem_table, segments) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_mem_table.len(), 0);
+        assert!(segments.is_empty());
+
+        // only the header
+        let m = metadata(new_wal.path).await.unwrap();
+        assert_eq!(m.len(), WAL_HEADER_SIZE as u64);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_wal_one() {
+        let temp_dir = TempDir::new("test_read_wal_one").unwrap();
+        let dir = temp_dir.path();
+
+        let entries: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"Apple", Some(b"Apple Smoothie")),
+            (b"Lime", Some(b"Lime Smoothie")),
+            (b"Orange", Some(b"Orange Smoothie")),
+        ];
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+
+        for (i, e) in entries.iter().enumerate() {
+            wal.set(e.0, e.1.unwrap(), i as u128).await.unwrap();
+        }
+        wal.flush().await.unwrap();
+
+        // keeps appending to the existing file
+        let (mut new_wal, new_mem_table, segments) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_wal.path, wal.path);
+        assert!(segments.is_empty());
+        new_wal.delete(b"Lime", 3).await.unwrap();
+        new_wal.flush().await.unwrap();
+
+        let mut reader = open_records(&new_wal.path).await;
+
+        for (i, e) in entries.iter().enumerate() {
+            check_entry(&mut reader, e.0, e.1, i as u128, false).await;
+
+            let mem_e = new_mem_table.get(e.0).unwrap();
+            assert_eq!(mem_e.key, e.0);
+            assert_eq!(mem_e.value.as_ref().unwrap().as_ref(), e.1.unwrap());
+            assert_eq!(mem_e.timestamp, i as u128);
+        }
+        check_entry(&mut reader, b"Lime", None, 3, true).await;
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_wal_multiple() {
+        let temp_dir = TempDir::new("test_read_wal_multiple").unwrap();
+        let dir = temp_dir.path();
+
+        let entries_1: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"Apple", Some(b"Apple Smoothie")),
+            (b"Lime", Some(b"Lime Smoothie")),
+            (b"Orange", Some(b"Orange Smoothie")),
+        ];
+        let mut wal_1 = WriteAheadLog::new(dir).await.unwrap();
+        for (i, e) in entries_1.iter().enumerate() {
+            wal_1.set(e.0, e.1.unwrap(), i as u128).await.unwrap();
+        }
+        wal_1.flush().await.unwrap();
+
+        let entries_2: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"Strawberry", Some(b"Strawberry Smoothie")),
+            (b"Blueberry", Some(b"Blueberry Smoothie")),
+            (b"Orange", Some(b"Orange Milkshake")),
+        ];
+        let mut wal_2 = WriteAheadLog::new(dir).await.unwrap();
+        for (i, e) in entries_2.iter().enumerate() {
+            wal_2.set(e.0, e.1.unwrap(), (i + 3) as u128).await.unwrap();
+        }
+        wal_2.flush().await.unwrap();
+
+        // the older file stays on disk, it still backs the mem_table
+        let (new_wal, new_mem_table, segments) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_wal.path, wal_2.path);
+        assert_eq!(segments, vec![wal_1.path.clone()]);
+
+        let mut reader = open_records(&wal_1.path).await;
+
+        for (i, e) in entries_1.iter().enumerate() {
+            check_entry(&mut reader, e.0, e.1, i as u128, false).await;
+
+            let mem_e = new_mem_table.get(e.0).unwrap();
+            if i != 2 {
+                assert_eq!(mem_e.key, e.0);
+                assert_eq!(mem_e.value.as_ref().unwrap().as_ref(), e.1.unwrap());
+                assert_eq!(mem_e.timestamp, i as u128);
+            } else {
+                assert_eq!(mem_e.key, e.0);
+                assert_ne!(mem_e.value.as_ref().unwrap().as_ref(), e.1.unwrap());
+                assert_ne!(mem_e.timestamp, i as u128);
+            }
+        }
+        let mut reader = open_records(&wal_2.path).await;
+        for (i, e) in entries_2.iter().enumerate() {
+            check_entry(&mut reader, e.0, e.1, (i + 3) as u128, false).await;
+
+            let mem_e = new_mem_table.get(e.0).unwrap();
+            assert_eq!(mem_e.key, e.0);
+            assert_eq!(mem_e.value.as_ref().unwrap().as_ref(), e.1.unwrap());
+            assert_eq!(mem_e.timestamp, (i + 3) as u128);
+        }
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_wal_with_corrupted_tail() {
+        let temp_dir = TempDir::new("test_read_wal_with_corrupted_tail").unwrap();
+        let dir = temp_dir.path();
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+        wal.set(b"Apple", b"Apple Smoothie", 1).await.unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", 2).await.unwrap();
+        wal.flush().await.unwrap();
+        let valid_len = metadata(&wal.path).await.unwrap().len();
+
+        // a record with a broken checksum followed by a torn write
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&wal.path)
+            .await
+            .unwrap();
+        let orange = Entry::new(b"Orange".to_vec(), Some(b"Orange Smoothie".to_vec()), 3);
+        let mut writer = tokio::io::BufWriter::new(file.try_clone().await.unwrap());
+        let prefix = [RecordType::Put as u8, Codec::None.tag()];
+        writer.write_all(&prefix).await.unwrap();
+        orange.write_to_v2(&mut writer).await.unwrap();
+        writer.flush().await.unwrap();
+        file.write_all(&(record_checksum(&prefix, &orange) ^ 1).to_le_bytes())
+            .await
+            .unwrap();
+        file.write_all(&[42; 7]).await.unwrap();
+        file.flush().await.unwrap();
+        assert!(metadata(&wal.path).await.unwrap().len() > valid_len);
+
+        let mut wal_iter = WALIterator::new(wal.path.clone()).await.unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = wal_iter.next().await {
+            records.push(record);
+        }
+        assert_eq!(records.len(), 3);
+        assert!(records[0].is_ok());
+        assert!(records[1].is_ok());
+        assert!(matches!(
+            records[2],
+            Err(WalReadError::ChecksumMismatch { offset }) if offset == valid_len
+        ));
+        assert_eq!(wal_iter.offset(), valid_len);
+
+        let (mut new_wal, new_mem_table, _) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_mem_table.len(), 2);
+        assert!(new_mem_table.get(b"Orange").is_none());
+
+        // new records go right after the last valid one
+        new_wal.set(b"Orange", b"Orange Smoothie", 3).await.unwrap();
+        new_wal.flush().await.unwrap();
+        let (_, new_mem_table, _) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_mem_table.len(), 3);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_wal_with_torn_last_record() {
+        let temp_dir = TempDir::new("test_read_wal_with_torn_last_record").unwrap();
+        let dir = temp_dir.path();
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+        wal.set(b"Apple", b"Apple Smoothie", 1).await.unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", 2).await.unwrap();
+        wal.flush().await.unwrap();
+        let file_len = metadata(&wal.path).await.unwrap().len();
+
+        // cut the last record in the middle of its value
+        OpenOptions::new()
+            .write(true)
+            .open(&wal.path)
+            .await
+            .unwrap()
+            .set_len(file_len - 12)
+            .await
+            .unwrap();
+
+        let mut wal_iter = WALIterator::new(wal.path.clone()).await.unwrap();
+        let first = entry(wal_iter.next().await.unwrap().unwrap());
+        assert_eq!(first.key, b"Apple");
+        match wal_iter.next().await {
+            Some(Err(WalReadError::UnexpectedEof { missing })) => assert_eq!(missing, 7),
+            other => panic!("expected UnexpectedEof, got {:?}", other.map(|r| r.is_ok())),
+        }
+        assert!(wal_iter.next().await.is_none());
+
+        // the truncated record is dropped, the rest is recovered
+        let (_, new_mem_table, _) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_mem_table.len(), 1);
+        assert!(new_mem_table.get(b"Apple").is_some());
+
+        // a record cut right after its type and codec tag
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&wal.path)
+            .await
+            .unwrap();
+        file.write_all(&[RecordType::Put as u8, Codec::None.tag()])
+            .await
+            .unwrap();
+        file.flush().await.unwrap();
+        let mut wal_iter = WALIterator::new(wal.path.clone()).await.unwrap();
+        assert_eq!(entry(wal_iter.next().await.unwrap().unwrap()).key, b"Apple");
+        match wal_iter.next().await {
+            // the key length, flags and timestamp of an empty tombstone, then its checksum
+            Some(Err(WalReadError::UnexpectedEof { missing })) => assert_eq!(missing, 3 + 4),
+            other => panic!("expected UnexpectedEof, got {:?}", other.map(|r| r.is_ok())),
+        }
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_with_sync_policy_always() {
+        let temp_dir = TempDir::new("test_write_with_sync_policy_always").unwrap();
+        let dir = temp_dir.path();
+
+        let mut wal = WriteAheadLog::new(dir)
+            .await
+            .unwrap()
+            .with_sync_policy(SyncPolicy::Always)
+            .await
+            .unwrap();
+        wal.set(b"Apple", b"Apple Smoothie", 1).await.unwrap();
+        wal.delete(b"Lime", 2).await.unwrap();
+
+        // durable without an explicit flush
+        let mut reader = open_records(&wal.path).await;
+        check_entry(&mut reader, b"Apple", Some(b"Apple Smoothie"), 1, false).await;
+        check_entry(&mut reader, b"Lime", None, 2, true).await;
+
+        // and back to the buffered writer
+        let mut wal = wal.with_sync_policy(SyncPolicy::Never).await.unwrap();
+        wal.set(b"Orange", b"Orange Smoothie", 3).await.unwrap();
+        wal.flush().await.unwrap();
+        check_entry(&mut reader, b"Orange", Some(b"Orange Smoothie"), 3, false).await;
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_wal_with_unsupported_version() {
+        let temp_dir = TempDir::new("test_read_wal_with_unsupported_version").unwrap();
+        let dir = temp_dir.path();
+
+        let path = dir.join("1.wal");
+        let mut header = WAL_MAGIC.to_vec();
+        header.extend_from_slice(&5u16.to_le_bytes());
+        tokio::fs::write(&path, header).await.unwrap();
+
+        let err = WALIterator::new(path).await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::UnsupportedWalVersion {
+                found: 5,
+                supported: 4
+            })
+        ));
+        assert!(WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new()
+        )
+        .await
+        .is_err());
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_headerless_wal() {
+        let temp_dir = TempDir::new("test_read_headerless_wal").unwrap();
+        let dir = temp_dir.path();
+
+        // the format before the header was introduced
+        let path = dir.join("1.wal");
+        let mut file = File::create(&path).await.unwrap();
+        for entry in [
+            Entry::new(b"Apple".to_vec(), Some(b"Apple Smoothie".to_vec()), 1),
+            Entry::new(b"Lime".to_vec(), None, 2),
+        ] {
+            entry.write_to(&mut file).await.unwrap();
+            file.write_all(&entry.checksum().to_le_bytes())
+                .await
+                .unwrap();
+        }
+        file.flush().await.unwrap();
+
+        let (_, new_mem_table, _) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_mem_table.len(), 2);
+        assert!(new_mem_table.get(b"Apple").is_some());
+        assert!(new_mem_table.get(b"Lime").unwrap().is_deleted());
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_append_batch() {
+        let temp_dir = TempDir::new("test_append_batch").unwrap();
+        let dir = temp_dir.path();
+
+        let entries = vec![
+            Entry::new(b"Apple".to_vec(), Some(b"Apple Smoothie".to_vec()), 1),
+            Entry::new(b"Lime".to_vec(), None, 2),
+            Entry::new(b"Orange".to_vec(), Some(b"Orange Smoothie".to_vec()), 3),
+        ];
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+        wal.append_batch(&entries).await.unwrap();
+
+        // flushed without an explicit flush
+        let mut reader = open_records(&wal.path).await;
+        check_batch_marker(&mut reader, RecordType::BatchBegin, 3).await;
+        check_entry(&mut reader, b"Apple", Some(b"Apple Smoothie"), 1, false).await;
+        check_entry(&mut reader, b"Lime", None, 2, true).await;
+        check_entry(&mut reader, b"Orange", Some(b"Orange Smoothie"), 3, false).await;
+        check_batch_marker(&mut reader, RecordType::BatchCommit, 3).await;
+
+        let mut wal = wal.with_sync_policy(SyncPolicy::Always).await.unwrap();
+        wal.append_batch(&entries[..1]).await.unwrap();
+        check_batch_marker(&mut reader, RecordType::BatchBegin, 1).await;
+        check_entry(&mut reader, b"Apple", Some(b"Apple Smoothie"), 1, false).await;
+        check_batch_marker(&mut reader, RecordType::BatchCommit, 1).await;
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dump_wal_with_a_corrupted_record() {
+        let temp_dir = TempDir::new("test_dump_wal").unwrap();
+        let dir = temp_dir.path();
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+        wal.set(b"Apple", b"Apple Smoothie", 1).await.unwrap();
+        wal.set(b"Banana", b"Banana Smoothie", 2).await.unwrap();
+        wal.append_batch(&[
+            Entry::new(b"Lime".to_vec(), None, 3),
+            Entry::new(b"Orange".to_vec(), Some(b"Orange Smoothie".to_vec()), 4),
+        ])
+        .await
+        .unwrap();
+        wal.flush().await.unwrap();
+        let path = wal.path();
+        assert_eq!(FileKind::detect(&path).await.unwrap(), Some(FileKind::Wal));
+
+        let mut dump = Vec::new();
+        let summary = WriteAheadLog::dump(&path, &mut dump, false).await.unwrap();
+        assert_eq!(
+            summary,
+            DumpSummary {
+                records: 6,
+                corruptions: 0
+            }
+        );
+        let dump = String::from_utf8(dump).unwrap();
+        assert!(dump.contains(&format!(
+            "{:>12}  key \"Apple\" timestamp 1 value 14 bytes\n",
+            WAL_HEADER_SIZE
+        )));
+        assert!(dump.contains("key \"Lime\" timestamp 3 tombstone\n"));
+        assert!(dump.contains("batch of 2 entries"));
+
+        // the value of Banana no longer matches its checksum, the dump goes on after it
+        let mut bytes = tokio::fs::read(&path).await.unwrap();
+        let at = bytes
+            .windows(6)
+            .position(|window| window == b"Banana")
+            .unwrap();
+        bytes[at] ^= 0xff;
+        tokio::fs::write(&path, bytes).await.unwrap();
+        let mut dump = Vec::new();
+        let summary = WriteAheadLog::dump(&path, &mut dump, true).await.unwrap();
+        assert_eq!(
+            summary,
+            DumpSummary {
+                records: 5,
+                corruptions: 1
+            }
+        );
+        let dump = String::from_utf8(dump).unwrap();
+        assert!(dump.contains("CORRUPTED Checksum mismatch"));
+        assert!(dump.contains("key \"Orange\" timestamp 4"));
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_wal_with_mixed_compression() {
+        let temp_dir = TempDir::new("test_read_wal_with_mixed_compression").unwrap();
+        let dir = temp_dir.path();
+
+        let json = br#"{"name":"apple","kind":"fruit","tags":["red","green"]}"#.repeat(16);
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+        wal.set(b"Apple", &json, 1).await.unwrap();
+        wal.set_compression(Codec::Lz4);
+        wal.set(b"Lime", &json, 2).await.unwrap();
+        wal.set(b"Orange", b"tiny", 3).await.unwrap();
+        wal.set_compression(Codec::Zstd);
+        wal.set(b"Strawberry", &json, 4).await.unwrap();
+        wal.delete(b"Blueberry", 5).await.unwrap();
+        wal.flush().await.unwrap();
+
+        // the compressed records take less space than the raw one
+        let file_len = metadata(&wal.path).await.unwrap().len();
+        assert!(file_len < (WAL_HEADER_SIZE + 2 * json.len()) as u64);
+
+        let mut wal_iter = WALIterator::new(wal.path.clone()).await.unwrap();
+        let mut entries = Vec::new();
+        while let Some(record) = wal_iter.next().await {
+            entries.push(entry(record.unwrap()));
+        }
+        assert_eq!(wal_iter.offset(), file_len);
+        assert_eq!(entries.len(), 5);
+        for i in [0, 1, 3] {
+            assert_eq!(entries[i].value.as_deref(), Some(json.as_slice()));
+        }
+        assert_eq!(entries[2].value.as_deref(), Some(&b"tiny"[..]));
+        assert!(entries[4].is_deleted());
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_wal_version_1() {
+        let temp_dir = TempDir::new("test_read_wal_version_1").unwrap();
+        let dir = temp_dir.path();
+
+        // records without a codec tag
+        let path = dir.join("1.wal");
+        let mut file = File::create(&path).await.unwrap();
+        file.write_all(&WAL_MAGIC).await.unwrap();
+        file.write_all(&1u16.to_le_bytes()).await.unwrap();
+        let apple = Entry::new(b"Apple".to_vec(), Some(b"Apple Smoothie".to_vec()), 1);
+        apple.write_to(&mut file).await.unwrap();
+        file.write_all(&apple.checksum().to_le_bytes())
+            .await
+            .unwrap();
+        file.flush().await.unwrap();
+
+        // replayed, but the new records go to a new file
+        let (new_wal, new_mem_table, segments) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(new_mem_table.get(b"Apple").is_some());
+        assert_ne!(new_wal.path, path);
+        assert_eq!(segments, vec![path]);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_wal_version_3() {
+        let temp_dir = TempDir::new("test_read_wal_version_3").unwrap();
+        let dir = temp_dir.path();
+
+        // records with the fixed width lengths and timestamp
+        let path = dir.join("1.wal");
+        let mut file = File::create(&path).await.unwrap();
+        file.write_all(&WAL_MAGIC).await.unwrap();
+        file.write_all(&3u16.to_le_bytes()).await.unwrap();
+        for entry in [
+            Entry::new(b"Apple".to_vec(), Some(b"Apple Smoothie".to_vec()), 1),
+            Entry::new(b"Lime".to_vec(), None, 2),
+        ] {
+            let record_type = match entry.is_deleted() {
+                true => RecordType::Delete,
+                false => RecordType::Put,
+            };
+            let prefix = [record_type as u8, Codec::None.tag()];
+            file.write_all(&prefix).await.unwrap();
+            entry.write_to(&mut file).await.unwrap();
+            file.write_all(&record_checksum(&prefix, &entry).to_le_bytes())
+                .await
+                .unwrap();
+        }
+        file.flush().await.unwrap();
+
+        let mut wal_iter = WALIterator::new(path.clone()).await.unwrap();
+        assert_eq!(wal_iter.version(), 3);
+        let apple = entry(wal_iter.next().await.unwrap().unwrap());
+        assert_eq!(apple.value.as_deref(), Some(&b"Apple Smoothie"[..]));
+        let lime = entry(wal_iter.next().await.unwrap().unwrap());
+        assert!(lime.is_deleted());
+        assert!(wal_iter.next().await.is_none());
+        assert_eq!(wal_iter.offset(), metadata(&path).await.unwrap().len());
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_wal_with_absurd_length_prefix() {
+        let temp_dir = TempDir::new("test_read_wal_with_absurd_length_prefix").unwrap();
+        let dir = temp_dir.path();
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+        wal.set(b"Apple", b"Apple Smoothie", 1).await.unwrap();
+        wal.flush().await.unwrap();
+
+        // a value length of 2^60 bytes
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&wal.path)
+            .await
+            .unwrap();
+        file.write_all(&[RecordType::Put as u8, Codec::None.tag(), 1, b'k', 0])
+            .await
+            .unwrap();
+        file.write_all(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x10])
+            .await
+            .unwrap();
+        file.flush().await.unwrap();
+
+        let mut wal_iter = WALIterator::new(wal.path.clone()).await.unwrap();
+        assert_eq!(entry(wal_iter.next().await.unwrap().unwrap()).key, b"Apple");
+        assert!(matches!(
+            wal_iter.next().await,
+            Some(Err(WalReadError::FieldTooLong { len, .. })) if len == 1 << 60
+        ));
+        assert!(wal_iter.next().await.is_none());
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_repair_wal_with_garbage_length_prefix() {
+        let temp_dir = TempDir::new("test_repair_wal_with_garbage_length_prefix").unwrap();
+        let dir = temp_dir.path();
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+        wal.set(b"Apple", b"Apple Smoothie", 1).await.unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", 2).await.unwrap();
+        wal.flush().await.unwrap();
+        let valid_len = metadata(&wal.path).await.unwrap().len();
+
+        // a record whose key length is garbage, followed by more valid records
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&wal.path)
+            .await
+            .unwrap();
+        file.write_all(&[RecordType::Put as u8, Codec::None.tag()])
+            .await
+            .unwrap();
+        file.write_all(&(u64::MAX / 2).to_le_bytes()).await.unwrap();
+        file.write_all(&[42; 5]).await.unwrap();
+        file.flush().await.unwrap();
+        let mut wal = WriteAheadLog::from_path(&wal.path).await.unwrap();
+        wal.set(b"Orange", b"Orange Smoothie", 3).await.unwrap();
+        wal.delete(b"Apple", 4).await.unwrap();
+        wal.flush().await.unwrap();
+        let file_len = metadata(&wal.path).await.unwrap().len();
+
+        let report = WriteAheadLog::repair(&wal.path).await.unwrap();
+        assert_eq!(report.salvaged_records, 4);
+        assert_eq!(report.skipped_bytes, 15);
+        assert_eq!(report.skipped_regions, 1);
+        assert_eq!(
+            report.salvaged_bytes,
+            file_len - WAL_HEADER_SIZE as u64 - 15
+        );
+        assert_eq!(
+            report.repaired_path.file_name().unwrap().to_str().unwrap(),
+            format!(
+                "{}.repaired",
+                wal.path.file_name().unwrap().to_str().unwrap()
+            )
+        );
+
+        let mut reader = open_records(&report.repaired_path).await;
+        check_entry(&mut reader, b"Apple", Some(b"Apple Smoothie"), 1, false).await;
+        check_entry(&mut reader, b"Lime", Some(b"Lime Smoothie"), 2, false).await;
+        check_entry(&mut reader, b"Orange", Some(b"Orange Smoothie"), 3, false).await;
+        check_entry(&mut reader, b"Apple", None, 4, true).await;
+
+        // by default everything after the bad record is dropped
+        tokio::fs::remove_file(&report.repaired_path).await.unwrap();
+        let (_, new_mem_table, _) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_mem_table.len(), 2);
+        assert_eq!(metadata(&wal.path).await.unwrap().len(), valid_len);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_wal_in_repair_mode() {
+        let temp_dir = TempDir::new("test_restore_wal_in_repair_mode").unwrap();
+        let dir = temp_dir.path();
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+        wal.set(b"Apple", b"Apple Smoothie", 1).await.unwrap();
+        let batch = [
+            Entry::new(b"Banana".to_vec(), Some(b"Banana Smoothie".to_vec()), 2),
+            Entry::new(b"Cherry".to_vec(), None, 2),
+        ];
+        wal.append_batch(&batch).await.unwrap();
+        wal.flush().await.unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&wal.path)
+            .await
+            .unwrap();
+        file.write_all(&[7; 20]).await.unwrap();
+        file.flush().await.unwrap();
+        let mut wal = WriteAheadLog::from_path(&wal.path).await.unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", 3).await.unwrap();
+        wal.flush().await.unwrap();
+
+        // the records before the garbage are applied and counted once
+        let (new_wal, new_mem_table, _, report) = WriteAheadLog::restore_from_dir_with_clock(
+            dir,
+            RecoveryMode::Repair,
+            None,
+            CancellationToken::new(),
+            &HybridClock,
+            Arc::new(LocalFs),
+            DEFAULT_MAX_FIELD_LEN,
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_wal.path, wal.path);
+        assert_eq!(new_mem_table.len(), 4);
+        assert!(new_mem_table.get(b"Lime").is_some());
+        assert_eq!((report.records_applied, report.tombstones), (4, 1));
+
+        // the original is kept aside, the repaired file took its place
+        let file_name = wal.path.file_name().unwrap().to_str().unwrap();
+        assert!(dir.join(format!("{}.corrupted", file_name)).exists());
+        assert!(!dir.join(format!("{}.repaired", file_name)).exists());
+        let (_, new_mem_table, _) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_mem_table.len(), 4);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_wal_with_batches() {
+        let temp_dir = TempDir::new("test_restore_wal_with_batches").unwrap();
+        let dir = temp_dir.path();
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+        wal.append_batch(&[
+            Entry::new(b"Apple".to_vec(), Some(b"Apple Smoothie".to_vec()), 1),
+            Entry::new(b"Lime".to_vec(), Some(b"Lime Smoothie".to_vec()), 1),
+        ])
+        .await
+        .unwrap();
+        wal.set(b"Orange", b"Orange Smoothie", 2).await.unwrap();
+        wal.flush().await.unwrap();
+        let valid_len = metadata(&wal.path).await.unwrap().len();
+
+        // a batch cut off before its commit record
+        wal.append_batch(&[
+            Entry::new(
+                b"Strawberry".to_vec(),
+                Some(b"Strawberry Smoothie".to_vec()),
+                3,
+            ),
+            Entry::new(b"Apple".to_vec(), None, 3),
+        ])
+        .await
+        .unwrap();
+        let file_len = metadata(&wal.path).await.unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&wal.path)
+            .await
+            .unwrap()
+            .set_len(file_len - 9)
+            .await
+            .unwrap();
+
+        let (_, new_mem_table, _) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_mem_table.len(), 3);
+        assert!(new_mem_table.get(b"Strawberry").is_none());
+        assert!(!new_mem_table.get(b"Apple").unwrap().is_deleted());
+        assert_eq!(metadata(&wal.path).await.unwrap().len(), valid_len);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_wal_with_unbalanced_batch() {
+        let temp_dir = TempDir::new("test_restore_wal_with_unbalanced_batch").unwrap();
+        let dir = temp_dir.path();
+
+        let mut wal = WriteAheadLog::new(dir).await.unwrap();
+        wal.set(b"Apple", b"Apple Smoothie", 1).await.unwrap();
+        wal.flush().await.unwrap();
+        let valid_len = metadata(&wal.path).await.unwrap().len();
+
+        // a commit claiming more records than its batch holds
+        let mut bytes = Vec::new();
+        encode_batch_marker(&mut bytes, RecordType::BatchBegin, 2);
+        bytes.extend(
+            encode_records(&[Entry::new(b"Lime".to_vec(), None, 2)], Codec::None)
+                .await
+                .unwrap(),
+        );
+        encode_batch_marker(&mut bytes, RecordType::BatchCommit, 2);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&wal.path)
+            .await
+            .unwrap();
+        file.write_all(&bytes).await.unwrap();
+        file.flush().await.unwrap();
+
+        let (_, new_mem_table, _) = WriteAheadLog::restore_from_dir(
+            dir,
+            RecoveryMode::Fail,
+            None,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new_mem_table.len(), 1);
+        assert_eq!(metadata(&wal.path).await.unwrap().len(), valid_len);
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// How many records are replayed between two progress reports within a file.
+const REPORT_EVERY_RECORDS: usize = 4096;
+
+/// How many records are replayed between two progress logs within a file, when nobody listens
+/// to the reports.
+const LOG_EVERY_RECORDS: usize = 32 * REPORT_EVERY_RECORDS;
+
+/// Summary of the WAL replay which restored a database, see
+/// [`Database::recovery_report`](crate::Database::recovery_report).
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RecoveryReport {
+    pub wal_files_replayed: usize,
+    /// Records applied to the MemTable, the writes of a batch each count
+    pub records_applied: usize,
+    /// Records among them which delete their key
+    pub tombstones: usize,
+    /// Bytes of the WAL files read
+    pub bytes_read: u64,
+    /// Bytes of torn or corrupted records cut off the end of the WAL files
+    pub truncated_tail_bytes: u64,
+    pub duration: Duration,
+}
+
+/// Progress of replaying the WAL files while restoring a database.
+#[derive(Debug, Clone)]
+pub struct RestoreProgress {
+    /// The WAL file being replayed.
+    pub file: PathBuf,
+    /// Index of the file among the `total_files` being replayed.
+    pub file_index: usize,
+    pub total_files: usize,
+    /// Bytes of the file processed so far, out of `file_len`.
+    pub bytes_processed: u64,
+    pub file_len: u64,
+    /// Records of the file applied to the MemTable so far.
+    pub records_applied: usize,
+    /// Whether the file has been replayed completely.
+    pub done: bool,
+}
+
+/// Sends [`RestoreProgress`] updates, when anyone is listening.
+pub(super) struct ProgressReporter {
+    sender: Option<mpsc::Sender<RestoreProgress>>,
+    current: Option<RestoreProgress>,
+    next_file_index: usize,
+    total_files: usize,
+}
+
+impl ProgressReporter {
+    pub(super) fn new(sender: Option<mpsc::Sender<RestoreProgress>>, total_files: usize) -> Self {
+        Self {
+            sender,
+            current: None,
+            next_file_index: 0,
+            total_files,
+        }
+    }
+
+    /// Move on to the next WAL file, a repaired file is replayed again under the same index.
+    pub(super) fn next_file(&mut self) {
+        self.next_file_index += 1;
+    }
+
+    pub(super) fn start_file(&mut self, file: &Path, file_len: u64) {
+        self.current = Some(RestoreProgress {
+            file: file.to_owned(),
+            file_index: self.next_file_index,
+            total_files: self.total_files,
+            bytes_processed: 0,
+            file_len,
+            records_applied: 0,
+            done: false,
+        });
+    }
+
+    /// Report every few records, the update is dropped when the receiver lags behind so the
+    /// replay never waits for it. Without a receiver, log every many more records instead.
+    pub(super) fn report(&mut self, bytes_processed: u64, records_applied: usize) {
+        let Some(current) = self.current.as_mut() else {
+            return;
+        };
+        if records_applied == 0 || !records_applied.is_multiple_of(REPORT_EVERY_RECORDS) {
+            return;
+        }
+        current.bytes_processed = bytes_processed;
+        current.records_applied = records_applied;
+        match self.sender.as_ref() {
+            Some(sender) => {
+                let _ = sender.try_send(current.clone());
+            }
+            None if records_applied.is_multiple_of(LOG_EVERY_RECORDS) => tracing::info!(
+                "Restoring wal file {}/{} {:?}: {}/{} bytes, {} records",
+                current.file_index + 1,
+                current.total_files,
+                current.file,
+                current.bytes_processed,
+                current.file_len,
+                current.records_applied
+            ),
+            None => {}
+        }
+    }
+
+    /// Report the end of the file, this one is always delivered unless the receiver is gone.
+    pub(super) async fn finish_file(&mut self, bytes_processed: u64, records_applied: usize) {
+        let (Some(sender), Some(mut current)) = (self.sender.as_ref(), self.current.take()) else {
+            return;
+        };
+        current.bytes_processed = bytes_processed;
+        current.records_applied = records_applied;
+        current.done = true;
+        let _ = sender.send(current).await;
+    }
+}
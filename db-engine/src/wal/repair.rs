@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+use super::{read_record, WALIterator, WriteAheadLog};
+use crate::{
+    entries::DEFAULT_MAX_FIELD_LEN,
+    storage::{self, LocalFs, Storage},
+};
+
+/// What to do when replaying a WAL file hits a bad record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Stop replaying the file at the first bad record and drop everything after it.
+    #[default]
+    Fail,
+    /// Salvage the valid records after the bad one with [`WriteAheadLog::repair`], the
+    /// original file is kept next to it with a `.corrupted` suffix.
+    Repair,
+}
+
+/// Outcome of [`WriteAheadLog::repair`].
+#[derive(Debug, Clone)]
+pub struct RepairReport {
+    /// The file holding the header and every valid record.
+    pub repaired_path: PathBuf,
+    pub salvaged_records: usize,
+    pub salvaged_bytes: u64,
+    pub skipped_bytes: u64,
+    /// Number of separate runs of skipped bytes.
+    pub skipped_regions: usize,
+}
+
+impl WriteAheadLog {
+    /// Scan the WAL file byte by byte and copy every record which decodes cleanly and passes
+    /// its checksum into `<name>.repaired`, skipping whatever lies between them.
+    pub async fn repair(path: &Path) -> Result<RepairReport> {
+        Self::repair_with_storage(path, &LocalFs, DEFAULT_MAX_FIELD_LEN).await
+    }
+
+    /// Like [`WriteAheadLog::repair`], for a file of `storage` whose keys and values are up to
+    /// `max_field_len` long.
+    pub async fn repair_with_storage(
+        path: &Path,
+        storage: &dyn Storage,
+        max_field_len: usize,
+    ) -> Result<RepairReport> {
+        let wal_iter = WALIterator::with_storage(path.to_owned(), storage).await?;
+        let (header_len, version) = (wal_iter.offset() as usize, wal_iter.version());
+        drop(wal_iter);
+
+        let bytes = storage::read(storage, path)
+            .await
+            .with_context(|| format!("read wal file {:?}", path))?;
+        let mut repaired = bytes[..header_len].to_vec();
+        let mut report = RepairReport {
+            repaired_path: with_suffix(path, "repaired"),
+            salvaged_records: 0,
+            salvaged_bytes: 0,
+            skipped_bytes: 0,
+            skipped_regions: 0,
+        };
+
+        let mut pos = header_len;
+        let mut skipping = false;
+        while pos < bytes.len() {
+            let mut input = &bytes[pos..];
+            let remaining = input.len() as u64;
+            match read_record(&mut input, pos as u64, version, remaining, max_field_len).await {
+                Ok(Some((_, len))) => {
+                    repaired.extend_from_slice(&bytes[pos..pos + len]);
+                    report.salvaged_records += 1;
+                    report.salvaged_bytes += len as u64;
+                    pos += len;
+                    skipping = false;
+                }
+                _ => {
+                    if !skipping {
+                        report.skipped_regions += 1;
+                        skipping = true;
+                    }
+                    report.skipped_bytes += 1;
+                    pos += 1;
+                }
+            }
+        }
+
+        storage::write(storage, &report.repaired_path, &repaired)
+            .await
+            .with_context(|| format!("write repaired wal file {:?}", report.repaired_path))?;
+
+        Ok(report)
+    }
+}
+
+/// `path` with `.<suffix>` appended to its file name.
+pub(super) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".");
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
@@ -0,0 +1,55 @@
+use crate::{entries::Entry, DbEntry};
+
+/// A group of writes applied to a [`Database`](crate::Database) with one WAL append.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    /// Without their timestamp, the batch gets one when written
+    operations: Vec<Entry>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a Key-Value pair.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.operations
+            .push(Entry::new(key.to_vec(), Some(value.to_vec()), 0));
+        self
+    }
+
+    /// Queue a Key-Value pair with the content type and the expiry of `entry`, e.g. one read
+    /// from another database. The timestamp of `entry` is not kept.
+    pub fn put(&mut self, entry: &DbEntry) -> &mut Self {
+        let mut operation = Entry::new(entry.key.clone(), None, 0);
+        operation.value = Some(entry.value.clone());
+        operation.content_type = entry.content_type().map(str::to_owned);
+        operation.expires_at = entry.expires_at();
+        self.operations.push(operation);
+        self
+    }
+
+    /// Queue a deletion of the key.
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.operations.push(Entry::new(key.to_vec(), None, 0));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// The operations in the order they were queued, all written at `timestamp`.
+    pub(crate) fn into_entries(self, timestamp: u128) -> Vec<Entry> {
+        let mut entries = self.operations;
+        for entry in entries.iter_mut() {
+            entry.timestamp = timestamp;
+        }
+        entries
+    }
+}
@@ -0,0 +1,247 @@
+//! Crashes injected by `db_engine::FaultyFs` at every write of a flush or a compaction, the
+//! database recovers every acknowledged write on the next open.
+use anyhow::Result;
+use db_engine::{Database, DatabaseBuilder, FaultyFs, SyncPolicy};
+use std::{collections::BTreeMap, fs, path::Path, sync::Arc};
+use tempdir::TempDir;
+use tokio::fs::create_dir_all;
+
+/// Open the database of `dir` on `storage` with every write synced, as a crash test needs
+async fn open_synced(dir: &Path, storage: &FaultyFs) -> Result<Database> {
+    DatabaseBuilder::new(dir.to_path_buf())
+        .sync_policy(SyncPolicy::Always)
+        .with_storage(Arc::new(storage.clone()))
+        .build()
+        .await
+}
+
+/// Reopen the database of `dir` after a crash, every key of `expected` reads its value
+async fn assert_recovered(dir: &Path, expected: &BTreeMap<Vec<u8>, Vec<u8>>) -> Result<()> {
+    let db = DatabaseBuilder::new(dir.to_path_buf()).build().await?;
+    for (key, value) in expected {
+        assert_eq!(db.get(key).await.unwrap().value, &value[..], "{:?}", dir);
+    }
+    assert_eq!(db.scan(..).await?.len(), expected.len());
+    assert!(db.verify().await?.corruptions.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_recovers_from_a_crash_at_any_write_of_a_flush() -> Result<()> {
+    let tmpdir = TempDir::new("flush_crash")?;
+    let expected: BTreeMap<_, _> = (0..50u32)
+        .map(|i| (format!("key{:02}", i).into_bytes(), vec![i as u8; 1000]))
+        .collect();
+    for n in 1.. {
+        let dir = tmpdir.path().join(n.to_string());
+        create_dir_all(&dir).await?;
+        let storage = FaultyFs::new();
+        let mut db = open_synced(&dir, &storage).await?;
+        for (key, value) in expected.iter() {
+            db.set(key, value).await?;
+        }
+        storage.fail_write(n);
+        let flushed = db.flush().await;
+        drop(db);
+
+        assert_recovered(&dir, &expected).await?;
+        if !storage.crashed() {
+            assert!(flushed.is_ok());
+            break;
+        }
+    }
+
+    tmpdir.close()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_recovers_from_a_crash_at_any_write_of_a_compaction() -> Result<()> {
+    let tmpdir = TempDir::new("compaction_crash")?;
+    for n in 1.. {
+        let dir = tmpdir.path().join(n.to_string());
+        create_dir_all(&dir).await?;
+        let storage = FaultyFs::new();
+        let mut db = open_synced(&dir, &storage).await?;
+        let mut expected = BTreeMap::new();
+        for round in 0..3u8 {
+            for i in (round * 10..60).step_by(3) {
+                let key = format!("key{:02}", i).into_bytes();
+                db.set(&key, &[round; 1000]).await?;
+                expected.insert(key, vec![round; 1000]);
+            }
+            db.flush().await?;
+        }
+        storage.fail_write(n);
+        let compacted = db.compact(u64::MAX).await;
+        drop(db);
+
+        assert_recovered(&dir, &expected).await?;
+        if !storage.crashed() {
+            assert_eq!(compacted?.input_files, 3);
+            break;
+        }
+    }
+
+    tmpdir.close()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_recovers_from_a_crash_between_the_wal_and_the_mem_table() -> Result<()> {
+    let tmpdir = TempDir::new("wal_crash")?;
+    let dir = tmpdir.path().to_path_buf();
+    let storage = FaultyFs::new();
+    let mut db = open_synced(&dir, &storage).await?;
+    let mut expected = BTreeMap::new();
+    for i in 0..20u32 {
+        let key = format!("key{:02}", i).into_bytes();
+        db.set(&key, b"value").await?;
+        expected.insert(key, b"value".to_vec());
+    }
+    db.flush().await?;
+    for i in (0..20u32).step_by(4) {
+        let key = format!("key{:02}", i).into_bytes();
+        db.delete(&key).await?;
+        expected.remove(&key);
+    }
+    db.set(b"key01", b"value2").await?;
+    expected.insert(b"key01".to_vec(), b"value2".to_vec());
+
+    // the record is in the WAL file, not synced, and the process dies before the MemTable
+    storage.crash_after_write(1);
+    assert!(db.set(b"key02", b"unacknowledged").await.is_err());
+    assert!(storage.crashed());
+    assert_eq!(db.get(b"key02").await.unwrap().value, &b"value"[..]);
+    drop(db);
+
+    // an unacknowledged write may or may not be replayed, never anything else
+    let replayed = DatabaseBuilder::new(dir.clone())
+        .build()
+        .await?
+        .get(b"key02")
+        .await
+        .unwrap()
+        .value;
+    assert!(replayed == b"value"[..] || replayed == b"unacknowledged"[..]);
+    expected.insert(b"key02".to_vec(), replayed.to_vec());
+    assert_recovered(&dir, &expected).await?;
+
+    tmpdir.close()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_recovers_from_a_crash_before_a_background_flush_removes_the_wal() -> Result<()> {
+    let tmpdir = TempDir::new("background_flush_crash")?;
+    let dir = tmpdir.path().to_path_buf();
+    let storage = FaultyFs::new();
+    let mut db = DatabaseBuilder::new(dir.clone())
+        .sync_policy(SyncPolicy::Always)
+        .max_mem_table_size(8 * 1024)
+        .with_storage(Arc::new(storage.clone()))
+        .build()
+        .await?;
+    let mut expected = BTreeMap::new();
+    for i in 0..40u8 {
+        let key = format!("key{:02}", i).into_bytes();
+        db.set(&key, &[i; 100]).await?;
+        expected.insert(key, vec![i; 100]);
+    }
+    db.flush().await?;
+    let sstables = level_0_sstables(&dir)?;
+    storage.fail_remove("wal");
+
+    // deletes and overwrites of the flushed keys, until a MemTable flushed in the
+    // background fails to remove its WAL files
+    let mut in_doubt = None;
+    for i in 40..1000u32 {
+        let key = format!("key{:02}", i % 40).into_bytes();
+        let value = vec![i as u8; 100];
+        let (written, value) = match i % 3 {
+            0 => (db.delete(&key).await.map(drop), None),
+            _ => (db.set(&key, &value).await.map(drop), Some(value)),
+        };
+        if written.is_err() {
+            in_doubt = Some((key, value));
+            break;
+        }
+        match value {
+            Some(value) => expected.insert(key, value),
+            None => expected.remove(&key),
+        };
+    }
+    assert!(storage.crashed());
+    // the SSTable of the flush made it, the WAL files backing it were left
+    assert!(db.flush().await.is_err());
+    assert_eq!(level_0_sstables(&dir)?, sstables + 1);
+    drop(db);
+
+    // the write which saw the error was not acknowledged, it may or may not have made it
+    let (key, value) = in_doubt.unwrap();
+    let recovered = DatabaseBuilder::new(dir.clone())
+        .build()
+        .await?
+        .get(&key)
+        .await
+        .map(|entry| entry.value.to_vec());
+    assert!(recovered.as_ref() == expected.get(&key) || recovered == value);
+    match recovered {
+        Some(recovered) => expected.insert(key, recovered),
+        None => expected.remove(&key),
+    };
+    assert_recovered(&dir, &expected).await?;
+
+    tmpdir.close()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_recovers_from_a_crash_before_a_compaction_removes_its_inputs() -> Result<()> {
+    let tmpdir = TempDir::new("compaction_removal_crash")?;
+    let dir = tmpdir.path().to_path_buf();
+    let storage = FaultyFs::new();
+    let mut db = open_synced(&dir, &storage).await?;
+    let mut expected = BTreeMap::new();
+    for round in 0..3u8 {
+        for i in (round * 10..60).step_by(3) {
+            let key = format!("key{:02}", i).into_bytes();
+            db.set(&key, &[round; 100]).await?;
+            expected.insert(key, vec![round; 100]);
+        }
+        db.flush().await?;
+    }
+    // the full compaction drops their tombstones, the older SSTables still hold the values
+    for i in (0..60u8).step_by(9) {
+        let key = format!("key{:02}", i).into_bytes();
+        db.delete(&key).await?;
+        expected.remove(&key);
+    }
+    db.flush().await?;
+
+    storage.fail_remove("db");
+    let _ = db.compact(u64::MAX).await;
+    assert!(storage.crashed());
+    drop(db);
+
+    assert_recovered(&dir, &expected).await?;
+
+    tmpdir.close()?;
+    Ok(())
+}
+
+/// The SSTables of level 0, at the root of `dir` and in its `L0` directory
+fn level_0_sstables(dir: &Path) -> Result<usize> {
+    let mut count = 0;
+    for dir in [dir.to_path_buf(), dir.join("L0")] {
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(dir)? {
+            if entry?.path().extension().is_some_and(|ext| ext == "db") {
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
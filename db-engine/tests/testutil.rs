@@ -0,0 +1,68 @@
+//! The engine through its public API, on the data of `db_engine::testutil`.
+use anyhow::Result;
+use db_engine::{testutil, Compaction, DatabaseBuilder, SSTableReader};
+use tempdir::TempDir;
+
+const KEYS: u64 = 1_000;
+const VALUE_LEN: usize = 100;
+
+#[tokio::test]
+async fn it_reads_back_the_populated_keys_after_a_restart() -> Result<()> {
+    for flush in [false, true] {
+        let tmpdir = TempDir::new("testutil_populated_db")?;
+        let db = testutil::populated_db(tmpdir.path(), KEYS, VALUE_LEN, flush).await?;
+        drop(db);
+
+        let db = DatabaseBuilder::new(tmpdir.path().to_path_buf())
+            .build()
+            .await?;
+        let replayed = match flush {
+            true => 0,
+            false => KEYS as usize,
+        };
+        assert_eq!(db.recovery_report().records_applied, replayed);
+        for i in testutil::shuffled(KEYS, 1) {
+            let entry = db.get(&testutil::key(i)).await.expect("populated key");
+            assert_eq!(entry.value, testutil::value(i, VALUE_LEN));
+        }
+        assert!(db.get(&testutil::key(KEYS)).await.is_none());
+
+        drop(db);
+        tmpdir.close()?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_compacts_the_written_sstables_into_the_newest_versions() -> Result<()> {
+    let tmpdir = TempDir::new("testutil_compaction")?;
+    let dir = tmpdir.path();
+    // the second file overwrites the upper half of the keys of the first one
+    testutil::write_sstable(&dir.join("1.db"), testutil::entries(0..KEYS, VALUE_LEN, 1)).await?;
+    testutil::write_sstable(
+        &dir.join("2.db"),
+        testutil::entries(KEYS / 2..KEYS * 3 / 2, VALUE_LEN, 2),
+    )
+    .await?;
+
+    let report = Compaction::new(dir.to_path_buf(), u64::MAX, "db")
+        .compact()
+        .await?;
+    assert_eq!(report.input_files, 2);
+    assert_eq!(report.entries_written, KEYS * 3 / 2);
+    assert_eq!(report.duplicates_skipped, KEYS / 2);
+
+    let reader = SSTableReader::new(&report.output_paths[0]).await?;
+    for i in [0, KEYS / 2, KEYS * 3 / 2 - 1] {
+        let entry = reader.get(&testutil::key(i)).await.expect("compacted key");
+        let timestamp = match i < KEYS / 2 {
+            true => 1,
+            false => 2,
+        };
+        assert_eq!(entry.timestamp, timestamp);
+        assert_eq!(entry.value.unwrap(), testutil::value(i, VALUE_LEN));
+    }
+
+    tmpdir.close()?;
+    Ok(())
+}
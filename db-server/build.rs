@@ -0,0 +1,40 @@
+//! Generate the tonic service of `proto/kv.proto`. There is no `protoc` to compile the file
+//! with, so its service is restated here and its messages are written by hand in
+//! `src/grpc/kv.rs`: a change to the file has to be made in both places.
+use tonic_build::manual::{Builder, Method, Service};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=proto/kv.proto");
+
+    let method = |name: &str, route_name: &str, input_type: &str, output_type: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(format!("super::{}", input_type))
+            .output_type(format!("super::{}", output_type))
+            .codec_path("tonic::codec::ProstCodec")
+    };
+    let kv = Service::builder()
+        .name("Kv")
+        .package("kv")
+        .method(method("get", "Get", "GetRequest", "Entry").build())
+        .method(method("set", "Set", "SetRequest", "SetResponse").build())
+        .method(method("delete", "Delete", "DeleteRequest", "DeleteResponse").build())
+        .method(
+            method(
+                "batch_write",
+                "BatchWrite",
+                "BatchWriteRequest",
+                "BatchWriteResponse",
+            )
+            .build(),
+        )
+        .method(
+            method("scan", "Scan", "ScanRequest", "Entry")
+                .server_streaming()
+                .build(),
+        )
+        .build();
+    Builder::new().compile(&[kv]);
+}
@@ -1,29 +1,277 @@
+use std::time::Duration;
+
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
+    Json,
 };
+use db_engine::Error;
+use tonic::{Code, Status};
+
+use crate::{handlers::prelude::ErrorResponse, timeout::TimedOut};
 
-// Make our own error that wraps `anyhow::Error`.
-pub struct AppError(anyhow::Error);
+// Make our own error, the unexpected failures wrap `anyhow::Error`.
+pub enum AppError {
+    /// The key of the request holds no value
+    NotFound(String),
+    /// The namespace of the request was never created, see [`crate::namespaces`]
+    NamespaceNotFound(String),
+    /// A parameter of the request cannot be used, with the reason
+    BadRequest(String),
+    /// A parameter of the request is well formed but out of range, with the reason
+    UnprocessableEntity(String),
+    /// The body of the request is in an encoding or format the route does not read
+    UnsupportedMediaType(String),
+    /// The `If-Match` header of a write does not match the entry of the key anymore
+    PreconditionFailed(String),
+    /// Another process holds what the request needs, e.g. the directory of a namespace
+    Conflict {
+        error: &'static str,
+        message: String,
+    },
+    /// A key or value longer than the database accepts
+    TooLarge(String),
+    /// The database cannot take the request, e.g. it is over its disk budget, a compaction is
+    /// already running or it is a read-only replica
+    Unavailable {
+        error: &'static str,
+        message: String,
+    },
+    /// The database did not answer within the request timeout, see [`crate::timeout`]
+    Timeout,
+    /// The client is over its rate limit, see [`crate::rate_limit`]
+    TooManyRequests { retry_after: Duration },
+    /// Anything else, e.g. a corrupt file. Only logged, the client gets a generic message.
+    Internal(anyhow::Error),
+}
+
+impl AppError {
+    /// The status, the machine-readable `error` code and the message of the response
+    fn parts(self) -> (StatusCode, &'static str, String) {
+        match self {
+            Self::NotFound(key) => (
+                StatusCode::NOT_FOUND,
+                "key_not_found",
+                format!("Key `{}` not found.", key),
+            ),
+            Self::NamespaceNotFound(namespace) => (
+                StatusCode::NOT_FOUND,
+                "namespace_not_found",
+                format!("Namespace `{}` not found.", namespace),
+            ),
+            Self::BadRequest(message) => (StatusCode::BAD_REQUEST, "bad_request", message),
+            Self::UnprocessableEntity(message) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "unprocessable_entity",
+                message,
+            ),
+            Self::UnsupportedMediaType(message) => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                message,
+            ),
+            Self::PreconditionFailed(key) => (
+                StatusCode::PRECONDITION_FAILED,
+                "precondition_failed",
+                format!("Key `{}` was written since.", key),
+            ),
+            Self::Conflict { error, message } => (StatusCode::CONFLICT, error, message),
+            Self::TooLarge(message) => (StatusCode::PAYLOAD_TOO_LARGE, "too_large", message),
+            Self::Unavailable { error, message } => {
+                (StatusCode::SERVICE_UNAVAILABLE, error, message)
+            }
+            Self::Timeout => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "timeout",
+                String::from("The database took too long to answer, try again later."),
+            ),
+            Self::TooManyRequests { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                String::from("Too many requests, try again later."),
+            ),
+            Self::Internal(err) => {
+                tracing::error!("Request failed: {:#}", err);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal_error",
+                    String::from("Something went wrong."),
+                )
+            }
+        }
+    }
+}
 
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        let retry_after = match &self {
+            // in whole seconds, rounded up so the client does not come back too early
+            Self::TooManyRequests { retry_after } => {
+                Some(retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0))
+            }
+            _ => None,
+        };
+        let timed_out = matches!(self, Self::Timeout);
+        let (status, error, message) = self.parts();
+        let mut response = (status, Json(ErrorResponse::new(error, message))).into_response();
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.into());
+        }
+        if timed_out {
+            response.extensions_mut().insert(TimedOut);
+        }
+        response
+    }
+}
+
+// Tell tonic how to convert `AppError` into the status of a gRPC call, see [`crate::grpc`]. The
+// message is the one of the HTTP response.
+impl From<AppError> for Status {
+    fn from(err: AppError) -> Self {
+        let code = match &err {
+            AppError::NotFound(_) | AppError::NamespaceNotFound(_) => Code::NotFound,
+            AppError::BadRequest(_)
+            | AppError::UnprocessableEntity(_)
+            | AppError::UnsupportedMediaType(_) => Code::InvalidArgument,
+            AppError::PreconditionFailed(_) => Code::FailedPrecondition,
+            AppError::Conflict { .. } => Code::Aborted,
+            AppError::TooLarge(_)
+            | AppError::TooManyRequests { .. }
+            | AppError::Unavailable {
+                error: "disk_budget_exceeded",
+                ..
+            } => Code::ResourceExhausted,
+            AppError::Unavailable { .. } | AppError::Timeout => Code::Unavailable,
+            AppError::Internal(_) => Code::Internal,
+        };
+        let (_, _, message) = err.parts();
+        Status::new(code, message)
     }
 }
 
 // This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
-// `Result<_, AppError>`. That way you don't need to do that manually.
+// `Result<_, AppError>`. That way you don't need to do that manually. The errors of the engine
+// the client can do something about get their own status, the others are internal.
 impl<E> From<E> for AppError
 where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        let err = err.into();
+        match err.downcast_ref::<Error>() {
+            Some(Error::CompactionInProgress(_)) => Self::Unavailable {
+                error: "compaction_in_progress",
+                message: String::from("A compaction is already running, try again later."),
+            },
+            Some(Error::DirectoryLocked(_)) => Self::Conflict {
+                error: "directory_locked",
+                message: String::from("Another process holds the database."),
+            },
+            Some(Error::DiskBudgetExceeded { .. }) => Self::Unavailable {
+                error: "disk_budget_exceeded",
+                message: String::from("The database is out of disk space, try again later."),
+            },
+            Some(Error::WriteStalled { .. }) => Self::Unavailable {
+                error: "write_stalled",
+                message: String::from(
+                    "The database is catching up on its writes, try again later.",
+                ),
+            },
+            Some(e @ Error::FieldTooLong { .. }) => Self::TooLarge(format!("{}.", e)),
+            Some(Error::ReadOnlyReplica) => Self::Unavailable {
+                error: "read_only_replica",
+                message: String::from("This server is a read-only replica, write to its primary."),
+            },
+            _ => Self::Internal(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use anyhow::{anyhow, Result};
+
+    use super::*;
+
+    async fn body(response: Response) -> Result<serde_json::Value> {
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    #[tokio::test]
+    async fn it_does_not_leak_the_internal_errors() -> Result<()> {
+        let err = anyhow!("secret detail").context("read sstable");
+        let response = AppError::from(err).into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = body(response).await?;
+        assert_eq!(body["error"], "internal_error");
+        assert!(!body.to_string().contains("secret"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_maps_the_engine_errors_to_a_status() -> Result<()> {
+        let cases = [
+            (
+                Error::CompactionInProgress(PathBuf::from("db")),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "compaction_in_progress",
+            ),
+            (
+                Error::DirectoryLocked(PathBuf::from("secret")),
+                StatusCode::CONFLICT,
+                "directory_locked",
+            ),
+            (
+                Error::DiskBudgetExceeded { used: 2, limit: 1 },
+                StatusCode::SERVICE_UNAVAILABLE,
+                "disk_budget_exceeded",
+            ),
+            (
+                Error::WriteStalled {
+                    level0_sstables: 2,
+                    pending_mem_tables: 1,
+                },
+                StatusCode::SERVICE_UNAVAILABLE,
+                "write_stalled",
+            ),
+            (
+                Error::FieldTooLong {
+                    field: "key",
+                    len: 2,
+                    max: 1,
+                },
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "too_large",
+            ),
+            (
+                Error::ReadOnlyReplica,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "read_only_replica",
+            ),
+            (
+                Error::Corruption {
+                    path: PathBuf::from("secret.db"),
+                    offset: 0,
+                    reason: String::from("checksum"),
+                },
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+            ),
+        ];
+        for (err, status, error) in cases {
+            // as the engine returns them, wrapped in some context
+            let response = AppError::from(anyhow::Error::from(err).context("set")).into_response();
+            assert_eq!(response.status(), status);
+            let body = body(response).await?;
+            assert_eq!(body["error"], error);
+            assert!(!body.to_string().contains("secret"));
+        }
+        Ok(())
     }
 }
@@ -1,28 +1,134 @@
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use axum::Router;
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use rustls_pemfile::Item;
 use tokio::signal;
+use tokio_util::sync::CancellationToken;
 
 pub struct AppServer {
     router: Router,
     socket_address: SocketAddr,
+    shutdown: Option<CancellationToken>,
+    tls: Option<TlsFiles>,
+}
+
+/// The PEM files of the certificate chain and of its private key
+#[derive(Clone)]
+struct TlsFiles {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl TlsFiles {
+    /// Read the certificates and the key, failing on a file without any
+    async fn load(&self) -> Result<RustlsConfig> {
+        let certs = read_pem(&self.cert_path)
+            .await?
+            .into_iter()
+            .filter_map(|item| match item {
+                Item::X509Certificate(cert) => Some(cert),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if certs.is_empty() {
+            bail!("no certificate in {:?}", self.cert_path);
+        }
+        let key = read_pem(&self.key_path)
+            .await?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .with_context(|| format!("no private key in {:?}", self.key_path))?;
+        Ok(RustlsConfig::from_der(certs, key).await?)
+    }
+}
+
+async fn read_pem(path: &Path) -> Result<Vec<Item>> {
+    let content = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("read {:?}", path))?;
+    rustls_pemfile::read_all(&mut content.as_slice()).with_context(|| format!("parse {:?}", path))
 }
 
 impl AppServer {
+    /// Serve until the shutdown, over TLS when configured. Fails at once when the certificate
+    /// or the key cannot be loaded.
     pub async fn start(self) -> Result<()> {
-        tracing::info!("Listening on {}", self.socket_address);
+        let shutdown = async move {
+            match self.shutdown {
+                Some(shutdown) => shutdown.cancelled_owned().await,
+                None => shutdown_signal().await,
+            }
+        };
+        let make_service = self
+            .router
+            .into_make_service_with_connect_info::<SocketAddr>();
 
-        axum::Server::bind(&self.socket_address)
-            .serve(
-                self.router
-                    .into_make_service_with_connect_info::<SocketAddr>(),
+        let Some(tls) = self.tls else {
+            tracing::info!("Listening on {}", self.socket_address);
+            axum::Server::bind(&self.socket_address)
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown)
+                .await?;
+            return Ok(());
+        };
+
+        let tls_config = tls.load().await.with_context(|| {
+            format!(
+                "load TLS certificate {:?} and key {:?}",
+                tls.cert_path, tls.key_path
             )
-            .with_graceful_shutdown(shutdown_signal())
-            .await?;
+        })?;
+        tracing::info!("Listening on {} over TLS", self.socket_address);
+        let reload = tokio::spawn(reload_on_sighup(tls_config.clone(), tls));
+        let handle = Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                shutdown.await;
+                // stop accepting, then wait for the connections to close
+                handle.graceful_shutdown(None);
+            }
+        });
+        let result = axum_server::bind_rustls(self.socket_address, tls_config)
+            .handle(handle)
+            .serve(make_service)
+            .await;
+        reload.abort();
+        Ok(result?)
+    }
+}
 
-        Ok(())
+/// Read the certificate and the key again on every SIGHUP, for the new connections. Keeps
+/// the previous ones when the files cannot be loaded.
+async fn reload_on_sighup(tls_config: RustlsConfig, tls: TlsFiles) {
+    #[cfg(unix)]
+    {
+        let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+            .expect("failed to install SIGHUP handler");
+        while hangup.recv().await.is_some() {
+            match tls.load().await {
+                Ok(reloaded) => {
+                    tls_config.reload_from_config(reloaded.get_inner());
+                    tracing::info!("Reloaded TLS certificate {:?}", tls.cert_path);
+                }
+                Err(e) => tracing::error!(
+                    "Keep the previous TLS certificate, cannot reload {:?}: {:#}",
+                    tls.cert_path,
+                    e
+                ),
+            }
+        }
     }
+    #[cfg(not(unix))]
+    let _ = (tls_config, tls);
 }
 
 pub struct AppServerBuilder(AppServer);
@@ -33,6 +139,8 @@ impl AppServerBuilder {
         let app_server = AppServer {
             router,
             socket_address: default_socket_address,
+            shutdown: None,
+            tls: None,
         };
         Self(app_server)
     }
@@ -42,12 +150,29 @@ impl AppServerBuilder {
         self
     }
 
+    /// Stop accepting connections once `shutdown` is cancelled, rather than on the signals of
+    /// [`shutdown_signal`], then finish the requests in flight.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.0.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Serve HTTPS with the PEM certificate chain in `cert_path` and the private key in
+    /// `key_path`, reloaded on SIGHUP, rather than plain HTTP.
+    pub fn with_tls(mut self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        self.0.tls = Some(TlsFiles {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        });
+        self
+    }
+
     pub fn build(self) -> AppServer {
         self.0
     }
 }
 
-async fn shutdown_signal() {
+pub async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -74,3 +199,104 @@ async fn shutdown_signal() {
 
     tracing::info!("signal received, starting graceful shutdown");
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::Arc, time::Duration};
+
+    use anyhow::Result;
+    use tempdir::TempDir;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use tokio_rustls::{
+        rustls::{self, ClientConfig, RootCertStore, ServerName},
+        TlsConnector,
+    };
+
+    use super::*;
+    use crate::{handlers::test_client::test_state, router};
+
+    #[tokio::test]
+    async fn it_fails_fast_on_an_invalid_certificate() -> Result<()> {
+        let tmpdir = TempDir::new("app_server_tls_invalid")?;
+        let (cert_path, key_path) = (
+            tmpdir.path().join("cert.pem"),
+            tmpdir.path().join("key.pem"),
+        );
+        let certificate = rcgen::generate_simple_self_signed(vec![String::from("localhost")])?;
+        std::fs::write(&cert_path, "not a certificate")?;
+        for key in [
+            String::from("not a key"),
+            certificate.serialize_private_key_pem(),
+        ] {
+            std::fs::write(&key_path, key)?;
+            let server = AppServerBuilder::new(Router::new())
+                .with_socket_address(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .with_tls(&cert_path, &key_path)
+                .with_shutdown(CancellationToken::new())
+                .build();
+
+            let err = tokio::time::timeout(Duration::from_secs(1), server.start())
+                .await?
+                .unwrap_err();
+            let err = format!("{:#}", err);
+            assert!(err.contains("load TLS certificate"));
+            assert!(err.contains("no certificate"));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_serves_https_until_the_shutdown() -> Result<()> {
+        let tmpdir = TempDir::new("app_server_tls")?;
+        let certificate = rcgen::generate_simple_self_signed(vec![String::from("localhost")])?;
+        let (cert_path, key_path) = (
+            tmpdir.path().join("cert.pem"),
+            tmpdir.path().join("key.pem"),
+        );
+        std::fs::write(&cert_path, certificate.serialize_pem()?)?;
+        std::fs::write(&key_path, certificate.serialize_private_key_pem())?;
+
+        let socket_address = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let shutdown = CancellationToken::new();
+        let server = AppServerBuilder::new(router::create(test_state(tmpdir.path()).await?))
+            .with_socket_address(socket_address)
+            .with_tls(&cert_path, &key_path)
+            .with_shutdown(shutdown.clone())
+            .build();
+        let server = tokio::spawn(server.start());
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&rustls::Certificate(certificate.serialize_der()?))?;
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let tcp = loop {
+            match TcpStream::connect(socket_address).await {
+                Ok(tcp) => break tcp,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let mut tls = connector
+            .connect(ServerName::try_from("localhost")?, tcp)
+            .await?;
+        tls.write_all(
+            b"GET /api/entry/missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+        let mut response = String::new();
+        tls.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 404"));
+        assert!(response.contains("key_not_found"));
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), server).await???;
+        tmpdir.close()?;
+        Ok(())
+    }
+}
@@ -1,22 +1,109 @@
 use anyhow::{Context, Result};
-use std::{fs::create_dir_all, path::PathBuf, sync::Arc};
-use tokio::sync::Mutex;
+use std::{
+    fs::create_dir_all,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tower_http::cors::CorsLayer;
 
-use db_engine::{Database, DatabaseBuilder};
+use db_engine::{DatabaseBuilder, RestoreProgress};
+
+use crate::{
+    config::Config,
+    cors::cors_layer,
+    db_handle::DbHandle,
+    namespaces::Namespaces,
+    rate_limit::{RateLimiter, RateLimits},
+    scheduler::SchedulerHandle,
+    timeout::ErrorCounts,
+};
 
 #[derive(Clone)]
 pub struct AppState {
-    pub db: Arc<Mutex<Database>>,
+    /// The database of the routes without a namespace
+    pub db: DbHandle,
+    pub namespaces: Namespaces,
+    pub rate_limits: RateLimits,
+    /// Size from which the responses are compressed, `None` not to compress, see
+    /// [`Config::compression_enabled`]
+    pub compress_from: Option<u16>,
+    /// `None` for no CORS headers, see [`Config::cors_allowed_origins`]
+    pub cors: Option<CorsLayer>,
+    /// See [`Config::max_value_size`]
+    pub max_value_size: usize,
+    /// See [`Config::request_timeout_ms`]
+    pub request_timeout: Duration,
+    /// The timeouts and the other 5xx of the API routes
+    pub error_counts: ErrorCounts,
+    /// Cancelled on the shutdown of the server, ends the streams of the watchers
+    pub shutdown: CancellationToken,
+}
+
+/// The state of the admin routes, see [`router::create_admin`](crate::router::create_admin).
+#[derive(Clone)]
+pub struct AdminState {
+    pub db: DbHandle,
+    pub namespaces: Namespaces,
+    pub scheduler: SchedulerHandle,
+    pub rate_limit: RateLimiter,
+    /// See [`Config::max_value_size`]
+    pub max_value_size: usize,
+    /// When the server started, for its uptime
+    pub started_at: Instant,
+    /// The ones of the API routes, see [`AppState::error_counts`]
+    pub error_counts: ErrorCounts,
+    /// Cancelled on the shutdown of the server, ends the streams of the replicas
+    pub shutdown: CancellationToken,
 }
 
 impl AppState {
-    pub async fn new() -> Result<Self> {
-        let db_dir_path = PathBuf::from("./db");
+    /// Restore the database of the `config`, giving up on it once `shutdown` is cancelled.
+    pub async fn new(config: &Config, shutdown: CancellationToken) -> Result<Self> {
+        let cors = cors_layer(config).context("configure CORS")?;
+        let db_dir_path = config.data_dir.clone();
         create_dir_all(&db_dir_path).context("create db dir")?;
+        let namespaces = Namespaces::new(config.namespaces_dir(), config.max_mem_table_size);
 
-        let db_engine = DatabaseBuilder::new(db_dir_path).await?.build();
-        let db = Arc::new(Mutex::new(db_engine));
+        // log the WAL replay and give up on it when asked to shut down meanwhile
+        let (progress, progress_receiver) = mpsc::channel(16);
+        tokio::spawn(log_restore_progress(progress_receiver));
+        let db_engine = DatabaseBuilder::new_with_progress(db_dir_path, progress, shutdown.clone())
+            .max_mem_table_size(config.max_mem_table_size)
+            .enable_metrics(true)
+            .replica(config.replicate_from.is_some())
+            .build()
+            .await
+            .context("restore database")?;
+        tracing::info!("Restored database: {:?}", db_engine.recovery_report());
+        let db = DbHandle::new(db_engine);
+
+        Ok(Self {
+            db,
+            namespaces,
+            rate_limits: RateLimits::from(config),
+            compress_from: config
+                .compression_enabled
+                .then_some(config.compression_min_size),
+            cors,
+            max_value_size: config.max_value_size,
+            request_timeout: config.request_timeout(),
+            error_counts: ErrorCounts::default(),
+            shutdown,
+        })
+    }
+}
 
-        Ok(Self { db })
+async fn log_restore_progress(mut receiver: mpsc::Receiver<RestoreProgress>) {
+    while let Some(progress) = receiver.recv().await {
+        tracing::info!(
+            "Restoring wal file {}/{} {:?}: {}/{} bytes, {} records",
+            progress.file_index + 1,
+            progress.total_files,
+            progress.file,
+            progress.bytes_processed,
+            progress.file_len,
+            progress.records_applied
+        );
     }
 }
@@ -0,0 +1,447 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::Uri;
+use clap::Parser;
+use serde::Deserialize;
+
+#[derive(Debug, Parser)]
+#[command(about = "A simple key-value database server")]
+struct Args {
+    /// TOML file of the settings, overridden by the environment variables
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Run as a read-only replica of the primary with this admin address, overrides
+    /// `REPLICATE_FROM`
+    #[arg(long, value_name = "URL")]
+    replicate_from: Option<String>,
+}
+
+/// The format of the logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Json,
+    Pretty,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "pretty" => Ok(Self::Pretty),
+            _ => Err(format!(
+                "unknown log format {:?}, expected json or pretty",
+                s
+            )),
+        }
+    }
+}
+
+/// The settings of the server, from the `--config` file and the environment variables.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address of the API, `BIND_ADDR`
+    pub bind_addr: SocketAddr,
+    /// Address of the admin routes, `ADMIN_BIND_ADDR`, on the loopback interface by default
+    pub admin_bind_addr: SocketAddr,
+    /// Address of the Redis protocol listener, `RESP_BIND_ADDR`, none by default
+    pub resp_bind_addr: Option<SocketAddr>,
+    /// Address of the gRPC listener, `GRPC_BIND_ADDR`, none by default
+    pub grpc_bind_addr: Option<SocketAddr>,
+    /// PEM certificate chain to serve HTTPS with, along with `tls_key_path`, `TLS_CERT_PATH`
+    pub tls_cert_path: Option<PathBuf>,
+    /// PEM private key of `tls_cert_path`, `TLS_KEY_PATH`
+    pub tls_key_path: Option<PathBuf>,
+    /// Directory of the database, shared by the API and the compactions, `DATA_DIR`
+    pub data_dir: PathBuf,
+    /// Directory of the namespaces, a database in a directory per namespace, `NAMESPACES_DIR`,
+    /// `namespaces` in `data_dir` by default
+    pub namespaces_dir: Option<PathBuf>,
+    /// `MAX_MEM_TABLE_SIZE`
+    pub max_mem_table_size: usize,
+    /// Longest value in bytes the API writes and the imports accept, `MAX_VALUE_SIZE`
+    pub max_value_size: usize,
+    /// Whether the scheduler compacts on its own, the admin route still does when disabled,
+    /// `COMPACTION_ENABLED`
+    pub compaction_enabled: bool,
+    /// Seconds between two compactions, `COMPACTION_INTERVAL_SECS`
+    pub compaction_interval_secs: u64,
+    /// Up to that many seconds added at random to each interval, so that the servers sharing a
+    /// disk do not compact in step, `COMPACTION_JITTER_SECS`
+    pub compaction_jitter_secs: u64,
+    /// Size under which the SSTables get compacted, `COMPACTION_LIMIT`
+    pub compaction_limit: u64,
+    /// Bytes per second the compactions may read and write, 0 for no limit,
+    /// `COMPACTION_THROTTLE_BYTES_PER_SEC`
+    pub compaction_throttle_bytes_per_sec: u64,
+    /// Age in seconds past which the compactions drop an entry, `COMPACTION_MAX_AGE_SECS`
+    pub compaction_max_age_secs: Option<u64>,
+    /// Seconds between two housekeeping passes of the scheduler, which remove the files a crash
+    /// left behind and the old archived WAL files, 0 for none, `HOUSEKEEPING_INTERVAL_SECS`
+    pub housekeeping_interval_secs: u64,
+    /// Age in seconds past which the housekeeping removes an archived WAL file, even if a CDC
+    /// stream has not replayed it yet, `WAL_ARCHIVE_MAX_AGE_SECS`
+    pub wal_archive_max_age_secs: u64,
+    /// Whether the requests of a client IP are limited, `RATE_LIMIT_ENABLED`
+    pub rate_limit_enabled: bool,
+    /// Reads a second per client, 0 for no limit, `RATE_LIMIT_READS_PER_SEC`
+    pub rate_limit_reads_per_sec: u32,
+    /// Writes and deletes a second per client, 0 for no limit, `RATE_LIMIT_WRITES_PER_SEC`
+    pub rate_limit_writes_per_sec: u32,
+    /// Admin requests a second per client, 0 for no limit, `RATE_LIMIT_ADMIN_PER_SEC`
+    pub rate_limit_admin_per_sec: u32,
+    /// Whether the API compresses its responses with gzip or deflate for the clients accepting
+    /// them and decompresses the request bodies sent so, `COMPRESSION_ENABLED`
+    pub compression_enabled: bool,
+    /// Bytes under which a response is sent as is, `COMPRESSION_MIN_SIZE`
+    pub compression_min_size: u16,
+    /// Origins of the browsers allowed to call the API, `*` for any, none to send no CORS
+    /// headers, `CORS_ALLOWED_ORIGINS` separated by commas
+    pub cors_allowed_origins: Vec<String>,
+    /// Seconds a browser may cache a preflight response, `CORS_MAX_AGE_SECS`
+    pub cors_max_age_secs: u64,
+    /// `LOG_FORMAT`
+    pub log_format: LogFormat,
+    /// Seconds from the shutdown signal until the server gives up on draining the requests,
+    /// the last compaction and the close of the database, `SHUTDOWN_TIMEOUT_SECS`
+    pub shutdown_timeout_secs: u64,
+    /// Milliseconds an API request may take before it is answered with a 503, e.g. on a stuck
+    /// disk, `REQUEST_TIMEOUT_MS`
+    pub request_timeout_ms: u64,
+    /// Admin address of the primary, e.g. `http://primary:8081`, to run as its read-only
+    /// replica, `REPLICATE_FROM` or `--replicate-from`. None by default.
+    pub replicate_from: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            admin_bind_addr: SocketAddr::from(([127, 0, 0, 1], 8081)),
+            resp_bind_addr: None,
+            grpc_bind_addr: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            data_dir: PathBuf::from("./db"),
+            namespaces_dir: None,
+            max_mem_table_size: 10 * 1024 * 1024,
+            max_value_size: 2 * 1024 * 1024,
+            compaction_enabled: true,
+            compaction_interval_secs: 60,
+            compaction_jitter_secs: 10,
+            compaction_limit: 50 * 1024 * 1024,
+            compaction_throttle_bytes_per_sec: 16 * 1024 * 1024,
+            compaction_max_age_secs: None,
+            housekeeping_interval_secs: 600,
+            wal_archive_max_age_secs: 24 * 60 * 60,
+            rate_limit_enabled: true,
+            rate_limit_reads_per_sec: 1000,
+            rate_limit_writes_per_sec: 200,
+            rate_limit_admin_per_sec: 10,
+            compression_enabled: true,
+            compression_min_size: 1024,
+            cors_allowed_origins: Vec::new(),
+            cors_max_age_secs: 3600,
+            log_format: LogFormat::Json,
+            shutdown_timeout_secs: 30,
+            request_timeout_ms: 5000,
+            replicate_from: None,
+        }
+    }
+}
+
+impl Config {
+    /// Load the settings from the command line arguments and the environment of the process
+    pub fn load() -> Result<Self> {
+        let args = Args::parse();
+        Self::from_sources(args.config.as_deref(), |name| match name {
+            "REPLICATE_FROM" if args.replicate_from.is_some() => args.replicate_from.clone(),
+            _ => std::env::var(name).ok(),
+        })
+    }
+
+    /// The defaults, overridden by the `file` when given, then by the variables found by `env`
+    fn from_sources(file: Option<&Path>, env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut config = match file {
+            Some(file) => {
+                let content = std::fs::read_to_string(file)
+                    .with_context(|| format!("read config file {:?}", file))?;
+                toml::from_str(&content).with_context(|| format!("parse config file {:?}", file))?
+            }
+            None => Self::default(),
+        };
+
+        override_from_env(&env, "BIND_ADDR", &mut config.bind_addr)?;
+        override_from_env(&env, "ADMIN_BIND_ADDR", &mut config.admin_bind_addr)?;
+        if let Some(addr) = env("RESP_BIND_ADDR") {
+            config.resp_bind_addr = Some(parse_env("RESP_BIND_ADDR", &addr)?);
+        }
+        if let Some(addr) = env("GRPC_BIND_ADDR") {
+            config.grpc_bind_addr = Some(parse_env("GRPC_BIND_ADDR", &addr)?);
+        }
+        if let Some(url) = env("REPLICATE_FROM") {
+            config.replicate_from = Some(url);
+        }
+        if let Some(cert_path) = env("TLS_CERT_PATH") {
+            config.tls_cert_path = Some(PathBuf::from(cert_path));
+        }
+        if let Some(key_path) = env("TLS_KEY_PATH") {
+            config.tls_key_path = Some(PathBuf::from(key_path));
+        }
+        override_from_env(&env, "DATA_DIR", &mut config.data_dir)?;
+        if let Some(namespaces_dir) = env("NAMESPACES_DIR") {
+            config.namespaces_dir = Some(PathBuf::from(namespaces_dir));
+        }
+        override_from_env(&env, "MAX_MEM_TABLE_SIZE", &mut config.max_mem_table_size)?;
+        override_from_env(&env, "MAX_VALUE_SIZE", &mut config.max_value_size)?;
+        override_from_env(&env, "COMPACTION_ENABLED", &mut config.compaction_enabled)?;
+        override_from_env(
+            &env,
+            "COMPACTION_INTERVAL_SECS",
+            &mut config.compaction_interval_secs,
+        )?;
+        override_from_env(
+            &env,
+            "COMPACTION_JITTER_SECS",
+            &mut config.compaction_jitter_secs,
+        )?;
+        override_from_env(&env, "COMPACTION_LIMIT", &mut config.compaction_limit)?;
+        override_from_env(
+            &env,
+            "COMPACTION_THROTTLE_BYTES_PER_SEC",
+            &mut config.compaction_throttle_bytes_per_sec,
+        )?;
+        if let Some(max_age) = env("COMPACTION_MAX_AGE_SECS") {
+            config.compaction_max_age_secs = Some(parse_env("COMPACTION_MAX_AGE_SECS", &max_age)?);
+        }
+        override_from_env(
+            &env,
+            "HOUSEKEEPING_INTERVAL_SECS",
+            &mut config.housekeeping_interval_secs,
+        )?;
+        override_from_env(
+            &env,
+            "WAL_ARCHIVE_MAX_AGE_SECS",
+            &mut config.wal_archive_max_age_secs,
+        )?;
+        override_from_env(&env, "RATE_LIMIT_ENABLED", &mut config.rate_limit_enabled)?;
+        override_from_env(
+            &env,
+            "RATE_LIMIT_READS_PER_SEC",
+            &mut config.rate_limit_reads_per_sec,
+        )?;
+        override_from_env(
+            &env,
+            "RATE_LIMIT_WRITES_PER_SEC",
+            &mut config.rate_limit_writes_per_sec,
+        )?;
+        override_from_env(
+            &env,
+            "RATE_LIMIT_ADMIN_PER_SEC",
+            &mut config.rate_limit_admin_per_sec,
+        )?;
+        override_from_env(&env, "COMPRESSION_ENABLED", &mut config.compression_enabled)?;
+        override_from_env(
+            &env,
+            "COMPRESSION_MIN_SIZE",
+            &mut config.compression_min_size,
+        )?;
+        if let Some(origins) = env("CORS_ALLOWED_ORIGINS") {
+            config.cors_allowed_origins = origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+        override_from_env(&env, "CORS_MAX_AGE_SECS", &mut config.cors_max_age_secs)?;
+        override_from_env(&env, "LOG_FORMAT", &mut config.log_format)?;
+        override_from_env(
+            &env,
+            "SHUTDOWN_TIMEOUT_SECS",
+            &mut config.shutdown_timeout_secs,
+        )?;
+        override_from_env(&env, "REQUEST_TIMEOUT_MS", &mut config.request_timeout_ms)?;
+
+        if config.tls_cert_path.is_some() != config.tls_key_path.is_some() {
+            bail!("TLS needs both a certificate and a key, or neither");
+        }
+        if config.compaction_interval_secs == 0 {
+            bail!("the compaction interval must be at least 1 second");
+        }
+        if config.request_timeout_ms == 0 {
+            bail!("the request timeout must be at least 1 millisecond");
+        }
+        if let Some(url) = config.replicate_from.as_deref() {
+            let uri =
+                Uri::from_str(url).with_context(|| format!("invalid REPLICATE_FROM {:?}", url))?;
+            if uri.scheme_str() != Some("http") || uri.authority().is_none() {
+                bail!(
+                    "REPLICATE_FROM must be the http:// address of the primary, not {:?}",
+                    url
+                );
+            }
+        }
+        Ok(config)
+    }
+
+    /// The certificate and the key, when TLS is configured
+    pub fn tls(&self) -> Option<(&Path, &Path)> {
+        self.tls_cert_path
+            .as_deref()
+            .zip(self.tls_key_path.as_deref())
+    }
+
+    pub fn namespaces_dir(&self) -> PathBuf {
+        self.namespaces_dir
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join("namespaces"))
+    }
+
+    pub fn compaction_interval(&self) -> Duration {
+        Duration::from_secs(self.compaction_interval_secs)
+    }
+
+    pub fn compaction_jitter(&self) -> Duration {
+        Duration::from_secs(self.compaction_jitter_secs)
+    }
+
+    /// `None` when the housekeeping is disabled
+    pub fn housekeeping_interval(&self) -> Option<Duration> {
+        (self.housekeeping_interval_secs > 0)
+            .then(|| Duration::from_secs(self.housekeeping_interval_secs))
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+}
+
+fn override_from_env<T>(
+    env: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    field: &mut T,
+) -> Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = env(name) {
+        *field = parse_env(name, &value)?;
+    }
+    Ok(())
+}
+
+fn parse_env<T>(name: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|e| anyhow!("parse {}={:?}: {}", name, value, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        let vars = vars.iter().copied().collect::<HashMap<_, _>>();
+        move |name| vars.get(name).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn it_loads_the_defaults_then_the_file_then_the_environment() -> Result<()> {
+        assert_eq!(Config::from_sources(None, env(&[]))?, Config::default());
+
+        let tmpdir = TempDir::new("config")?;
+        let file = tmpdir.path().join("server.toml");
+        std::fs::write(
+            &file,
+            r#"
+            bind_addr = "127.0.0.1:9000"
+            data_dir = "/var/lib/db"
+            compaction_interval_secs = 5
+            log_format = "pretty"
+            "#,
+        )?;
+        let config = Config::from_sources(Some(&file), env(&[]))?;
+        assert_eq!(config.bind_addr, SocketAddr::from(([127, 0, 0, 1], 9000)));
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/db"));
+        assert_eq!(config.compaction_interval(), Duration::from_secs(5));
+        assert_eq!(config.log_format, LogFormat::Pretty);
+        assert_eq!(config.compaction_limit, Config::default().compaction_limit);
+
+        let config = Config::from_sources(
+            Some(&file),
+            env(&[
+                ("DATA_DIR", "/tmp/db"),
+                ("COMPACTION_MAX_AGE_SECS", "3600"),
+                ("LOG_FORMAT", "json"),
+                ("COMPACTION_ENABLED", "false"),
+                ("REPLICATE_FROM", "http://primary:8081"),
+                ("GRPC_BIND_ADDR", "0.0.0.0:50051"),
+                (
+                    "CORS_ALLOWED_ORIGINS",
+                    "https://ui.example.com, http://localhost:3000",
+                ),
+            ]),
+        )?;
+        assert_eq!(config.bind_addr, SocketAddr::from(([127, 0, 0, 1], 9000)));
+        assert_eq!(config.data_dir, PathBuf::from("/tmp/db"));
+        assert_eq!(config.compaction_max_age_secs, Some(3600));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert!(!config.compaction_enabled);
+        assert_eq!(
+            config.replicate_from.as_deref(),
+            Some("http://primary:8081")
+        );
+        assert_eq!(
+            config.grpc_bind_addr,
+            Some(SocketAddr::from(([0, 0, 0, 0], 50051)))
+        );
+        assert_eq!(
+            config.cors_allowed_origins,
+            ["https://ui.example.com", "http://localhost:3000"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn it_rejects_the_invalid_settings() -> Result<()> {
+        let err = Config::from_sources(None, env(&[("COMPACTION_LIMIT", "50MB")])).unwrap_err();
+        assert!(err.to_string().contains("COMPACTION_LIMIT"));
+        assert!(Config::from_sources(None, env(&[("LOG_FORMAT", "xml")])).is_err());
+        assert!(Config::from_sources(None, env(&[("COMPACTION_INTERVAL_SECS", "0")])).is_err());
+        assert!(Config::from_sources(None, env(&[("TLS_CERT_PATH", "cert.pem")])).is_err());
+        for url in ["primary:8081", "https://primary:8081", "http://"] {
+            assert!(Config::from_sources(None, env(&[("REPLICATE_FROM", url)])).is_err());
+        }
+
+        let tmpdir = TempDir::new("config")?;
+        let file = tmpdir.path().join("server.toml");
+        std::fs::write(&file, "data_directory = \"/var/lib/db\"")?;
+        assert!(Config::from_sources(Some(&file), env(&[])).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use axum::http::{header, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::{config::Config, request_id::REQUEST_ID_HEADER};
+
+/// The CORS headers of the API for the [`Config::cors_allowed_origins`], `None` when there
+/// are none. The preflights are answered by the layer itself, before the routes and their
+/// rate limits.
+pub fn cors_layer(config: &Config) -> Result<Option<CorsLayer>> {
+    let allow_origin = match config.cors_allowed_origins.as_slice() {
+        [] => return Ok(None),
+        [any] if any == "*" => AllowOrigin::any(),
+        origins => {
+            if origins.iter().any(|origin| origin == "*") {
+                bail!("CORS origin `*` cannot be listed along with other origins");
+            }
+            let origins = origins
+                .iter()
+                .map(|origin| {
+                    HeaderValue::from_str(origin)
+                        .with_context(|| format!("invalid CORS origin {:?}", origin))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            AllowOrigin::list(origins)
+        }
+    };
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers([
+                header::CONTENT_TYPE,
+                header::IF_MATCH,
+                REQUEST_ID_HEADER.clone(),
+            ])
+            .expose_headers([header::ETAG, header::RETRY_AFTER, REQUEST_ID_HEADER.clone()])
+            .max_age(Duration::from_secs(config.cors_max_age_secs)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use tempdir::TempDir;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        handlers::test_client::{send_with_headers, test_state},
+        rate_limit::{RateLimiter, RateLimits},
+        router,
+    };
+
+    fn config(origins: &[&str]) -> Config {
+        Config {
+            cors_allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn it_rejects_the_invalid_origins() {
+        assert!(cors_layer(&config(&[])).unwrap().is_none());
+        assert!(cors_layer(&config(&["*"])).unwrap().is_some());
+        assert!(cors_layer(&config(&["*", "https://ui.example.com"])).is_err());
+        assert!(cors_layer(&config(&["https://ui.example.com\n"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_answers_the_preflights_of_the_allowed_origins() -> Result<()> {
+        let tmpdir = TempDir::new("cors_test")?;
+        let mut state = test_state(tmpdir.path()).await?;
+        state.cors = cors_layer(&config(&["https://ui.example.com"]))?;
+        // the preflights do not count against the rate limits either
+        state.rate_limits = RateLimits {
+            writes: RateLimiter::new(1),
+            ..RateLimits::default()
+        };
+        let router = router::create(state);
+        let uri = "/api/entry/hello";
+
+        for _ in 0..2 {
+            let request = Request::options(uri)
+                .header("origin", "https://ui.example.com")
+                .header("access-control-request-method", "POST")
+                .header("access-control-request-headers", "content-type,if-match")
+                .body(Body::empty())?;
+            let response = router.clone().oneshot(request).await?;
+            assert_eq!(response.status(), StatusCode::OK);
+            let headers = response.headers();
+            assert_eq!(
+                headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+                "https://ui.example.com"
+            );
+            assert_eq!(
+                headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+                "GET,POST,DELETE"
+            );
+            assert!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+                .to_str()?
+                .contains("if-match"));
+            assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "3600");
+        }
+
+        let origin = [("origin", "https://ui.example.com")];
+        let (status, headers, _) =
+            send_with_headers(&router, Method::POST, uri, &origin, "world").await?;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, headers_of_get, _) =
+            send_with_headers(&router, Method::GET, uri, &origin, "").await?;
+        assert_eq!(status, StatusCode::OK);
+        for headers in [&headers, &headers_of_get] {
+            assert_eq!(
+                headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+                "https://ui.example.com"
+            );
+            assert!(headers[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+                .to_str()?
+                .contains("etag"));
+        }
+
+        // another origin gets no CORS headers, its browser blocks the response
+        let other = [("origin", "https://evil.example.com")];
+        let (_, headers, _) = send_with_headers(&router, Method::GET, uri, &other, "").await?;
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
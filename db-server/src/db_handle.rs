@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use db_engine::Database;
+use tokio::sync::{OwnedRwLockWriteGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Shared access to the [`Database`] of the server. The reads (`get`, scans, stats) only
+/// need `&Database` and run concurrently, the writes and the flushes take turns.
+#[derive(Clone)]
+pub struct DbHandle(Arc<RwLock<Database>>);
+
+impl DbHandle {
+    pub fn new(db: Database) -> Self {
+        Self(Arc::new(RwLock::new(db)))
+    }
+
+    /// Wait for the ongoing write, if any, then read alongside the other readers
+    pub async fn read(&self) -> RwLockReadGuard<'_, Database> {
+        self.0.read().await
+    }
+
+    /// Wait for every reader and writer to be done, then write alone
+    pub async fn write(&self) -> RwLockWriteGuard<'_, Database> {
+        self.0.write().await
+    }
+
+    /// Like [`DbHandle::write`], the guard can move to another task
+    pub async fn write_owned(&self) -> OwnedRwLockWriteGuard<Database> {
+        Arc::clone(&self.0).write_owned().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use anyhow::Result;
+    use db_engine::DatabaseBuilder;
+    use tempdir::TempDir;
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    const KEYS: usize = 1_000;
+    const TASKS: usize = 16;
+    const REQUESTS_PER_TASK: usize = 2_000;
+
+    async fn test_db(dir: &TempDir) -> Result<Database> {
+        let mut db = DatabaseBuilder::new(dir.path().to_path_buf())
+            .build()
+            .await?;
+        for i in 0..KEYS {
+            db.set(format!("key{}", i).as_bytes(), b"value").await?;
+        }
+        db.flush().await?;
+        Ok(db)
+    }
+
+    #[tokio::test]
+    async fn it_reads_while_another_reader_holds_the_database() -> Result<()> {
+        let tmpdir = TempDir::new("db_handle")?;
+        let handle = DbHandle::new(test_db(&tmpdir).await?);
+
+        let reader = handle.read().await;
+        let entry = tokio::time::timeout(Duration::from_secs(1), async {
+            handle.read().await.get(b"key1").await
+        })
+        .await?;
+        assert_eq!(entry.unwrap().value, &b"value"[..]);
+        drop(reader);
+
+        handle.write().await.set(b"key1", b"other").await?;
+        assert_eq!(
+            handle.read().await.get(b"key1").await.unwrap().value,
+            &b"other"[..]
+        );
+        Ok(())
+    }
+
+    /// 90% reads, 10% writes from concurrent tasks, through a `Mutex` then through a
+    /// `DbHandle`. `cargo test --release -- --ignored --nocapture load_test` to compare.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    #[ignore]
+    async fn load_test() -> Result<()> {
+        let tmpdir = TempDir::new("db_handle_mutex")?;
+        let mutex = Arc::new(Mutex::new(test_db(&tmpdir).await?));
+        let elapsed = run_load(move |i| {
+            let mutex = Arc::clone(&mutex);
+            async move {
+                let key = format!("key{}", i % KEYS);
+                match i % 10 {
+                    0 => drop(mutex.lock().await.set(key.as_bytes(), b"new").await),
+                    _ => drop(mutex.lock().await.get(key.as_bytes()).await),
+                }
+            }
+        })
+        .await;
+        println!("Mutex<Database>: {:.0} requests/s", throughput(elapsed));
+
+        let tmpdir = TempDir::new("db_handle_rwlock")?;
+        let handle = DbHandle::new(test_db(&tmpdir).await?);
+        let elapsed = run_load(move |i| {
+            let handle = handle.clone();
+            async move {
+                let key = format!("key{}", i % KEYS);
+                match i % 10 {
+                    0 => drop(handle.write().await.set(key.as_bytes(), b"new").await),
+                    _ => drop(handle.read().await.get(key.as_bytes()).await),
+                }
+            }
+        })
+        .await;
+        println!("DbHandle: {:.0} requests/s", throughput(elapsed));
+        Ok(())
+    }
+
+    async fn run_load<F, Fut>(request: F) -> Duration
+    where
+        F: Fn(usize) -> Fut + Clone + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let start = Instant::now();
+        let tasks = (0..TASKS)
+            .map(|task| {
+                let request = request.clone();
+                tokio::spawn(async move {
+                    for i in 0..REQUESTS_PER_TASK {
+                        request(task * REQUESTS_PER_TASK + i).await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        start.elapsed()
+    }
+
+    fn throughput(elapsed: Duration) -> f64 {
+        (TASKS * REQUESTS_PER_TASK) as f64 / elapsed.as_secs_f64()
+    }
+}
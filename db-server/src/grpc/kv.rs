@@ -0,0 +1,111 @@
+//! The messages of `proto/kv.proto`, written by hand as prost would generate them, and the
+//! service generated from them by `build.rs`. See the file for the meaning of the fields.
+use prost::bytes::Bytes;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Entry {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub value: Bytes,
+    #[prost(uint64, tag = "3")]
+    pub timestamp: u64,
+    #[prost(string, tag = "4")]
+    pub content_type: String,
+    #[prost(uint64, tag = "5")]
+    pub expires_at: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetRequest {
+    #[prost(string, tag = "1")]
+    pub namespace: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub key: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SetRequest {
+    #[prost(string, tag = "1")]
+    pub namespace: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub value: Vec<u8>,
+    #[prost(string, tag = "4")]
+    pub content_type: String,
+    #[prost(uint64, tag = "5")]
+    pub ttl_seconds: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SetResponse {
+    #[prost(uint64, tag = "1")]
+    pub timestamp: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteRequest {
+    #[prost(string, tag = "1")]
+    pub namespace: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub key: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteResponse {
+    #[prost(uint32, tag = "1")]
+    pub deleted: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BatchWriteRequest {
+    #[prost(string, tag = "1")]
+    pub namespace: String,
+    #[prost(message, repeated, tag = "2")]
+    pub writes: Vec<Write>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Write {
+    #[prost(oneof = "write::Operation", tags = "1, 2")]
+    pub operation: Option<write::Operation>,
+}
+
+pub mod write {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Operation {
+        #[prost(message, tag = "1")]
+        Put(super::Put),
+        #[prost(bytes, tag = "2")]
+        Delete(Vec<u8>),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Put {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+    #[prost(string, tag = "3")]
+    pub content_type: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BatchWriteResponse {
+    #[prost(uint32, tag = "1")]
+    pub writes: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ScanRequest {
+    #[prost(string, tag = "1")]
+    pub namespace: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub prefix: Vec<u8>,
+    #[prost(uint32, tag = "3")]
+    pub limit: u32,
+}
+
+include!(concat!(env!("OUT_DIR"), "/kv.Kv.rs"));
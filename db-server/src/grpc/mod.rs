@@ -0,0 +1,413 @@
+//! A gRPC listener for the clients which only speak gRPC, see
+//! [`Config::grpc_bind_addr`](crate::config::Config::grpc_bind_addr). It serves the `Kv`
+//! service of `proto/kv.proto` from the same [`AppState`] as the `/api` routes, namespaces
+//! included.
+
+pub mod kv;
+
+use std::{pin::Pin, time::Duration};
+
+use anyhow::{Context, Result};
+use db_engine::{Database, DbEntry, Entry, WriteBatch};
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use tokio::{net::TcpListener, sync::OwnedRwLockWriteGuard};
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_util::sync::CancellationToken;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    app_error::AppError, app_state::AppState, db_handle::DbHandle, handlers, timeout::write_within,
+};
+use kv::{kv_server::KvServer, write::Operation};
+
+/// Room for the fields of a message around its value, see [`AppState::max_value_size`]
+const MESSAGE_OVERHEAD: usize = 64 * 1024;
+
+const MAX_TTL_SECONDS: u64 = handlers::prelude::MAX_TTL_SECONDS as u64;
+
+/// Entries of the engine read at once by a `Scan`
+const SCAN_PAGE_LEN: usize = 100;
+
+pub struct KvService {
+    state: AppState,
+}
+
+impl KvService {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+
+    /// Serve the calls of the `listener` until the `shutdown`, then wait for the ones in
+    /// progress. The scans in progress end on the shutdown as well.
+    pub async fn serve(self, listener: TcpListener, shutdown: CancellationToken) -> Result<()> {
+        tracing::info!("Listening for gRPC on {}", listener.local_addr()?);
+        let max_message_size = self.state.max_value_size + MESSAGE_OVERHEAD;
+        Server::builder()
+            .add_service(KvServer::new(self).max_decoding_message_size(max_message_size))
+            .serve_with_incoming_shutdown(
+                TcpListenerStream::new(listener),
+                shutdown.cancelled_owned(),
+            )
+            .await
+            .context("serve gRPC")
+    }
+
+    /// The database of `namespace`, the default one when empty
+    async fn db(&self, namespace: &str) -> Result<DbHandle, AppError> {
+        match namespace {
+            "" => Ok(self.state.db.clone()),
+            namespace => self.state.namespaces.get(namespace).await,
+        }
+    }
+
+    fn check_value_len(&self, value: &[u8]) -> Result<(), AppError> {
+        match value.len() > self.state.max_value_size {
+            true => Err(AppError::TooLarge(format!(
+                "The value is longer than {} bytes.",
+                self.state.max_value_size
+            ))),
+            false => Ok(()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl kv::kv_server::Kv for KvService {
+    async fn get(&self, request: Request<kv::GetRequest>) -> Result<Response<kv::Entry>, Status> {
+        let kv::GetRequest { namespace, key } = request.into_inner();
+        let db = self.db(&namespace).await?;
+        let entry = db.read().await.get(&key).await;
+        match entry {
+            Some(entry) => Ok(Response::new(entry.into())),
+            None => Err(AppError::NotFound(String::from_utf8_lossy(&key).into_owned()).into()),
+        }
+    }
+
+    async fn set(
+        &self,
+        request: Request<kv::SetRequest>,
+    ) -> Result<Response<kv::SetResponse>, Status> {
+        let kv::SetRequest {
+            namespace,
+            key,
+            value,
+            content_type,
+            ttl_seconds,
+        } = request.into_inner();
+        self.check_value_len(&value)?;
+        let ttl = match ttl_seconds {
+            0 => None,
+            1..=MAX_TTL_SECONDS => Some(Duration::from_secs(ttl_seconds)),
+            _ => {
+                return Err(AppError::UnprocessableEntity(format!(
+                    "ttl_seconds must be between 0 and {}, got {}.",
+                    MAX_TTL_SECONDS, ttl_seconds
+                ))
+                .into())
+            }
+        };
+        if ttl.is_some() && !content_type.is_empty() {
+            return Err(AppError::BadRequest(String::from(
+                "A value with a content type cannot expire.",
+            ))
+            .into());
+        }
+
+        let db = self.db(&namespace).await?;
+        let write = move |mut db: OwnedRwLockWriteGuard<Database>| async move {
+            Ok(match (ttl, content_type.as_str()) {
+                (Some(ttl), _) => db.set_with_ttl(&key, &value, ttl).await?,
+                (None, "") => db.set(&key, &value).await?,
+                (None, content_type) => db.set_typed(&key, &value, content_type).await?,
+            })
+        };
+        let timestamp = write_within(self.state.request_timeout, &db, write).await?;
+        Ok(Response::new(kv::SetResponse {
+            timestamp: timestamp as u64,
+        }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<kv::DeleteRequest>,
+    ) -> Result<Response<kv::DeleteResponse>, Status> {
+        let kv::DeleteRequest { namespace, key } = request.into_inner();
+        let db = self.db(&namespace).await?;
+        let delete = move |mut db: OwnedRwLockWriteGuard<Database>| async move {
+            Ok(db.delete(&key).await?)
+        };
+        let deleted = write_within(self.state.request_timeout, &db, delete).await?;
+        Ok(Response::new(kv::DeleteResponse {
+            deleted: deleted as u32,
+        }))
+    }
+
+    async fn batch_write(
+        &self,
+        request: Request<kv::BatchWriteRequest>,
+    ) -> Result<Response<kv::BatchWriteResponse>, Status> {
+        let kv::BatchWriteRequest { namespace, writes } = request.into_inner();
+        let mut batch = WriteBatch::new();
+        for write in writes {
+            match write.operation {
+                Some(Operation::Put(put)) if put.content_type.is_empty() => {
+                    self.check_value_len(&put.value)?;
+                    batch.set(&put.key, &put.value);
+                }
+                Some(Operation::Put(put)) => {
+                    self.check_value_len(&put.value)?;
+                    let entry =
+                        Entry::new(put.key, Some(put.value), 0).with_content_type(put.content_type);
+                    batch.put(&DbEntry::try_from(entry).expect("a value"));
+                }
+                Some(Operation::Delete(key)) => {
+                    batch.delete(&key);
+                }
+                None => {
+                    return Err(AppError::BadRequest(String::from(
+                        "A write is neither a put nor a delete.",
+                    ))
+                    .into())
+                }
+            }
+        }
+
+        let db = self.db(&namespace).await?;
+        let write = move |mut db: OwnedRwLockWriteGuard<Database>| async move {
+            Ok(db.write(batch).await?)
+        };
+        let writes = write_within(self.state.request_timeout, &db, write).await?;
+        Ok(Response::new(kv::BatchWriteResponse {
+            writes: writes as u32,
+        }))
+    }
+
+    type ScanStream = Pin<Box<dyn Stream<Item = Result<kv::Entry, Status>> + Send>>;
+
+    /// A page of the engine is read only once the client took the entries of the one before,
+    /// HTTP/2 flow control holds the stream back while it reads slowly.
+    async fn scan(
+        &self,
+        request: Request<kv::ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let kv::ScanRequest {
+            namespace,
+            prefix,
+            limit,
+        } = request.into_inner();
+        let scan = Scan {
+            db: self.db(&namespace).await?,
+            prefix,
+            start_after: None,
+            remaining: match limit {
+                0 => usize::MAX,
+                limit => limit as usize,
+            },
+        };
+        let pages = stream::try_unfold(Some(scan), |scan| async move {
+            let Some(mut scan) = scan else {
+                return Ok::<_, Status>(None);
+            };
+            let (page, next_start) = scan
+                .db
+                .read()
+                .await
+                .scan_prefix_page(
+                    &scan.prefix,
+                    scan.start_after.as_deref(),
+                    scan.remaining.min(SCAN_PAGE_LEN),
+                )
+                .await
+                .map_err(|e| Status::from(AppError::from(e)))?;
+            scan.remaining -= page.len();
+            scan.start_after = next_start;
+            // the tombstones take room in a page, it may be short or even empty
+            let next = (scan.start_after.is_some() && scan.remaining > 0).then_some(scan);
+            Ok(Some((page, next)))
+        });
+        let entries = pages
+            .map_ok(|page| stream::iter(page).map(kv::Entry::from).map(Ok))
+            .try_flatten()
+            .take_until(self.state.shutdown.clone().cancelled_owned());
+        Ok(Response::new(Box::pin(entries)))
+    }
+}
+
+/// Where a `Scan` is at between two pages
+struct Scan {
+    db: DbHandle,
+    prefix: Vec<u8>,
+    /// The last key sent, `None` before the first page
+    start_after: Option<Vec<u8>>,
+    /// Entries to send before the `limit` of the request is reached
+    remaining: usize,
+}
+
+impl From<DbEntry> for kv::Entry {
+    fn from(entry: DbEntry) -> Self {
+        Self {
+            content_type: entry.content_type().unwrap_or_default().to_owned(),
+            expires_at: entry.expires_at().unwrap_or_default() as u64,
+            key: entry.key,
+            value: entry.value,
+            timestamp: entry.timestamp as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use anyhow::Result;
+    use tempdir::TempDir;
+    use tokio::task::JoinHandle;
+    use tonic::{transport::Channel, Code};
+
+    use super::*;
+    use crate::handlers::test_client::test_state;
+    use kv::kv_client::KvClient;
+
+    /// Serve `state` on a free port until its shutdown
+    async fn serve(state: &AppState) -> Result<(KvClient<Channel>, JoinHandle<Result<()>>)> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let addr = listener.local_addr()?;
+        let server =
+            tokio::spawn(KvService::new(state.clone()).serve(listener, state.shutdown.clone()));
+        let client = KvClient::connect(format!("http://{}", addr)).await?;
+        Ok((client, server))
+    }
+
+    fn put(key: &str, value: &str) -> kv::Write {
+        kv::Write {
+            operation: Some(Operation::Put(kv::Put {
+                key: key.into(),
+                value: value.into(),
+                content_type: String::new(),
+            })),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_serves_the_key_value_api() -> Result<()> {
+        let tmpdir = TempDir::new("grpc_test")?;
+        let mut state = test_state(tmpdir.path()).await?;
+        state.max_value_size = 1024;
+        let (mut client, _) = serve(&state).await?;
+
+        let set = client
+            .set(kv::SetRequest {
+                key: b"hello".to_vec(),
+                value: b"{}".to_vec(),
+                content_type: String::from("application/json"),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        let entry = client
+            .get(kv::GetRequest {
+                key: b"hello".to_vec(),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        assert_eq!(&entry.value[..], b"{}");
+        assert_eq!(entry.timestamp, set.timestamp);
+        assert_eq!(entry.content_type, "application/json");
+
+        let batch = kv::BatchWriteRequest {
+            writes: vec![
+                put("a", "1"),
+                put("b", "2"),
+                kv::Write {
+                    operation: Some(Operation::Delete(b"hello".to_vec())),
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(client.batch_write(batch).await?.into_inner().writes, 3);
+        let deleted = client
+            .delete(kv::DeleteRequest {
+                key: b"a".to_vec(),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        assert_eq!(deleted.deleted, 1);
+        assert_eq!(state.db.read().await.get(b"b").await.unwrap().value, "2");
+
+        // the errors of the HTTP routes, as gRPC codes
+        for (key, namespace) in [("hello", ""), ("a", ""), ("b", "missing")] {
+            let status = client
+                .get(kv::GetRequest {
+                    namespace: namespace.to_owned(),
+                    key: key.into(),
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::NotFound);
+        }
+        let status = client
+            .set(kv::SetRequest {
+                key: b"large".to_vec(),
+                value: vec![b'x'; 1025],
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_streams_the_scans_until_the_shutdown() -> Result<()> {
+        let tmpdir = TempDir::new("grpc_test")?;
+        let state = test_state(tmpdir.path()).await?;
+        {
+            let mut db = state.db.write().await;
+            for i in 0..250 {
+                db.set(format!("user{:03}", i).as_bytes(), b"value").await?;
+            }
+            db.set(b"zebra", b"value").await?;
+            // tombstones in the first page of the engine
+            for i in (0..50).step_by(2) {
+                db.delete(format!("user{:03}", i).as_bytes()).await?;
+            }
+        }
+        let (mut client, server) = serve(&state).await?;
+
+        let scan = |limit| kv::ScanRequest {
+            prefix: b"user".to_vec(),
+            limit,
+            ..Default::default()
+        };
+        let entries = client
+            .scan(scan(0))
+            .await?
+            .into_inner()
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(entries.len(), 225);
+        assert_eq!(entries[0].key, b"user001");
+        assert!(entries.windows(2).all(|pair| pair[0].key < pair[1].key));
+        let entries = client
+            .scan(scan(120))
+            .await?
+            .into_inner()
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(entries.len(), 120);
+
+        // a scan in progress ends with the server
+        let mut entries = client.scan(scan(0)).await?.into_inner();
+        assert!(entries.message().await?.is_some());
+        state.shutdown.cancel();
+        while entries.message().await?.is_some() {}
+        server.await??;
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use db_engine::{CleanupReport, CompactionReport, DatabaseStats, RecoveryReport};
+use serde::{Deserialize, Serialize};
+
+use crate::{app_error::AppError, app_state::AdminState};
+
+#[derive(Deserialize)]
+pub struct CompactParams {
+    /// `false` to only start the compaction, true by default
+    wait: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct FlushResponse {
+    /// The new SSTable, `None` when the MemTable was empty
+    sstable_path: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+pub struct NamespaceResponse {
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct StatsResponse {
+    data_dir: PathBuf,
+    uptime_secs: u64,
+    database: DatabaseStats,
+    sstables: Vec<FileStats>,
+    wal: FileStats,
+    operations: OperationCounts,
+    scheduler: SchedulerStats,
+    /// How the database was restored when the server started
+    last_recovery: RecoveryReport,
+    /// Timestamp of the last write of the primary applied, on a replica only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replica_last_applied_timestamp: Option<u128>,
+}
+
+#[derive(Serialize)]
+pub struct FileStats {
+    path: PathBuf,
+    size: u64,
+}
+
+/// Number of operations since the start
+#[derive(Serialize)]
+pub struct OperationCounts {
+    get: u64,
+    set: u64,
+    delete: u64,
+    flush: u64,
+    /// Batches of writes, counted once whatever their number of entries
+    write: u64,
+    /// API requests answered with a 503 `timeout`
+    timeouts: u64,
+    /// API requests answered with a 5xx other than a timeout
+    server_errors: u64,
+}
+
+#[derive(Serialize)]
+pub struct SchedulerStats {
+    /// `None` before the first compaction
+    last_run_unix_secs: Option<u64>,
+    last_duration_ms: Option<u64>,
+    last_report: Option<CompactionReport>,
+    /// `None` unless the last compaction failed
+    last_error: Option<String>,
+    skipped_ticks: u64,
+    /// `None` before the first housekeeping pass
+    last_housekeeping_unix_secs: Option<u64>,
+    /// Files removed by the last housekeeping pass
+    last_cleanup: Option<CleanupReport>,
+    last_housekeeping_error: Option<String>,
+}
+
+/// Statistics of the database and of the server. Only takes the database lock for a moment,
+/// not the compaction lock, so it answers during a compaction.
+pub async fn stats_handler(
+    State(state): State<AdminState>,
+) -> Result<Json<StatsResponse>, AppError> {
+    let db = state.db.read().await;
+    let wal = FileStats {
+        size: db.wal_size().await?,
+        path: db.wal_path(),
+    };
+    let sstables = db
+        .sstable_files()
+        .await?
+        .into_iter()
+        .map(|(path, size)| FileStats { path, size })
+        .collect();
+    let metrics = db.metrics_snapshot();
+    let (data_dir, database) = (db.dir().to_path_buf(), db.stats());
+    let replica_last_applied_timestamp = db.is_replica().then(|| db.last_applied_timestamp());
+    let last_recovery = db.recovery_report().clone();
+    drop(db);
+
+    let status = state.scheduler.status();
+    let errors = state.error_counts.snapshot();
+    Ok(Json(StatsResponse {
+        data_dir,
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        database,
+        sstables,
+        wal,
+        operations: OperationCounts {
+            get: metrics.get.count,
+            set: metrics.set.count,
+            delete: metrics.delete.count,
+            flush: metrics.flush.count,
+            write: metrics.write.count,
+            timeouts: errors.timeouts,
+            server_errors: errors.server_errors,
+        },
+        scheduler: SchedulerStats {
+            last_run_unix_secs: status.last_run.map(unix_secs),
+            last_duration_ms: status
+                .last_duration
+                .map(|duration| duration.as_millis() as u64),
+            last_report: status.last_report,
+            last_error: status.last_error,
+            skipped_ticks: status.skipped_ticks,
+            last_housekeeping_unix_secs: status.last_housekeeping.map(unix_secs),
+            last_cleanup: status.last_cleanup,
+            last_housekeeping_error: status.last_housekeeping_error,
+        },
+        last_recovery,
+        replica_last_applied_timestamp,
+    }))
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Compact the database now instead of at the next tick of the scheduler and answer with the
+/// report. With `wait=false`, answers 202 at once and the scheduler compacts in the background.
+pub async fn compact_handler(
+    State(state): State<AdminState>,
+    Query(params): Query<CompactParams>,
+) -> Result<(StatusCode, Json<Option<CompactionReport>>), AppError> {
+    match params.wait.unwrap_or(true) {
+        true => Ok((StatusCode::OK, Json(Some(state.scheduler.compact().await?)))),
+        false => {
+            state.scheduler.trigger_now();
+            Ok((StatusCode::ACCEPTED, Json(None)))
+        }
+    }
+}
+
+/// Flush the MemTable to a new SSTable now.
+pub async fn flush_handler(
+    State(state): State<AdminState>,
+) -> Result<Json<FlushResponse>, AppError> {
+    let sstable_path = state.db.write().await.flush().await?;
+    Ok(Json(FlushResponse { sstable_path }))
+}
+
+/// Create the namespace `name`, served under `/api/:name` from now on. Answers 201 when new,
+/// 200 when it already existed.
+pub async fn create_namespace_handler(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<NamespaceResponse>), AppError> {
+    let status = match state.namespaces.create(&name).await? {
+        true => StatusCode::CREATED,
+        false => StatusCode::OK,
+    };
+    Ok((status, Json(NamespaceResponse { name })))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::{Method, StatusCode};
+    use db_engine::Compaction;
+    use tempdir::TempDir;
+
+    use crate::{
+        handlers::test_client::{send_to, test_admin_state, test_state},
+        router,
+    };
+
+    #[tokio::test]
+    async fn it_flushes_and_compacts_on_demand() -> Result<()> {
+        let tmpdir = TempDir::new("admin_test")?;
+        let dir = tmpdir.path();
+        let state = test_state(dir).await?;
+        let admin_state = test_admin_state(&state, dir).await;
+        let scheduler = admin_state.scheduler.clone();
+        let admin = router::create_admin(admin_state);
+        let (status, stats) = send_to(&admin, Method::GET, "/admin/stats", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(stats["scheduler"]["last_run_unix_secs"].is_null());
+        assert!(stats["scheduler"]["last_cleanup"].is_null());
+        assert_eq!(stats["operations"]["timeouts"], 0);
+        assert_eq!(stats["sstables"].as_array().unwrap().len(), 0);
+        assert_eq!(stats["last_recovery"]["wal_files_replayed"], 0);
+
+        let (status, body) = send_to(&admin, Method::POST, "/admin/flush", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["sstable_path"].is_null());
+        for key in ["a", "b"] {
+            state.db.write().await.set(key.as_bytes(), b"value").await?;
+            let (_, body) = send_to(&admin, Method::POST, "/admin/flush", "").await?;
+            assert!(body["sstable_path"].as_str().unwrap().ends_with(".db"));
+        }
+
+        let (status, report) = send_to(&admin, Method::POST, "/admin/compact", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["input_files"], 2);
+        assert_eq!(report["entries_written"], 2);
+
+        // the scheduler or another request is compacting
+        let lock = Compaction::try_lock(dir).await?;
+        let (status, body) = send_to(&admin, Method::POST, "/admin/compact", "").await?;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["error"], "compaction_in_progress");
+
+        // the stats still answer meanwhile
+        state.db.write().await.set(b"c", b"value").await?;
+        let (status, stats) = send_to(&admin, Method::GET, "/admin/stats", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stats["data_dir"], dir.to_str().unwrap());
+        assert_eq!(stats["database"]["mem_table_len"], 1);
+        let sstables = stats["sstables"].as_array().unwrap();
+        assert_eq!(sstables.len(), 1);
+        assert!(sstables[0]["size"].as_u64().unwrap() > 0);
+        assert!(stats["wal"]["size"].as_u64().unwrap() > 0);
+        assert!(stats["scheduler"]["last_run_unix_secs"].is_u64());
+        assert_eq!(stats["scheduler"]["last_report"]["input_files"], 2);
+        assert!(stats["scheduler"]["last_error"].is_string());
+        assert_eq!(stats["scheduler"]["skipped_ticks"], 0);
+        lock.release().await?;
+
+        // in the background, done once the scheduler acknowledges its shutdown
+        send_to(&admin, Method::POST, "/admin/flush", "").await?;
+        let (status, body) = send_to(&admin, Method::POST, "/admin/compact?wait=false", "").await?;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(body.is_null());
+        scheduler.shutdown().await;
+        let (_, stats) = send_to(&admin, Method::GET, "/admin/stats", "").await?;
+        assert!(stats["scheduler"]["last_error"].is_null());
+        assert_eq!(stats["scheduler"]["last_report"]["entries_written"], 3);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
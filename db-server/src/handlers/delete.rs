@@ -1,15 +1,33 @@
 use axum::{
     extract::{Path, State},
+    http::{header, HeaderMap},
     Json,
 };
 
-use crate::{app_error::AppError, app_state::AppState};
+use super::{
+    etag::check_if_match,
+    namespace::{KeyPath, NamespaceDb},
+};
+use db_engine::Database;
+use tokio::sync::OwnedRwLockWriteGuard;
+
+use crate::{app_error::AppError, app_state::AppState, timeout::write_within};
 
+/// Delete `key`. With an `If-Match` header, only deletes the entry of that `ETag`.
 pub async fn delete_handler(
     State(state): State<AppState>,
-    Path(key): Path<String>,
+    NamespaceDb(db): NamespaceDb,
+    Path(KeyPath { key }): Path<KeyPath>,
+    headers: HeaderMap,
 ) -> Result<Json<usize>, AppError> {
-    let db = state.db.clone();
-    let result = db.lock().await.delete(key.as_bytes()).await?;
-    Ok(Json(result))
+    let delete = move |mut db: OwnedRwLockWriteGuard<Database>| async move {
+        if headers.contains_key(header::IF_MATCH) {
+            let current = db.get(key.as_bytes()).await;
+            check_if_match(&headers, &key, current.as_ref())?;
+        }
+        Ok(db.delete(key.as_bytes()).await?)
+    };
+    Ok(Json(
+        write_within(state.request_timeout, &db, delete).await?,
+    ))
 }
@@ -4,18 +4,34 @@ use axum::{
 };
 use serde::Serialize;
 
+use crate::request_id;
+
+/// The body of every error: a machine-readable `error` code, a message for humans and the id
+/// of the request, to find it in the logs.
 #[derive(Serialize, Default)]
 pub struct ErrorResponse {
     error: String,
     message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl ErrorResponse {
+    pub fn new(error: &str, message: String) -> Self {
+        Self {
+            error: error.to_owned(),
+            message: Some(message),
+            request_id: request_id::current(),
+        }
+    }
 }
 
 pub async fn not_found_handler(uri: Uri) -> (StatusCode, Json<ErrorResponse>) {
     (
         StatusCode::NOT_FOUND,
-        Json(ErrorResponse {
-            error: String::from("not_found"),
-            message: Some(format!("Requested path `{}` not found.", uri.path())),
-        }),
+        Json(ErrorResponse::new(
+            "not_found",
+            format!("Requested path `{}` not found.", uri.path()),
+        )),
     )
 }
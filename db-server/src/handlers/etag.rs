@@ -0,0 +1,90 @@
+use axum::http::{header, HeaderMap, HeaderValue};
+use db_engine::DbEntry;
+
+use crate::app_error::AppError;
+
+/// The `ETag` of an entry, the timestamp of its last write as a strong tag
+pub fn etag(timestamp: u128) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{}\"", timestamp)).expect("a number is a valid header")
+}
+
+/// Check the `If-Match` header of a write against the `current` entry of `key`: passes
+/// without the header, a missing key is a 404 and an entry written since a 412. Run it under
+/// the write lock of the database, so no write lands between the check and the write.
+pub fn check_if_match(
+    headers: &HeaderMap,
+    key: &str,
+    current: Option<&DbEntry>,
+) -> Result<(), AppError> {
+    let Some(if_match) = headers.get(header::IF_MATCH) else {
+        return Ok(());
+    };
+    let Some(current) = current else {
+        return Err(AppError::NotFound(key.to_owned()));
+    };
+    let if_match = if_match
+        .to_str()
+        .map_err(|_| AppError::BadRequest(String::from("Invalid If-Match header.")))?;
+    let current_etag = etag(current.timestamp);
+    let matches = if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == current_etag);
+    match matches {
+        true => Ok(()),
+        false => Err(AppError::PreconditionFailed(key.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::{header, Method, StatusCode};
+    use tempdir::TempDir;
+
+    use crate::{
+        handlers::test_client::{send_with_headers, test_state},
+        router,
+    };
+
+    #[tokio::test]
+    async fn it_only_writes_when_the_etag_matches() -> Result<()> {
+        let tmpdir = TempDir::new("etag_test")?;
+        let router = router::create(test_state(tmpdir.path()).await?);
+        let uri = "/api/entry/hello";
+
+        let (status, headers, created) =
+            send_with_headers(&router, Method::POST, uri, &[], "world").await?;
+        assert_eq!(status, StatusCode::CREATED);
+        let etag = headers[header::ETAG].to_str()?.to_owned();
+        assert_eq!(etag, format!("\"{}\"", created["timestamp"]));
+        let (_, headers, _) = send_with_headers(&router, Method::GET, uri, &[], "").await?;
+        assert_eq!(headers[header::ETAG], etag.as_str());
+
+        // the first write with the ETag wins, the second one is stale
+        let if_match = [("if-match", etag.as_str())];
+        let (status, headers, _) =
+            send_with_headers(&router, Method::POST, uri, &if_match, "first").await?;
+        assert_eq!(status, StatusCode::CREATED);
+        let new_etag = headers[header::ETAG].to_str()?.to_owned();
+        assert_ne!(new_etag, etag);
+        let (status, _, body) =
+            send_with_headers(&router, Method::POST, uri, &if_match, "second").await?;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(body["error"], "precondition_failed");
+        let (status, _, _) = send_with_headers(&router, Method::DELETE, uri, &if_match, "").await?;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        let (_, _, entry) = send_with_headers(&router, Method::GET, uri, &[], "").await?;
+        assert_eq!(entry["value"], "first");
+
+        let if_match = [("if-match", new_etag.as_str())];
+        let (status, _, _) = send_with_headers(&router, Method::DELETE, uri, &if_match, "").await?;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, _) =
+            send_with_headers(&router, Method::POST, uri, &[("if-match", "*")], "again").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
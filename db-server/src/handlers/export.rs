@@ -0,0 +1,182 @@
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{Query, State},
+    http::{header, HeaderValue},
+};
+use futures_util::{stream, Stream, TryStreamExt};
+use serde::Deserialize;
+
+use crate::app_state::AdminState;
+
+/// Keys read for each chunk of the export, the most held in memory at once
+const EXPORT_PAGE_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    prefix: String,
+    /// Only the entries written at or after this timestamp, in microseconds
+    since_timestamp: Option<u64>,
+}
+
+/// Download the live entries starting with `prefix` as NDJSON, a [`db_engine::DbEntry`] in
+/// JSON per line with the key and value in base64, in ascending key order.
+///
+/// The response is streamed a page of keys at a time, each page read under its own short
+/// read lock so the writes go on meanwhile. The pages continue after the last key sent, so a
+/// flush or a compaction between two of them neither repeats nor skips a key. A key written
+/// during the export shows its newest value if its page comes after the write.
+pub async fn export_handler(
+    State(state): State<AdminState>,
+    Query(params): Query<ExportParams>,
+) -> (
+    [(header::HeaderName, HeaderValue); 2],
+    StreamBody<impl Stream<Item = anyhow::Result<Bytes>>>,
+) {
+    let since = u128::from(params.since_timestamp.unwrap_or_default());
+    let prefix = params.prefix.into_bytes();
+    let db = state.db;
+
+    // `None` once the last page is sent, else the key the next page starts after
+    let pages = stream::try_unfold(Some(None), move |start_after: Option<Option<Vec<u8>>>| {
+        let (db, prefix) = (db.clone(), prefix.clone());
+        async move {
+            let Some(start_after) = start_after else {
+                return Ok(None);
+            };
+            let (entries, next_start) = db
+                .read()
+                .await
+                .scan_prefix_page(&prefix, start_after.as_deref(), EXPORT_PAGE_SIZE)
+                .await?;
+            let mut chunk = Vec::new();
+            for entry in entries.iter().filter(|entry| entry.timestamp >= since) {
+                serde_json::to_writer(&mut chunk, entry)?;
+                chunk.push(b'\n');
+            }
+            anyhow::Ok(Some((Bytes::from(chunk), next_start.map(Some))))
+        }
+    });
+    let body = pages
+        .try_filter(|chunk| std::future::ready(!chunk.is_empty()))
+        .inspect_err(|e| tracing::error!("Export failed, the download is cut short: {:#}", e));
+
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/x-ndjson"),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                HeaderValue::from_static("attachment; filename=\"export.ndjson\""),
+            ),
+        ],
+        StreamBody::new(body),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use axum::{
+        body::{Body, HttpBody},
+        http::{header, Request, StatusCode},
+    };
+    use db_engine::{DatabaseBuilder, DbEntry, WriteBatch};
+    use tempdir::TempDir;
+    use tower::ServiceExt;
+
+    use crate::{
+        handlers::test_client::{test_admin_state, test_state},
+        router,
+    };
+
+    const ENTRIES: usize = 3000;
+
+    #[tokio::test]
+    async fn it_exports_the_keyspace_for_a_reimport() -> Result<()> {
+        let tmpdir = TempDir::new("export_handler_test")?;
+        let state = test_state(tmpdir.path()).await?;
+        {
+            let mut db = state.db.write().await;
+            for chunk in (0..ENTRIES).collect::<Vec<_>>().chunks(500) {
+                let mut batch = WriteBatch::new();
+                for i in chunk {
+                    batch.set(
+                        format!("item:{:05}", i).as_bytes(),
+                        format!("value {}", i).as_bytes(),
+                    );
+                }
+                db.write(batch).await?;
+                db.flush().await?;
+            }
+            db.delete(b"item:00042").await?;
+            db.set(b"other", b"value").await?;
+        }
+        let admin = router::create_admin(test_admin_state(&state, tmpdir.path()).await);
+
+        let request = Request::get("/admin/export?prefix=item:").body(Body::empty())?;
+        let response = admin.clone().oneshot(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-ndjson"
+        );
+        assert!(response.headers()[header::CONTENT_DISPOSITION]
+            .to_str()?
+            .starts_with("attachment"));
+
+        // a flush in the middle of the download changes nothing
+        let mut body = response.into_body();
+        let mut exported = Vec::new();
+        let mut chunks = 0;
+        while let Some(chunk) = tokio::time::timeout(Duration::from_secs(5), body.data()).await? {
+            exported.extend_from_slice(&chunk?);
+            chunks += 1;
+            if chunks == 1 {
+                let mut db = state.db.write().await;
+                db.set(b"item:99999", b"written during the export").await?;
+                db.flush().await?;
+            }
+        }
+        assert!(chunks > 1);
+
+        let import_dir = TempDir::new("export_handler_import")?;
+        let mut imported = DatabaseBuilder::new(import_dir.path().to_path_buf())
+            .build()
+            .await?;
+        let mut keys = Vec::new();
+        for line in std::str::from_utf8(&exported)?.lines() {
+            let entry: DbEntry = serde_json::from_str(line)?;
+            imported.set(&entry.key, &entry.value).await?;
+            keys.push(entry.key);
+        }
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(keys.len(), ENTRIES);
+        assert!(!keys.contains(&b"item:00042".to_vec()));
+        assert_eq!(keys.last().unwrap(), b"item:99999");
+        assert_eq!(
+            imported.get(b"item:02999").await.unwrap().value,
+            &b"value 2999"[..]
+        );
+        assert!(imported.get(b"other").await.is_none());
+
+        // only the writes since the timestamp
+        let since = state.db.write().await.set(b"item:new", b"value").await?;
+        let uri = format!("/admin/export?since_timestamp={}", since);
+        let response = admin
+            .oneshot(Request::get(uri).body(Body::empty())?)
+            .await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let entry: DbEntry = serde_json::from_slice(&body)?;
+        assert_eq!(entry.key, b"item:new");
+        assert_eq!(body.iter().filter(|byte| **byte == b'\n').count(), 1);
+
+        import_dir.close()?;
+        tmpdir.close()?;
+        Ok(())
+    }
+}
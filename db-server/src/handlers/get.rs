@@ -1,35 +1,81 @@
-use std::sync::Arc;
-
 use axum::{
-    extract::{Path, State},
+    extract::Path,
+    http::header,
+    response::{IntoResponse, Response},
     Json,
 };
 use serde::Serialize;
 
-use crate::app_state::AppState;
+use super::{
+    etag::etag,
+    namespace::{KeyPath, NamespaceDb},
+};
+use crate::app_error::AppError;
 
 #[derive(Serialize)]
 pub struct Entry {
     key: String,
     value: String,
     timestamp: u128,
+    /// Microseconds since the Unix epoch, `None` for a value which never expires
+    expires_at: Option<u128>,
 }
 
+/// A value stored with a content type is sent as is under that type, the others as an UTF-8
+/// string in a JSON entry. A missing or expired key is a 404. The `ETag` is the timestamp of the entry,
+/// for the `If-Match` of the writes.
 pub async fn get_handler(
-    State(state): State<AppState>,
-    Path(key): Path<String>,
-) -> Json<Option<Entry>> {
-    let db = Arc::clone(&state.db);
-    let db_entry = db.lock().await.get(key.as_bytes()).await;
-
-    let mut entry = None;
-    if let Some(data) = db_entry {
-        entry = Some(Entry {
-            key: String::from_utf8_lossy(&data.key).into_owned(),
-            value: String::from_utf8_lossy(&data.value).into_owned(),
-            timestamp: data.timestamp,
-        })
+    NamespaceDb(db): NamespaceDb,
+    Path(KeyPath { key }): Path<KeyPath>,
+) -> Result<Response, AppError> {
+    let db_entry = db.read().await.get(key.as_bytes()).await;
+
+    let Some(data) = db_entry else {
+        return Err(AppError::NotFound(key));
+    };
+    let etag = etag(data.timestamp);
+    if let Some(content_type) = data.content_type() {
+        return Ok((
+            [(header::CONTENT_TYPE, content_type.to_owned())],
+            [(header::ETAG, etag)],
+            data.value,
+        )
+            .into_response());
     }
+    let entry = Entry {
+        key: String::from_utf8_lossy(&data.key).into_owned(),
+        value: String::from_utf8_lossy(&data.value).into_owned(),
+        timestamp: data.timestamp,
+        expires_at: data.expires_at(),
+    };
+
+    Ok(([(header::ETAG, etag)], Json(entry)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::{Method, StatusCode};
+    use tempdir::TempDir;
 
-    Json(entry)
+    use crate::handlers::test_client::{send, test_state};
+
+    #[tokio::test]
+    async fn it_answers_404_for_a_missing_key() -> Result<()> {
+        let tmpdir = TempDir::new("get_handler_test")?;
+        let state = test_state(tmpdir.path()).await?;
+
+        let (status, body) = send(&state, Method::GET, "/api/entry/missing", "").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "key_not_found");
+        assert_eq!(body["message"], "Key `missing` not found.");
+
+        send(&state, Method::POST, "/api/entry/deleted", "value").await?;
+        send(&state, Method::DELETE, "/api/entry/deleted", "").await?;
+        let (status, _) = send(&state, Method::GET, "/api/entry/deleted", "").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        tmpdir.close()?;
+        Ok(())
+    }
 }
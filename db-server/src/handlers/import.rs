@@ -0,0 +1,335 @@
+use std::{io, pin::Pin};
+
+use async_compression::tokio::bufread::GzipDecoder;
+use axum::{
+    extract::{BodyStream, Query, State},
+    http::{header, HeaderMap},
+    Json,
+};
+use db_engine::{DbEntry, WriteBatch};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio_util::io::StreamReader;
+
+use crate::{app_error::AppError, app_state::AdminState};
+
+/// Records written with one WAL append
+const IMPORT_BATCH_LEN: usize = 500;
+
+/// Room for the key, the timestamps and the JSON around the base64 value of a line
+const LINE_OVERHEAD: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+pub struct ImportParams {
+    /// Validate the records without writing them
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct ImportResponse {
+    /// Records written, or the valid ones on a dry run
+    imported: u64,
+    /// Lines which are not a record, e.g. a truncated line
+    skipped: u64,
+    /// Records refused, e.g. a value longer than the limit
+    failed: u64,
+    dry_run: bool,
+    /// `false` when the import stopped before the end of the body. The records counted in
+    /// `imported` are written and stay, the ones after them are not.
+    complete: bool,
+    /// Why the import stopped early
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Import the NDJSON of [`export_handler`](super::export::export_handler), gzip-encoded when
+/// the `Content-Encoding` says so. The body is read a line at a time, so any size goes, and
+/// written in batches with new timestamps.
+pub async fn import_handler(
+    State(state): State<AdminState>,
+    Query(params): Query<ImportParams>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Result<Json<ImportResponse>, AppError> {
+    let body = StreamReader::new(body.map_err(io::Error::other));
+    let mut reader: Pin<Box<dyn AsyncBufRead + Send>> = match headers
+        .get(header::CONTENT_ENCODING)
+        .map(|value| value.as_bytes())
+    {
+        None | Some(b"identity") => Box::pin(body),
+        Some(b"gzip") => Box::pin(BufReader::new(GzipDecoder::new(body))),
+        Some(encoding) => {
+            return Err(AppError::UnsupportedMediaType(format!(
+                "Unsupported Content-Encoding {:?}, expected gzip or identity.",
+                String::from_utf8_lossy(encoding)
+            )))
+        }
+    };
+
+    let max_line_len = state.max_value_size / 3 * 4 + LINE_OVERHEAD;
+    let max_key_len = state.db.read().await.max_field_len();
+    let mut response = ImportResponse {
+        dry_run: params.dry_run,
+        ..ImportResponse::default()
+    };
+    let mut batch = WriteBatch::new();
+    let mut line = Vec::new();
+    loop {
+        let record = match read_line(&mut reader, &mut line, max_line_len).await {
+            Ok(Some(Line::Complete)) => parse_record(&line, state.max_value_size, max_key_len),
+            Ok(Some(Line::TooLong)) => Record::TooLarge,
+            Ok(None) => {
+                response.complete = true;
+                break;
+            }
+            Err(e) => {
+                response.error = Some(format!("Read the body: {}", e));
+                break;
+            }
+        };
+        match record {
+            Record::Entry(entry) => {
+                batch.put(&entry);
+            }
+            Record::Blank => {}
+            Record::Malformed => response.skipped += 1,
+            Record::TooLarge => response.failed += 1,
+        }
+        if batch.len() >= IMPORT_BATCH_LEN {
+            if let Err(e) = write_batch(&state, &mut batch, &mut response).await {
+                response.error = Some(e);
+                break;
+            }
+        }
+    }
+    // the records read in full before an error are written too
+    if let Err(e) = write_batch(&state, &mut batch, &mut response).await {
+        response.complete = false;
+        response.error.get_or_insert(e);
+    }
+    if !response.complete {
+        tracing::warn!("Import stopped early: {:?}", response);
+    }
+    Ok(Json(response))
+}
+
+enum Record {
+    Entry(DbEntry),
+    /// An empty line, not counted
+    Blank,
+    Malformed,
+    TooLarge,
+}
+
+fn parse_record(line: &[u8], max_value_size: usize, max_key_len: usize) -> Record {
+    if line.trim_ascii().is_empty() {
+        return Record::Blank;
+    }
+    match serde_json::from_slice::<DbEntry>(line) {
+        Ok(entry) if entry.value.len() > max_value_size || entry.key.len() > max_key_len => {
+            Record::TooLarge
+        }
+        Ok(entry) => Record::Entry(entry),
+        Err(_) => Record::Malformed,
+    }
+}
+
+/// Write the `batch` unless it is a dry run, counting its records as imported
+async fn write_batch(
+    state: &AdminState,
+    batch: &mut WriteBatch,
+    response: &mut ImportResponse,
+) -> Result<(), String> {
+    let batch = std::mem::take(batch);
+    let len = batch.len() as u64;
+    if !response.dry_run && !batch.is_empty() {
+        if let Err(e) = state.db.write().await.write(batch).await {
+            response.failed += len;
+            return Err(format!("Write the records: {:#}", e));
+        }
+    }
+    response.imported += len;
+    Ok(())
+}
+
+enum Line {
+    Complete,
+    /// Longer than the limit, skipped to its end
+    TooLong,
+}
+
+/// Read the next line into `line`, without its newline and at most `max_len` bytes long.
+/// `None` at the end of the input.
+async fn read_line(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    line: &mut Vec<u8>,
+    max_len: usize,
+) -> io::Result<Option<Line>> {
+    line.clear();
+    let (mut read_any, mut too_long) = (false, false);
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(read_any.then_some(match too_long {
+                true => Line::TooLong,
+                false => Line::Complete,
+            }));
+        }
+        read_any = true;
+        let newline = available.iter().position(|byte| *byte == b'\n');
+        let part = &available[..newline.unwrap_or(available.len())];
+        if !too_long && line.len() + part.len() > max_len {
+            too_long = true;
+            line.clear();
+        }
+        if !too_long {
+            line.extend_from_slice(part);
+        }
+        let consumed = newline.map_or(available.len(), |newline| newline + 1);
+        reader.consume(consumed);
+        if newline.is_some() {
+            return Ok(Some(match too_long {
+                true => Line::TooLong,
+                false => Line::Complete,
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use async_compression::tokio::write::GzipEncoder;
+    use axum::{
+        body::{Body, Bytes},
+        http::{Request, StatusCode},
+        Router,
+    };
+    use db_engine::DatabaseBuilder;
+    use serde_json::Value;
+    use tempdir::TempDir;
+    use tokio::io::AsyncWriteExt;
+    use tower::ServiceExt;
+
+    use crate::{
+        handlers::test_client::{test_admin_state, test_state},
+        router,
+    };
+
+    const ENTRIES: usize = 1200;
+
+    async fn import(admin: &Router, uri: &str, encoding: &str, body: Body) -> Result<Value> {
+        let request = Request::post(uri)
+            .header("content-encoding", encoding)
+            .body(body)?;
+        let response = admin.clone().oneshot(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    #[tokio::test]
+    async fn it_imports_an_export() -> Result<()> {
+        // exported by another database
+        let export_dir = TempDir::new("import_handler_export")?;
+        let mut source = DatabaseBuilder::new(export_dir.path().to_path_buf())
+            .build()
+            .await?;
+        for i in 0..ENTRIES {
+            source
+                .set(
+                    format!("key{:05}", i).as_bytes(),
+                    format!("value {}", i).as_bytes(),
+                )
+                .await?;
+        }
+        source
+            .set_typed(b"typed", br#"{"a":1}"#, "application/json")
+            .await?;
+        let mut ndjson = Vec::new();
+        for entry in source.scan_prefix(b"").await? {
+            serde_json::to_writer(&mut ndjson, &entry)?;
+            ndjson.push(b'\n');
+        }
+        ndjson.extend_from_slice(b"\n{\"key\": \"truncated\n");
+        let too_large = format!(
+            "{{\"key\":\"Ymln\",\"value\":\"{}\",\"timestamp\":1}}\n",
+            "A".repeat(4096)
+        );
+        ndjson.extend_from_slice(too_large.as_bytes());
+
+        let tmpdir = TempDir::new("import_handler_test")?;
+        let state = test_state(tmpdir.path()).await?;
+        let mut admin_state = test_admin_state(&state, tmpdir.path()).await;
+        admin_state.max_value_size = 1024;
+        let admin = router::create_admin(admin_state);
+
+        let dry_run = import(
+            &admin,
+            "/admin/import?dry_run=true",
+            "identity",
+            Body::from(ndjson.clone()),
+        )
+        .await?;
+        assert_eq!(dry_run["imported"], ENTRIES + 1);
+        assert_eq!(dry_run["dry_run"], true);
+        assert!(state.db.read().await.get(b"key00000").await.is_none());
+
+        let mut gzip = GzipEncoder::new(Vec::new());
+        gzip.write_all(&ndjson).await?;
+        gzip.shutdown().await?;
+        let report = import(
+            &admin,
+            "/admin/import",
+            "gzip",
+            Body::from(gzip.into_inner()),
+        )
+        .await?;
+        assert_eq!(report["imported"], ENTRIES + 1);
+        assert_eq!(report["skipped"], 1);
+        assert_eq!(report["failed"], 1);
+        assert_eq!(report["complete"], true);
+        let db = state.db.read().await;
+        assert_eq!(db.scan_prefix(b"key").await?.len(), ENTRIES);
+        let typed = db.get(b"typed").await.unwrap();
+        assert_eq!(typed.content_type(), Some("application/json"));
+        assert!(db.get(b"big").await.is_none());
+        drop(db);
+
+        // cut short: the records read before stay written
+        let lines = ndjson.split_inclusive(|byte| *byte == b'\n');
+        let first_lines = lines.take(10).flatten().copied().collect::<Vec<_>>();
+        let chunks: Vec<Result<Bytes, std::io::Error>> = vec![
+            Ok(Bytes::from(first_lines)),
+            Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "client went away",
+            )),
+        ];
+        let report = import(
+            &admin,
+            "/admin/import",
+            "identity",
+            Body::wrap_stream(futures_util::stream::iter(chunks)),
+        )
+        .await?;
+        assert_eq!(report["imported"], 10);
+        assert_eq!(report["complete"], false);
+        assert!(report["error"]
+            .as_str()
+            .unwrap()
+            .contains("client went away"));
+
+        let request = Request::post("/admin/import")
+            .header("content-encoding", "br")
+            .body(Body::empty())?;
+        let response = admin.oneshot(request).await?;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        export_dir.close()?;
+        tmpdir.close()?;
+        Ok(())
+    }
+}
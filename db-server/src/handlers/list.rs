@@ -0,0 +1,151 @@
+use axum::{extract::Query, Json};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+
+use super::namespace::NamespaceDb;
+use crate::app_error::AppError;
+
+/// Most entries of a page, whatever the `limit` asked for.
+const MAX_LIMIT: usize = 1000;
+
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    #[serde(default)]
+    prefix: String,
+    limit: Option<usize>,
+    /// The `next_cursor` of the previous page
+    cursor: Option<String>,
+    #[serde(default)]
+    include_values: bool,
+}
+
+#[derive(Serialize)]
+pub struct ListEntry {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ListResponse {
+    entries: Vec<ListEntry>,
+    /// Where the next page starts, `None` after the last page
+    next_cursor: Option<String>,
+}
+
+/// A page of the keys starting with `prefix`, in ascending order. The engine is scanned a few
+/// keys at a time, so a large `limit` does not load the whole prefix.
+pub async fn list_handler(
+    NamespaceDb(db): NamespaceDb,
+    Query(params): Query<ListParams>,
+) -> Result<Json<ListResponse>, AppError> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let mut start_after = match params.cursor {
+        Some(cursor) => Some(
+            URL_SAFE_NO_PAD
+                .decode(cursor)
+                .map_err(|_| AppError::BadRequest(String::from("Invalid cursor.")))?,
+        ),
+        None => None,
+    };
+
+    let db = db.read().await;
+    let mut entries = Vec::new();
+    // the tombstones take room in a page of the engine, scan until the page is full
+    while entries.len() < limit {
+        let (page, next_start) = db
+            .scan_prefix_page(
+                params.prefix.as_bytes(),
+                start_after.as_deref(),
+                limit - entries.len(),
+            )
+            .await?;
+        entries.extend(page.into_iter().map(|entry| {
+            ListEntry {
+                key: String::from_utf8_lossy(&entry.key).into_owned(),
+                value: params
+                    .include_values
+                    .then(|| String::from_utf8_lossy(&entry.value).into_owned()),
+            }
+        }));
+        start_after = next_start;
+        if start_after.is_none() {
+            break;
+        }
+    }
+
+    Ok(Json(ListResponse {
+        entries,
+        next_cursor: start_after.map(|key| URL_SAFE_NO_PAD.encode(key)),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::{Method, StatusCode};
+    use tempdir::TempDir;
+
+    use crate::handlers::test_client::{send, test_state};
+
+    #[tokio::test]
+    async fn it_pages_through_the_keys() -> Result<()> {
+        let tmpdir = TempDir::new("list_handler_test")?;
+        let state = test_state(tmpdir.path()).await?;
+        {
+            let mut db = state.db.write().await;
+            for i in 0..2_600 {
+                db.set(format!("user{:04}", i).as_bytes(), b"value").await?;
+            }
+            db.set(b"zebra", b"value").await?;
+            db.flush().await?;
+            // 100 tombstones among the first keys
+            for i in (0..200).step_by(2) {
+                db.delete(format!("user{:04}", i).as_bytes()).await?;
+            }
+        }
+
+        let mut keys = Vec::new();
+        let mut page_sizes = Vec::new();
+        let mut uri = String::from("/api/entries?prefix=user&limit=1000");
+        loop {
+            let (status, page) = send(&state, Method::GET, &uri, "").await?;
+            assert_eq!(status, StatusCode::OK);
+            let entries = page["entries"].as_array().unwrap();
+            page_sizes.push(entries.len());
+            keys.extend(
+                entries
+                    .iter()
+                    .map(|entry| entry["key"].as_str().unwrap().to_owned()),
+            );
+            assert!(entries.iter().all(|entry| entry.get("value").is_none()));
+            match page["next_cursor"].as_str() {
+                Some(cursor) => {
+                    uri = format!("/api/entries?prefix=user&limit=1000&cursor={}", cursor)
+                }
+                None => break,
+            }
+        }
+        assert_eq!(page_sizes, [1000, 1000, 500]);
+        let expected = (1..200)
+            .step_by(2)
+            .chain(200..2_600)
+            .map(|i| format!("user{:04}", i))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+
+        // the values on demand, the server caps the limit
+        let uri = "/api/entries?prefix=user&limit=5000&include_values=true";
+        let (_, page) = send(&state, Method::GET, uri, "").await?;
+        assert_eq!(page["entries"].as_array().unwrap().len(), 1000);
+        assert_eq!(page["entries"][0]["value"], "value");
+
+        let (status, _) = send(&state, Method::GET, "/api/entries?cursor=%21", "").await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
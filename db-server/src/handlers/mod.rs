@@ -1,5 +1,15 @@
+mod admin;
 mod delete;
 mod error_handler;
+mod etag;
+mod export;
 mod get;
+mod import;
+mod list;
+mod namespace;
 pub mod prelude;
+mod replication;
 mod set;
+#[cfg(test)]
+pub(crate) mod test_client;
+mod watch;
@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path},
+    http::request::Parts,
+};
+use serde::Deserialize;
+
+use crate::{app_error::AppError, app_state::AppState, db_handle::DbHandle};
+
+/// The database of the request: the one of the `:namespace` of the route when it has one,
+/// see [`Namespaces`](crate::namespaces::Namespaces), the default database otherwise.
+pub struct NamespaceDb(pub DbHandle);
+
+#[async_trait]
+impl FromRequestParts<AppState> for NamespaceDb {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        let Path(params) = Path::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| AppError::BadRequest(e.body_text()))?;
+        match params.get("namespace") {
+            Some(namespace) => Ok(Self(state.namespaces.get(namespace).await?)),
+            None => Ok(Self(state.db.clone())),
+        }
+    }
+}
+
+/// The `:key` of the entry routes, alongside the `:namespace` if any
+#[derive(Deserialize)]
+pub struct KeyPath {
+    pub key: String,
+}
@@ -1,4 +1,10 @@
+pub use super::admin::{compact_handler, create_namespace_handler, flush_handler, stats_handler};
 pub use super::delete::delete_handler;
-pub use super::error_handler::not_found_handler;
+pub use super::error_handler::{not_found_handler, ErrorResponse};
+pub use super::export::export_handler;
 pub use super::get::get_handler;
-pub use super::set::set_handler;
+pub use super::import::import_handler;
+pub use super::list::list_handler;
+pub use super::replication::replication_handler;
+pub use super::set::{set_handler, MAX_TTL_SECONDS};
+pub use super::watch::watch_handler;
@@ -0,0 +1,53 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use db_engine::CdcError;
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{app_error::AppError, app_state::AdminState};
+
+#[derive(Deserialize)]
+pub struct ReplicationParams {
+    /// Timestamp of the last change the replica applied, 0 for all of them. A string, the
+    /// query strings do not carry a u128.
+    from: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LaggedData {
+    resume_from: u128,
+}
+
+/// Stream the changes of the database after `from` to a replica as server-sent events, see
+/// [`Database::cdc_stream`](db_engine::Database::cdc_stream): a `change` event with the JSON
+/// of each [`ChangeEvent`](db_engine::ChangeEvent). A replica reading too slowly gets a
+/// `lagged` event with the timestamp to resume from, then the stream ends. The stream also
+/// ends on the shutdown of the server. The namespaces are not replicated.
+pub async fn replication_handler(
+    State(state): State<AdminState>,
+    Query(params): Query<ReplicationParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let from = match params.from.as_deref() {
+        Some(from) => from
+            .parse()
+            .map_err(|_| AppError::BadRequest(format!("Invalid timestamp `{}`.", from)))?,
+        None => 0,
+    };
+    let changes = state.db.read().await.cdc_stream(from).await?;
+    let stream = changes
+        .map(|change| {
+            let event = match change {
+                Ok(change) => Event::default().event("change").json_data(change),
+                Err(CdcError::Lagged { resume_from }) => Event::default()
+                    .event("lagged")
+                    .json_data(LaggedData { resume_from }),
+            };
+            Ok(event.expect("serializable"))
+        })
+        .take_until(state.shutdown.cancelled_owned());
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
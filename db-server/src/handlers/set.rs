@@ -1,20 +1,180 @@
+use std::time::Duration;
+
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     Json,
 };
+use serde::{Deserialize, Serialize};
+
+use super::{
+    etag::{check_if_match, etag},
+    namespace::{KeyPath, NamespaceDb},
+};
+use db_engine::Database;
+use tokio::sync::OwnedRwLockWriteGuard;
+
+use crate::{app_error::AppError, app_state::AppState, timeout::write_within};
 
-use crate::{app_error::AppError, app_state::AppState};
+/// Longest `ttl_seconds`, 10 years.
+pub const MAX_TTL_SECONDS: i64 = 10 * 365 * 24 * 60 * 60;
 
+#[derive(Deserialize)]
+pub struct SetParams {
+    /// Seconds until the value expires, 0 or none for never
+    ttl_seconds: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SetResponse {
+    key: String,
+    timestamp: u128,
+    /// Microseconds since the Unix epoch, `None` for a value which never expires
+    expires_at: Option<u128>,
+}
+
+/// Write the request body as the value of `key`, answers with the timestamp of the write and
+/// its `ETag`. With an `If-Match` header, only overwrites the entry of that `ETag`. With
+/// `ttl_seconds`, the value reads as deleted once they passed.
 pub async fn set_handler(
     State(state): State<AppState>,
-    Path(key): Path<String>,
+    NamespaceDb(db): NamespaceDb,
+    Path(KeyPath { key }): Path<KeyPath>,
+    Query(params): Query<SetParams>,
+    headers: HeaderMap,
     value: String, // get the value from request body
-) -> Result<Json<usize>, AppError> {
-    let db = state.db.clone();
-    let result = db
-        .lock()
-        .await
-        .set(key.as_bytes(), value.as_bytes())
-        .await?;
-    Ok(Json(result))
+) -> Result<
+    (
+        StatusCode,
+        [(header::HeaderName, HeaderValue); 1],
+        Json<SetResponse>,
+    ),
+    AppError,
+> {
+    let ttl = match params.ttl_seconds.unwrap_or_default() {
+        0 => None,
+        ttl_seconds @ 1..=MAX_TTL_SECONDS => Some(Duration::from_secs(ttl_seconds as u64)),
+        ttl_seconds => {
+            return Err(AppError::UnprocessableEntity(format!(
+                "ttl_seconds must be between 0 and {}, got {}.",
+                MAX_TTL_SECONDS, ttl_seconds
+            )))
+        }
+    };
+
+    let write_key = key.clone();
+    let write = move |mut db: OwnedRwLockWriteGuard<Database>| async move {
+        let key = write_key;
+        if headers.contains_key(header::IF_MATCH) {
+            let current = db.get(key.as_bytes()).await;
+            check_if_match(&headers, &key, current.as_ref())?;
+        }
+        Ok(match ttl {
+            Some(ttl) => {
+                let timestamp = db
+                    .set_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
+                    .await?;
+                (timestamp, Some(timestamp + ttl.as_micros()))
+            }
+            None => (db.set(key.as_bytes(), value.as_bytes()).await?, None),
+        })
+    };
+    let (timestamp, expires_at) = write_within(state.request_timeout, &db, write).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::ETAG, etag(timestamp))],
+        Json(SetResponse {
+            key,
+            timestamp,
+            expires_at,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    use anyhow::Result;
+    use axum::http::{Method, StatusCode};
+    use db_engine::Clock;
+    use tempdir::TempDir;
+
+    use crate::handlers::test_client::{send, test_state, test_state_with_clock};
+
+    /// One microsecond further on every call, and as far as the test moves it
+    struct ManualClock(AtomicU64);
+
+    impl ManualClock {
+        fn advance(&self, secs: u64) {
+            self.0.fetch_add(secs * 1_000_000, Ordering::Relaxed);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Result<u128> {
+            Ok(u128::from(self.0.fetch_add(1, Ordering::Relaxed)))
+        }
+    }
+
+    #[tokio::test]
+    async fn it_writes_the_value() -> Result<()> {
+        let tmpdir = TempDir::new("set_handler_test")?;
+        let state = test_state(tmpdir.path()).await?;
+
+        let (status, created) = send(&state, Method::POST, "/api/entry/hello", "world").await?;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(created["key"], "hello");
+        assert!(created["timestamp"].as_u64().is_some());
+
+        let (status, entry) = send(&state, Method::GET, "/api/entry/hello", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(entry["key"], "hello");
+        assert_eq!(entry["value"], "world");
+        assert_eq!(entry["timestamp"], created["timestamp"]);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_expires_the_value_after_the_ttl() -> Result<()> {
+        let tmpdir = TempDir::new("set_handler_ttl_test")?;
+        let clock = Arc::new(ManualClock(AtomicU64::new(1_000_000)));
+        let state = test_state_with_clock(tmpdir.path(), Arc::clone(&clock) as _).await?;
+
+        let uri = "/api/entry/session?ttl_seconds=300";
+        let (status, created) = send(&state, Method::POST, uri, "token").await?;
+        assert_eq!(status, StatusCode::CREATED);
+        let expires_at = created["timestamp"].as_u64().unwrap() + 300_000_000;
+        assert_eq!(created["expires_at"], expires_at);
+        let uri = "/api/entry/forever?ttl_seconds=0";
+        let (_, created) = send(&state, Method::POST, uri, "value").await?;
+        assert!(created["expires_at"].is_null());
+
+        let (status, entry) = send(&state, Method::GET, "/api/entry/session", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(entry["expires_at"], expires_at);
+
+        clock.advance(300);
+        let (status, _) = send(&state, Method::GET, "/api/entry/session", "").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, entry) = send(&state, Method::GET, "/api/entry/forever", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(entry["expires_at"].is_null());
+
+        for ttl_seconds in ["-1", "315360001"] {
+            let uri = format!("/api/entry/session?ttl_seconds={}", ttl_seconds);
+            let (status, body) = send(&state, Method::POST, &uri, "token").await?;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(body["error"], "unprocessable_entity");
+            assert!(body["message"].as_str().unwrap().contains(ttl_seconds));
+        }
+
+        tmpdir.close()?;
+        Ok(())
+    }
 }
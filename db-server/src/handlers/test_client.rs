@@ -0,0 +1,112 @@
+use std::{path::Path, sync::Arc, time::Instant};
+
+use anyhow::Result;
+use axum::{
+    body::Body,
+    http::{HeaderMap, Method, Request, StatusCode},
+    Router,
+};
+use db_engine::{Clock, DatabaseBuilder, HybridClock};
+use serde_json::Value;
+use tokio_util::sync::CancellationToken;
+use tower::ServiceExt;
+
+use crate::{
+    app_state::{AdminState, AppState},
+    config::Config,
+    db_handle::DbHandle,
+    namespaces::Namespaces,
+    rate_limit::{RateLimiter, RateLimits},
+    router,
+    scheduler::{Scheduler, SchedulerConfig},
+};
+
+/// The state of a server over a database in `dir`, without rate limits
+pub async fn test_state(dir: &Path) -> Result<AppState> {
+    test_state_with_clock(dir, Arc::new(HybridClock)).await
+}
+
+/// Like [`test_state`], the timestamps of the database taken from `clock`
+pub async fn test_state_with_clock(dir: &Path, clock: Arc<dyn Clock>) -> Result<AppState> {
+    let db = DatabaseBuilder::new(dir.to_path_buf())
+        .clock(clock)
+        .build()
+        .await?;
+    let max_mem_table_size = Config::default().max_mem_table_size;
+    Ok(AppState {
+        db: DbHandle::new(db),
+        namespaces: Namespaces::new(dir.join("namespaces"), max_mem_table_size),
+        rate_limits: RateLimits::default(),
+        compress_from: None,
+        cors: None,
+        max_value_size: Config::default().max_value_size,
+        request_timeout: Config::default().request_timeout(),
+        error_counts: Default::default(),
+        shutdown: CancellationToken::new(),
+    })
+}
+
+/// The state of the admin routes over the database of `state` in `dir`, its scheduler only
+/// compacts on demand and without a size limit
+pub async fn test_admin_state(state: &AppState, dir: &Path) -> AdminState {
+    let config = SchedulerConfig {
+        enabled: false,
+        compact_limit: u64::MAX,
+        ..SchedulerConfig::from(&Config::default())
+    };
+    AdminState {
+        db: state.db.clone(),
+        namespaces: state.namespaces.clone(),
+        scheduler: Scheduler::new(dir.to_path_buf(), config, state.db.clone())
+            .with_namespaces(state.namespaces.clone())
+            .spawn(),
+        rate_limit: RateLimiter::default(),
+        max_value_size: state.max_value_size,
+        started_at: Instant::now(),
+        error_counts: state.error_counts.clone(),
+        shutdown: state.shutdown.clone(),
+    }
+}
+
+/// Send a request through the API router, returns the status and the JSON body of the
+/// response.
+pub async fn send(
+    state: &AppState,
+    method: Method,
+    uri: &str,
+    body: &str,
+) -> Result<(StatusCode, Value)> {
+    send_to(&router::create(state.clone()), method, uri, body).await
+}
+
+/// Like [`send`], through any `router`
+pub async fn send_to(
+    router: &Router,
+    method: Method,
+    uri: &str,
+    body: &str,
+) -> Result<(StatusCode, Value)> {
+    let (status, _, body) = send_with_headers(router, method, uri, &[], body).await?;
+    Ok((status, body))
+}
+
+/// Like [`send_to`], with the `headers` of the request, also returns the headers of the
+/// response.
+pub async fn send_with_headers(
+    router: &Router,
+    method: Method,
+    uri: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(StatusCode, HeaderMap, Value)> {
+    let mut request = Request::builder().method(method).uri(uri);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let request = request.body(Body::from(body.to_owned()))?;
+    let response = router.clone().oneshot(request).await?;
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok((status, headers, serde_json::from_slice(&body)?))
+}
@@ -0,0 +1,125 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use db_engine::{ChangeEvent, ChangeKind};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+
+use super::namespace::NamespaceDb;
+use crate::app_state::AppState;
+
+#[derive(Deserialize)]
+pub struct WatchParams {
+    #[serde(default)]
+    prefix: String,
+}
+
+#[derive(Serialize)]
+struct ChangeData {
+    operation: ChangeKind,
+    key: String,
+    timestamp: u128,
+}
+
+#[derive(Serialize)]
+struct LaggedData {
+    /// Events dropped because the client read too slowly
+    missed: u64,
+}
+
+/// Stream the sets and deletes of the keys starting with `prefix` as server-sent events, a
+/// `change` event with the JSON of each write. A client reading too slowly misses the oldest
+/// writes and gets a `lagged` event with their count instead, the writers never wait for it.
+/// The stream ends on the shutdown of the server.
+pub async fn watch_handler(
+    State(state): State<AppState>,
+    NamespaceDb(db): NamespaceDb,
+    Query(params): Query<WatchParams>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = BroadcastStream::new(db.read().await.subscribe());
+    let prefix = params.prefix.into_bytes();
+    let stream = events
+        .filter_map(move |event| {
+            let event = match event {
+                Ok(ChangeEvent {
+                    kind,
+                    key,
+                    timestamp,
+                    ..
+                }) => key.starts_with(&prefix).then(|| {
+                    Event::default().event("change").json_data(ChangeData {
+                        operation: kind,
+                        key: String::from_utf8_lossy(&key).into_owned(),
+                        timestamp,
+                    })
+                }),
+                Err(BroadcastStreamRecvError::Lagged(missed)) => Some(
+                    Event::default()
+                        .event("lagged")
+                        .json_data(LaggedData { missed }),
+                ),
+            };
+            std::future::ready(event.map(|event| Ok(event.expect("serializable"))))
+        })
+        .take_until(state.shutdown.cancelled_owned());
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use axum::{
+        body::{Body, HttpBody},
+        http::{header, Request, StatusCode},
+    };
+    use tempdir::TempDir;
+    use tower::ServiceExt;
+
+    use crate::{handlers::test_client::test_state, router};
+
+    #[tokio::test]
+    async fn it_streams_the_writes_of_the_prefix() -> Result<()> {
+        let tmpdir = TempDir::new("watch_handler_test")?;
+        let state = test_state(tmpdir.path()).await?;
+        let request = Request::get("/api/watch?prefix=user:").body(Body::empty())?;
+        let response = router::create(state.clone()).oneshot(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+
+        state.db.write().await.set(b"other", b"value").await?;
+        let timestamp = state.db.write().await.set(b"user:1", b"alice").await?;
+        state.db.write().await.delete(b"user:1").await?;
+
+        let mut body = response.into_body();
+        let mut received = String::new();
+        while received.matches("event:change").count() < 2 {
+            let chunk = tokio::time::timeout(Duration::from_secs(1), body.data())
+                .await?
+                .unwrap()?;
+            received.push_str(std::str::from_utf8(&chunk)?);
+        }
+        assert!(received.contains(&format!(
+            "data:{{\"operation\":\"set\",\"key\":\"user:1\",\"timestamp\":{}}}",
+            timestamp
+        )));
+        assert!(received.contains("\"operation\":\"delete\""));
+        assert!(!received.contains("other"));
+
+        // the stream ends with the server
+        state.shutdown.cancel();
+        let end = tokio::time::timeout(Duration::from_secs(1), body.data()).await?;
+        assert!(end.is_none());
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
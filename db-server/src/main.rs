@@ -1,41 +1,178 @@
 mod app_error;
 mod app_server;
 mod app_state;
+mod config;
+mod cors;
+mod db_handle;
+mod grpc;
 mod handlers;
+mod namespaces;
+mod rate_limit;
+mod replication;
+mod request_id;
+mod resp;
 mod router;
 mod scheduler;
+mod timeout;
 
-use std::net::SocketAddr;
+use std::time::Instant;
 
-use anyhow::{Context, Result};
-use app_server::AppServerBuilder;
+use anyhow::{bail, Context, Result};
+use app_server::{shutdown_signal, AppServerBuilder};
+use app_state::{AdminState, AppState};
+use config::{Config, LogFormat};
+use grpc::KvService;
+use replication::Replicator;
+use resp::RespServer;
 use scheduler::Scheduler;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    init_tracing_subscriber();
+    let config = Config::load().context("load config")?;
+    init_tracing_subscriber(config.log_format);
+    tracing::info!("Starting with {:?}", config);
+
+    // Cancelled on SIGINT or SIGTERM, every task winds down from it
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.cancel();
+        }
+    });
+
+    let api_state = AppState::new(&config, shutdown.clone())
+        .await
+        .context("create API AppState")?;
+    let (db, namespaces) = (api_state.db.clone(), api_state.namespaces.clone());
 
     // To run database compaction in the background
-    let scheduler = Scheduler::new("./db", 50 * 1024 * 1024);
-    tokio::spawn(async move { scheduler.perform().await });
+    let scheduler = Scheduler::new(
+        config.data_dir.clone(),
+        (&config).into(),
+        api_state.db.clone(),
+    )
+    .with_namespaces(api_state.namespaces.clone())
+    .spawn();
+
+    // The admin routes listen on the loopback interface unless configured otherwise
+    let admin = router::create_admin(AdminState {
+        db: api_state.db.clone(),
+        namespaces: api_state.namespaces.clone(),
+        scheduler: scheduler.clone(),
+        rate_limit: api_state.rate_limits.admin.clone(),
+        max_value_size: config.max_value_size,
+        started_at: Instant::now(),
+        error_counts: api_state.error_counts.clone(),
+        shutdown: shutdown.clone(),
+    });
+    let with_tls = |builder: AppServerBuilder| match config.tls() {
+        Some((cert_path, key_path)) => builder.with_tls(cert_path, key_path),
+        None => builder,
+    };
+    let admin_server = with_tls(AppServerBuilder::new(admin))
+        .with_socket_address(config.admin_bind_addr)
+        .with_shutdown(shutdown.clone())
+        .build();
+
+    // The Redis protocol listener, bound now so a taken port fails the start
+    let resp = match config.resp_bind_addr {
+        Some(addr) => Some((
+            RespServer::new(api_state.db.clone(), config.max_value_size),
+            TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("bind RESP listener to {}", addr))?,
+        )),
+        None => None,
+    };
+
+    // The gRPC listener, on the same state as the API routes
+    let grpc = match config.grpc_bind_addr {
+        Some(addr) => Some((
+            KvService::new(api_state.clone()),
+            TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("bind gRPC listener to {}", addr))?,
+        )),
+        None => None,
+    };
+
+    // A replica follows the change stream of its primary until the shutdown
+    let replicator = config
+        .replicate_from
+        .as_deref()
+        .map(|primary| Replicator::new(primary, api_state.db.clone()));
 
     // Start the Database API server
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
-    let app = router::create().await.context("create router")?;
-    let app_server = AppServerBuilder::new(app).with_socket_address(addr).build();
+    let app = router::create(api_state);
+    let app_server = with_tls(AppServerBuilder::new(app))
+        .with_socket_address(config.bind_addr)
+        .with_shutdown(shutdown.clone())
+        .build();
 
-    app_server.start().await.context("start api server")?;
-    Ok(())
+    let serve_then_close = async {
+        tokio::try_join!(
+            async { app_server.start().await.context("start api server") },
+            async { admin_server.start().await.context("start admin server") },
+            async {
+                match resp {
+                    Some((server, listener)) => server
+                        .serve(listener, shutdown.clone())
+                        .await
+                        .context("serve RESP"),
+                    None => Ok(()),
+                }
+            },
+            async {
+                match grpc {
+                    Some((service, listener)) => service.serve(listener, shutdown.clone()).await,
+                    None => Ok(()),
+                }
+            },
+            async {
+                if let Some(replicator) = replicator {
+                    replicator.run(shutdown.clone()).await;
+                }
+                Ok(())
+            },
+            // stop compacting while the requests drain, after the compaction in progress
+            async {
+                shutdown.cancelled().await;
+                scheduler.shutdown().await;
+                Ok(())
+            },
+        )?;
+        tracing::info!("Shutdown: servers and scheduler stopped, closing the database");
+        db.write().await.close().await.context("close database")?;
+        namespaces.close().await?;
+        tracing::info!("Shutdown: database closed");
+        Ok(())
+    };
+    let deadline = async {
+        shutdown.cancelled().await;
+        tokio::time::sleep(config.shutdown_timeout()).await;
+    };
+    tokio::select! {
+        result = serve_then_close => result,
+        _ = deadline => bail!("shutdown took longer than {:?}", config.shutdown_timeout()),
+    }
 }
 
-fn init_tracing_subscriber() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                "db_server=debug,tower_http=debug,axum::rejection=trace".into()
-            }),
-        )
-        .with(tracing_subscriber::fmt::layer().json())
-        .init();
+fn init_tracing_subscriber(log_format: LogFormat) {
+    let registry = tracing_subscriber::registry().with(
+        tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| "db_server=debug,tower_http=debug,axum::rejection=trace".into()),
+    );
+    match log_format {
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json())
+            .init(),
+        LogFormat::Pretty => registry
+            .with(tracing_subscriber::fmt::layer().pretty())
+            .init(),
+    }
 }
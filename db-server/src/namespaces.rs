@@ -0,0 +1,242 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result};
+use db_engine::DatabaseBuilder;
+use tokio::sync::RwLock;
+
+use crate::{app_error::AppError, db_handle::DbHandle};
+
+/// Longest name of a namespace
+const MAX_NAME_LEN: usize = 64;
+
+/// Names taken by the routes of the default database, `/api/entry/:key` and the like
+const RESERVED_NAMES: [&str; 3] = ["entry", "entries", "watch"];
+
+/// The isolated databases served under `/api/:namespace`, each in its own directory of
+/// `dir`. A namespace is created by [`Namespaces::create`], then opened on its first request
+/// after a restart. Cheap to clone.
+#[derive(Clone)]
+pub struct Namespaces(Arc<Inner>);
+
+struct Inner {
+    dir: PathBuf,
+    max_mem_table_size: usize,
+    open: RwLock<HashMap<String, DbHandle>>,
+}
+
+impl Namespaces {
+    pub fn new(dir: PathBuf, max_mem_table_size: usize) -> Self {
+        Self(Arc::new(Inner {
+            dir,
+            max_mem_table_size,
+            open: RwLock::default(),
+        }))
+    }
+
+    /// The database of the namespace `name`, opened on first use. A 404 when it was never
+    /// created.
+    pub async fn get(&self, name: &str) -> Result<DbHandle, AppError> {
+        validate_name(name)?;
+        if let Some(db) = self.0.open.read().await.get(name) {
+            return Ok(db.clone());
+        }
+        let dir = self.0.dir.join(name);
+        if !tokio::fs::try_exists(&dir).await? {
+            return Err(AppError::NamespaceNotFound(name.to_owned()));
+        }
+        Ok(self.open(name, dir).await?)
+    }
+
+    /// Create the namespace `name` and open its database, `false` when it already existed
+    pub async fn create(&self, name: &str) -> Result<bool, AppError> {
+        validate_name(name)?;
+        let dir = self.0.dir.join(name);
+        let created = !tokio::fs::try_exists(&dir).await?;
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("create namespace dir {:?}", dir))?;
+        self.open(name, dir).await?;
+        Ok(created)
+    }
+
+    /// The namespaces opened since the start, sorted by name
+    pub async fn open_namespaces(&self) -> Vec<(String, DbHandle)> {
+        let mut namespaces = self
+            .0
+            .open
+            .read()
+            .await
+            .iter()
+            .map(|(name, db)| (name.clone(), db.clone()))
+            .collect::<Vec<_>>();
+        namespaces.sort_by(|(a, _), (b, _)| a.cmp(b));
+        namespaces
+    }
+
+    /// Close the database of every open namespace, see [`db_engine::Database::close`]
+    pub async fn close(&self) -> Result<()> {
+        for (name, db) in self.open_namespaces().await {
+            db.write()
+                .await
+                .close()
+                .await
+                .with_context(|| format!("close namespace {}", name))?;
+        }
+        Ok(())
+    }
+
+    async fn open(&self, name: &str, dir: PathBuf) -> Result<DbHandle> {
+        let mut open = self.0.open.write().await;
+        // opened by another request while this one waited for the lock
+        if let Some(db) = open.get(name) {
+            return Ok(db.clone());
+        }
+        tracing::info!("Open namespace {} in {:?}", name, dir);
+        let db = DatabaseBuilder::new(dir)
+            .max_mem_table_size(self.0.max_mem_table_size)
+            .enable_metrics(true)
+            .build()
+            .await
+            .with_context(|| format!("open namespace {}", name))?;
+        let db = DbHandle::new(db);
+        open.insert(name.to_owned(), db.clone());
+        Ok(db)
+    }
+}
+
+/// A name is ASCII letters, digits, `-` and `_`, so it can never leave the directory of the
+/// namespaces, e.g. with `..` or a slash.
+fn validate_name(name: &str) -> Result<(), AppError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+        && !RESERVED_NAMES.contains(&name);
+    match valid {
+        true => Ok(()),
+        false => Err(AppError::BadRequest(format!(
+            "Invalid namespace `{}`, expected up to {} letters, digits, `-` or `_`.",
+            name, MAX_NAME_LEN
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::{Method, StatusCode};
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{
+        handlers::test_client::{send, send_to, test_admin_state, test_state},
+        router,
+    };
+
+    #[test]
+    fn it_rejects_the_names_leaving_the_directory() {
+        for name in ["tenant-1", "Tenant_2"] {
+            assert!(validate_name(name).is_ok());
+        }
+        let too_long = "a".repeat(MAX_NAME_LEN + 1);
+        for name in [
+            "",
+            "..",
+            "a/b",
+            "a\\b",
+            ".hidden",
+            "entry",
+            too_long.as_str(),
+        ] {
+            assert!(validate_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_isolates_the_namespaces() -> Result<()> {
+        let tmpdir = TempDir::new("namespaces_test")?;
+        let state = test_state(tmpdir.path()).await?;
+        let admin_state = test_admin_state(&state, tmpdir.path()).await;
+        let scheduler = admin_state.scheduler.clone();
+        let admin = router::create_admin(admin_state);
+
+        let (status, _) = send(&state, Method::GET, "/api/tenant/entry/key", "").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, body) = send(&state, Method::POST, "/api/tenant/entry/key", "v").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "namespace_not_found");
+        let (status, _) = send(&state, Method::GET, "/api/%2E%2E/entry/key", "").await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        for expected in [StatusCode::CREATED, StatusCode::OK] {
+            let (status, body) =
+                send_to(&admin, Method::PUT, "/admin/namespaces/tenant", "").await?;
+            assert_eq!(status, expected);
+            assert_eq!(body["name"], "tenant");
+        }
+        let (status, _) = send_to(&admin, Method::PUT, "/admin/namespaces/..", "").await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        send(&state, Method::POST, "/api/tenant/entry/key", "tenant").await?;
+        send(&state, Method::POST, "/api/entry/key", "default").await?;
+        let (_, body) = send(&state, Method::GET, "/api/tenant/entry/key", "").await?;
+        assert_eq!(body["value"], "tenant");
+        let (_, body) = send(&state, Method::GET, "/api/entry/key", "").await?;
+        assert_eq!(body["value"], "default");
+        let (_, body) = send(&state, Method::GET, "/api/tenant/entries", "").await?;
+        assert_eq!(body["entries"].as_array().unwrap().len(), 1);
+        send(&state, Method::DELETE, "/api/tenant/entry/key", "").await?;
+        let (_, body) = send(&state, Method::GET, "/api/entry/key", "").await?;
+        assert_eq!(body["value"], "default");
+
+        // compacted along with the default database
+        let (_, tenant) = state.namespaces.open_namespaces().await.remove(0);
+        for key in ["a", "b"] {
+            let mut db = tenant.write().await;
+            db.set(key.as_bytes(), b"value").await?;
+            db.flush().await?;
+        }
+        let (status, _) = send_to(&admin, Method::POST, "/admin/compact", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(tenant.read().await.sstable_files().await?.len(), 1);
+
+        // opened again from its directory after a restart
+        send(&state, Method::POST, "/api/tenant/entry/kept", "value").await?;
+        state.namespaces.close().await?;
+        scheduler.shutdown().await;
+        drop((state, admin, tenant, scheduler));
+        let restarted = test_state(tmpdir.path()).await?;
+        assert!(restarted.namespaces.open_namespaces().await.is_empty());
+        let (status, body) = send(&restarted, Method::GET, "/api/tenant/entry/kept", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["value"], "value");
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_leaves_the_namespaces_out_of_the_default_disk_usage() -> Result<()> {
+        let tmpdir = TempDir::new("namespaces_test")?;
+        let state = test_state(tmpdir.path()).await?;
+        send(&state, Method::POST, "/api/entry/key", "default").await?;
+        state.db.write().await.flush().await?;
+        let disk_usage = state.db.read().await.stats().disk_usage;
+
+        let admin = router::create_admin(test_admin_state(&state, tmpdir.path()).await);
+        send_to(&admin, Method::PUT, "/admin/namespaces/tenant", "").await?;
+        let value = "v".repeat(10_000);
+        send(&state, Method::POST, "/api/tenant/entry/key", &value).await?;
+        let (_, tenant) = state.namespaces.open_namespaces().await.remove(0);
+        tenant.write().await.flush().await?;
+
+        // measured again from the files of its directory
+        let (status, _) = send_to(&admin, Method::POST, "/admin/compact", "").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(state.db.read().await.stats().disk_usage, disk_usage);
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{app_error::AppError, config::Config};
+
+/// Clients tracked before the idle ones are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// The budget of every route group, see [`Config::rate_limit_enabled`].
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    /// `GET` on the API
+    pub reads: RateLimiter,
+    /// `POST` and `DELETE` on the API
+    pub writes: RateLimiter,
+    /// Every admin route
+    pub admin: RateLimiter,
+}
+
+impl From<&Config> for RateLimits {
+    fn from(config: &Config) -> Self {
+        if !config.rate_limit_enabled {
+            return Self::default();
+        }
+        Self {
+            reads: RateLimiter::new(config.rate_limit_reads_per_sec),
+            writes: RateLimiter::new(config.rate_limit_writes_per_sec),
+            admin: RateLimiter::new(config.rate_limit_admin_per_sec),
+        }
+    }
+}
+
+/// A token bucket per client IP: a client may send up to a second of requests at once, then
+/// as many per second as the budget. The default limiter lets everything through.
+#[derive(Clone, Default)]
+pub struct RateLimiter(Option<Arc<Buckets>>);
+
+struct Buckets {
+    per_sec: f64,
+    clients: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    /// `per_sec` requests a second for every client, 0 for no limit
+    pub fn new(per_sec: u32) -> Self {
+        match per_sec {
+            0 => Self(None),
+            per_sec => Self(Some(Arc::new(Buckets {
+                per_sec: f64::from(per_sec),
+                clients: Mutex::default(),
+            }))),
+        }
+    }
+
+    /// Take a token from the bucket of `client`, or tell how long until the next one
+    fn acquire(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let Some(buckets) = self.0.as_ref() else {
+            return Ok(());
+        };
+        let per_sec = buckets.per_sec;
+        let mut clients = buckets.clients.lock().unwrap();
+        if clients.len() >= MAX_TRACKED_CLIENTS && !clients.contains_key(&client) {
+            // the buckets full again are the same as new ones
+            clients.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * per_sec
+                    < per_sec
+            });
+        }
+        let bucket = clients.entry(client).or_insert(Bucket {
+            tokens: per_sec,
+            updated_at: now,
+        });
+        let refill = now.duration_since(bucket.updated_at).as_secs_f64() * per_sec;
+        bucket.tokens = (bucket.tokens + refill).min(per_sec);
+        bucket.updated_at = now;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Answer 429 with a `Retry-After` header to the clients over the budget of the `limiter`.
+/// The client is the IP of the connection, the requests without one share a bucket.
+pub async fn rate_limit_middleware<B>(
+    State(limiter): State<RateLimiter>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    match limiter.acquire(client, Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            tracing::warn!("Rate limit exceeded by {}", client);
+            AppError::TooManyRequests { retry_after }.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::{
+        body::Body,
+        http::{header, Method, StatusCode},
+    };
+    use tempdir::TempDir;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{handlers::test_client::test_state, router};
+
+    #[test]
+    fn it_refills_the_bucket_of_each_client_over_time() {
+        let limiter = RateLimiter::new(2);
+        let (client, other) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        let now = Instant::now();
+        assert!(limiter.acquire(client, now).is_ok());
+        assert!(limiter.acquire(client, now).is_ok());
+        assert_eq!(
+            limiter.acquire(client, now),
+            Err(Duration::from_millis(500))
+        );
+        assert!(limiter.acquire(other, now).is_ok());
+        assert!(limiter
+            .acquire(client, now + Duration::from_millis(500))
+            .is_ok());
+
+        let unlimited = RateLimiter::new(0);
+        assert!((0..100).all(|_| unlimited.acquire(client, now).is_ok()));
+    }
+
+    #[tokio::test]
+    async fn it_answers_429_over_the_budget_of_the_route_group() -> Result<()> {
+        let tmpdir = TempDir::new("rate_limit_test")?;
+        let mut state = test_state(tmpdir.path()).await?;
+        state.rate_limits = RateLimits {
+            writes: RateLimiter::new(1),
+            ..RateLimits::default()
+        };
+        let router = router::create(state);
+        let send = |method: Method, client: [u8; 4]| {
+            let mut request = Request::builder()
+                .method(method)
+                .uri("/api/entry/hello")
+                .body(Body::from("world"))
+                .unwrap();
+            let addr = SocketAddr::from((client, 4000));
+            request.extensions_mut().insert(ConnectInfo(addr));
+            router.clone().oneshot(request)
+        };
+
+        assert_eq!(
+            send(Method::POST, [10, 0, 0, 1]).await?.status(),
+            StatusCode::CREATED
+        );
+        let response = send(Method::DELETE, [10, 0, 0, 1]).await?;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let body: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(body["error"], "rate_limited");
+
+        // the reads and the other clients have their own budget
+        assert_eq!(
+            send(Method::GET, [10, 0, 0, 1]).await?.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(Method::DELETE, [10, 0, 0, 2]).await?.status(),
+            StatusCode::OK
+        );
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use axum::{body::HttpBody, http::Uri};
+use db_engine::{ChangeEvent, Entry};
+use hyper::{client::HttpConnector, Client};
+use tokio_util::sync::CancellationToken;
+
+use crate::db_handle::DbHandle;
+
+/// Wait before connecting again after the change stream of the primary ends, doubled after
+/// each failure up to [`MAX_RETRY_DELAY`]
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Follows the primary of [`Config::replicate_from`](crate::config::Config::replicate_from):
+/// applies the changes of its `/admin/replication` stream to the database of the replica,
+/// resuming after the last one applied on every connection.
+pub struct Replicator {
+    primary: String,
+    db: DbHandle,
+    client: Client<HttpConnector>,
+}
+
+impl Replicator {
+    pub fn new(primary: &str, db: DbHandle) -> Self {
+        Self {
+            primary: primary.trim_end_matches('/').to_string(),
+            db,
+            client: Client::new(),
+        }
+    }
+
+    /// Follow the primary until `shutdown`, connecting again whenever the stream ends: the
+    /// primary restarts, or the replica fell too far behind
+    pub async fn run(self, shutdown: CancellationToken) {
+        tracing::info!("Start replicating from {}", self.primary);
+        let mut retry_delay = MIN_RETRY_DELAY;
+        loop {
+            let followed = tokio::select! {
+                followed = self.follow() => followed,
+                _ = shutdown.cancelled() => break,
+            };
+            match followed {
+                Ok(applied) => {
+                    tracing::info!(
+                        "The change stream of the primary ended after {} changes",
+                        applied
+                    );
+                    retry_delay = MIN_RETRY_DELAY;
+                }
+                Err(e) => {
+                    tracing::error!("Error while replicating from {}: {:#}", self.primary, e);
+                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(retry_delay) => {}
+                _ = shutdown.cancelled() => break,
+            }
+        }
+        tracing::info!("Stop replicating");
+    }
+
+    /// Apply the changes of one connection to the primary, returns how many
+    async fn follow(&self) -> Result<u64> {
+        // the entries of a batch share their timestamp, the last one may have been cut short
+        let from = self
+            .db
+            .read()
+            .await
+            .last_applied_timestamp()
+            .saturating_sub(1);
+        let uri: Uri = format!("{}/admin/replication?from={}", self.primary, from).parse()?;
+        let response = self
+            .client
+            .get(uri)
+            .await
+            .context("connect to the primary")?;
+        if !response.status().is_success() {
+            bail!("the primary answered {}", response.status());
+        }
+
+        let mut body = response.into_body();
+        let (mut buffer, mut applied) = (Vec::new(), 0);
+        while let Some(chunk) = body.data().await {
+            buffer.extend_from_slice(&chunk.context("read the change stream")?);
+            while let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+                let message = buffer.drain(..end + 2).collect::<Vec<_>>();
+                match parse_event(&message)? {
+                    Some(("change", data)) => {
+                        let change: ChangeEvent =
+                            serde_json::from_str(&data).context("parse a change")?;
+                        self.db
+                            .write()
+                            .await
+                            .apply_replicated(Entry::from(change))
+                            .await?;
+                        applied += 1;
+                    }
+                    Some(("lagged", _)) => {
+                        tracing::warn!("Fell behind the primary, resuming from the last change");
+                        return Ok(applied);
+                    }
+                    // the keep-alive comments
+                    _ => {}
+                }
+            }
+        }
+        Ok(applied)
+    }
+}
+
+/// The name and the data of a server-sent event, `None` for a comment
+fn parse_event(message: &[u8]) -> Result<Option<(&str, String)>> {
+    let message = std::str::from_utf8(message).context("read a server-sent event")?;
+    let (mut name, mut data) = (None, Vec::new());
+    for line in message.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            name = Some(value.trim_start());
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push(value.trim_start());
+        }
+    }
+    Ok(name.map(|name| (name, data.join("\n"))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use anyhow::Result;
+    use db_engine::{DatabaseBuilder, Error};
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{
+        handlers::test_client::{test_admin_state, test_state},
+        router,
+    };
+
+    /// Wait until the replica has applied the write of the primary at `timestamp`
+    async fn caught_up(replica: &DbHandle, timestamp: u128) -> Result<()> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while replica.read().await.last_applied_timestamp() < timestamp {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_applies_the_changes_of_the_primary() -> Result<()> {
+        let (primary_dir, replica_dir) = (TempDir::new("primary")?, TempDir::new("replica")?);
+        let state = test_state(primary_dir.path()).await?;
+        let admin = router::create_admin(test_admin_state(&state, primary_dir.path()).await);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let primary = format!("http://{}/", listener.local_addr()?);
+        tokio::spawn(axum::Server::from_tcp(listener)?.serve(admin.into_make_service()));
+
+        let replica = DbHandle::new(
+            DatabaseBuilder::new(replica_dir.path().to_path_buf())
+                .replica(true)
+                .build()
+                .await?,
+        );
+        let follow = |shutdown: CancellationToken| {
+            tokio::spawn(Replicator::new(&primary, replica.clone()).run(shutdown))
+        };
+
+        let before = state.db.write().await.set(b"before", b"1").await?;
+        let shutdown = CancellationToken::new();
+        let replicator = follow(shutdown.clone());
+        caught_up(&replica, before).await?;
+        let after = state
+            .db
+            .write()
+            .await
+            .set_typed(b"after", b"{}", "application/json")
+            .await?;
+        caught_up(&replica, after).await?;
+        let entry = replica.read().await.get(b"after").await.unwrap();
+        assert_eq!(
+            (entry.timestamp, entry.content_type()),
+            (after, Some("application/json"))
+        );
+        assert!(matches!(
+            replica
+                .write()
+                .await
+                .delete(b"after")
+                .await
+                .unwrap_err()
+                .downcast_ref(),
+            Some(Error::ReadOnlyReplica)
+        ));
+        shutdown.cancel();
+        replicator.await?;
+
+        // a new replicator resumes after the last change applied
+        state.db.write().await.delete(b"before").await?;
+        let last = state.db.write().await.set(b"last", b"2").await?;
+        let shutdown = CancellationToken::new();
+        let replicator = follow(shutdown.clone());
+        caught_up(&replica, last).await?;
+        assert!(replica.read().await.get(b"before").await.is_none());
+        shutdown.cancel();
+        replicator.await?;
+
+        primary_dir.close()?;
+        replica_dir.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn it_parses_the_server_sent_events() -> Result<()> {
+        let event = parse_event(b"event:change\ndata:{\"key\":\"a2V5\"}\n\n")?;
+        assert_eq!(event, Some(("change", String::from("{\"key\":\"a2V5\"}"))));
+        assert_eq!(parse_event(b":\n\n")?, None);
+        assert!(parse_event(&[0xff, b'\n', b'\n']).is_err());
+        Ok(())
+    }
+}
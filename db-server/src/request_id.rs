@@ -0,0 +1,144 @@
+use axum::{
+    http::{HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+/// Header carrying the id of a request, taken from the client or generated, and sent back
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest id accepted from a client, a longer one is replaced
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Give every request an id: the `x-request-id` of the client when it is short and printable,
+/// a new UUID otherwise. The handlers see it in the header and through [`current`], the
+/// response echoes it.
+pub async fn request_id_middleware<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .filter(|id| id.bytes().all(|byte| byte.is_ascii_graphic()))
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let header_value = HeaderValue::from_str(&request_id).expect("checked or generated above");
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER.clone(), header_value.clone());
+
+    let mut response = REQUEST_ID.scope(request_id, next.run(request)).await;
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER.clone(), header_value);
+    response
+}
+
+/// The id of the request being handled, `None` outside of [`request_id_middleware`]
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(String::clone).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use anyhow::Result;
+    use axum::http::{Method, StatusCode};
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{
+        handlers::test_client::{send_with_headers, test_state},
+        router,
+    };
+
+    #[tokio::test]
+    async fn it_tags_the_errors_with_the_request_id() -> Result<()> {
+        let tmpdir = TempDir::new("request_id_test")?;
+        let router = router::create(test_state(tmpdir.path()).await?);
+
+        let headers = [("x-request-id", "client-id-1")];
+        let (status, response_headers, body) =
+            send_with_headers(&router, Method::GET, "/api/entry/missing", &headers, "").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(response_headers[&REQUEST_ID_HEADER], "client-id-1");
+        assert_eq!(body["request_id"], "client-id-1");
+
+        // generated when missing or unfit, for the unknown routes too
+        let long_id = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for headers in [vec![], vec![("x-request-id", long_id.as_str())]] {
+            let (status, response_headers, body) =
+                send_with_headers(&router, Method::GET, "/unknown", &headers, "").await?;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let request_id = response_headers[&REQUEST_ID_HEADER].to_str()?;
+            assert!(Uuid::parse_str(request_id).is_ok());
+            assert_eq!(body["request_id"], request_id);
+        }
+
+        assert!(current().is_none());
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    /// The JSON logs written while it is the default subscriber
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn it_logs_every_response_with_the_request_id_and_the_latency() -> Result<()> {
+        let tmpdir = TempDir::new("access_log_test")?;
+        let router = router::create(test_state(tmpdir.path()).await?);
+        let logs = Logs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let headers = [("x-request-id", "client-id-2")];
+        send_with_headers(&router, Method::GET, "/api/entry/missing", &headers, "").await?;
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone())?;
+        let access_log = logs
+            .lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .find(|line| line["fields"]["message"] == "Response sent")
+            .expect("an access log line");
+        assert_eq!(access_log["level"], "INFO");
+        assert_eq!(access_log["fields"]["status"], 404);
+        assert!(access_log["fields"]["latency_ms"].as_f64().unwrap() >= 0.0);
+        let span = &access_log["span"];
+        assert_eq!(span["method"], "GET");
+        assert_eq!(span["path"], "/api/entry/missing");
+        assert_eq!(span["matched_path"], "/api/entry/:key");
+        assert_eq!(span["request_id"], "client-id-2");
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
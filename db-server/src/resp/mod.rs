@@ -0,0 +1,488 @@
+//! A listener speaking the Redis protocol (RESP2), for the Redis clients and tools, see
+//! [`Config::resp_bind_addr`](crate::config::Config::resp_bind_addr). It serves the default
+//! database with a few of the Redis commands: `PING`, `GET`, `SET`, `DEL`, `EXISTS`,
+//! `EXPIRE`, `TTL`, `SCAN` and `QUIT`.
+
+mod protocol;
+
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::db_handle::DbHandle;
+use protocol::{parse_int, read_command, Reply};
+
+/// Replies held back for the rest of a pipeline before they are sent anyway
+const MAX_PENDING_REPLIES_LEN: usize = 64 * 1024;
+
+/// Cursors of `SCAN` remembered, the oldest ones are forgotten past it
+const MAX_OPEN_CURSORS: usize = 1024;
+
+/// Keys of a `SCAN` page without a `COUNT`, as in Redis
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+const MAX_SCAN_COUNT: usize = 1000;
+
+pub struct RespServer {
+    db: DbHandle,
+    /// The longest bulk string of a command, see
+    /// [`Config::max_value_size`](crate::config::Config::max_value_size)
+    max_value_size: usize,
+    cursors: Mutex<Cursors>,
+}
+
+/// The keys the `SCAN`s continue after, by cursor. A cursor is a number as the clients
+/// expect, the key itself could be any bytes.
+#[derive(Default)]
+struct Cursors {
+    last_id: u64,
+    open: BTreeMap<u64, Vec<u8>>,
+}
+
+impl RespServer {
+    pub fn new(db: DbHandle, max_value_size: usize) -> Self {
+        Self {
+            db,
+            max_value_size,
+            cursors: Mutex::default(),
+        }
+    }
+
+    /// Serve the connections of the `listener` until the `shutdown`, then wait for them to end
+    /// after their command in progress.
+    pub async fn serve(self, listener: TcpListener, shutdown: CancellationToken) -> Result<()> {
+        tracing::info!("Listening for RESP on {}", listener.local_addr()?);
+        let server = Arc::new(self);
+        let connections = TaskTracker::new();
+        loop {
+            let (stream, peer) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // e.g. out of file descriptors, give the connections time to close
+                        tracing::warn!("Failed to accept a RESP connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+            };
+            let (server, shutdown) = (Arc::clone(&server), shutdown.clone());
+            connections.spawn(async move {
+                if let Err(e) = server.handle_connection(stream, shutdown).await {
+                    tracing::debug!("RESP connection of {} lost: {}", peer, e);
+                }
+            });
+        }
+        connections.close();
+        connections.wait().await;
+        tracing::info!("RESP listener stopped");
+        Ok(())
+    }
+
+    /// Run the commands of the client in order. The replies of a pipeline are sent together,
+    /// once no command is left to read.
+    async fn handle_connection(
+        &self,
+        stream: TcpStream,
+        shutdown: CancellationToken,
+    ) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut replies = Vec::new();
+        loop {
+            let command = tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                command = read_command(&mut reader, self.max_value_size) => command,
+            };
+            let args = match command {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    Reply::error(format!("Protocol error: {}", e)).write_to(&mut replies);
+                    return writer.write_all(&replies).await;
+                }
+                Err(e) => return Err(e),
+            };
+            let Some(name) = args.first() else {
+                continue;
+            };
+            let quit = name.eq_ignore_ascii_case(b"QUIT");
+            self.execute(args).await.write_to(&mut replies);
+            if quit || reader.buffer().is_empty() || replies.len() > MAX_PENDING_REPLIES_LEN {
+                writer.write_all(&replies).await?;
+                replies.clear();
+            }
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn execute(&self, args: Vec<Vec<u8>>) -> Reply {
+        let (command, args) = args.split_first().expect("checked by the caller");
+        let name = String::from_utf8_lossy(command).to_ascii_lowercase();
+        let result = match name.as_str() {
+            "ping" => ping(args),
+            "get" => self.get(args).await,
+            "set" => self.set(args).await,
+            "del" => self.del(args).await,
+            "exists" => self.exists(args).await,
+            "expire" => self.expire(args).await,
+            "ttl" => self.ttl(args).await,
+            "scan" => self.scan(args).await,
+            "quit" => Ok(Reply::Simple("OK")),
+            _ => {
+                let args = args
+                    .iter()
+                    .map(|arg| format!("'{}' ", String::from_utf8_lossy(arg)))
+                    .collect::<String>();
+                return Reply::error(format!(
+                    "unknown command '{}', with args beginning with: {}",
+                    String::from_utf8_lossy(command),
+                    args
+                ));
+            }
+        };
+        result.unwrap_or_else(|e| {
+            tracing::debug!("RESP command {} failed: {:#}", name, e);
+            Reply::error(format!("{:#}", e))
+        })
+    }
+
+    async fn get(&self, args: &[Vec<u8>]) -> Result<Reply> {
+        let [key] = args else {
+            bail!(wrong_arity("get"));
+        };
+        let entry = self.db.read().await.get(key).await;
+        Ok(Reply::Bulk(entry.map(|entry| entry.value.to_vec())))
+    }
+
+    /// `SET key value [EX seconds | PX milliseconds]`
+    async fn set(&self, args: &[Vec<u8>]) -> Result<Reply> {
+        let [key, value, options @ ..] = args else {
+            bail!(wrong_arity("set"));
+        };
+        let ttl = match options {
+            [] => None,
+            [unit, amount] => {
+                let amount = parse_int(amount)
+                    .filter(|amount| *amount > 0)
+                    .ok_or_else(|| anyhow!("invalid expire time in 'set' command"))?;
+                match unit.to_ascii_uppercase().as_slice() {
+                    b"EX" => Some(Duration::from_secs(amount as u64)),
+                    b"PX" => Some(Duration::from_millis(amount as u64)),
+                    _ => bail!("syntax error"),
+                }
+            }
+            _ => bail!("syntax error"),
+        };
+        let mut db = self.db.write().await;
+        match ttl {
+            Some(ttl) => db.set_with_ttl(key, value, ttl).await?,
+            None => db.set(key, value).await?,
+        };
+        Ok(Reply::Simple("OK"))
+    }
+
+    /// The number of keys deleted, the missing ones not counted
+    async fn del(&self, args: &[Vec<u8>]) -> Result<Reply> {
+        if args.is_empty() {
+            bail!(wrong_arity("del"));
+        }
+        let mut db = self.db.write().await;
+        let mut deleted = 0;
+        for key in args {
+            if db.get(key).await.is_some() {
+                db.delete(key).await?;
+                deleted += 1;
+            }
+        }
+        Ok(Reply::Integer(deleted))
+    }
+
+    /// The number of keys found, a key given twice counted twice
+    async fn exists(&self, args: &[Vec<u8>]) -> Result<Reply> {
+        if args.is_empty() {
+            bail!(wrong_arity("exists"));
+        }
+        let db = self.db.read().await;
+        let mut found = 0;
+        for key in args {
+            found += i64::from(db.get(key).await.is_some());
+        }
+        Ok(Reply::Integer(found))
+    }
+
+    /// `EXPIRE key seconds`, a key expiring in 0 seconds or less is deleted. The value is
+    /// written again with the TTL, without the content type a value set over HTTP may have.
+    async fn expire(&self, args: &[Vec<u8>]) -> Result<Reply> {
+        let [key, seconds] = args else {
+            bail!(wrong_arity("expire"));
+        };
+        let seconds =
+            parse_int(seconds).ok_or_else(|| anyhow!("value is not an integer or out of range"))?;
+        let mut db = self.db.write().await;
+        let Some(entry) = db.get(key).await else {
+            return Ok(Reply::Integer(0));
+        };
+        if seconds > 0 {
+            let ttl = Duration::from_secs(seconds as u64);
+            db.set_with_ttl(key, &entry.value, ttl).await?;
+        } else {
+            db.delete(key).await?;
+        }
+        Ok(Reply::Integer(1))
+    }
+
+    /// Seconds until the key expires, -1 when it never does, -2 when it is missing
+    async fn ttl(&self, args: &[Vec<u8>]) -> Result<Reply> {
+        let [key] = args else {
+            bail!(wrong_arity("ttl"));
+        };
+        let Some(entry) = self.db.read().await.get(key).await else {
+            return Ok(Reply::Integer(-2));
+        };
+        let Some(expires_at) = entry.expires_at() else {
+            return Ok(Reply::Integer(-1));
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        // rounded to the closest second, as Redis does
+        let remaining = (expires_at.saturating_sub(now) + 500_000) / 1_000_000;
+        Ok(Reply::Integer(remaining as i64))
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`, the keys in ascending order. Only the
+    /// patterns of a prefix, e.g. `user:*`, or of a single key are supported.
+    async fn scan(&self, args: &[Vec<u8>]) -> Result<Reply> {
+        let [cursor, options @ ..] = args else {
+            bail!(wrong_arity("scan"));
+        };
+        let (mut pattern, mut count) = (&b"*"[..], DEFAULT_SCAN_COUNT);
+        for option in options.chunks(2) {
+            match option {
+                [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = value,
+                [name, value] if name.eq_ignore_ascii_case(b"COUNT") => {
+                    count = parse_int(value)
+                        .filter(|count| *count > 0)
+                        .ok_or_else(|| anyhow!("syntax error"))?
+                        .min(MAX_SCAN_COUNT as i64) as usize;
+                }
+                _ => bail!("syntax error"),
+            }
+        }
+        let (prefix, exact) = match pattern.split_last() {
+            Some((b'*', prefix)) if !is_glob(prefix) => (prefix, false),
+            _ if !is_glob(pattern) => (pattern, true),
+            _ => bail!("only the patterns of a prefix such as 'user:*' are supported"),
+        };
+        let start_after = match cursor.as_slice() {
+            b"0" => None,
+            cursor => {
+                let cursors = self.cursors.lock().unwrap();
+                let key = parse_int(cursor).and_then(|cursor| cursors.open.get(&(cursor as u64)));
+                Some(key.ok_or_else(|| anyhow!("invalid cursor"))?.clone())
+            }
+        };
+
+        let (entries, next_start) = self
+            .db
+            .read()
+            .await
+            .scan_prefix_page(prefix, start_after.as_deref(), count)
+            .await?;
+        let keys = entries
+            .into_iter()
+            .filter(|entry| !exact || entry.key == prefix)
+            .map(|entry| Reply::Bulk(Some(entry.key)))
+            .collect();
+        let next_cursor = match next_start {
+            Some(key) => self.cursors.lock().unwrap().open(key),
+            None => 0,
+        };
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next_cursor.to_string().into_bytes())),
+            Reply::Array(keys),
+        ]))
+    }
+}
+
+impl Cursors {
+    /// A new cursor continuing after `key`
+    fn open(&mut self, key: Vec<u8>) -> u64 {
+        if self.open.len() >= MAX_OPEN_CURSORS {
+            self.open.pop_first();
+        }
+        self.last_id += 1;
+        self.open.insert(self.last_id, key);
+        self.last_id
+    }
+}
+
+fn ping(args: &[Vec<u8>]) -> Result<Reply> {
+    match args {
+        [] => Ok(Reply::Simple("PONG")),
+        [message] => Ok(Reply::Bulk(Some(message.clone()))),
+        _ => bail!(wrong_arity("ping")),
+    }
+}
+
+fn wrong_arity(command: &str) -> String {
+    format!("wrong number of arguments for '{}' command", command)
+}
+
+fn is_glob(pattern: &[u8]) -> bool {
+    pattern
+        .iter()
+        .any(|byte| matches!(byte, b'*' | b'?' | b'[' | b'\\'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tempdir::TempDir;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    use super::*;
+    use crate::handlers::test_client::test_state;
+
+    /// Send `commands` in one write, as a pipeline, and read `expected_len` bytes of replies
+    async fn pipeline(
+        addr: SocketAddr,
+        commands: &[&[&str]],
+        expected_len: usize,
+    ) -> Result<String> {
+        let mut request = Vec::new();
+        for command in commands {
+            let args = command
+                .iter()
+                .map(|arg| Reply::Bulk(Some(arg.as_bytes().to_vec())))
+                .collect();
+            Reply::Array(args).write_to(&mut request);
+        }
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(&request).await?;
+        let mut replies = vec![0; expected_len];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut replies)).await??;
+        Ok(String::from_utf8(replies)?)
+    }
+
+    async fn reply_line(stream: &mut BufReader<TcpStream>) -> Result<String> {
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        Ok(line.trim_end_matches("\r\n").to_owned())
+    }
+
+    async fn bulk_string(stream: &mut BufReader<TcpStream>) -> Result<String> {
+        let len = reply_line(stream).await?[1..].parse::<usize>()?;
+        let mut bulk = vec![0; len + 2];
+        stream.read_exact(&mut bulk).await?;
+        bulk.truncate(len);
+        Ok(String::from_utf8(bulk)?)
+    }
+
+    #[tokio::test]
+    async fn it_answers_the_redis_commands() -> Result<()> {
+        let tmpdir = TempDir::new("resp_test")?;
+        let state = test_state(tmpdir.path()).await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(
+            RespServer::new(state.db.clone(), state.max_value_size)
+                .serve(listener, shutdown.clone()),
+        );
+
+        let expected = "+PONG\r\n+OK\r\n$5\r\nworld\r\n$-1\r\n:1\r\n:2\r\n:1\r\n:0\r\n:-2\r\n\
+            +OK\r\n:-1\r\n:1\r\n:100\r\n+OK\r\n:60\r\n\
+            -ERR unknown command 'FLUSHALL', with args beginning with: 'now' \r\n\
+            -ERR wrong number of arguments for 'get' command\r\n-ERR syntax error\r\n";
+        let replies = pipeline(
+            addr,
+            &[
+                &["PING"],
+                &["SET", "hello", "world"],
+                &["get", "hello"],
+                &["GET", "missing"],
+                &["EXISTS", "hello", "missing"],
+                &["EXISTS", "hello", "hello"],
+                &["DEL", "hello", "missing"],
+                &["DEL", "hello"],
+                &["TTL", "hello"],
+                &["SET", "session", "token"],
+                &["TTL", "session"],
+                &["EXPIRE", "session", "100"],
+                &["TTL", "session"],
+                &["SET", "cache", "value", "EX", "60"],
+                &["TTL", "cache"],
+                &["FLUSHALL", "now"],
+                &["GET"],
+                &["SET", "key", "value", "NX"],
+            ],
+            expected.len(),
+        )
+        .await?;
+        assert_eq!(replies, expected);
+
+        // every key of the prefix once, a page at a time
+        for i in 0..25 {
+            let key = format!("user:{:02}", i);
+            state.db.write().await.set(key.as_bytes(), b"value").await?;
+        }
+        let mut cursor = String::from("0");
+        let mut keys = Vec::new();
+        loop {
+            let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+            let mut request = Vec::new();
+            Reply::Array(
+                ["SCAN", &cursor, "MATCH", "user:*", "COUNT", "10"]
+                    .iter()
+                    .map(|arg| Reply::Bulk(Some(arg.as_bytes().to_vec())))
+                    .collect(),
+            )
+            .write_to(&mut request);
+            stream.get_mut().write_all(&request).await?;
+            // the next cursor, then the keys in a nested array
+            assert_eq!(reply_line(&mut stream).await?, "*2");
+            cursor = bulk_string(&mut stream).await?;
+            let len = reply_line(&mut stream).await?[1..].parse::<usize>()?;
+            assert!(len <= 10);
+            for _ in 0..len {
+                keys.push(bulk_string(&mut stream).await?);
+            }
+            if cursor == "0" {
+                break;
+            }
+        }
+        let expected_keys = (0..25)
+            .map(|i| format!("user:{:02}", i))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected_keys);
+
+        // a malformed command closes the connection
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"*1\r\n$x\r\n").await?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        assert_eq!(reply, "-ERR Protocol error: invalid bulk length\r\n");
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+        tmpdir.close()?;
+        Ok(())
+    }
+}
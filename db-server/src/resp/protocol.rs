@@ -0,0 +1,179 @@
+use std::{fmt::Display, io};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// Most arguments of a command
+const MAX_ARGS: i64 = 1024 * 1024;
+
+/// Longest line, a header or an inline command
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+/// A reply of the server, in RESP2
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// A status, e.g. `OK`
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    /// `None` for the null bulk string, e.g. for a missing key
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    /// A generic `ERR` error
+    pub fn error(message: impl Display) -> Self {
+        Self::Error(format!("ERR {}", message))
+    }
+
+    /// Append the reply to `out`
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Simple(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+            Self::Error(message) => {
+                // a line break would end the error early
+                let message = message.replace(['\r', '\n'], " ");
+                out.extend_from_slice(format!("-{}\r\n", message).as_bytes())
+            }
+            Self::Integer(value) => out.extend_from_slice(format!(":{}\r\n", value).as_bytes()),
+            Self::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Self::Bulk(Some(value)) => {
+                out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                out.extend_from_slice(value);
+                out.extend_from_slice(b"\r\n");
+            }
+            Self::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.write_to(out);
+                }
+            }
+        }
+    }
+}
+
+/// Read the next command of a client: an array of bulk strings, as the client libraries send,
+/// or an inline command, as typed in a telnet session. `None` once the client is gone. A
+/// malformed command is an [`io::ErrorKind::InvalidData`] error, the rest of the stream cannot
+/// be read after it.
+pub async fn read_command(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    max_bulk_len: usize,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader).await? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    };
+    let count = match parse_int(count) {
+        Some(count) if count <= MAX_ARGS => count.max(0),
+        _ => return Err(invalid_data("invalid multibulk length")),
+    };
+
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let line = read_line(reader)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let len = match line.strip_prefix(b"$").and_then(parse_int) {
+            Some(len) if (0..=max_bulk_len as i64).contains(&len) => len as usize,
+            _ => return Err(invalid_data("invalid bulk length")),
+        };
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid_data("bulk string not ended by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// The next line without its line break, `None` at the end of the stream
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+    match line.pop() {
+        None => Ok(None),
+        Some(b'\n') => {
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            Ok(Some(line))
+        }
+        Some(_) if line.len() as u64 + 1 == MAX_LINE_LEN => Err(invalid_data("line too long")),
+        Some(_) => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+pub(super) fn parse_int(digits: &[u8]) -> Option<i64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_all(mut input: &[u8]) -> io::Result<Vec<Vec<Vec<u8>>>> {
+        let mut commands = Vec::new();
+        while let Some(command) = read_command(&mut input, 16).await? {
+            commands.push(command);
+        }
+        Ok(commands)
+    }
+
+    #[tokio::test]
+    async fn it_reads_the_commands_of_a_pipeline() -> io::Result<()> {
+        let commands = read_all(b"*2\r\n$3\r\nGET\r\n$0\r\n\r\nPING  hello\r\n*0\r\n").await?;
+        assert_eq!(
+            commands,
+            [
+                vec![b"GET".to_vec(), Vec::new()],
+                vec![b"PING".to_vec(), b"hello".to_vec()],
+                vec![],
+            ]
+        );
+
+        for (input, kind) in [
+            (&b"*2\r\n$3\r\nGET\r\n"[..], io::ErrorKind::UnexpectedEof),
+            (b"*1\r\n$17\r\n", io::ErrorKind::InvalidData),
+            (b"*1\r\n:3\r\nGET\r\n", io::ErrorKind::InvalidData),
+            (b"*1\r\n$3\r\nGETS\r\n", io::ErrorKind::InvalidData),
+            (b"*x\r\n", io::ErrorKind::InvalidData),
+        ] {
+            assert_eq!(read_all(input).await.unwrap_err().kind(), kind);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn it_writes_the_replies() {
+        let mut out = Vec::new();
+        Reply::Array(vec![
+            Reply::Simple("OK"),
+            Reply::error("bad\r\ncommand"),
+            Reply::Integer(-2),
+            Reply::Bulk(Some(b"value".to_vec())),
+            Reply::Bulk(None),
+        ])
+        .write_to(&mut out);
+        assert_eq!(
+            out,
+            b"*5\r\n+OK\r\n-ERR bad  command\r\n:-2\r\n$5\r\nvalue\r\n$-1\r\n"
+        );
+    }
+}
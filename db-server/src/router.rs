@@ -1,43 +1,270 @@
-use anyhow::{Context, Result};
 use axum::{
-    extract::MatchedPath,
+    extract::{DefaultBodyLimit, MatchedPath},
     http::Request,
-    routing::{delete, get, post},
+    middleware,
+    routing::{delete, get, post, put},
     Router,
 };
-use tower_http::trace::TraceLayer;
-
-use crate::{app_state::AppState, handlers::prelude::*};
-
-pub async fn create() -> Result<Router> {
-    let api_state = AppState::new().await.context("create API AppState")?;
-
-    let router = Router::new().merge(api_router(api_state)).layer(
-        TraceLayer::new_for_http().make_span_with(|request: &Request<_>| {
-            // Log the matched route's path (with placeholders not filled in).
-            // Use request.uri() or OriginalUri if you want the real path.
-            let matched_path = request
-                .extensions()
-                .get::<MatchedPath>()
-                .map(MatchedPath::as_str);
-
-            tracing::info_span!(
-                "http_request",
-                method = ?request.method(),
-                matched_path,
-                some_other_field = tracing::field::Empty,
-            )
-        }),
-    );
+use std::time::Duration;
+
+use axum::{
+    body::{Body, HttpBody},
+    error_handling::HandleErrorLayer,
+    response::Response,
+    BoxError,
+};
+use futures_util::stream;
+use tower::{timeout::TimeoutLayer, ServiceBuilder};
+use tower_http::{
+    compression::{
+        predicate::{NotForContentType, SizeAbove},
+        CompressionLayer, Predicate,
+    },
+    decompression::{DecompressionBody, RequestDecompressionLayer},
+    map_request_body::MapRequestBodyLayer,
+    trace::TraceLayer,
+};
+use tracing::Span;
+
+use crate::{
+    app_error::AppError,
+    app_state::{AdminState, AppState},
+    handlers::prelude::*,
+    rate_limit::{rate_limit_middleware, RateLimits},
+    request_id::{request_id_middleware, REQUEST_ID_HEADER},
+    timeout::{count_errors_middleware, handle_timeout_error},
+};
 
-    Ok(router)
+pub fn create(api_state: AppState) -> Router {
+    let (compress_from, cors) = (api_state.compress_from, api_state.cors.clone());
+    let mut router = api_router(api_state);
+    if let Some(min_size) = compress_from {
+        router = with_compression(router, min_size);
+    }
+    // outside of the routes, so the preflights skip the rate limits and the 405 of `OPTIONS`
+    if let Some(cors) = cors {
+        router = router.layer(cors);
+    }
+    with_tracing(router)
 }
 
-fn api_router(state: AppState) -> Router {
+/// Compress the responses of at least `min_size` bytes for the clients accepting gzip or
+/// deflate, and decompress the request bodies sent with either. The body limits of the routes
+/// count the decompressed bytes, so a small compressed body cannot expand past them.
+fn with_compression(router: Router, min_size: u16) -> Router {
+    let predicate = SizeAbove::new(min_size)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        // the events of the watchers go out as they come
+        .and(NotForContentType::const_new("text/event-stream"));
+    router.layer(
+        ServiceBuilder::new()
+            .layer(CompressionLayer::new().compress_when(predicate))
+            .layer(HandleErrorLayer::new(|e: BoxError| async move {
+                AppError::Internal(anyhow::anyhow!(e))
+            }))
+            .layer(RequestDecompressionLayer::new())
+            // back to the body of the routes, read as the extractors pull it
+            .layer(MapRequestBodyLayer::new(|body: DecompressionBody<Body>| {
+                let chunks = stream::unfold(Box::pin(body), |mut body| async move {
+                    body.data().await.map(|chunk| (chunk, body))
+                });
+                Body::wrap_stream(chunks)
+            })),
+    )
+}
+
+/// The admin routes, apart from the API so they can be served on another address or behind
+/// an auth layer.
+pub fn create_admin(admin_state: AdminState) -> Router {
+    let rate_limit =
+        middleware::from_fn_with_state(admin_state.rate_limit.clone(), rate_limit_middleware);
+    with_tracing(
+        Router::new()
+            .route("/admin/compact", post(compact_handler))
+            .route("/admin/export", get(export_handler))
+            .route("/admin/flush", post(flush_handler))
+            .route("/admin/import", post(import_handler))
+            .route("/admin/namespaces/:name", put(create_namespace_handler))
+            .route("/admin/replication", get(replication_handler))
+            .route("/admin/stats", get(stats_handler))
+            .route_layer(rate_limit)
+            .with_state(admin_state)
+            .fallback(not_found_handler),
+    )
+}
+
+fn with_tracing(router: Router) -> Router {
     Router::new()
-        .route("/api/entry/:key", get(get_handler))
-        .route("/api/entry/:key", post(set_handler))
-        .route("/api/entry/:key", delete(delete_handler))
+        .merge(router)
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request<_>| {
+                    // The matched route's path (with placeholders not filled in) next to the
+                    // real path, to group the requests by route.
+                    let matched_path = request
+                        .extensions()
+                        .get::<MatchedPath>()
+                        .map(MatchedPath::as_str);
+                    let request_id = request
+                        .headers()
+                        .get(&REQUEST_ID_HEADER)
+                        .and_then(|value| value.to_str().ok());
+
+                    tracing::info_span!(
+                        "http_request",
+                        method = ?request.method(),
+                        path = request.uri().path(),
+                        matched_path,
+                        request_id,
+                        some_other_field = tracing::field::Empty,
+                    )
+                })
+                // the access log, a line per request with the method and the path of its span
+                .on_response(|response: &Response, latency: Duration, _span: &Span| {
+                    tracing::info!(
+                        status = response.status().as_u16(),
+                        latency_ms = latency.as_secs_f64() * 1000.0,
+                        "Response sent"
+                    )
+                }),
+        )
+        // outside of the tracing, so its span has the id
+        .layer(middleware::from_fn(request_id_middleware))
+}
+
+fn api_router(state: AppState) -> Router {
+    let RateLimits { reads, writes, .. } = state.rate_limits.clone();
+    let reads = middleware::from_fn_with_state(reads, rate_limit_middleware);
+    let writes = middleware::from_fn_with_state(writes, rate_limit_middleware);
+    let max_value_size = DefaultBodyLimit::max(state.max_value_size);
+    // the default database, then the namespaces, see `NamespaceDb`
+    let mut router = Router::new();
+    for prefix in ["/api", "/api/:namespace"] {
+        let entry = format!("{}/entry/:key", prefix);
+        router = router
+            .route(&entry, get(get_handler).route_layer(reads.clone()))
+            .route(
+                &entry,
+                post(set_handler)
+                    .route_layer(writes.clone())
+                    .layer(max_value_size.clone()),
+            )
+            .route(&entry, delete(delete_handler).route_layer(writes.clone()))
+            .route(
+                &format!("{}/entries", prefix),
+                get(list_handler).route_layer(reads.clone()),
+            )
+            .route(
+                &format!("{}/watch", prefix),
+                get(watch_handler).route_layer(reads.clone()),
+            );
+    }
+    let (request_timeout, error_counts) = (state.request_timeout, state.error_counts.clone());
+    router
         .with_state(state)
         .fallback(not_found_handler)
+        // a stuck disk answers 503 instead of hanging the request, the writes run to their end
+        // anyway, see `timeout::write_within`
+        .layer(
+            ServiceBuilder::new()
+                .layer(middleware::from_fn_with_state(
+                    error_counts,
+                    count_errors_middleware,
+                ))
+                .layer(HandleErrorLayer::new(handle_timeout_error))
+                .layer(TimeoutLayer::new(request_timeout)),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use async_compression::tokio::{bufread::GzipDecoder, write::GzipEncoder};
+    use axum::http::{header, Method, StatusCode};
+    use tempdir::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::handlers::test_client::{send_to, test_state};
+
+    async fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+        let mut encoder = GzipEncoder::new(Vec::new());
+        encoder.write_all(data).await?;
+        encoder.shutdown().await?;
+        Ok(encoder.into_inner())
+    }
+
+    async fn get(router: &Router, uri: &str, accept_encoding: &str) -> Result<Response> {
+        let request = Request::get(uri)
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(Body::empty())?;
+        Ok(router.clone().oneshot(request).await?)
+    }
+
+    #[tokio::test]
+    async fn it_compresses_the_responses_and_decompresses_the_requests() -> Result<()> {
+        let tmpdir = TempDir::new("compression_test")?;
+        let mut state = test_state(tmpdir.path()).await?;
+        state.compress_from = Some(1024);
+        state.max_value_size = 64 * 1024;
+        let document = format!("[{}0]", "{\"field\":\"value\"},".repeat(500));
+        {
+            let mut db = state.db.write().await;
+            db.set_typed(b"doc", document.as_bytes(), "application/octet-stream")
+                .await?;
+            db.set_typed(b"small", b"tiny", "application/octet-stream")
+                .await?;
+        }
+        let router = create(state.clone());
+
+        let response = get(&router, "/api/entry/doc", "gzip").await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        assert!(body.len() < document.len());
+        let mut decoded = String::new();
+        GzipDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .await?;
+        assert_eq!(decoded, document);
+
+        // as is for the other clients and under the minimum size
+        for (uri, accept_encoding, len) in [
+            ("/api/entry/doc", "identity", document.len()),
+            ("/api/entry/small", "gzip", 4),
+        ] {
+            let response = get(&router, uri, accept_encoding).await?;
+            assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+            assert_eq!(response.headers()[header::CONTENT_LENGTH], len.to_string());
+        }
+        let response = get(&router, "/api/watch", "gzip").await?;
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+
+        let send_gzip = |uri: &str, encoding: &str, body: Vec<u8>| {
+            let request = Request::post(uri)
+                .header(header::CONTENT_ENCODING, encoding)
+                .body(Body::from(body))
+                .unwrap();
+            router.clone().oneshot(request)
+        };
+        let response = send_gzip("/api/entry/hello", "gzip", gzip(b"world").await?).await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let (_, entry) = send_to(&router, Method::GET, "/api/entry/hello", "").await?;
+        assert_eq!(entry["value"], "world");
+
+        // a small body expanding past the limit stops at the limit
+        let bomb = gzip(&vec![b'a'; 1024 * 1024]).await?;
+        assert!(bomb.len() < 64 * 1024);
+        let response = send_gzip("/api/entry/bomb", "gzip", bomb).await?;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(state.db.read().await.get(b"bomb").await.is_none());
+        let response = send_gzip("/api/entry/hello", "br", b"world".to_vec()).await?;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        tmpdir.close()?;
+        Ok(())
+    }
 }
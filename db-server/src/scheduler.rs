@@ -1,37 +1,478 @@
-use std::path::PathBuf;
+use anyhow::Result;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use db_engine::Compaction;
+use db_engine::{
+    CleanupReport, CompactionFilter, CompactionReport, Entry, Error, FilterDecision, WriteAheadLog,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::MissedTickBehavior,
+};
+
+use crate::{config::Config, db_handle::DbHandle, namespaces::Namespaces};
+
+/// Drops the entries written longer than `max_age` ago.
+struct MaxAgeFilter {
+    max_age: Duration,
+}
+
+impl CompactionFilter for MaxAgeFilter {
+    fn decide(&self, entry: &Entry) -> FilterDecision {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        match now.saturating_sub(entry.timestamp) > self.max_age.as_micros() {
+            true => FilterDecision::Drop,
+            false => FilterDecision::Keep,
+        }
+    }
+}
+
+/// When and how the [`Scheduler`] compacts.
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+    pub interval: Duration,
+    /// Up to that much added at random to each `interval`
+    pub jitter: Duration,
+    /// Size under which the SSTables get compacted
+    pub compact_limit: u64,
+    /// `false` to only compact on [`SchedulerHandle::trigger_now`] and [`SchedulerHandle::compact`]
+    pub enabled: bool,
+    /// Bytes per second a compaction may read and write, 0 for no limit
+    pub throttle_bytes_per_sec: u64,
+    /// Age past which a compaction drops an entry
+    pub max_age: Option<Duration>,
+    /// Time between two housekeeping passes, `None` for none
+    pub housekeeping_interval: Option<Duration>,
+    /// Age past which the housekeeping removes an archived WAL file
+    pub wal_archive_max_age: Duration,
+}
+
+impl From<&Config> for SchedulerConfig {
+    fn from(config: &Config) -> Self {
+        Self {
+            interval: config.compaction_interval(),
+            jitter: config.compaction_jitter(),
+            compact_limit: config.compaction_limit,
+            enabled: config.compaction_enabled,
+            throttle_bytes_per_sec: config.compaction_throttle_bytes_per_sec,
+            max_age: config.compaction_max_age_secs.map(Duration::from_secs),
+            housekeeping_interval: config.housekeeping_interval(),
+            wal_archive_max_age: Duration::from_secs(config.wal_archive_max_age_secs),
+        }
+    }
+}
+
+/// The last compaction and housekeeping pass of the [`Scheduler`], on a tick or on demand.
+#[derive(Debug, Clone, Default)]
+pub struct SchedulerStatus {
+    pub last_run: Option<SystemTime>,
+    pub last_duration: Option<Duration>,
+    /// `None` until a compaction succeeds
+    pub last_report: Option<CompactionReport>,
+    /// The error of the last compaction, `None` once one succeeds again
+    pub last_error: Option<String>,
+    /// Ticks skipped because a compaction was still running
+    pub skipped_ticks: u64,
+    pub last_housekeeping: Option<SystemTime>,
+    /// Files removed by the last housekeeping pass, of the database and the open namespaces
+    pub last_cleanup: Option<CleanupReport>,
+    /// The errors of the last housekeeping pass, `None` once one succeeds again
+    pub last_housekeeping_error: Option<String>,
+}
+
+enum Command {
+    Trigger,
+    Housekeep,
+    /// Stop the loop, then acknowledge
+    Shutdown(oneshot::Sender<()>),
+}
 
 pub struct Scheduler {
     db_dir_path: PathBuf,
-    compact_limit: u64,
-    file_ext: String,
+    config: SchedulerConfig,
+    db: DbHandle,
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Compacted after the database, the ones open at the time
+    namespaces: Option<Namespaces>,
+    /// Set for the length of a compaction of this scheduler, on a tick or on demand
+    compacting: AtomicBool,
+    status: Mutex<SchedulerStatus>,
+}
+
+/// Controls a spawned [`Scheduler`], cheap to clone.
+#[derive(Clone)]
+pub struct SchedulerHandle {
+    commands: mpsc::Sender<Command>,
+    scheduler: Arc<Scheduler>,
 }
 
 impl Scheduler {
-    pub fn new(db_dir: &str, compact_limit: u64) -> Self {
+    /// Compact `db`, the database in `db_dir_path`, as the `config` says and remove its orphan
+    /// files on every housekeeping pass
+    pub fn new(db_dir_path: PathBuf, config: SchedulerConfig, db: DbHandle) -> Self {
+        let compaction_filter = config
+            .max_age
+            .map(|max_age| Arc::new(MaxAgeFilter { max_age }) as Arc<dyn CompactionFilter>);
         Self {
-            db_dir_path: PathBuf::from(db_dir),
-            compact_limit,
-            file_ext: "db".to_string(),
+            db_dir_path,
+            config,
+            db,
+            compaction_filter,
+            namespaces: None,
+            compacting: AtomicBool::new(false),
+            status: Mutex::default(),
+        }
+    }
+
+    /// Also compact the databases of the `namespaces` open by then, on every compaction
+    pub fn with_namespaces(mut self, namespaces: Namespaces) -> Self {
+        self.namespaces = Some(namespaces);
+        self
+    }
+
+    /// Run the loop of the scheduler in a new task, until [`SchedulerHandle::shutdown`]
+    pub fn spawn(self) -> SchedulerHandle {
+        let (commands, receiver) = mpsc::channel(1);
+        let scheduler = Arc::new(self);
+        tokio::spawn(Arc::clone(&scheduler).run(receiver));
+        SchedulerHandle {
+            commands,
+            scheduler,
         }
     }
 
-    pub async fn perform(&self) {
+    /// Compact on every tick and on every trigger, clean up on every housekeeping tick. A
+    /// compaction or a housekeeping pass in progress runs to its end, the commands arriving
+    /// meanwhile wait for it.
+    async fn run(self: Arc<Self>, mut commands: mpsc::Receiver<Command>) {
         tracing::info!("Start scheduler to compact the database");
+        // unlike the compaction delay, not reset by the other commands
+        let mut housekeeping = self.config.housekeeping_interval.map(|period| {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
 
         loop {
-            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
-
-            tracing::info!("Start compacting the database");
-            let db_compaction = Compaction::new(
-                self.db_dir_path.clone(),
-                self.compact_limit,
-                self.file_ext.as_str(),
-            );
-            if let Err(e) = db_compaction.compact().await {
-                tracing::error!("Error while compacting: {}", e);
+            let tick = async {
+                match self.config.enabled {
+                    true => tokio::time::sleep(self.next_delay()).await,
+                    false => std::future::pending().await,
+                }
+            };
+            let housekeeping_tick = async {
+                match housekeeping.as_mut() {
+                    Some(interval) => {
+                        interval.tick().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            let command = tokio::select! {
+                _ = tick => Command::Trigger,
+                _ = housekeeping_tick => Command::Housekeep,
+                command = commands.recv() => command.unwrap_or_else(|| {
+                    // every handle is gone, nobody waits for the acknowledgement
+                    Command::Shutdown(oneshot::channel().0)
+                }),
+            };
+            match command {
+                Command::Trigger => self.tick().await,
+                Command::Housekeep => {
+                    let report = self.housekeep().await;
+                    tracing::info!("Housekeeping report: {:?}", report);
+                }
+                Command::Shutdown(ack) => {
+                    tracing::info!("Stop scheduler");
+                    // the databases are released by the time the shutdown is acknowledged
+                    drop(self);
+                    let _ = ack.send(());
+                    return;
+                }
+            }
+        }
+    }
+
+    fn next_delay(&self) -> Duration {
+        let jitter = self.config.jitter.as_millis() as u64;
+        self.config.interval + Duration::from_millis(fastrand::u64(0..=jitter))
+    }
+
+    async fn tick(&self) {
+        tracing::info!("Start compacting the database");
+        match self.compact().await {
+            Ok(report) => tracing::info!("Compaction report: {:?}", report),
+            Err(e) if matches!(e.downcast_ref(), Some(Error::CompactionInProgress(_))) => {
+                tracing::warn!("Skip compacting, the previous compaction is still running");
+                self.status.lock().unwrap().skipped_ticks += 1;
+            }
+            Err(e) => tracing::error!("Error while compacting: {}", e),
+        }
+    }
+
+    /// Compact the database right away, like every tick does, then the open namespaces. Fails
+    /// with [`Error::CompactionInProgress`] while another compaction is running, of this
+    /// scheduler or of another process. The report is the one of the database, the failures
+    /// of the namespaces only show in the status.
+    async fn compact(&self) -> Result<CompactionReport> {
+        if self.compacting.swap(true, Ordering::AcqRel) {
+            return Err(Error::CompactionInProgress(self.db_dir_path.clone()).into());
+        }
+        let started_at = Instant::now();
+        let result = self.compact_db(&self.db).await;
+        let namespace_errors = self.compact_namespaces().await;
+        self.compacting.store(false, Ordering::Release);
+
+        let mut status = self.status.lock().unwrap();
+        status.last_run = Some(SystemTime::now());
+        status.last_duration = Some(started_at.elapsed());
+        match result.as_ref() {
+            Ok(report) => {
+                status.last_report = Some(report.clone());
+                status.last_error =
+                    (!namespace_errors.is_empty()).then(|| namespace_errors.join("; "));
+            }
+            Err(e) => status.last_error = Some(format!("{:#}", e)),
+        }
+        result
+    }
+
+    /// Compact `db` like its own compactions, through the filter of the scheduler as well
+    async fn compact_db(&self, db: &DbHandle) -> Result<CompactionReport> {
+        db.read()
+            .await
+            .compact_with(
+                self.config.compact_limit,
+                self.compaction_filter.clone(),
+                self.config.throttle_bytes_per_sec,
+            )
+            .await
+    }
+
+    /// Compact every open namespace, a failing one does not stop the others. Returns the
+    /// errors.
+    async fn compact_namespaces(&self) -> Vec<String> {
+        let Some(namespaces) = self.namespaces.as_ref() else {
+            return Vec::new();
+        };
+        let mut errors = Vec::new();
+        for (name, db) in namespaces.open_namespaces().await {
+            match self.compact_db(&db).await {
+                Ok(report) => {
+                    tracing::info!("Compaction report of namespace {}: {:?}", name, report)
+                }
+                Err(e) => {
+                    tracing::error!("Error while compacting namespace {}: {:#}", name, e);
+                    errors.push(format!("namespace {}: {:#}", name, e));
+                }
+            }
+        }
+        errors
+    }
+
+    /// Remove the files a crash left behind and the archived WAL files older than
+    /// `wal_archive_max_age`, of the database then of the open namespaces. A failing database
+    /// does not stop the others, the errors only show in the status. The engine never removes
+    /// the files modified in the last [`db_engine::MIN_CLEANUP_AGE`].
+    async fn housekeep(&self) -> CleanupReport {
+        let mut report = CleanupReport::default();
+        let mut errors = Vec::new();
+        match self.cleanup(&self.db_dir_path, &self.db).await {
+            Ok(removed) => report.add(&removed),
+            Err(e) => {
+                tracing::error!("Error while cleaning up the database: {:#}", e);
+                errors.push(format!("{:#}", e));
             }
         }
+        let namespaces = match self.namespaces.as_ref() {
+            Some(namespaces) => namespaces.open_namespaces().await,
+            None => Vec::new(),
+        };
+        for (name, db) in namespaces {
+            let dir = db.read().await.dir().to_path_buf();
+            match self.cleanup(&dir, &db).await {
+                Ok(removed) => report.add(&removed),
+                Err(e) => {
+                    tracing::error!("Error while cleaning up namespace {}: {:#}", name, e);
+                    errors.push(format!("namespace {}: {:#}", name, e));
+                }
+            }
+        }
+
+        let mut status = self.status.lock().unwrap();
+        status.last_housekeeping = Some(SystemTime::now());
+        status.last_cleanup = Some(report.clone());
+        status.last_housekeeping_error = (!errors.is_empty()).then(|| errors.join("; "));
+        report
+    }
+
+    async fn cleanup(&self, dir: &Path, db: &DbHandle) -> Result<CleanupReport> {
+        let mut report = db.read().await.cleanup_orphans().await?;
+        report.add(&WriteAheadLog::prune_archive(dir, self.config.wal_archive_max_age).await?);
+        Ok(report)
+    }
+}
+
+impl SchedulerHandle {
+    /// Compact at once instead of at the next tick, without waiting for the compaction. The
+    /// trigger is skipped if a compaction is running by then.
+    pub fn trigger_now(&self) {
+        // a full channel already holds a command the loop is about to run
+        let _ = self.commands.try_send(Command::Trigger);
+    }
+
+    /// Stop the loop, after the compaction in progress if any. Returns at once when the loop
+    /// is already stopped.
+    pub async fn shutdown(&self) {
+        let (ack, acked) = oneshot::channel();
+        if self.commands.send(Command::Shutdown(ack)).await.is_ok() {
+            let _ = acked.await;
+        }
+    }
+
+    /// Compact the database now and wait for the report, see [`SchedulerHandle::trigger_now`]
+    /// to only start it
+    pub async fn compact(&self) -> Result<CompactionReport> {
+        self.scheduler.compact().await
+    }
+
+    /// The last compaction and housekeeping pass, updated after each one
+    pub fn status(&self) -> SchedulerStatus {
+        self.scheduler.status.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::handlers::test_client::test_state;
+
+    fn test_config(enabled: bool) -> SchedulerConfig {
+        SchedulerConfig {
+            enabled,
+            compact_limit: u64::MAX,
+            ..SchedulerConfig::from(&Config::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn it_stops_between_two_compactions_on_shutdown() -> Result<()> {
+        let tmpdir = TempDir::new("scheduler_test")?;
+        let state = test_state(tmpdir.path()).await?;
+        let scheduler = Scheduler::new(
+            tmpdir.path().to_path_buf(),
+            test_config(true),
+            state.db.clone(),
+        );
+
+        let handle = scheduler.spawn();
+        tokio::time::timeout(Duration::from_secs(1), handle.shutdown()).await?;
+        assert!(handle.status().last_run.is_none());
+        // a stopped scheduler ignores the commands
+        handle.trigger_now();
+        tokio::time::timeout(Duration::from_secs(1), handle.shutdown()).await?;
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_compacts_when_triggered_and_skips_while_compacting() -> Result<()> {
+        let tmpdir = TempDir::new("scheduler_trigger_test")?;
+        let state = test_state(tmpdir.path()).await?;
+        for key in ["a", "b"] {
+            let mut db = state.db.write().await;
+            db.set(key.as_bytes(), b"value").await?;
+            db.flush().await?;
+        }
+        let scheduler = Scheduler::new(
+            tmpdir.path().to_path_buf(),
+            test_config(false),
+            state.db.clone(),
+        );
+        let handle = scheduler.spawn();
+
+        // the commands run in order, the trigger is done once the shutdown is acknowledged
+        handle.trigger_now();
+        handle.shutdown().await;
+        let status = handle.status();
+        assert!(status.last_run.is_some() && status.last_duration.is_some());
+        assert_eq!(status.last_report.unwrap().input_files, 2);
+        assert_eq!(status.skipped_ticks, 0);
+
+        let scheduler = Scheduler::new(
+            tmpdir.path().to_path_buf(),
+            test_config(false),
+            state.db.clone(),
+        );
+        scheduler.compacting.store(true, Ordering::Release);
+        let handle = scheduler.spawn();
+        handle.trigger_now();
+        handle.shutdown().await;
+        let status = handle.status();
+        assert_eq!(status.skipped_ticks, 1);
+        assert!(status.last_run.is_none());
+        assert!(matches!(
+            handle.compact().await.unwrap_err().downcast_ref(),
+            Some(Error::CompactionInProgress(_))
+        ));
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_removes_the_old_orphans_and_archived_wal_files_when_housekeeping() -> Result<()> {
+        let tmpdir = TempDir::new("scheduler_housekeeping_test")?;
+        let dir = tmpdir.path();
+        let state = test_state(dir).await?;
+        let archive = dir.join("cdc");
+        std::fs::create_dir_all(&archive)?;
+        let old = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        for (path, modified) in [
+            (dir.join("1.db.tmp"), old),
+            (dir.join("2.db.tmp"), SystemTime::now()),
+            (archive.join("100-1.wal"), old),
+        ] {
+            std::fs::write(&path, b"orphan")?;
+            std::fs::File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(modified)?;
+        }
+
+        let scheduler = Scheduler::new(dir.to_path_buf(), test_config(false), state.db.clone())
+            .with_namespaces(state.namespaces.clone());
+        let report = scheduler.housekeep().await;
+        assert_eq!(
+            report,
+            CleanupReport {
+                tmp_files_removed: 1,
+                orphaned_files_removed: 0,
+                archived_wal_files_removed: 1,
+                bytes_freed: 12,
+            }
+        );
+        assert!(!dir.join("1.db.tmp").exists() && dir.join("2.db.tmp").exists());
+        let status = scheduler.status.lock().unwrap().clone();
+        assert!(status.last_housekeeping.is_some() && status.last_housekeeping_error.is_none());
+        assert_eq!(status.last_cleanup, Some(report));
+        assert!(status.last_run.is_none());
+
+        tmpdir.close()?;
+        Ok(())
     }
 }
@@ -0,0 +1,189 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+
+use db_engine::Database;
+use tokio::sync::OwnedRwLockWriteGuard;
+
+use crate::{app_error::AppError, db_handle::DbHandle};
+
+/// Marks the responses of the requests which ran out of time, see [`AppError::Timeout`]
+#[derive(Clone, Copy)]
+pub struct TimedOut;
+
+/// The API responses with a 5xx since the start, the timeouts apart from the other errors.
+/// Cheap to clone, the clones count together.
+#[derive(Clone, Default)]
+pub struct ErrorCounts(Arc<Counts>);
+
+#[derive(Default)]
+struct Counts {
+    timeouts: AtomicU64,
+    server_errors: AtomicU64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ErrorCountsSnapshot {
+    pub timeouts: u64,
+    /// The 5xx other than the timeouts
+    pub server_errors: u64,
+}
+
+impl ErrorCounts {
+    pub fn snapshot(&self) -> ErrorCountsSnapshot {
+        ErrorCountsSnapshot {
+            timeouts: self.0.timeouts.load(Ordering::Relaxed),
+            server_errors: self.0.server_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Count the responses with a 5xx in `counts`
+pub async fn count_errors_middleware<B>(
+    State(counts): State<ErrorCounts>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let response = next.run(request).await;
+    if response.extensions().get::<TimedOut>().is_some() {
+        counts.0.timeouts.fetch_add(1, Ordering::Relaxed);
+    } else if response.status().is_server_error() {
+        counts.0.server_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    response
+}
+
+/// The error of the `TimeoutLayer` of the API routes, or of a layer below it
+pub async fn handle_timeout_error(e: BoxError) -> Response {
+    match e.is::<tower::timeout::error::Elapsed>() {
+        true => AppError::Timeout.into_response(),
+        false => AppError::Internal(anyhow::anyhow!(e)).into_response(),
+    }
+}
+
+/// Write to `db` with `operation`, or answer [`AppError::Timeout`] after `timeout`. The engine
+/// must never stop in the middle of a write or a flush: once it holds the lock, `operation`
+/// runs to its end in a task of its own and only the wait for it is given up. A timed out
+/// write may still be applied.
+pub async fn write_within<T, F>(
+    timeout: Duration,
+    db: &DbHandle,
+    operation: impl FnOnce(OwnedRwLockWriteGuard<Database>) -> F,
+) -> Result<T, AppError>
+where
+    F: Future<Output = Result<T, AppError>> + Send + 'static,
+    T: Send + 'static,
+{
+    // dropped at any point by the `TimeoutLayer` as well, which leaves the task running
+    let write = async {
+        let db = db.write_owned().await;
+        tokio::spawn(operation(db)).await
+    };
+    match tokio::time::timeout(timeout, write).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => Err(AppError::Internal(e.into())),
+        Err(_) => Err(AppError::Timeout),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::{Method, StatusCode};
+    use db_engine::DatabaseBuilder;
+    use tempdir::TempDir;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::handlers::test_client::{send, test_state};
+
+    #[tokio::test]
+    async fn it_answers_503_when_the_database_is_stuck() -> Result<()> {
+        let tmpdir = TempDir::new("timeout_test")?;
+        let mut state = test_state(tmpdir.path()).await?;
+        state.request_timeout = Duration::from_millis(50);
+
+        // a write stuck on the disk, holding the lock
+        let stuck = state.db.write().await;
+        for (method, body) in [(Method::POST, "value"), (Method::GET, "")] {
+            let (status, error) = send(&state, method, "/api/entry/key", body).await?;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(error["error"], "timeout");
+        }
+        drop(stuck);
+
+        // the timed out write gave up on the lock
+        let (status, _) = send(&state, Method::POST, "/api/entry/key", "value").await?;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            state.error_counts.snapshot(),
+            ErrorCountsSnapshot {
+                timeouts: 2,
+                server_errors: 0,
+            }
+        );
+
+        tmpdir.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_completes_the_timed_out_writes_and_flushes() -> Result<()> {
+        let tmpdir = TempDir::new("timeout_test")?;
+        let dir = tmpdir.path().to_path_buf();
+        let db = DatabaseBuilder::new(dir.clone())
+            .max_mem_table_size(1024 * 1024)
+            .build()
+            .await?;
+        let db = DbHandle::new(db);
+
+        // past the WAL buffer, each one fills the MemTable and flushes it
+        let values = (0..3)
+            .map(|i| vec![b'a' + i; 1536 * 1024])
+            .collect::<Vec<_>>();
+        for (i, value) in values.iter().enumerate() {
+            let written = value.clone();
+            let (resume, resumed) = oneshot::channel();
+            let result = write_within(Duration::from_millis(10), &db, move |mut db| async move {
+                // still waiting when the request gives up
+                resumed.await.ok();
+                db.set(i.to_string().as_bytes(), &written).await?;
+                Ok(())
+            })
+            .await;
+            assert!(matches!(result, Err(AppError::Timeout)));
+            resume.send(()).unwrap();
+
+            // the lock is taken once the write is done
+            let db = db.write().await;
+            assert_eq!(
+                &db.get(i.to_string().as_bytes()).await.unwrap().value,
+                value
+            );
+        }
+        db.write().await.wait_for_flush().await?;
+        drop(db);
+
+        let db = DatabaseBuilder::new(dir).build().await?;
+        for (i, value) in values.iter().enumerate() {
+            let entry = db.get(i.to_string().as_bytes()).await.unwrap();
+            assert_eq!(&entry.value, value);
+        }
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}